@@ -0,0 +1,230 @@
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use base64::engine::general_purpose::{STANDARD, URL_SAFE_NO_PAD};
+use base64::Engine;
+use hmac::{Hmac, Mac};
+use serde::Deserialize;
+use sha1::Sha1;
+use sha2::Sha256;
+
+/// Channel token verification. Tokens are HS256 JWTs signed with a shared
+/// secret whose `channel` claim names the channel they grant access to, or
+/// `*` for every channel, with an optional `exp` expiration.
+#[derive(Clone, Default)]
+pub struct Config {
+    /// Tokens are not required when there is no secret
+    pub secret: Option<Vec<u8>>,
+}
+
+#[derive(Deserialize)]
+struct Header {
+    alg: String,
+}
+
+#[derive(Deserialize)]
+struct Claims {
+    channel: String,
+    exp: Option<u64>,
+}
+
+impl Config {
+    pub fn new(secret: Option<String>) -> Self {
+        Self {
+            secret: secret.map(String::into_bytes),
+        }
+    }
+
+    /// Checks that a token grants access to a channel.
+    pub fn verify(&self, token: Option<&str>, channel: &str) -> Result<(), anyhow::Error> {
+        let secret = match &self.secret {
+            Some(secret) => secret,
+            None => return Ok(()),
+        };
+        let token = token.ok_or_else(|| anyhow::anyhow!("missing token"))?;
+
+        let mut parts = token.split('.');
+        let (header, claims, signature) = match (parts.next(), parts.next(), parts.next()) {
+            (Some(header), Some(claims), Some(signature)) if parts.next().is_none() => {
+                (header, claims, signature)
+            }
+            _ => anyhow::bail!("malformed token"),
+        };
+
+        let header: Header = serde_json::from_slice(&URL_SAFE_NO_PAD.decode(header)?)?;
+        if header.alg != "HS256" {
+            anyhow::bail!("unsupported token algorithm {}", header.alg);
+        }
+        let mut mac = Hmac::<Sha256>::new_from_slice(secret)?;
+        mac.update(&token.as_bytes()[..signed_len(token)]);
+        mac.verify_slice(&URL_SAFE_NO_PAD.decode(signature)?)
+            .map_err(|_| anyhow::anyhow!("invalid token signature"))?;
+
+        let claims: Claims = serde_json::from_slice(&URL_SAFE_NO_PAD.decode(claims)?)?;
+        if claims.channel != "*" && claims.channel != channel {
+            anyhow::bail!("token not valid for channel {}", channel);
+        }
+        if let Some(exp) = claims.exp {
+            let now = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
+            if exp <= now {
+                anyhow::bail!("expired token");
+            }
+        }
+        Ok(())
+    }
+
+    /// Time-limited TURN credentials, as in the TURN REST API: the username
+    /// is the expiration timestamp and the password its HMAC-SHA1.
+    pub fn turn_credentials(&self, ttl: Duration) -> Option<(String, String)> {
+        let secret = self.secret.as_ref()?;
+        let exp = SystemTime::now().duration_since(UNIX_EPOCH).ok()? + ttl;
+        let username = exp.as_secs().to_string();
+        let password = turn_password(secret, &username);
+        Some((username, password))
+    }
+
+    /// Password of TURN credentials that did not expire, the username may
+    /// carry a `:` separated suffix after the timestamp.
+    pub fn turn_password(&self, username: &str) -> Result<String, anyhow::Error> {
+        let secret = match &self.secret {
+            Some(secret) => secret,
+            None => anyhow::bail!("no secret"),
+        };
+        let exp: u64 = username.split(':').next().unwrap_or_default().parse()?;
+        let now = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
+        if exp <= now {
+            anyhow::bail!("expired credentials");
+        }
+        Ok(turn_password(secret, username))
+    }
+}
+
+fn turn_password(secret: &[u8], username: &str) -> String {
+    let mut mac = Hmac::<Sha1>::new_from_slice(secret).expect("hmac accepts any key");
+    mac.update(username.as_bytes());
+    STANDARD.encode(mac.finalize().into_bytes())
+}
+
+/// Length of the signed part of a token, the header and the claims.
+fn signed_len(token: &str) -> usize {
+    token.rfind('.').unwrap_or(token.len())
+}
+
+/// Extracts the token of an `Authorization: Bearer` header value.
+pub fn bearer(authorization: Option<&str>) -> Option<&str> {
+    let (scheme, token) = authorization?.trim().split_once(' ')?;
+    scheme.eq_ignore_ascii_case("bearer").then(|| token.trim())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config() -> Config {
+        Config::new(Some("secret".to_string()))
+    }
+
+    fn token(secret: &str, header: &str, claims: &str) -> String {
+        let signed = format!(
+            "{}.{}",
+            URL_SAFE_NO_PAD.encode(header),
+            URL_SAFE_NO_PAD.encode(claims)
+        );
+        let mut mac = Hmac::<Sha256>::new_from_slice(secret.as_bytes()).unwrap();
+        mac.update(signed.as_bytes());
+        let signature = URL_SAFE_NO_PAD.encode(mac.finalize().into_bytes());
+        format!("{}.{}", signed, signature)
+    }
+
+    fn hs256(claims: &str) -> String {
+        token("secret", r#"{"alg": "HS256", "typ": "JWT"}"#, claims)
+    }
+
+    #[test]
+    fn tokens() {
+        let config = config();
+        let demo = hs256(r#"{"channel": "demo"}"#);
+        assert!(config.verify(Some(&demo), "demo").is_ok());
+        assert!(config.verify(Some(&demo), "other").is_err());
+        assert!(config.verify(None, "demo").is_err());
+
+        let any = hs256(r#"{"channel": "*"}"#);
+        assert!(config.verify(Some(&any), "demo").is_ok());
+        assert!(config.verify(Some(&any), "other").is_ok());
+
+        let valid = hs256(r#"{"channel": "demo", "exp": 4102444800}"#);
+        assert!(config.verify(Some(&valid), "demo").is_ok());
+        let expired = hs256(r#"{"channel": "demo", "exp": 1000000000}"#);
+        assert!(config.verify(Some(&expired), "demo").is_err());
+
+        // Tokens are not needed without a secret
+        assert!(Config::default().verify(None, "demo").is_ok());
+        assert!(Config::default().verify(Some("invalid"), "demo").is_ok());
+    }
+
+    #[test]
+    fn invalid_tokens() {
+        let config = config();
+        let claims = r#"{"channel": "demo"}"#;
+        let invalid = [
+            token("other", r#"{"alg": "HS256"}"#, claims),
+            token("secret", r#"{"alg": "HS512"}"#, claims),
+            token("secret", r#"{"alg": "none"}"#, claims),
+            format!("{}.", hs256(claims).rsplit_once('.').unwrap().0),
+            format!("{}.e30", hs256(claims)),
+            hs256(claims).replacen('.', "", 1),
+            hs256(r#"{"exp": 4102444800}"#),
+            hs256("channel"),
+            "e30.e30.***".to_string(),
+            String::new(),
+        ];
+        for token in &invalid {
+            assert!(config.verify(Some(token), "demo").is_err(), "{}", token);
+        }
+
+        // The signature covers the claims
+        let demo = hs256(r#"{"channel": "demo"}"#);
+        let other = URL_SAFE_NO_PAD.encode(r#"{"channel": "other"}"#);
+        let mut parts: Vec<&str> = demo.split('.').collect();
+        parts[1] = &other;
+        assert!(config.verify(Some(&parts.join(".")), "other").is_err());
+    }
+
+    #[test]
+    fn bearer_headers() {
+        assert_eq!(bearer(Some("Bearer abc")), Some("abc"));
+        assert_eq!(bearer(Some(" bearer  abc ")), Some("abc"));
+        assert_eq!(bearer(Some("BEARER abc")), Some("abc"));
+        assert_eq!(bearer(Some("Basic abc")), None);
+        assert_eq!(bearer(Some("Bearer")), None);
+        assert_eq!(bearer(Some("Bearerabc")), None);
+        assert_eq!(bearer(Some("")), None);
+        assert_eq!(bearer(None), None);
+    }
+
+    #[test]
+    fn turn_credentials() {
+        let config = config();
+        let (username, password) = config.turn_credentials(Duration::from_secs(3600)).unwrap();
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs();
+        let exp: u64 = username.parse().unwrap();
+        assert!((now + 3599..=now + 3601).contains(&exp));
+        assert_eq!(config.turn_password(&username).unwrap(), password);
+
+        // Known HMAC-SHA1 of the username, with a suffix after the timestamp
+        assert_eq!(
+            config.turn_password("4102444800:alice").unwrap(),
+            "laQOyqtMLoReuc/kjT4h6rH+c9A="
+        );
+        assert!(config.turn_password("1000000000:alice").is_err());
+        assert!(config.turn_password("alice").is_err());
+        assert!(config.turn_password("").is_err());
+
+        assert!(Config::default()
+            .turn_credentials(Duration::from_secs(3600))
+            .is_none());
+        assert!(Config::default().turn_password("4102444800").is_err());
+    }
+}
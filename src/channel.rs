@@ -1,18 +1,71 @@
 use tokio::sync::broadcast;
 
+use crate::control;
+
 #[derive(Debug)]
 pub struct Channel {
     pub name: String,
     pub broadcast: broadcast::Sender<Vec<u8>>,
+    pub presence: broadcast::Sender<control::Presence>,
+    pub members: usize,
+    /// Whether transports may compress the messages of the channel
+    pub compression: bool,
     // pub connections: HashMap<String, Arc<Mutex<connection::Connection>>>,
 }
 
+/// Receivers of a channel, subscribed before a transport joins it.
+#[derive(Debug)]
+pub struct Subscription {
+    pub messages: broadcast::Receiver<Vec<u8>>,
+    pub presence: broadcast::Receiver<control::Presence>,
+}
+
+/// Channel just created, with receivers subscribed before any message was
+/// sent to it.
+#[derive(Debug)]
+pub struct Created {
+    pub name: String,
+    pub subscription: Subscription,
+}
+
 impl Channel {
     pub fn new(name: &str) -> Self {
         let (tx, _rx) = broadcast::channel::<Vec<u8>>(64);
+        let (presence, _rx) = broadcast::channel::<control::Presence>(16);
         Self {
             name: name.to_string(),
             broadcast: tx,
+            presence,
+            members: 0,
+            compression: true,
         }
     }
+
+    pub fn subscribe(&self) -> Subscription {
+        Subscription {
+            messages: self.broadcast.subscribe(),
+            presence: self.presence.subscribe(),
+        }
+    }
+
+    pub fn join(&mut self, session: u64) -> usize {
+        self.members += 1;
+        self.notify(session, control::PresenceEvent::Join);
+        self.members
+    }
+
+    pub fn leave(&mut self, session: u64) -> usize {
+        self.members = self.members.saturating_sub(1);
+        self.notify(session, control::PresenceEvent::Leave);
+        self.members
+    }
+
+    fn notify(&self, session: u64, event: control::PresenceEvent) {
+        let _ = self.presence.send(control::Presence {
+            channel: self.name.clone(),
+            session,
+            event,
+            members: self.members,
+        });
+    }
 }
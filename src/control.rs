@@ -0,0 +1,236 @@
+use serde::{Deserialize, Serialize};
+
+/// Control message sent by a client, as JSON in a WebSocket text frame or as
+/// a line on the WebTransport control stream.
+#[derive(Debug, Deserialize)]
+pub struct Request {
+    #[serde(default)]
+    pub id: Option<u64>,
+    #[serde(flatten)]
+    pub command: Command,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Command {
+    Join {
+        channel: String,
+    },
+    Leave {
+        channel: String,
+    },
+    Subscribe {
+        channel: String,
+        #[serde(default)]
+        tracks: Option<Vec<u32>>,
+    },
+    Ping,
+    /// Publishes `data` in the connection channel
+    Message {
+        #[serde(default)]
+        encoding: Option<Encoding>,
+        data: serde_json::Value,
+    },
+}
+
+/// Control message sent by the server, either as the answer to a request
+/// (echoing its id) or unsolicited like presence notifications.
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Response {
+    Joined {
+        #[serde(skip_serializing_if = "Option::is_none")]
+        id: Option<u64>,
+        channel: String,
+        members: usize,
+    },
+    Left {
+        #[serde(skip_serializing_if = "Option::is_none")]
+        id: Option<u64>,
+        channel: String,
+    },
+    Subscribed {
+        #[serde(skip_serializing_if = "Option::is_none")]
+        id: Option<u64>,
+        channel: String,
+        tracks: Option<Vec<u32>>,
+    },
+    Pong {
+        #[serde(skip_serializing_if = "Option::is_none")]
+        id: Option<u64>,
+        time: u64,
+    },
+    Error {
+        #[serde(skip_serializing_if = "Option::is_none")]
+        id: Option<u64>,
+        message: String,
+    },
+    Presence(Presence),
+    /// Nonce a UDP source echoes in its registration, proving it receives
+    /// what is sent to its address
+    Challenge {
+        nonce: String,
+    },
+    /// Channel message delivered to connections using the JSON framing
+    Message {
+        channel: String,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        encoding: Option<Encoding>,
+        data: serde_json::Value,
+    },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Encoding {
+    Base64,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Presence {
+    pub channel: String,
+    pub session: u64,
+    pub event: PresenceEvent,
+    pub members: usize,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PresenceEvent {
+    Join,
+    Leave,
+}
+
+pub fn parse(text: &str) -> Result<Request, anyhow::Error> {
+    Ok(serde_json::from_str(text)?)
+}
+
+pub fn encode(response: &Response) -> String {
+    serde_json::to_string(response).expect("control responses are always serializable")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn requests() {
+        let request = parse(r#"{"type":"join","channel":"chat","id":7}"#).unwrap();
+        assert_eq!(request.id, Some(7));
+        assert!(matches!(request.command, Command::Join { channel } if channel == "chat"));
+
+        let request = parse(r#"{"type":"leave","channel":"chat"}"#).unwrap();
+        assert_eq!(request.id, None);
+        assert!(matches!(request.command, Command::Leave { channel } if channel == "chat"));
+
+        let request = parse(r#"{"type":"subscribe","channel":"live","tracks":[1,2]}"#).unwrap();
+        assert!(matches!(
+            request.command,
+            Command::Subscribe { channel, tracks: Some(tracks) } if channel == "live" && tracks == [1, 2]
+        ));
+        let request = parse(r#"{"type":"subscribe","channel":"live"}"#).unwrap();
+        assert!(matches!(
+            request.command,
+            Command::Subscribe { tracks: None, .. }
+        ));
+
+        let request = parse(r#"{"type":"ping","id":1}"#).unwrap();
+        assert!(matches!(request.command, Command::Ping));
+
+        let request = parse(r#"{"type":"message","data":{"text":"hi"}}"#).unwrap();
+        assert!(matches!(
+            request.command,
+            Command::Message { encoding: None, data } if data["text"] == "hi"
+        ));
+        let request = parse(r#"{"type":"message","encoding":"base64","data":"AP8Q"}"#).unwrap();
+        assert!(matches!(
+            request.command,
+            Command::Message {
+                encoding: Some(Encoding::Base64),
+                ..
+            }
+        ));
+    }
+
+    #[test]
+    fn invalid_requests() {
+        let invalid = [
+            "",
+            "join chat",
+            "[]",
+            r#"{"channel":"chat"}"#,
+            r#"{"type":"publish","channel":"chat"}"#,
+            r#"{"type":"join"}"#,
+            r#"{"type":"join","channel":5}"#,
+            r#"{"type":"join","channel":"chat","id":-1}"#,
+            r#"{"type":"subscribe","channel":"live","tracks":"all"}"#,
+            r#"{"type":"message"}"#,
+            r#"{"type":"message","encoding":"hex","data":"00"}"#,
+            r#"{"type":"ping"} trailing"#,
+        ];
+        for text in invalid {
+            assert!(parse(text).is_err(), "{}", text);
+        }
+    }
+
+    #[test]
+    fn responses() {
+        let cases = [
+            (
+                Response::Joined {
+                    id: Some(7),
+                    channel: "chat".to_string(),
+                    members: 2,
+                },
+                r#"{"type":"joined","id":7,"channel":"chat","members":2}"#,
+            ),
+            (
+                Response::Left {
+                    id: None,
+                    channel: "chat".to_string(),
+                },
+                r#"{"type":"left","channel":"chat"}"#,
+            ),
+            (
+                Response::Subscribed {
+                    id: None,
+                    channel: "live".to_string(),
+                    tracks: None,
+                },
+                r#"{"type":"subscribed","channel":"live","tracks":null}"#,
+            ),
+            (
+                Response::Pong {
+                    id: Some(1),
+                    time: 1700000000000,
+                },
+                r#"{"type":"pong","id":1,"time":1700000000000}"#,
+            ),
+            (
+                Response::Error {
+                    id: None,
+                    message: "unknown channel".to_string(),
+                },
+                r#"{"type":"error","message":"unknown channel"}"#,
+            ),
+            (
+                Response::Presence(Presence {
+                    channel: "chat".to_string(),
+                    session: 3,
+                    event: PresenceEvent::Leave,
+                    members: 1,
+                }),
+                r#"{"type":"presence","channel":"chat","session":3,"event":"leave","members":1}"#,
+            ),
+            (
+                Response::Challenge {
+                    nonce: "1700000000.abc".to_string(),
+                },
+                r#"{"type":"challenge","nonce":"1700000000.abc"}"#,
+            ),
+        ];
+        for (response, expected) in cases {
+            assert_eq!(encode(&response), expected);
+        }
+    }
+}
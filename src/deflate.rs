@@ -0,0 +1,675 @@
+use std::io;
+use std::pin::Pin;
+use std::task::{ready, Context, Poll};
+
+use flate2::{Compress, Compression, Decompress, FlushCompress, FlushDecompress};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+
+/// Bytes of the empty stored block ending every sync flush, removed from the
+/// compressed messages as required by RFC 7692.
+const TRAILER: [u8; 4] = [0x00, 0x00, 0xff, 0xff];
+const MAX_MESSAGE_SIZE: usize = 64 << 20;
+
+/// Server side settings of the permessage-deflate extension.
+#[derive(Debug, Clone)]
+pub struct Config {
+    pub enabled: bool,
+    /// LZ77 window used to compress, between 9 and 15 bits
+    pub server_max_window_bits: u8,
+    pub server_no_context_takeover: bool,
+    pub client_no_context_takeover: bool,
+}
+
+/// Parameters agreed with a client during the handshake.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Params {
+    pub server_max_window_bits: u8,
+    pub server_no_context_takeover: bool,
+    pub client_no_context_takeover: bool,
+}
+
+impl Params {
+    pub fn header_value(&self) -> String {
+        let mut value = "permessage-deflate".to_string();
+        if self.server_max_window_bits < 15 {
+            value += &format!("; server_max_window_bits={}", self.server_max_window_bits);
+        }
+        if self.server_no_context_takeover {
+            value += "; server_no_context_takeover";
+        }
+        if self.client_no_context_takeover {
+            value += "; client_no_context_takeover";
+        }
+        value
+    }
+}
+
+/// Picks the first acceptable permessage-deflate offer of a
+/// `Sec-WebSocket-Extensions` request header.
+pub fn negotiate(config: &Config, offers: &str) -> Option<Params> {
+    if !config.enabled {
+        return None;
+    }
+    offers
+        .split(',')
+        .find_map(|offer| accept_offer(config, offer))
+}
+
+fn accept_offer(config: &Config, offer: &str) -> Option<Params> {
+    let mut params = offer.split(';').map(str::trim);
+    if params.next()? != "permessage-deflate" {
+        return None;
+    }
+
+    let mut accepted = Params {
+        server_max_window_bits: config.server_max_window_bits,
+        server_no_context_takeover: config.server_no_context_takeover,
+        client_no_context_takeover: config.client_no_context_takeover,
+    };
+    for param in params {
+        let (name, value) = match param.split_once('=') {
+            Some((name, value)) => (name.trim(), Some(value.trim().trim_matches('"'))),
+            None => (param, None),
+        };
+        match (name, value) {
+            ("server_no_context_takeover", None) => accepted.server_no_context_takeover = true,
+            // Only a hint, the client context is reset when configured
+            ("client_no_context_takeover", None) => {}
+            ("server_max_window_bits", Some(bits)) => {
+                // zlib cannot compress with a window of 8 bits, decline those offers
+                let bits = bits
+                    .parse::<u8>()
+                    .ok()
+                    .filter(|bits| (9..=15).contains(bits))?;
+                accepted.server_max_window_bits = accepted.server_max_window_bits.min(bits);
+            }
+            // Decompression always uses the largest window, any client window works
+            ("client_max_window_bits", _) => {}
+            _ => return None,
+        }
+    }
+    Some(accepted)
+}
+
+pub struct Deflater {
+    compress: Compress,
+    no_context_takeover: bool,
+}
+
+impl Deflater {
+    pub fn new(params: &Params) -> Self {
+        Self {
+            compress: Compress::new_with_window_bits(
+                Compression::default(),
+                false,
+                params.server_max_window_bits,
+            ),
+            no_context_takeover: params.server_no_context_takeover,
+        }
+    }
+
+    pub fn compress(&mut self, data: &[u8]) -> io::Result<Vec<u8>> {
+        let mut output = Vec::with_capacity(data.len() / 2 + 64);
+        let start = self.compress.total_in();
+        loop {
+            let consumed = (self.compress.total_in() - start) as usize;
+            self.compress
+                .compress_vec(&data[consumed..], &mut output, FlushCompress::Sync)?;
+            let consumed = (self.compress.total_in() - start) as usize;
+            if consumed == data.len() && output.len() < output.capacity() {
+                break;
+            }
+            output.reserve(output.capacity());
+        }
+
+        if output.ends_with(&TRAILER) {
+            output.truncate(output.len() - TRAILER.len());
+        }
+        if self.no_context_takeover {
+            self.compress.reset();
+        }
+        Ok(output)
+    }
+}
+
+struct Inflater {
+    decompress: Decompress,
+    no_context_takeover: bool,
+}
+
+impl Inflater {
+    fn new(params: &Params) -> Self {
+        Self {
+            decompress: Decompress::new(false),
+            no_context_takeover: params.client_no_context_takeover,
+        }
+    }
+
+    fn inflate(&mut self, input: &[u8], output: &mut Vec<u8>) -> io::Result<()> {
+        let start = self.decompress.total_in();
+        loop {
+            if output.len() > MAX_MESSAGE_SIZE {
+                return Err(invalid_data("inflated message too large"));
+            }
+            output.reserve(input.len().max(1024));
+            let consumed = (self.decompress.total_in() - start) as usize;
+            self.decompress
+                .decompress_vec(&input[consumed..], output, FlushDecompress::Sync)?;
+            let consumed = (self.decompress.total_in() - start) as usize;
+            if consumed == input.len() && output.len() < output.capacity() {
+                return Ok(());
+            }
+        }
+    }
+
+    fn end_message(&mut self) {
+        if self.no_context_takeover {
+            self.decompress.reset(false);
+        }
+    }
+}
+
+/// Stream below the WebSocket protocol inflating the frames of compressed
+/// messages sent by the client, as tungstenite rejects frames with RSV1.
+pub struct DeflateStream<S> {
+    inner: S,
+    inflater: Option<Inflater>,
+    input: Vec<u8>,
+    output: Vec<u8>,
+    output_pos: usize,
+    compressed: bool,
+    message_size: usize,
+}
+
+impl<S> DeflateStream<S> {
+    pub fn new(inner: S) -> Self {
+        Self {
+            inner,
+            inflater: None,
+            input: Vec::new(),
+            output: Vec::new(),
+            output_pos: 0,
+            compressed: false,
+            message_size: 0,
+        }
+    }
+
+    /// Starts inflating frames, to be called once the handshake is completed.
+    pub fn enable(&mut self, params: &Params) {
+        self.inflater = Some(Inflater::new(params));
+    }
+
+    /// Moves the next complete frame of the input to the output, inflating it
+    /// if needed. Returns false when more input is needed.
+    fn rewrite_frame(&mut self) -> io::Result<bool> {
+        let (header_len, payload_len) = match parse_header(&self.input)? {
+            Some(lengths) => lengths,
+            None => return Ok(false),
+        };
+        if self.input.len() < header_len + payload_len {
+            return Ok(false);
+        }
+
+        let frame: Vec<u8> = self.input.drain(..header_len + payload_len).collect();
+        let fin = frame[0] & 0x80 != 0;
+        let rsv1 = frame[0] & 0x40 != 0;
+        let opcode = frame[0] & 0x0f;
+        match opcode {
+            0x1 | 0x2 => {
+                self.compressed = rsv1;
+                self.message_size = 0;
+            }
+            0x0 if rsv1 => return Err(invalid_data("RSV1 set in continuation frame")),
+            _ => {}
+        }
+        if !self.compressed || opcode >= 0x8 {
+            self.output.extend_from_slice(&frame);
+            return Ok(true);
+        }
+
+        let mask = match frame[1] & 0x80 != 0 {
+            true => Some([
+                frame[header_len - 4],
+                frame[header_len - 3],
+                frame[header_len - 2],
+                frame[header_len - 1],
+            ]),
+            false => None,
+        };
+        let mut payload = frame[header_len..].to_vec();
+        if let Some(mask) = mask {
+            apply_mask(&mut payload, mask);
+        }
+
+        let inflater = self.inflater.as_mut().expect("inflating without inflater");
+        let mut inflated = Vec::new();
+        inflater.inflate(&payload, &mut inflated)?;
+        if fin {
+            inflater.inflate(&TRAILER, &mut inflated)?;
+            inflater.end_message();
+            self.compressed = false;
+        }
+        self.message_size += inflated.len();
+        if self.message_size > MAX_MESSAGE_SIZE {
+            return Err(invalid_data("inflated message too large"));
+        }
+
+        self.output.push(frame[0] & !0x40);
+        let mask_bit = frame[1] & 0x80;
+        match inflated.len() {
+            len if len < 126 => self.output.push(mask_bit | len as u8),
+            len if len <= u16::MAX as usize => {
+                self.output.push(mask_bit | 126);
+                self.output.extend_from_slice(&(len as u16).to_be_bytes());
+            }
+            len => {
+                self.output.push(mask_bit | 127);
+                self.output.extend_from_slice(&(len as u64).to_be_bytes());
+            }
+        }
+        if let Some(mask) = mask {
+            self.output.extend_from_slice(&mask);
+            apply_mask(&mut inflated, mask);
+        }
+        self.output.extend_from_slice(&inflated);
+        Ok(true)
+    }
+}
+
+/// Returns the header and payload lengths of the frame at the start of the
+/// buffer, or None if the header is not complete yet.
+fn parse_header(buffer: &[u8]) -> io::Result<Option<(usize, usize)>> {
+    if buffer.len() < 2 {
+        return Ok(None);
+    }
+    let masked = buffer[1] & 0x80 != 0;
+    let (extended, payload_len) = match buffer[1] & 0x7f {
+        126 if buffer.len() >= 4 => (2, u16::from_be_bytes([buffer[2], buffer[3]]) as u64),
+        127 if buffer.len() >= 10 => {
+            let mut len = [0u8; 8];
+            len.copy_from_slice(&buffer[2..10]);
+            (8, u64::from_be_bytes(len))
+        }
+        126 | 127 => return Ok(None),
+        len => (0, len as u64),
+    };
+    if payload_len > MAX_MESSAGE_SIZE as u64 {
+        return Err(invalid_data("frame too large"));
+    }
+    let header_len = 2 + extended + if masked { 4 } else { 0 };
+    Ok(Some((header_len, payload_len as usize)))
+}
+
+fn apply_mask(data: &mut [u8], mask: [u8; 4]) {
+    for (i, byte) in data.iter_mut().enumerate() {
+        *byte ^= mask[i % 4];
+    }
+}
+
+fn invalid_data(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message.to_string())
+}
+
+impl<S> AsyncRead for DeflateStream<S>
+where
+    S: AsyncRead + Unpin,
+{
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        if this.inflater.is_none() {
+            return Pin::new(&mut this.inner).poll_read(cx, buf);
+        }
+
+        loop {
+            if this.output_pos < this.output.len() {
+                let len = buf.remaining().min(this.output.len() - this.output_pos);
+                buf.put_slice(&this.output[this.output_pos..this.output_pos + len]);
+                this.output_pos += len;
+                if this.output_pos == this.output.len() {
+                    this.output.clear();
+                    this.output_pos = 0;
+                }
+                return Poll::Ready(Ok(()));
+            }
+            if this.rewrite_frame()? {
+                continue;
+            }
+
+            let mut chunk = [0u8; 4096];
+            let mut read = ReadBuf::new(&mut chunk);
+            ready!(Pin::new(&mut this.inner).poll_read(cx, &mut read))?;
+            if read.filled().is_empty() {
+                // Let the WebSocket protocol report any truncated frame
+                if this.input.is_empty() {
+                    return Poll::Ready(Ok(()));
+                }
+                this.output = std::mem::take(&mut this.input);
+                continue;
+            }
+            this.input.extend_from_slice(read.filled());
+        }
+    }
+}
+
+impl<S> AsyncWrite for DeflateStream<S>
+where
+    S: AsyncWrite + Unpin,
+{
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.get_mut().inner).poll_write(cx, buf)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::AsyncReadExt;
+
+    const MASK: [u8; 4] = [0x37, 0xfa, 0x21, 0x3d];
+    const TEXT: u8 = 0x1;
+    const CONTINUATION: u8 = 0x0;
+    const PING: u8 = 0x9;
+    const FIN: u8 = 0x80;
+    const RSV1: u8 = 0x40;
+
+    fn agreed(no_context_takeover: bool) -> Params {
+        Params {
+            server_max_window_bits: 15,
+            server_no_context_takeover: no_context_takeover,
+            client_no_context_takeover: no_context_takeover,
+        }
+    }
+
+    fn frame(first: u8, mask: Option<[u8; 4]>, payload: &[u8]) -> Vec<u8> {
+        let mask_bit = if mask.is_some() { 0x80 } else { 0 };
+        let mut frame = vec![first];
+        match payload.len() {
+            len if len < 126 => frame.push(mask_bit | len as u8),
+            len if len <= u16::MAX as usize => {
+                frame.push(mask_bit | 126);
+                frame.extend_from_slice(&(len as u16).to_be_bytes());
+            }
+            len => {
+                frame.push(mask_bit | 127);
+                frame.extend_from_slice(&(len as u64).to_be_bytes());
+            }
+        }
+        let mut payload = payload.to_vec();
+        if let Some(mask) = mask {
+            frame.extend_from_slice(&mask);
+            apply_mask(&mut payload, mask);
+        }
+        frame.extend_from_slice(&payload);
+        frame
+    }
+
+    /// Splits the output of the stream back into frames, with their first
+    /// byte and unmasked payload.
+    fn frames(mut data: &[u8]) -> Vec<(u8, Vec<u8>)> {
+        let mut frames = Vec::new();
+        while !data.is_empty() {
+            let (header_len, payload_len) = parse_header(data).unwrap().unwrap();
+            let mut payload = data[header_len..header_len + payload_len].to_vec();
+            if data[1] & 0x80 != 0 {
+                let mut mask = [0u8; 4];
+                mask.copy_from_slice(&data[header_len - 4..header_len]);
+                apply_mask(&mut payload, mask);
+            }
+            frames.push((data[0], payload));
+            data = &data[header_len + payload_len..];
+        }
+        frames
+    }
+
+    async fn read(input: &[u8], params: Option<&Params>) -> io::Result<Vec<u8>> {
+        let mut stream = DeflateStream::new(input);
+        if let Some(params) = params {
+            stream.enable(params);
+        }
+        let mut output = Vec::new();
+        stream.read_to_end(&mut output).await?;
+        Ok(output)
+    }
+
+    #[tokio::test]
+    async fn masked_and_unmasked() {
+        let params = agreed(false);
+        for mask in [Some(MASK), None] {
+            let compressed = Deflater::new(&params).compress(b"Hello, Hello").unwrap();
+            let input = frame(FIN | RSV1 | TEXT, mask, &compressed);
+            let output = read(&input, Some(&params)).await.unwrap();
+            assert_eq!(frames(&output), [(FIN | TEXT, b"Hello, Hello".to_vec())]);
+            // The mask is kept for the WebSocket protocol to check it
+            assert_eq!(output[1] & 0x80 != 0, mask.is_some());
+        }
+    }
+
+    #[tokio::test]
+    async fn large_message() {
+        let params = agreed(false);
+        let message: Vec<u8> = (0..200_000u32).map(|i| (i * 7 % 251) as u8).collect();
+        let compressed = Deflater::new(&params).compress(&message).unwrap();
+        let input = frame(FIN | RSV1 | TEXT, Some(MASK), &compressed);
+        let output = read(&input, Some(&params)).await.unwrap();
+        assert_eq!(frames(&output), [(FIN | TEXT, message)]);
+    }
+
+    #[tokio::test]
+    async fn fragmented() {
+        let params = agreed(false);
+        let message = b"a fragmented message, compressed once and split in three";
+        let compressed = Deflater::new(&params).compress(message).unwrap();
+        let (first, rest) = compressed.split_at(compressed.len() / 3);
+        let (second, third) = rest.split_at(rest.len() / 2);
+        let input = [
+            frame(RSV1 | TEXT, Some(MASK), first),
+            frame(CONTINUATION, Some(MASK), second),
+            frame(FIN | CONTINUATION, Some(MASK), third),
+        ]
+        .concat();
+
+        let output = frames(&read(&input, Some(&params)).await.unwrap());
+        assert_eq!(
+            output.iter().map(|(first, _)| *first).collect::<Vec<_>>(),
+            [TEXT, CONTINUATION, FIN | CONTINUATION]
+        );
+        let inflated: Vec<u8> = output.into_iter().flat_map(|(_, data)| data).collect();
+        assert_eq!(inflated, message);
+    }
+
+    #[tokio::test]
+    async fn rsv1_in_continuation() {
+        let params = agreed(false);
+        let compressed = Deflater::new(&params).compress(b"message").unwrap();
+        let (first, second) = compressed.split_at(2);
+        let input = [
+            frame(RSV1 | TEXT, Some(MASK), first),
+            frame(FIN | RSV1 | CONTINUATION, Some(MASK), second),
+        ]
+        .concat();
+        let err = read(&input, Some(&params)).await.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[tokio::test]
+    async fn control_between_fragments() {
+        let params = agreed(false);
+        let message = b"interrupted by a ping";
+        let compressed = Deflater::new(&params).compress(message).unwrap();
+        let (first, second) = compressed.split_at(compressed.len() / 2);
+        let ping = frame(FIN | PING, Some(MASK), b"ping");
+        let input = [
+            frame(RSV1 | TEXT, Some(MASK), first),
+            ping.clone(),
+            frame(FIN | CONTINUATION, Some(MASK), second),
+        ]
+        .concat();
+
+        let output = read(&input, Some(&params)).await.unwrap();
+        let output = frames(&output);
+        assert_eq!(output[1], (FIN | PING, b"ping".to_vec()));
+        assert_eq!(output[0].0, TEXT);
+        assert_eq!(output[2].0, FIN | CONTINUATION);
+        assert_eq!([&output[0].1[..], &output[2].1[..]].concat(), message);
+    }
+
+    #[tokio::test]
+    async fn uncompressed_messages_pass_through() {
+        let params = agreed(false);
+        let compressed = Deflater::new(&params).compress(b"compressed").unwrap();
+        let plain = [
+            frame(FIN | TEXT, Some(MASK), b"plain"),
+            frame(0x2, Some(MASK), b"fragmented "),
+            frame(FIN | CONTINUATION, Some(MASK), b"binary"),
+        ]
+        .concat();
+        let input = [
+            plain.clone(),
+            frame(FIN | RSV1 | TEXT, Some(MASK), &compressed),
+        ]
+        .concat();
+
+        let output = read(&input, Some(&params)).await.unwrap();
+        assert_eq!(output[..plain.len()], plain);
+        assert_eq!(
+            frames(&output[plain.len()..]),
+            [(FIN | TEXT, b"compressed".to_vec())]
+        );
+    }
+
+    #[tokio::test]
+    async fn context_takeover() {
+        let params = agreed(false);
+        let mut deflater = Deflater::new(&params);
+        let messages: [&[u8]; 3] = [b"repeated payload", b"repeated payload", b"repeated"];
+        let compressed: Vec<Vec<u8>> = messages
+            .iter()
+            .map(|message| deflater.compress(message).unwrap())
+            .collect();
+        // The next messages refer to the window of the first one
+        assert!(compressed[1].len() < compressed[0].len());
+
+        let input: Vec<u8> = compressed
+            .iter()
+            .flat_map(|data| frame(FIN | RSV1 | TEXT, Some(MASK), data))
+            .collect();
+        let output = read(&input, Some(&params)).await.unwrap();
+        let expected: Vec<_> = messages
+            .iter()
+            .map(|message| (FIN | TEXT, message.to_vec()))
+            .collect();
+        assert_eq!(frames(&output), expected);
+
+        // Without context takeover, the window is reset between messages
+        assert!(read(&input, Some(&agreed(true))).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn no_context_takeover() {
+        let params = agreed(true);
+        let mut deflater = Deflater::new(&params);
+        let first = deflater.compress(b"repeated payload").unwrap();
+        let second = deflater.compress(b"repeated payload").unwrap();
+        assert_eq!(first, second);
+
+        let input = [
+            frame(FIN | RSV1 | TEXT, Some(MASK), &first),
+            frame(FIN | RSV1 | TEXT, Some(MASK), &second),
+        ]
+        .concat();
+        let output = read(&input, Some(&params)).await.unwrap();
+        assert_eq!(
+            frames(&output),
+            [
+                (FIN | TEXT, b"repeated payload".to_vec()),
+                (FIN | TEXT, b"repeated payload".to_vec())
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn decompressed_size_limit() {
+        let params = agreed(false);
+        let bomb = vec![0u8; MAX_MESSAGE_SIZE + 1024];
+        let compressed = Deflater::new(&params).compress(&bomb).unwrap();
+        assert!(compressed.len() < MAX_MESSAGE_SIZE / 100);
+
+        let input = frame(FIN | RSV1 | TEXT, Some(MASK), &compressed);
+        let err = read(&input, Some(&params)).await.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+
+        // Also across the fragments of a message
+        let (half, rest) = compressed.split_at(compressed.len() / 2);
+        let input = [
+            frame(RSV1 | TEXT, Some(MASK), half),
+            frame(FIN | CONTINUATION, Some(MASK), rest),
+        ]
+        .concat();
+        let err = read(&input, Some(&params)).await.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[tokio::test]
+    async fn frame_size_limit() {
+        let mut input = vec![FIN | RSV1 | TEXT, 0x80 | 127];
+        input.extend_from_slice(&(MAX_MESSAGE_SIZE as u64 + 1).to_be_bytes());
+        input.extend_from_slice(&MASK);
+        let err = read(&input, Some(&agreed(false))).await.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[tokio::test]
+    async fn without_deflate() {
+        let params = agreed(false);
+        let compressed = Deflater::new(&params).compress(b"compressed").unwrap();
+        // Handshake bytes, frames with RSV1 and a truncated frame are left to
+        // the WebSocket protocol
+        let input = [
+            b"GET /channels/demo HTTP/1.1\r\n\r\n".to_vec(),
+            frame(FIN | RSV1 | TEXT, Some(MASK), &compressed),
+            vec![FIN | TEXT, 0x80 | 10, 0x01],
+        ]
+        .concat();
+        assert_eq!(read(&input, None).await.unwrap(), input);
+    }
+
+    #[tokio::test]
+    async fn truncated_frame() {
+        let input = frame(FIN | TEXT, Some(MASK), b"truncated");
+        let output = read(&input[..8], Some(&agreed(false))).await.unwrap();
+        assert_eq!(output, input[..8]);
+    }
+
+    #[test]
+    fn deflater_strips_trailer() {
+        let compressed = Deflater::new(&agreed(false)).compress(b"data").unwrap();
+        assert!(!compressed.ends_with(&TRAILER));
+
+        let mut decompress = Decompress::new(false);
+        let mut output = Vec::with_capacity(64);
+        decompress
+            .decompress_vec(
+                &[compressed, TRAILER.to_vec()].concat(),
+                &mut output,
+                FlushDecompress::Sync,
+            )
+            .unwrap();
+        assert_eq!(output, b"data");
+    }
+}
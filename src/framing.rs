@@ -0,0 +1,187 @@
+use base64::Engine;
+
+use crate::control;
+use crate::rush;
+
+/// Payload format of a connection, selected with the WebSocket subprotocol.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Framing {
+    /// Opaque bytes, the default when no subprotocol is requested
+    Raw,
+    /// Binary messages must start with a valid RUSH header
+    Rush,
+    /// Messages are delivered as JSON envelopes in text frames
+    Json,
+}
+
+const FRAMINGS: &[Framing] = &[Framing::Raw, Framing::Rush, Framing::Json];
+
+impl Framing {
+    pub fn protocol(&self) -> &'static str {
+        match self {
+            Framing::Raw => "prism.raw",
+            Framing::Rush => "prism.rush",
+            Framing::Json => "prism.json",
+        }
+    }
+
+    /// Picks the first supported protocol of a `Sec-WebSocket-Protocol`
+    /// request header.
+    pub fn negotiate(protocols: &str) -> Option<Self> {
+        protocols.split(',').map(str::trim).find_map(|protocol| {
+            FRAMINGS
+                .iter()
+                .find(|framing| framing.protocol() == protocol)
+                .copied()
+        })
+    }
+
+    /// Checks that a binary message received from the client follows the
+    /// framing rules before publishing it.
+    pub fn validate(&self, data: &[u8]) -> Result<(), anyhow::Error> {
+        match self {
+            Framing::Rush => match rush::Header::parse(data) {
+                Some(_) => Ok(()),
+                None => anyhow::bail!("invalid rush header"),
+            },
+            Framing::Raw | Framing::Json => Ok(()),
+        }
+    }
+}
+
+/// Wraps a channel message in a JSON envelope, embedding it as is when it is
+/// JSON and base64 encoded otherwise.
+pub fn envelope(channel: &str, data: &[u8]) -> control::Response {
+    let (encoding, data) = match serde_json::from_slice(data) {
+        Ok(value) => (None, value),
+        Err(_) => (
+            Some(control::Encoding::Base64),
+            serde_json::Value::String(base64::engine::general_purpose::STANDARD.encode(data)),
+        ),
+    };
+    control::Response::Message {
+        channel: channel.to_string(),
+        encoding,
+        data,
+    }
+}
+
+/// Extracts the payload of a message published through the control protocol.
+pub fn payload(
+    encoding: Option<control::Encoding>,
+    data: &serde_json::Value,
+) -> Result<Vec<u8>, anyhow::Error> {
+    match (encoding, data) {
+        (None, data) => Ok(serde_json::to_vec(data)?),
+        (Some(control::Encoding::Base64), serde_json::Value::String(data)) => {
+            Ok(base64::engine::general_purpose::STANDARD.decode(data)?)
+        }
+        (Some(control::Encoding::Base64), _) => anyhow::bail!("base64 data must be a string"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rush_frame(payload: &[u8]) -> Vec<u8> {
+        let header = rush::Header {
+            length: (rush::HEADER_LEN + payload.len()) as u32,
+            seq: 1,
+            frame_type: rush::VIDEO,
+            codec: rush::H264,
+            source: 0,
+            timestamp: 3000,
+            track_id: 2,
+        };
+        header.encode(payload)
+    }
+
+    #[test]
+    fn negotiation() {
+        let cases = [
+            ("prism.raw", Some(Framing::Raw)),
+            ("prism.rush", Some(Framing::Rush)),
+            ("prism.json", Some(Framing::Json)),
+            // The first supported one wins
+            ("chat, prism.json ,prism.rush", Some(Framing::Json)),
+            ("prism.rush,prism.raw", Some(Framing::Rush)),
+            ("prism.xml", None),
+            ("PRISM.RAW", None),
+            ("", None),
+        ];
+        for (protocols, expected) in cases {
+            assert_eq!(Framing::negotiate(protocols), expected, "{}", protocols);
+        }
+        for framing in FRAMINGS {
+            assert_eq!(Framing::negotiate(framing.protocol()), Some(*framing));
+        }
+    }
+
+    #[test]
+    fn validation() {
+        let frame = rush_frame(&[0x65, 0x88]);
+        assert!(Framing::Rush.validate(&frame).is_ok());
+        assert!(Framing::Rush.validate(&frame[..rush::HEADER_LEN]).is_err());
+        assert!(Framing::Rush.validate(b"not a rush frame").is_err());
+        let mut longer = frame.clone();
+        longer.push(0);
+        assert!(Framing::Rush.validate(&longer).is_err());
+
+        assert!(Framing::Raw.validate(b"anything").is_ok());
+        assert!(Framing::Json.validate(&[0xff, 0x00]).is_ok());
+    }
+
+    #[test]
+    fn envelopes() {
+        let json = control::encode(&envelope("chat", br#"{"text":"hi"}"#));
+        assert_eq!(
+            json,
+            r#"{"type":"message","channel":"chat","data":{"text":"hi"}}"#
+        );
+
+        let json = control::encode(&envelope("video", &[0x00, 0xff, 0x10]));
+        assert_eq!(
+            json,
+            r#"{"type":"message","channel":"video","encoding":"base64","data":"AP8Q"}"#
+        );
+
+        // Text which is not JSON is encoded too
+        let json = control::encode(&envelope("chat", b"hello"));
+        assert_eq!(
+            json,
+            r#"{"type":"message","channel":"chat","encoding":"base64","data":"aGVsbG8="}"#
+        );
+    }
+
+    #[test]
+    fn payloads() {
+        let data = serde_json::json!({"text": "hi"});
+        assert_eq!(payload(None, &data).unwrap(), br#"{"text":"hi"}"#);
+        assert_eq!(payload(None, &serde_json::json!(42)).unwrap(), b"42");
+
+        let data = serde_json::json!("AP8Q");
+        assert_eq!(
+            payload(Some(control::Encoding::Base64), &data).unwrap(),
+            [0x00, 0xff, 0x10]
+        );
+        let invalid = serde_json::json!("not base64!");
+        assert!(payload(Some(control::Encoding::Base64), &invalid).is_err());
+        let number = serde_json::json!(42);
+        assert!(payload(Some(control::Encoding::Base64), &number).is_err());
+
+        // Envelopes give back the published bytes
+        for data in [&br#"{"a":[1,2]}"#[..], &[0x00, 0x01][..], &b"text"[..]] {
+            match envelope("chat", data) {
+                control::Response::Message {
+                    encoding,
+                    data: value,
+                    ..
+                } => {
+                    assert_eq!(payload(encoding, &value).unwrap(), data)
+                }
+                response => panic!("unexpected {:?}", response),
+            }
+        }
+    }
+}
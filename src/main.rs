@@ -1,6 +1,6 @@
 use std::{
     fs,
-    net::SocketAddr,
+    net::{IpAddr, SocketAddr},
     path::PathBuf,
     sync::{Arc, Mutex},
     time::Duration,
@@ -15,18 +15,35 @@ use tracing::*;
 
 use h3_quinn::quinn;
 
+pub mod auth;
 pub mod channel;
+pub mod control;
+pub mod deflate;
+pub mod framing;
 pub mod module;
+pub mod moq;
+pub mod mqtt;
+pub mod proxy;
+pub mod quic;
+pub mod ratelimit;
+pub mod rtp_ingest;
+pub mod rush;
 pub mod server;
+pub mod session;
+pub mod sse;
+pub mod stats;
 pub mod transport;
+pub mod udp;
+pub mod unix;
 pub mod util;
 pub mod webrtc;
 pub mod websocket;
 pub mod webtransport;
+pub mod webtransport_h2;
 pub mod whip;
 use crate::transport::Transport;
 
-const ALPN_QUIC_HTTP: &[&[u8]] = &[b"h3", b"rush"];
+const ALPN_QUIC_HTTP: &[&[u8]] = &[b"h3", quic::ALPN, moq::ALPN];
 
 #[derive(Parser, Debug)]
 #[clap(name = "prism")]
@@ -43,6 +60,114 @@ struct Opt {
     /// Address to listen on for quic
     #[clap(long = "ws_listen", default_value = "[::]:4434")]
     ws_listen: SocketAddr,
+    /// Seconds between websocket pings, 0 disables them
+    #[clap(long = "ws_ping_interval", default_value = "15")]
+    ws_ping_interval: u64,
+    /// Seconds to wait for a websocket pong before closing, 0 disables it
+    #[clap(long = "ws_pong_timeout", default_value = "10")]
+    ws_pong_timeout: u64,
+    /// Seconds without websocket traffic before closing, 0 disables it
+    #[clap(long = "ws_idle_timeout", default_value = "60")]
+    ws_idle_timeout: u64,
+    /// Address to listen on for plain websocket, for TLS terminated by a load balancer
+    #[clap(long = "ws_plain_listen")]
+    ws_plain_listen: Option<SocketAddr>,
+    /// Proxy address or network allowed to report the client address, can be repeated
+    #[clap(long = "trusted_proxy", value_parser = proxy::parse_network)]
+    trusted_proxies: Vec<ipnet::IpNet>,
+    /// Expect a PROXY protocol header from trusted proxies instead of forwarding headers
+    #[clap(long = "proxy_protocol")]
+    proxy_protocol: bool,
+    /// WebSocket connections and HTTP requests allowed per second and client address, 0 disables the limit
+    #[clap(long = "rate_limit", default_value = "0")]
+    rate_limit: f64,
+    /// Connections and requests a client can make at once before being rate limited
+    #[clap(long = "rate_burst", default_value = "20")]
+    rate_burst: u32,
+    /// Disable permessage-deflate compression of websocket messages
+    #[clap(long = "ws_no_deflate")]
+    ws_no_deflate: bool,
+    /// Window bits used to compress websocket messages
+    #[clap(long = "ws_deflate_window_bits", default_value = "15", value_parser = clap::value_parser!(u8).range(9..=15))]
+    ws_deflate_window_bits: u8,
+    /// Reset the compression context after every websocket message sent
+    #[clap(long = "ws_deflate_no_context_takeover")]
+    ws_deflate_no_context_takeover: bool,
+    /// Ask clients to reset the compression context after every message
+    #[clap(long = "ws_deflate_client_no_context_takeover")]
+    ws_deflate_client_no_context_takeover: bool,
+    /// Channel whose messages are never compressed, can be repeated
+    #[clap(long = "uncompressed_channel")]
+    uncompressed_channels: Vec<String>,
+    /// Address to listen on for WebRTC
+    #[clap(long = "webrtc_listen", default_value = "[::]:4435")]
+    webrtc_listen: SocketAddr,
+    /// Address to listen on for ICE-TCP, for clients without UDP
+    #[clap(long = "webrtc_tcp_listen")]
+    webrtc_tcp_listen: Option<SocketAddr>,
+    /// Public address announced in the WebRTC candidates behind a 1:1 NAT, can be repeated
+    #[clap(long = "public_ip")]
+    public_ips: Vec<IpAddr>,
+    /// Address to listen on for STUN and TURN over UDP, TURN is disabled without it
+    #[clap(long = "turn_listen")]
+    turn_listen: Option<SocketAddr>,
+    /// Address to listen on for TURN over TCP
+    #[clap(long = "turn_tcp_listen", requires = "turn_listen")]
+    turn_tcp_listen: Option<SocketAddr>,
+    /// Address to listen on for WHIP
+    #[clap(long = "whip_listen", default_value = "127.0.0.1:8080")]
+    whip_listen: SocketAddr,
+    /// Address to listen on for raw UDP endpoints
+    #[clap(long = "udp_listen")]
+    udp_listen: Option<SocketAddr>,
+    /// UDP source bound to a channel without registration, as address=channel, can be repeated
+    #[clap(long = "udp_endpoint", requires = "udp_listen", value_parser = udp::parse_endpoint)]
+    udp_endpoints: Vec<(SocketAddr, String)>,
+    /// Accept UDP registrations without a token when there is no --token_secret
+    #[clap(long = "udp_anonymous", requires = "udp_listen")]
+    udp_anonymous: bool,
+    /// Seconds without datagrams before a registered UDP endpoint expires
+    #[clap(long = "udp_idle_timeout", default_value = "60")]
+    udp_idle_timeout: u64,
+    /// Path of the Unix socket for services on the same host
+    #[clap(long = "unix_listen")]
+    unix_listen: Option<PathBuf>,
+    /// Permissions of the Unix socket file, in octal
+    #[clap(long = "unix_mode", default_value = "660", value_parser = unix::parse_mode)]
+    unix_mode: u32,
+    /// User id allowed to connect to the Unix socket, can be repeated
+    #[clap(long = "unix_allow_uid")]
+    unix_allowed_uids: Vec<u32>,
+    /// Group id allowed to connect to the Unix socket, can be repeated
+    #[clap(long = "unix_allow_gid")]
+    unix_allowed_gids: Vec<u32>,
+    /// Address to listen on for MQTT
+    #[clap(long = "mqtt_listen")]
+    mqtt_listen: Option<SocketAddr>,
+    /// Address to listen on for MQTT over TLS
+    #[clap(long = "mqtts_listen")]
+    mqtts_listen: Option<SocketAddr>,
+    /// Address to listen on for RTP publishers like ffmpeg
+    #[clap(long = "rtp_listen")]
+    rtp_listen: Option<SocketAddr>,
+    /// Channel the RTP streams described by --rtp_sdp are published to
+    #[clap(long = "rtp_channel", requires = "rtp_sdp")]
+    rtp_channel: Option<String>,
+    /// Session description of the RTP streams, also accepted with PUT /rtp/{channel}
+    #[clap(long = "rtp_sdp", requires_all = ["rtp_listen", "rtp_channel", "rtp_source"])]
+    rtp_sdp: Option<PathBuf>,
+    /// Address the RTP streams described by --rtp_sdp are sent from, with an optional port
+    #[clap(long = "rtp_source", requires = "rtp_sdp", value_parser = rtp_ingest::Source::parse)]
+    rtp_source: Option<rtp_ingest::Source>,
+    /// SSRC of the RTP stream described by --rtp_sdp, any when not given
+    #[clap(long = "rtp_ssrc", requires = "rtp_sdp")]
+    rtp_ssrc: Option<u32>,
+    /// Secret signing the channel tokens, tokens are not required without it
+    #[clap(long = "token_secret")]
+    token_secret: Option<String>,
+    /// Bearer token of the admin API on --whip_listen, disabled without it
+    #[clap(long = "admin_token")]
+    admin_token: Option<String>,
 }
 
 #[tokio::main]
@@ -57,7 +182,7 @@ async fn main() -> Result<()> {
     let options = Opt::parse();
 
     let key = fs::read(options.key.clone()).context("failed to read private key")?;
-    let key = if options.key.extension().map_or(false, |x| x == "der") {
+    let key = if options.key.extension().is_some_and(|x| x == "der") {
         rustls::PrivateKey(key)
     } else {
         let pkcs8 = rustls_pemfile::pkcs8_private_keys(&mut &*key)
@@ -77,7 +202,7 @@ async fn main() -> Result<()> {
         }
     };
     let certs = fs::read(options.cert.clone()).context("failed to read certificate chain")?;
-    let certs = if options.cert.extension().map_or(false, |x| x == "der") {
+    let certs = if options.cert.extension().is_some_and(|x| x == "der") {
         vec![rustls::Certificate(certs)]
     } else {
         rustls_pemfile::certs(&mut &*certs)
@@ -106,23 +231,225 @@ async fn main() -> Result<()> {
     let (endpoint, mut incoming) = quinn::Endpoint::server(config, options.wt_listen)?;
     info!("listening webtransport on {}", endpoint.local_addr()?);
 
-    let server = Arc::new(Mutex::new(server::Server::new()));
+    let mut server = server::Server::new();
+    server.limiter = Arc::new(ratelimit::Limiter::new(
+        options.rate_limit,
+        options.rate_burst,
+    ));
+    server.uncompressed_channels = options.uncompressed_channels.iter().cloned().collect();
+    let server = Arc::new(Mutex::new(server));
 
-    let webrtc = webrtc::WebRtcModule::new(server.clone());
+    let auth = auth::Config::new(options.token_secret.clone());
+    let turn = match options.turn_listen {
+        Some(listen) => {
+            if options.token_secret.is_none() {
+                anyhow::bail!("TURN needs --token_secret to sign its credentials");
+            }
+            let relay_ip = options
+                .public_ips
+                .first()
+                .copied()
+                .or_else(|| Some(listen.ip()).filter(|ip| !ip.is_unspecified()))
+                .context("TURN needs --public_ip or a specific --turn_listen address")?;
+            Some(webrtc::turn::Config {
+                listen,
+                tcp_listen: options.turn_tcp_listen,
+                relay_ip,
+                auth: auth.clone(),
+            })
+        }
+        None => None,
+    };
+
+    let webrtc_config = webrtc::Config {
+        listen: options.webrtc_listen,
+        tcp_listen: options.webrtc_tcp_listen,
+        public_ips: options.public_ips.clone(),
+        turn: turn.clone(),
+    };
+    let webrtc = webrtc::WebRtcModule::new(server.clone(), webrtc_config);
     webrtc.start().await?;
 
-    let whip = whip::WhipModule::new(server.clone());
-    whip.start().await?;
+    if let Some(listen) = options.udp_listen {
+        let udp_config = udp::Config {
+            listen,
+            endpoints: options.udp_endpoints.clone(),
+            idle_timeout: Duration::from_secs(options.udp_idle_timeout),
+            auth: auth.clone(),
+            anonymous: options.udp_anonymous,
+        };
+        let udp = udp::UdpModule::new(server.clone(), udp_config);
+        tokio::spawn(async move {
+            if let Err(err) = udp.start().await {
+                error!("udp failed: {}", err);
+            }
+        });
+    }
+
+    if let Some(path) = options.unix_listen.clone() {
+        let unix_config = unix::Config {
+            path,
+            mode: options.unix_mode,
+            uids: options.unix_allowed_uids.clone(),
+            gids: options.unix_allowed_gids.clone(),
+        };
+        let listener = unix::bind(&unix_config)?;
+        info!("listening unix on {}", unix_config.path.display());
+
+        let server = server.clone();
+        tokio::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                info!("incoming connection unix");
+
+                let transport =
+                    unix::UnixTransport::new(server.clone(), stream, unix_config.clone());
+                tokio::spawn(async move {
+                    let _ = transport.process().await;
+                });
+            }
+        });
+    }
+
+    let mqtt_config = mqtt::Config { auth: auth.clone() };
+    if let Some(listen) = options.mqtt_listen {
+        let listener = TcpListener::bind(listen).await?;
+        info!("listening mqtt on {}", listener.local_addr()?);
+
+        let server = server.clone();
+        let mqtt_config = mqtt_config.clone();
+        tokio::spawn(async move {
+            while let Ok((stream, peer)) = listener.accept().await {
+                info!("incoming connection mqtt from {}", peer);
+
+                let transport =
+                    mqtt::MqttTransport::new(server.clone(), stream, peer, mqtt_config.clone());
+                tokio::spawn(async move {
+                    let _ = transport.process().await;
+                });
+            }
+        });
+    }
+
+    if let Some(listen) = options.mqtts_listen {
+        // Devices are often limited to TLS 1.2
+        let mqtt_tls = rustls::ServerConfig::builder()
+            .with_safe_defaults()
+            .with_no_client_auth()
+            .with_single_cert(certs.clone(), key.clone())?;
+        let acceptor = TlsAcceptor::from(Arc::new(mqtt_tls));
+        let listener = TcpListener::bind(listen).await?;
+        info!("listening mqtts on {}", listener.local_addr()?);
+
+        let server = server.clone();
+        let mqtt_config = mqtt_config.clone();
+        tokio::spawn(async move {
+            while let Ok((stream, peer)) = listener.accept().await {
+                info!("incoming connection mqtts from {}", peer);
+
+                let server = server.clone();
+                let acceptor = acceptor.clone();
+                let mqtt_config = mqtt_config.clone();
+                tokio::spawn(async move {
+                    let stream = match acceptor.accept(stream).await {
+                        Ok(stream) => stream,
+                        Err(err) => {
+                            info!("mqtts handshake from {} failed: {}", peer, err);
+                            return;
+                        }
+                    };
+                    let transport = mqtt::MqttTransport::new(server, stream, peer, mqtt_config);
+                    let _ = transport.process().await;
+                });
+            }
+        });
+    }
+
+    let rtp_ingest = match options.rtp_listen {
+        Some(listen) => {
+            let description = match (&options.rtp_channel, &options.rtp_source, &options.rtp_sdp) {
+                (Some(channel), Some(source), Some(path)) => {
+                    let sdp = fs::read_to_string(path).context("failed to read rtp sdp")?;
+                    let source = rtp_ingest::Source {
+                        ssrc: options.rtp_ssrc,
+                        ..*source
+                    };
+                    Some(rtp_ingest::Description::parse(channel, source, &sdp)?)
+                }
+                _ => None,
+            };
+            let ingest = Arc::new(rtp_ingest::Ingest::new(description));
+            let rtp_config = rtp_ingest::Config {
+                listen,
+                ingest: ingest.clone(),
+            };
+            let rtp = rtp_ingest::RtpIngestModule::new(server.clone(), rtp_config);
+            tokio::spawn(async move {
+                if let Err(err) = rtp.start().await {
+                    error!("rtp ingest failed: {}", err);
+                }
+            });
+            Some(ingest)
+        }
+        None => None,
+    };
+
+    let proxy_config = proxy::Config {
+        trusted: options.trusted_proxies.clone(),
+        proxy_protocol: options.proxy_protocol,
+    };
+    let whip_config = whip::Config {
+        listen: options.whip_listen,
+        auth,
+        proxy: proxy_config.clone(),
+        admin_token: options.admin_token.clone(),
+        ice_servers: turn.map(|turn| turn.urls()).unwrap_or_default(),
+        rtp_ingest,
+    };
+    let whip = whip::WhipModule::new(server.clone(), whip_config);
+    tokio::spawn(async move {
+        if let Err(err) = whip.start().await {
+            error!("whip failed: {}", err);
+        }
+    });
+
+    let ws_config = websocket::Config {
+        ping_interval: Duration::from_secs(options.ws_ping_interval),
+        pong_timeout: Duration::from_secs(options.ws_pong_timeout),
+        idle_timeout: Duration::from_secs(options.ws_idle_timeout),
+        proxy: proxy_config,
+        deflate: deflate::Config {
+            enabled: !options.ws_no_deflate,
+            server_max_window_bits: options.ws_deflate_window_bits,
+            server_no_context_takeover: options.ws_deflate_no_context_takeover,
+            client_no_context_takeover: options.ws_deflate_client_no_context_takeover,
+        },
+        mqtt: mqtt_config,
+    };
 
     let clone = server.clone();
+    let quic_ws_config = ws_config.clone();
     tokio::spawn(async move {
-        while let Some(new_conn) = incoming.next().await {
+        while let Some(mut new_conn) = incoming.next().await {
             info!("incoming connection quic");
 
             let server = clone.clone();
+            let ws_config = quic_ws_config.clone();
             tokio::spawn(async move {
-                let transport = webtransport::WebTransport::new(server, new_conn);
-                let _ = transport.process().await;
+                match quic::alpn(&mut new_conn).await.as_deref() {
+                    Some(quic::ALPN) => {
+                        let transport = quic::QuicTransport::new(server, new_conn);
+                        let _ = transport.process().await;
+                    }
+                    Some(moq::ALPN) => {
+                        let transport = moq::MoqTransport::new(server, new_conn);
+                        let _ = transport.process().await;
+                    }
+                    _ => {
+                        let transport =
+                            webtransport::WebTransport::new(server, new_conn, ws_config);
+                        let _ = transport.process().await;
+                    }
+                }
             });
         }
     });
@@ -136,20 +463,69 @@ async fn main() -> Result<()> {
         .with_single_cert(certs.clone(), key.clone())?;
     ws_tls.max_early_data_size = u32::MAX; // TODO
     ws_tls.key_log = Arc::new(rustls::KeyLogFile::new());
+    // HTTP/2 carries WebTransport for clients that can't reach the QUIC port
+    ws_tls.alpn_protocols = vec![b"h2".to_vec(), b"http/1.1".to_vec()];
 
     let acceptor = TlsAcceptor::from(Arc::new(ws_tls));
+
+    if let Some(ws_plain_listen) = options.ws_plain_listen {
+        let listener = TcpListener::bind(ws_plain_listen).await?;
+        info!("listening plain websocket on {}", listener.local_addr()?);
+
+        let server = server.clone();
+        let ws_config = ws_config.clone();
+        tokio::spawn(async move {
+            while let Ok((mut stream, peer)) = listener.accept().await {
+                info!("incoming connection tcp plain from {}", peer);
+
+                let server = server.clone();
+                let ws_config = ws_config.clone();
+                tokio::spawn(async move {
+                    let remote = match ws_config.proxy.accept(&mut stream, peer).await {
+                        Ok(remote) => remote,
+                        Err(err) => {
+                            error!("proxy protocol from {} failed: {}", peer, err);
+                            return;
+                        }
+                    };
+                    let transport = websocket::WebSocket::new(server, stream, remote, ws_config);
+                    let _ = transport.process().await;
+                });
+            }
+        });
+    }
+
     let listener = TcpListener::bind(options.ws_listen).await?;
     info!("listening websocket on {}", listener.local_addr()?);
 
-    while let Ok((stream, _)) = listener.accept().await {
-        info!("incoming connection tcp");
+    while let Ok((mut stream, peer)) = listener.accept().await {
+        info!("incoming connection tcp from {}", peer);
 
         let server = server.clone();
         let acceptor = acceptor.clone();
+        let ws_config = ws_config.clone();
         tokio::spawn(async move {
-            let stream = acceptor.accept(stream).await;
-            let transport = websocket::WebSocket::new(server, stream.unwrap());
-            let _ = transport.process().await;
+            let remote = match ws_config.proxy.accept(&mut stream, peer).await {
+                Ok(remote) => remote,
+                Err(err) => {
+                    error!("proxy protocol from {} failed: {}", peer, err);
+                    return;
+                }
+            };
+            let stream = match acceptor.accept(stream).await {
+                Ok(stream) => stream,
+                Err(err) => {
+                    info!("tls handshake from {} failed: {}", remote, err);
+                    return;
+                }
+            };
+            if stream.get_ref().1.alpn_protocol() == Some(b"h2") {
+                let transport = webtransport_h2::WebTransportH2::new(server, stream, remote);
+                let _ = transport.process().await;
+            } else {
+                let transport = websocket::WebSocket::new(server, stream, remote, ws_config);
+                let _ = transport.process().await;
+            }
         });
     }
 
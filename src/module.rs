@@ -0,0 +1,25 @@
+use tokio::sync::{mpsc, oneshot};
+
+/// Commands waiting for a module, the senders waiting when it is full.
+const COMMAND_QUEUE: usize = 16;
+
+/// Command sent to a module, answered on its reply channel.
+#[derive(Debug)]
+pub struct Message {
+    pub data: Vec<u8>,
+    pub reply: oneshot::Sender<Vec<u8>>,
+}
+
+/// Module registered in the server, receiving commands from the others.
+#[derive(Debug)]
+pub struct Module {
+    pub commands: mpsc::Sender<Message>,
+}
+
+impl Module {
+    /// Creates a module with the receiver of its commands.
+    pub fn new() -> (Self, mpsc::Receiver<Message>) {
+        let (commands, receiver) = mpsc::channel(COMMAND_QUEUE);
+        (Self { commands }, receiver)
+    }
+}
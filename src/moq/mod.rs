@@ -0,0 +1,802 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex, Weak};
+use std::time::Duration;
+
+use anyhow::Context;
+use async_trait::async_trait;
+use bytes::{Bytes, BytesMut};
+use futures_util::select;
+use futures_util::{FutureExt, StreamExt};
+use quinn::{Connecting, NewConnection, RecvStream, SendStream};
+use tokio::sync::{broadcast, mpsc, watch};
+use tokio::task::JoinHandle;
+
+use tracing::*;
+
+use crate::server;
+use crate::stats;
+use crate::transport;
+
+pub mod relay;
+pub mod wire;
+
+use relay::{Object, Relay, Track, Upstream};
+use wire::{Datagram, Filter, Message, SubgroupHeader};
+
+/// ALPN of MoQT over raw QUIC.
+pub const ALPN: &[u8] = b"moq-00";
+/// Time given to the client to open its control stream and set it up.
+const SETUP_TIMEOUT: Duration = Duration::from_secs(10);
+/// Largest object accepted.
+const MAX_OBJECT_SIZE: usize = 16 * 1024 * 1024;
+/// Subscriptions a client may make, told in SERVER_SETUP.
+const MAX_SUBSCRIPTIONS: u64 = 1 << 20;
+/// ROLE of the relay, publisher and subscriber.
+const PUBSUB: u64 = 0x03;
+
+type Published = Arc<Mutex<HashMap<u64, Arc<Track>>>>;
+
+/// Media over QUIC Transport (draft-ietf-moq-transport-07) session of a
+/// publisher, a subscriber or both, prism acting as their relay:
+///
+/// - the first bidirectional stream opened by the client is the control
+///   stream, starting with CLIENT_SETUP
+/// - ANNOUNCE registers the publisher of a namespace, tracks of which are
+///   subscribed from it on the first SUBSCRIBE and shared by all the
+///   subscribers
+/// - objects are received and relayed on subgroup streams, or as datagrams
+///   for the ones published as datagrams
+pub struct MoqTransport {
+    server: server::ServerPtr,
+    connecting: Connecting,
+    closed_tx: watch::Sender<bool>,
+    closed: watch::Receiver<bool>,
+}
+
+impl MoqTransport {
+    pub fn new(server: server::ServerPtr, connecting: Connecting) -> Self {
+        let (closed_tx, closed) = watch::channel(false);
+        Self {
+            server,
+            connecting,
+            closed_tx,
+            closed,
+        }
+    }
+}
+
+#[async_trait]
+impl transport::Transport for MoqTransport {
+    fn close(&self) {
+        let _ = self.closed_tx.send(true);
+    }
+
+    async fn process(self) -> Result<(), anyhow::Error> {
+        let mut closed = self.closed.clone();
+        let NewConnection {
+            connection,
+            mut uni_streams,
+            mut bi_streams,
+            mut datagrams,
+            ..
+        } = match self.connecting.await {
+            Ok(conn) => conn,
+            Err(err) => {
+                error!("accepting connection failed: {:?}", err);
+                return Ok(());
+            }
+        };
+        let remote = connection.remote_address();
+        info!("moq connection established from {}", remote);
+
+        let setup = async {
+            let (send, recv) = bi_streams.next().await.context("connection closed")??;
+            let mut control = ControlStream::new(send, recv);
+            match control.recv().await?.context("control stream closed")? {
+                Message::ClientSetup { versions, .. } if versions.contains(&wire::VERSION) => {}
+                Message::ClientSetup { versions, .. } => {
+                    anyhow::bail!("unsupported versions {:x?}", versions)
+                }
+                message => anyhow::bail!("unexpected {:?}", message),
+            }
+            control
+                .send(&Message::ServerSetup {
+                    version: wire::VERSION,
+                    params: vec![
+                        (wire::ROLE, wire::varint_param(PUBSUB)),
+                        (
+                            wire::MAX_SUBSCRIBE_ID,
+                            wire::varint_param(MAX_SUBSCRIPTIONS),
+                        ),
+                    ],
+                })
+                .await?;
+            Ok::<_, anyhow::Error>(control)
+        };
+        let mut control = match tokio::time::timeout(SETUP_TIMEOUT, setup).await {
+            Ok(Ok(control)) => control,
+            Ok(Err(err)) => {
+                info!("moq setup from {} failed: {}", remote, err);
+                connection.close(wire::PROTOCOL_VIOLATION.into(), b"setup failed");
+                return Ok(());
+            }
+            Err(_) => {
+                info!("moq setup from {} timed out", remote);
+                connection.close(wire::PROTOCOL_VIOLATION.into(), b"setup timed out");
+                return Ok(());
+            }
+        };
+
+        let (relay, stats) = {
+            let server = self.server.lock().unwrap();
+            (server.moq.clone(), server.stats.clone())
+        };
+        stats.record_connection();
+        let mut session = Session {
+            server: self.server,
+            relay,
+            connection: connection.clone(),
+            announced: Vec::new(),
+            published: Arc::new(Mutex::new(HashMap::new())),
+            pending: HashMap::new(),
+            next_id: 0,
+            subscriptions: HashMap::new(),
+        };
+        let (upstream_tx, mut upstream) = mpsc::channel(16);
+        let (messages_tx, mut messages) = mpsc::channel(64);
+
+        let initiator = loop {
+            select! {
+                _ = closed.changed().fuse() => break stats::Initiator::Local,
+                message = control.recv().fuse() => {
+                    let message = match message {
+                        Ok(Some(message)) => message,
+                        Ok(None) => {
+                            info!("moq control stream from {} closed", remote);
+                            break stats::Initiator::Remote;
+                        }
+                        Err(err) => {
+                            info!("moq control stream from {} failed: {}", remote, err);
+                            connection.close(wire::PROTOCOL_VIOLATION.into(), b"invalid control message");
+                            break stats::Initiator::Local;
+                        }
+                    };
+                    debug!("moq control: {:?}", message);
+                    let response = session.handle(message, &upstream_tx, &messages_tx);
+                    if let Some(response) = response {
+                        if let Err(err) = control.send(&response).await {
+                            info!("moq control stream from {} failed: {}", remote, err);
+                            break stats::Initiator::Remote;
+                        }
+                    }
+                },
+                request = upstream.recv().fuse() => {
+                    let message = match request {
+                        Some(request) => session.request(request),
+                        None => continue,
+                    };
+                    if let Some(message) = message {
+                        if let Err(err) = control.send(&message).await {
+                            info!("moq control stream from {} failed: {}", remote, err);
+                            break stats::Initiator::Remote;
+                        }
+                    }
+                },
+                message = messages.recv().fuse() => {
+                    if let Some(message) = message {
+                        if let Message::SubscribeDone { id, .. } | Message::SubscribeError { id, .. } = message {
+                            session.subscriptions.remove(&id);
+                        }
+                        if let Err(err) = control.send(&message).await {
+                            info!("moq control stream from {} failed: {}", remote, err);
+                            break stats::Initiator::Remote;
+                        }
+                    }
+                },
+                stream = uni_streams.next().fuse() => {
+                    match stream {
+                        Some(Ok(stream)) => {
+                            tokio::spawn(receive(stream, session.published.clone()));
+                        },
+                        Some(Err(err)) => {
+                            info!("moq connection from {} closed: {}", remote, err);
+                            break stats::Initiator::Remote;
+                        },
+                        None => break stats::Initiator::Remote,
+                    }
+                },
+                datagram = datagrams.next().fuse() => {
+                    match datagram {
+                        Some(Ok(datagram)) => session.receive_datagram(datagram),
+                        Some(Err(_)) | None => break stats::Initiator::Remote,
+                    }
+                },
+            }
+        };
+
+        stats.record_close("moq", initiator, stats::CLOSE_ABNORMAL);
+        session.close(&upstream_tx);
+        connection.close(wire::NO_ERROR.into(), b"");
+        info!("moq connection finished");
+        Ok(())
+    }
+}
+
+/// Tracks published and subscribed by a client.
+struct Session {
+    server: server::ServerPtr,
+    relay: Arc<Relay>,
+    connection: quinn::Connection,
+    announced: Vec<Vec<String>>,
+    /// Tracks subscribed from the client by the relay, by subscribe id
+    published: Published,
+    pending: HashMap<u64, (Arc<Track>, relay::SubscribeReply)>,
+    next_id: u64,
+    /// Tracks subscribed by the client
+    subscriptions: HashMap<u64, JoinHandle<()>>,
+}
+
+impl Session {
+    /// Handles a control message of the client, returning the response to
+    /// send back if any.
+    fn handle(
+        &mut self,
+        message: Message,
+        upstream: &mpsc::Sender<Upstream>,
+        messages: &mpsc::Sender<Message>,
+    ) -> Option<Message> {
+        match message {
+            Message::Announce { namespace, .. } => {
+                if self.relay.announce(&namespace, upstream.clone()) {
+                    info!(
+                        "moq namespace announced {}",
+                        relay::channel_name(&namespace)
+                    );
+                    self.announced.push(namespace.clone());
+                    Some(Message::AnnounceOk { namespace })
+                } else {
+                    Some(Message::AnnounceError {
+                        namespace,
+                        code: wire::INTERNAL_ERROR,
+                        reason: "already announced".to_string(),
+                    })
+                }
+            }
+            Message::Unannounce { namespace } => {
+                self.relay.unannounce(&namespace, upstream);
+                self.announced.retain(|announced| *announced != namespace);
+                None
+            }
+            Message::Subscribe(subscribe) => {
+                let id = subscribe.id;
+                let task = tokio::spawn(serve(
+                    self.server.clone(),
+                    self.relay.clone(),
+                    self.connection.clone(),
+                    subscribe,
+                    messages.clone(),
+                ));
+                if let Some(previous) = self.subscriptions.insert(id, task) {
+                    previous.abort();
+                }
+                None
+            }
+            Message::Unsubscribe { id } => {
+                let task = self.subscriptions.remove(&id)?;
+                task.abort();
+                Some(Message::SubscribeDone {
+                    id,
+                    code: wire::UNSUBSCRIBED,
+                    reason: "unsubscribed".to_string(),
+                    last: None,
+                })
+            }
+            Message::SubscribeOk { id, .. } => {
+                let (track, reply) = self.pending.remove(&id)?;
+                self.published.lock().unwrap().insert(id, track);
+                let _ = reply.send(Ok(()));
+                None
+            }
+            Message::SubscribeError {
+                id, code, reason, ..
+            } => {
+                let (_, reply) = self.pending.remove(&id)?;
+                let _ = reply.send(Err((code, reason)));
+                None
+            }
+            Message::SubscribeDone { id, .. } => {
+                // Dropping the track ends it for its subscribers
+                let track = self.published.lock().unwrap().remove(&id)?;
+                self.relay.remove_track(&track);
+                None
+            }
+            message => {
+                debug!("moq control message ignored: {:?}", message);
+                None
+            }
+        }
+    }
+
+    /// Forwards a request of the relay to the client, as a publisher.
+    fn request(&mut self, request: Upstream) -> Option<Message> {
+        match request {
+            Upstream::Subscribe { track, reply } => {
+                let id = self.next_id;
+                self.next_id += 1;
+                let subscribe = wire::Subscribe {
+                    id,
+                    alias: id,
+                    namespace: track.namespace.clone(),
+                    name: track.name.clone(),
+                    priority: 0x80,
+                    // Publisher's order
+                    group_order: 0x0,
+                    filter: Filter::LatestGroup,
+                    params: Vec::new(),
+                };
+                self.pending.insert(id, (track, reply));
+                Some(Message::Subscribe(subscribe))
+            }
+            Upstream::Unsubscribe(track) => {
+                let mut published = self.published.lock().unwrap();
+                let id = published
+                    .iter()
+                    .find(|(_, published)| Arc::ptr_eq(published, &track))
+                    .map(|(id, _)| *id)?;
+                published.remove(&id);
+                Some(Message::Unsubscribe { id })
+            }
+        }
+    }
+
+    fn receive_datagram(&self, data: Bytes) {
+        let datagram = match Datagram::decode(&data) {
+            Ok(datagram) => datagram,
+            Err(err) => {
+                debug!("moq datagram dropped: {}", err);
+                return;
+            }
+        };
+        let track = self
+            .published
+            .lock()
+            .unwrap()
+            .get(&datagram.subscribe_id)
+            .cloned();
+        match track {
+            Some(track) => track.push(Object {
+                group: datagram.group,
+                subgroup: 0,
+                id: datagram.object,
+                priority: datagram.priority,
+                status: datagram.status,
+                payload: datagram.payload,
+                datagram: true,
+            }),
+            None => debug!(
+                "moq datagram of unknown subscription {}",
+                datagram.subscribe_id
+            ),
+        }
+    }
+
+    fn close(self, upstream: &mpsc::Sender<Upstream>) {
+        for task in self.subscriptions.values() {
+            task.abort();
+        }
+        for namespace in &self.announced {
+            self.relay.unannounce(namespace, upstream);
+        }
+        for track in self.published.lock().unwrap().values() {
+            self.relay.remove_track(track);
+        }
+        for (track, _) in self.pending.values() {
+            self.relay.remove_track(track);
+        }
+    }
+}
+
+/// Leaves a track when its subscription ends, including when aborted.
+struct Subscription {
+    relay: Arc<Relay>,
+    track: Weak<Track>,
+}
+
+impl Drop for Subscription {
+    fn drop(&mut self) {
+        if let Some(track) = self.track.upgrade() {
+            self.relay.release(&track);
+        }
+    }
+}
+
+/// Subscribes a client to a track and sends it the objects.
+async fn serve(
+    server: server::ServerPtr,
+    relay: Arc<Relay>,
+    connection: quinn::Connection,
+    subscribe: wire::Subscribe,
+    messages: mpsc::Sender<Message>,
+) {
+    let id = subscribe.id;
+    let track = match relay
+        .subscribe(&server, &subscribe.namespace, &subscribe.name)
+        .await
+    {
+        Ok(track) => track,
+        Err((code, reason)) => {
+            debug!("moq subscribe {} failed: {}", id, reason);
+            let _ = messages
+                .send(Message::SubscribeError {
+                    id,
+                    code,
+                    reason,
+                    alias: subscribe.alias,
+                })
+                .await;
+            return;
+        }
+    };
+    info!(
+        "moq subscribed to {}/{}",
+        relay::channel_name(&track.namespace),
+        track.name
+    );
+    let _subscription = Subscription {
+        relay: relay.clone(),
+        track: Arc::downgrade(&track),
+    };
+    // Only the receiver is kept, the track ends when its publisher drops it
+    let relay::Subscriber {
+        cached,
+        largest,
+        mut objects,
+    } = track.subscribe();
+    drop(track);
+
+    let ok = Message::SubscribeOk {
+        id,
+        expires: 0,
+        // Ascending
+        group_order: 0x1,
+        largest,
+    };
+    if messages.send(ok).await.is_err() {
+        return;
+    }
+
+    let mut sink = Sink::new(connection, &subscribe);
+    let cached = match subscribe.filter {
+        Filter::LatestObject => Vec::new(),
+        _ => cached,
+    };
+    let mut cached = cached.into_iter();
+    let (code, reason) = loop {
+        let object = match cached.next() {
+            Some(object) => object,
+            None => match objects.recv().await {
+                Ok(object) => object,
+                Err(broadcast::error::RecvError::Lagged(skipped)) => {
+                    debug!("moq subscription {} skipped {} objects", id, skipped);
+                    continue;
+                }
+                Err(broadcast::error::RecvError::Closed) => {
+                    break (wire::TRACK_ENDED, "track ended")
+                }
+            },
+        };
+        if sink.ended(&object) {
+            break (wire::SUBSCRIPTION_ENDED, "subscription ended");
+        }
+        if !sink.wants(&object) {
+            continue;
+        }
+        if let Err(err) = sink.send(&object).await {
+            debug!("moq subscription {} failed: {}", id, err);
+            return;
+        }
+    };
+
+    let last = sink.finish();
+    let _ = messages
+        .send(Message::SubscribeDone {
+            id,
+            code,
+            reason: reason.to_string(),
+            last,
+        })
+        .await;
+}
+
+/// Writes the objects of a subscription, a stream per subgroup.
+struct Sink {
+    connection: quinn::Connection,
+    subscribe_id: u64,
+    alias: u64,
+    filter: Filter,
+    streams: HashMap<(u64, u64), SendStream>,
+    last: Option<(u64, u64)>,
+}
+
+impl Sink {
+    fn new(connection: quinn::Connection, subscribe: &wire::Subscribe) -> Self {
+        Self {
+            connection,
+            subscribe_id: subscribe.id,
+            alias: subscribe.alias,
+            filter: subscribe.filter,
+            streams: HashMap::new(),
+            last: None,
+        }
+    }
+
+    /// Whether an object is at or after the start of the subscription.
+    fn wants(&self, object: &Object) -> bool {
+        match self.filter {
+            Filter::AbsoluteStart { group, object: id }
+            | Filter::AbsoluteRange {
+                group, object: id, ..
+            } => (object.group, object.id) >= (group, id),
+            _ => true,
+        }
+    }
+
+    /// Whether an object is past the end of the subscription, a zero end
+    /// object including the whole end group.
+    fn ended(&self, object: &Object) -> bool {
+        match self.filter {
+            Filter::AbsoluteRange {
+                end_group,
+                end_object,
+                ..
+            } => {
+                object.group > end_group
+                    || (object.group == end_group && end_object != 0 && object.id > end_object)
+            }
+            _ => false,
+        }
+    }
+
+    async fn send(&mut self, object: &Object) -> Result<(), anyhow::Error> {
+        if object.datagram {
+            let datagram = Datagram {
+                subscribe_id: self.subscribe_id,
+                alias: self.alias,
+                group: object.group,
+                object: object.id,
+                priority: object.priority,
+                status: object.status,
+                payload: object.payload.clone(),
+            };
+            if let Err(err) = self.connection.send_datagram(datagram.encode()) {
+                debug!("moq datagram dropped: {}", err);
+            }
+            self.last = Some((object.group, object.id));
+            return Ok(());
+        }
+
+        // A new group ends the streams of the previous ones
+        if self.streams.keys().any(|(group, _)| *group < object.group) {
+            let ended: Vec<_> = self
+                .streams
+                .keys()
+                .filter(|(group, _)| *group < object.group)
+                .copied()
+                .collect();
+            for key in ended {
+                if let Some(stream) = self.streams.remove(&key) {
+                    finish(stream);
+                }
+            }
+        }
+
+        let key = (object.group, object.subgroup);
+        let stream = match self.streams.get_mut(&key) {
+            Some(stream) => stream,
+            None => {
+                let mut stream = self.connection.open_uni().await?;
+                // Newer groups first when the connection is congested
+                stream.set_priority(object.group.min(i32::MAX as u64) as i32)?;
+                let mut header = BytesMut::new();
+                SubgroupHeader {
+                    subscribe_id: self.subscribe_id,
+                    alias: self.alias,
+                    group: object.group,
+                    subgroup: object.subgroup,
+                    priority: object.priority,
+                }
+                .encode(&mut header);
+                stream.write_all(&header).await?;
+                self.streams.entry(key).or_insert(stream)
+            }
+        };
+        let mut data = BytesMut::with_capacity(object.payload.len() + 16);
+        wire::encode_stream_object(&mut data, object.id, object.status, &object.payload);
+        stream.write_all(&data).await?;
+        self.last = Some((object.group, object.id));
+
+        if object.status >= wire::END_OF_GROUP {
+            if let Some(stream) = self.streams.remove(&key) {
+                finish(stream);
+            }
+        }
+        Ok(())
+    }
+
+    /// Ends the open streams, returning the last object sent.
+    fn finish(&mut self) -> Option<(u64, u64)> {
+        for (_, stream) in self.streams.drain() {
+            finish(stream);
+        }
+        self.last
+    }
+}
+
+/// Finishes a stream without waiting for its acknowledgement.
+fn finish(mut stream: SendStream) {
+    tokio::spawn(async move {
+        let _ = stream.finish().await;
+    });
+}
+
+/// Pushes the objects of a subgroup stream of the client to the track it
+/// was subscribed for.
+async fn receive(stream: RecvStream, published: Published) {
+    let mut reader = Reader::new(stream);
+    let header = match reader.decode(SubgroupHeader::decode).await {
+        Ok(Some(header)) => header,
+        Ok(None) => return,
+        Err(err) => {
+            debug!("moq stream failed: {}", err);
+            reader.stop();
+            return;
+        }
+    };
+    let track = match published.lock().unwrap().get(&header.subscribe_id) {
+        Some(track) => Arc::downgrade(track),
+        None => {
+            debug!("moq stream of unknown subscription {}", header.subscribe_id);
+            reader.stop();
+            return;
+        }
+    };
+
+    loop {
+        let (id, status, length) = match reader.decode(wire::decode_stream_object).await {
+            Ok(Some(object)) => object,
+            Ok(None) => return,
+            Err(err) => {
+                debug!("moq stream failed: {}", err);
+                reader.stop();
+                return;
+            }
+        };
+        if length > MAX_OBJECT_SIZE {
+            debug!("moq stream with object of {} bytes", length);
+            reader.stop();
+            return;
+        }
+        let payload = match reader.read(length).await {
+            Ok(payload) => payload,
+            Err(err) => {
+                debug!("moq stream failed: {}", err);
+                return;
+            }
+        };
+        // The subscription ended, the rest of the stream is not relayed
+        let track = match track.upgrade() {
+            Some(track) => track,
+            None => {
+                reader.stop();
+                return;
+            }
+        };
+        track.push(Object {
+            group: header.group,
+            subgroup: header.subgroup,
+            id,
+            priority: header.priority,
+            status,
+            payload,
+            datagram: false,
+        });
+    }
+}
+
+/// Buffered reads of a stream of the client.
+struct Reader {
+    stream: RecvStream,
+    buffer: BytesMut,
+}
+
+impl Reader {
+    fn new(stream: RecvStream) -> Self {
+        Self {
+            stream,
+            buffer: BytesMut::new(),
+        }
+    }
+
+    /// Reads more of the stream, false at its end.
+    async fn fill(&mut self) -> Result<bool, anyhow::Error> {
+        let mut chunk = [0u8; 4096];
+        match self.stream.read(&mut chunk).await? {
+            Some(n) => {
+                self.buffer.extend_from_slice(&chunk[..n]);
+                Ok(true)
+            }
+            None => Ok(false),
+        }
+    }
+
+    /// Decodes the next value, None when the stream ends before it starts.
+    async fn decode<T>(
+        &mut self,
+        decode: impl Fn(&mut &[u8]) -> Result<T, wire::Error>,
+    ) -> Result<Option<T>, anyhow::Error> {
+        loop {
+            let mut buf = &self.buffer[..];
+            match decode(&mut buf) {
+                Ok(value) => {
+                    let size = self.buffer.len() - buf.len();
+                    let _ = self.buffer.split_to(size);
+                    return Ok(Some(value));
+                }
+                Err(wire::Error::Short) => {}
+                Err(err) => return Err(err.into()),
+            }
+            if self.buffer.len() > wire::MAX_MESSAGE_SIZE {
+                anyhow::bail!("message too long");
+            }
+            if !self.fill().await? {
+                if self.buffer.is_empty() {
+                    return Ok(None);
+                }
+                anyhow::bail!("truncated message");
+            }
+        }
+    }
+
+    async fn read(&mut self, length: usize) -> Result<Bytes, anyhow::Error> {
+        while self.buffer.len() < length {
+            if !self.fill().await? {
+                anyhow::bail!("truncated object");
+            }
+        }
+        Ok(self.buffer.split_to(length).freeze())
+    }
+
+    fn stop(&mut self) {
+        let _ = self.stream.stop(wire::PROTOCOL_VIOLATION.into());
+    }
+}
+
+/// Control stream of the client.
+struct ControlStream {
+    send: SendStream,
+    reader: Reader,
+}
+
+impl ControlStream {
+    fn new(send: SendStream, recv: RecvStream) -> Self {
+        Self {
+            send,
+            reader: Reader::new(recv),
+        }
+    }
+
+    async fn recv(&mut self) -> Result<Option<Message>, anyhow::Error> {
+        self.reader
+            .decode(|buf| {
+                let (message, size) = Message::decode(buf)?;
+                *buf = &buf[size..];
+                Ok(message)
+            })
+            .await
+    }
+
+    async fn send(&mut self, message: &Message) -> Result<(), anyhow::Error> {
+        self.send.write_all(&message.encode()).await?;
+        Ok(())
+    }
+}
@@ -0,0 +1,329 @@
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use bytes::Bytes;
+use tokio::sync::{broadcast, mpsc, oneshot};
+use tracing::*;
+
+use super::wire;
+use crate::rush;
+use crate::server;
+
+/// Time given to a publisher to accept a subscription relayed to it.
+const SUBSCRIBE_TIMEOUT: Duration = Duration::from_secs(10);
+/// Most objects cached for the current group of a track.
+const MAX_CACHED_OBJECTS: usize = 4096;
+
+#[derive(Debug)]
+pub struct Object {
+    pub group: u64,
+    pub subgroup: u64,
+    pub id: u64,
+    pub priority: u8,
+    pub status: u64,
+    pub payload: Bytes,
+    /// Received as a datagram, and forwarded as one
+    pub datagram: bool,
+}
+
+#[derive(Debug, Default)]
+struct Cache {
+    /// Objects of the latest group, for subscribers joining it
+    objects: Vec<Arc<Object>>,
+    largest: Option<(u64, u64)>,
+}
+
+/// Track relayed to any number of subscribers.
+#[derive(Debug)]
+pub struct Track {
+    pub namespace: Vec<String>,
+    pub name: String,
+    objects: broadcast::Sender<Arc<Object>>,
+    cache: Mutex<Cache>,
+    subscribers: AtomicUsize,
+    /// Publisher the track is subscribed from, told when it is not used
+    /// anymore
+    upstream: Option<mpsc::Sender<Upstream>>,
+    /// Channel the payloads are published into
+    channel: Option<broadcast::Sender<Vec<u8>>>,
+}
+
+impl Track {
+    fn new(
+        namespace: &[String],
+        name: &str,
+        upstream: Option<mpsc::Sender<Upstream>>,
+        channel: Option<broadcast::Sender<Vec<u8>>>,
+    ) -> Self {
+        let (objects, _rx) = broadcast::channel(256);
+        Self {
+            namespace: namespace.to_vec(),
+            name: name.to_string(),
+            objects,
+            cache: Mutex::new(Cache::default()),
+            subscribers: AtomicUsize::new(0),
+            upstream,
+            channel,
+        }
+    }
+
+    pub fn push(&self, object: Object) {
+        if let Some(channel) = &self.channel {
+            if !object.payload.is_empty() {
+                let _ = channel.send(object.payload.to_vec());
+            }
+        }
+
+        let object = Arc::new(object);
+        let mut cache = self.cache.lock().unwrap();
+        let position = (object.group, object.id);
+        match cache.largest {
+            Some((group, _)) if object.group < group => {
+                // Late object of a previous group, only for the live subscribers
+            }
+            Some((group, _)) if object.group == group => {
+                if cache.objects.len() < MAX_CACHED_OBJECTS {
+                    cache.objects.push(object.clone());
+                }
+            }
+            _ => {
+                cache.objects.clear();
+                cache.objects.push(object.clone());
+            }
+        }
+        // None is smaller than any position
+        if cache.largest < Some(position) {
+            cache.largest = Some(position);
+        }
+        // Sent under the lock, a subscriber gets each object once from the
+        // cache or live
+        let _ = self.objects.send(object);
+    }
+
+    pub fn subscribe(&self) -> Subscriber {
+        let cache = self.cache.lock().unwrap();
+        Subscriber {
+            cached: cache.objects.clone(),
+            largest: cache.largest,
+            objects: self.objects.subscribe(),
+        }
+    }
+}
+
+/// Objects of a track for a new subscriber.
+pub struct Subscriber {
+    /// Objects of the latest group
+    pub cached: Vec<Arc<Object>>,
+    pub largest: Option<(u64, u64)>,
+    /// Objects that follow the cached ones
+    pub objects: broadcast::Receiver<Arc<Object>>,
+}
+
+/// Outcome of a subscription relayed to a publisher, the SUBSCRIBE_ERROR
+/// code and reason when refused.
+pub type SubscribeReply = oneshot::Sender<Result<(), (u64, String)>>;
+
+/// Request of the relay to the session of a publisher.
+#[derive(Debug)]
+pub enum Upstream {
+    Subscribe {
+        track: Arc<Track>,
+        reply: SubscribeReply,
+    },
+    Unsubscribe(Arc<Track>),
+}
+
+/// Announced namespaces and relayed tracks, shared by the MoQT sessions.
+///
+/// A track is subscribed once from the publisher that announced its
+/// namespace, whatever the number of subscribers, and unsubscribed after
+/// the last one leaves. Namespaces nobody announced are served from the
+/// prism channel of the same name, each message as a group.
+#[derive(Debug, Default)]
+pub struct Relay {
+    announcements: Mutex<HashMap<Vec<String>, mpsc::Sender<Upstream>>>,
+    /// Tracks by namespace and name
+    tracks: Mutex<HashMap<TrackKey, Arc<Track>>>,
+}
+
+type TrackKey = (Vec<String>, String);
+
+/// Channel of a namespace, its fields joined by slashes.
+pub fn channel_name(namespace: &[String]) -> String {
+    namespace.join("/")
+}
+
+impl Relay {
+    pub fn announce(&self, namespace: &[String], publisher: mpsc::Sender<Upstream>) -> bool {
+        let mut announcements = self.announcements.lock().unwrap();
+        match announcements.get(namespace) {
+            Some(current) if !current.is_closed() => false,
+            _ => {
+                announcements.insert(namespace.to_vec(), publisher);
+                true
+            }
+        }
+    }
+
+    pub fn unannounce(&self, namespace: &[String], publisher: &mpsc::Sender<Upstream>) {
+        let mut announcements = self.announcements.lock().unwrap();
+        if announcements
+            .get(namespace)
+            .is_some_and(|current| current.same_channel(publisher))
+        {
+            announcements.remove(namespace);
+        }
+    }
+
+    /// Track for a new subscriber, subscribed from its publisher if not
+    /// relayed yet. Errors are SUBSCRIBE_ERROR codes and reasons.
+    pub async fn subscribe(
+        &self,
+        server: &server::ServerPtr,
+        namespace: &[String],
+        name: &str,
+    ) -> Result<Arc<Track>, (u64, String)> {
+        let key = (namespace.to_vec(), name.to_string());
+        let publisher = self.announcements.lock().unwrap().get(namespace).cloned();
+        // Objects of announced tracks are published into the channel
+        let channel = match &publisher {
+            Some(_) => {
+                let channel = server
+                    .lock()
+                    .unwrap()
+                    .find_or_create_channel(&channel_name(namespace));
+                let tx = channel.lock().await.broadcast.clone();
+                Some(tx)
+            }
+            None => None,
+        };
+        let track = {
+            let mut tracks = self.tracks.lock().unwrap();
+            if let Some(track) = tracks.get(&key) {
+                track.subscribers.fetch_add(1, Ordering::Relaxed);
+                return Ok(track.clone());
+            }
+            let track = Arc::new(Track::new(namespace, name, publisher.clone(), channel));
+            track.subscribers.fetch_add(1, Ordering::Relaxed);
+            tracks.insert(key, track.clone());
+            track
+        };
+
+        let publisher = match publisher {
+            Some(publisher) => publisher,
+            None => {
+                self.bridge(server, track.clone()).await;
+                return Ok(track);
+            }
+        };
+
+        let (reply, result) = oneshot::channel();
+        let request = Upstream::Subscribe {
+            track: track.clone(),
+            reply,
+        };
+        let result = match publisher.send(request).await {
+            Ok(()) => match tokio::time::timeout(SUBSCRIBE_TIMEOUT, result).await {
+                Ok(Ok(result)) => result,
+                Ok(Err(_)) => Err((wire::INTERNAL_ERROR, "publisher gone".to_string())),
+                Err(_) => Err((wire::TIMEOUT, "publisher timed out".to_string())),
+            },
+            Err(_) => Err((wire::INTERNAL_ERROR, "publisher gone".to_string())),
+        };
+        match result {
+            Ok(()) => Ok(track),
+            Err(err) => {
+                self.remove_track(&track);
+                Err(err)
+            }
+        }
+    }
+
+    /// Feeds a track with the messages of the channel of its namespace,
+    /// only the ones of a RUSH track when it is named after its id.
+    async fn bridge(&self, server: &server::ServerPtr, track: Arc<Track>) {
+        let channel = server
+            .lock()
+            .unwrap()
+            .find_or_create_channel(&channel_name(&track.namespace));
+        let mut messages = channel.lock().await.broadcast.subscribe();
+        let track_id: Option<u32> = track.name.parse().ok();
+        info!(
+            "moq track {}/{} relayed from its channel",
+            channel_name(&track.namespace),
+            track.name
+        );
+
+        tokio::spawn(async move {
+            let mut group = 0;
+            loop {
+                let data = match messages.recv().await {
+                    Ok(data) => data,
+                    Err(broadcast::error::RecvError::Lagged(_)) => continue,
+                    Err(broadcast::error::RecvError::Closed) => break,
+                };
+                if track.subscribers.load(Ordering::Relaxed) == 0 {
+                    break;
+                }
+                if let Some(track_id) = track_id {
+                    if rush::Header::parse(&data).map(|header| header.track_id) != Some(track_id) {
+                        continue;
+                    }
+                }
+                track.push(Object {
+                    group,
+                    subgroup: 0,
+                    id: 0,
+                    priority: 0,
+                    status: wire::NORMAL,
+                    payload: data.into(),
+                    datagram: false,
+                });
+                // Each message is a whole group, its stream ends with it
+                track.push(Object {
+                    group,
+                    subgroup: 0,
+                    id: 1,
+                    priority: 0,
+                    status: wire::END_OF_GROUP,
+                    payload: Bytes::new(),
+                    datagram: false,
+                });
+                group += 1;
+            }
+        });
+    }
+
+    /// Leaves a track, unsubscribing it from its publisher when it was the
+    /// last subscriber.
+    pub fn release(&self, track: &Arc<Track>) {
+        let mut tracks = self.tracks.lock().unwrap();
+        if track.subscribers.fetch_sub(1, Ordering::Relaxed) != 1 {
+            return;
+        }
+        let key = (track.namespace.clone(), track.name.clone());
+        if tracks
+            .get(&key)
+            .is_some_and(|current| Arc::ptr_eq(current, track))
+        {
+            tracks.remove(&key);
+        }
+        if let Some(upstream) = &track.upstream {
+            let _ = upstream.try_send(Upstream::Unsubscribe(track.clone()));
+        }
+    }
+
+    /// Forgets a track ended by its publisher.
+    pub fn remove_track(&self, track: &Arc<Track>) {
+        let mut tracks = self.tracks.lock().unwrap();
+        let key = (track.namespace.clone(), track.name.clone());
+        if tracks
+            .get(&key)
+            .is_some_and(|current| Arc::ptr_eq(current, track))
+        {
+            tracks.remove(&key);
+        }
+    }
+}
@@ -0,0 +1,896 @@
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+
+use crate::util::{self, write_varint};
+
+/// draft-ietf-moq-transport-07
+pub const VERSION: u64 = 0xff000007;
+/// Largest control message accepted, the larger ones are refused from
+/// their length.
+pub const MAX_MESSAGE_SIZE: usize = 64 * 1024;
+
+/// Setup parameters.
+pub const ROLE: u64 = 0x00;
+pub const MAX_SUBSCRIBE_ID: u64 = 0x02;
+
+/// Types of the data streams and datagrams.
+pub const OBJECT_DATAGRAM: u64 = 0x01;
+pub const STREAM_HEADER_SUBGROUP: u64 = 0x04;
+
+/// Object statuses.
+pub const NORMAL: u64 = 0x0;
+pub const END_OF_GROUP: u64 = 0x3;
+
+/// Session termination codes.
+pub const NO_ERROR: u32 = 0x0;
+pub const PROTOCOL_VIOLATION: u32 = 0x3;
+
+/// SUBSCRIBE_ERROR codes.
+pub const INTERNAL_ERROR: u64 = 0x0;
+pub const TIMEOUT: u64 = 0x5;
+
+/// SUBSCRIBE_DONE status codes.
+pub const UNSUBSCRIBED: u64 = 0x0;
+pub const TRACK_ENDED: u64 = 0x3;
+pub const SUBSCRIPTION_ENDED: u64 = 0x4;
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum Error {
+    /// More bytes are needed to decode
+    Short,
+    Invalid(&'static str),
+}
+
+impl std::fmt::Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Error::Short => write!(f, "truncated message"),
+            Error::Invalid(what) => write!(f, "invalid {}", what),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+pub type Params = Vec<(u64, Vec<u8>)>;
+
+/// Objects sent from a position, the latest group or object without one.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Filter {
+    LatestGroup,
+    LatestObject,
+    AbsoluteStart {
+        group: u64,
+        object: u64,
+    },
+    AbsoluteRange {
+        group: u64,
+        object: u64,
+        end_group: u64,
+        end_object: u64,
+    },
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Subscribe {
+    pub id: u64,
+    pub alias: u64,
+    pub namespace: Vec<String>,
+    pub name: String,
+    pub priority: u8,
+    pub group_order: u8,
+    pub filter: Filter,
+    pub params: Params,
+}
+
+/// Control messages, the ones prism does not use are skipped.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Message {
+    ClientSetup {
+        versions: Vec<u64>,
+        params: Params,
+    },
+    ServerSetup {
+        version: u64,
+        params: Params,
+    },
+    Subscribe(Subscribe),
+    SubscribeOk {
+        id: u64,
+        expires: u64,
+        group_order: u8,
+        /// Largest group and object published so far
+        largest: Option<(u64, u64)>,
+    },
+    SubscribeError {
+        id: u64,
+        code: u64,
+        reason: String,
+        alias: u64,
+    },
+    Announce {
+        namespace: Vec<String>,
+        params: Params,
+    },
+    AnnounceOk {
+        namespace: Vec<String>,
+    },
+    AnnounceError {
+        namespace: Vec<String>,
+        code: u64,
+        reason: String,
+    },
+    Unannounce {
+        namespace: Vec<String>,
+    },
+    Unsubscribe {
+        id: u64,
+    },
+    SubscribeDone {
+        id: u64,
+        code: u64,
+        reason: String,
+        /// Final group and object sent
+        last: Option<(u64, u64)>,
+    },
+    AnnounceCancel {
+        namespace: Vec<String>,
+        code: u64,
+        reason: String,
+    },
+    GoAway {
+        uri: String,
+    },
+    Other(u64),
+}
+
+impl Message {
+    /// Decodes a message from the start of a buffer, returning it with its
+    /// size or `Error::Short` when incomplete.
+    pub fn decode(data: &[u8]) -> Result<(Self, usize), Error> {
+        let mut buf = data;
+        let kind = read_varint(&mut buf)?;
+        let length = read_varint(&mut buf)? as usize;
+        if length > MAX_MESSAGE_SIZE {
+            return Err(Error::Invalid("message length"));
+        }
+        if buf.len() < length {
+            return Err(Error::Short);
+        }
+        let size = data.len() - buf.len() + length;
+        let mut payload = &buf[..length];
+        let message = Self::decode_payload(kind, &mut payload).map_err(|err| match err {
+            // The length is known, a short payload is malformed
+            Error::Short => Error::Invalid("message length"),
+            err => err,
+        })?;
+        Ok((message, size))
+    }
+
+    fn decode_payload(kind: u64, buf: &mut &[u8]) -> Result<Self, Error> {
+        let message = match kind {
+            0x40 => {
+                let count = read_varint(buf)?;
+                let versions = (0..count)
+                    .map(|_| read_varint(buf))
+                    .collect::<Result<_, _>>()?;
+                Message::ClientSetup {
+                    versions,
+                    params: read_params(buf)?,
+                }
+            }
+            0x41 => Message::ServerSetup {
+                version: read_varint(buf)?,
+                params: read_params(buf)?,
+            },
+            0x03 => {
+                let id = read_varint(buf)?;
+                let alias = read_varint(buf)?;
+                let namespace = read_namespace(buf)?;
+                let name = read_string(buf)?;
+                let priority = read_u8(buf)?;
+                let group_order = read_u8(buf)?;
+                let filter = match read_varint(buf)? {
+                    0x1 => Filter::LatestGroup,
+                    0x2 => Filter::LatestObject,
+                    0x3 => Filter::AbsoluteStart {
+                        group: read_varint(buf)?,
+                        object: read_varint(buf)?,
+                    },
+                    0x4 => Filter::AbsoluteRange {
+                        group: read_varint(buf)?,
+                        object: read_varint(buf)?,
+                        end_group: read_varint(buf)?,
+                        end_object: read_varint(buf)?,
+                    },
+                    _ => return Err(Error::Invalid("filter type")),
+                };
+                Message::Subscribe(Subscribe {
+                    id,
+                    alias,
+                    namespace,
+                    name,
+                    priority,
+                    group_order,
+                    filter,
+                    params: read_params(buf)?,
+                })
+            }
+            0x04 => {
+                let id = read_varint(buf)?;
+                let expires = read_varint(buf)?;
+                let group_order = read_u8(buf)?;
+                let largest = match read_u8(buf)? {
+                    0 => None,
+                    _ => Some((read_varint(buf)?, read_varint(buf)?)),
+                };
+                Message::SubscribeOk {
+                    id,
+                    expires,
+                    group_order,
+                    largest,
+                }
+            }
+            0x05 => Message::SubscribeError {
+                id: read_varint(buf)?,
+                code: read_varint(buf)?,
+                reason: read_string(buf)?,
+                alias: read_varint(buf)?,
+            },
+            0x06 => Message::Announce {
+                namespace: read_namespace(buf)?,
+                params: read_params(buf)?,
+            },
+            0x07 => Message::AnnounceOk {
+                namespace: read_namespace(buf)?,
+            },
+            0x08 => Message::AnnounceError {
+                namespace: read_namespace(buf)?,
+                code: read_varint(buf)?,
+                reason: read_string(buf)?,
+            },
+            0x09 => Message::Unannounce {
+                namespace: read_namespace(buf)?,
+            },
+            0x0A => Message::Unsubscribe {
+                id: read_varint(buf)?,
+            },
+            0x0B => {
+                let id = read_varint(buf)?;
+                let code = read_varint(buf)?;
+                let reason = read_string(buf)?;
+                let last = match read_u8(buf)? {
+                    0 => None,
+                    _ => Some((read_varint(buf)?, read_varint(buf)?)),
+                };
+                Message::SubscribeDone {
+                    id,
+                    code,
+                    reason,
+                    last,
+                }
+            }
+            0x0C => Message::AnnounceCancel {
+                namespace: read_namespace(buf)?,
+                code: read_varint(buf)?,
+                reason: read_string(buf)?,
+            },
+            0x10 => Message::GoAway {
+                uri: read_string(buf)?,
+            },
+            kind => Message::Other(kind),
+        };
+        Ok(message)
+    }
+
+    pub fn encode(&self) -> Bytes {
+        let mut payload = BytesMut::new();
+        let buf = &mut payload;
+        let kind = match self {
+            Message::ClientSetup { versions, params } => {
+                write_varint(buf, versions.len() as u64);
+                for version in versions {
+                    write_varint(buf, *version);
+                }
+                write_params(buf, params);
+                0x40
+            }
+            Message::ServerSetup { version, params } => {
+                write_varint(buf, *version);
+                write_params(buf, params);
+                0x41
+            }
+            Message::Subscribe(subscribe) => {
+                write_varint(buf, subscribe.id);
+                write_varint(buf, subscribe.alias);
+                write_namespace(buf, &subscribe.namespace);
+                write_string(buf, &subscribe.name);
+                buf.put_u8(subscribe.priority);
+                buf.put_u8(subscribe.group_order);
+                match subscribe.filter {
+                    Filter::LatestGroup => write_varint(buf, 0x1),
+                    Filter::LatestObject => write_varint(buf, 0x2),
+                    Filter::AbsoluteStart { group, object } => {
+                        write_varint(buf, 0x3);
+                        write_varint(buf, group);
+                        write_varint(buf, object);
+                    }
+                    Filter::AbsoluteRange {
+                        group,
+                        object,
+                        end_group,
+                        end_object,
+                    } => {
+                        write_varint(buf, 0x4);
+                        write_varint(buf, group);
+                        write_varint(buf, object);
+                        write_varint(buf, end_group);
+                        write_varint(buf, end_object);
+                    }
+                }
+                write_params(buf, &subscribe.params);
+                0x03
+            }
+            Message::SubscribeOk {
+                id,
+                expires,
+                group_order,
+                largest,
+            } => {
+                write_varint(buf, *id);
+                write_varint(buf, *expires);
+                buf.put_u8(*group_order);
+                write_position(buf, *largest);
+                0x04
+            }
+            Message::SubscribeError {
+                id,
+                code,
+                reason,
+                alias,
+            } => {
+                write_varint(buf, *id);
+                write_varint(buf, *code);
+                write_string(buf, reason);
+                write_varint(buf, *alias);
+                0x05
+            }
+            Message::Announce { namespace, params } => {
+                write_namespace(buf, namespace);
+                write_params(buf, params);
+                0x06
+            }
+            Message::AnnounceOk { namespace } => {
+                write_namespace(buf, namespace);
+                0x07
+            }
+            Message::AnnounceError {
+                namespace,
+                code,
+                reason,
+            } => {
+                write_namespace(buf, namespace);
+                write_varint(buf, *code);
+                write_string(buf, reason);
+                0x08
+            }
+            Message::Unannounce { namespace } => {
+                write_namespace(buf, namespace);
+                0x09
+            }
+            Message::Unsubscribe { id } => {
+                write_varint(buf, *id);
+                0x0A
+            }
+            Message::SubscribeDone {
+                id,
+                code,
+                reason,
+                last,
+            } => {
+                write_varint(buf, *id);
+                write_varint(buf, *code);
+                write_string(buf, reason);
+                write_position(buf, *last);
+                0x0B
+            }
+            Message::AnnounceCancel {
+                namespace,
+                code,
+                reason,
+            } => {
+                write_namespace(buf, namespace);
+                write_varint(buf, *code);
+                write_string(buf, reason);
+                0x0C
+            }
+            Message::GoAway { uri } => {
+                write_string(buf, uri);
+                0x10
+            }
+            Message::Other(kind) => *kind,
+        };
+
+        let mut data = BytesMut::with_capacity(payload.len() + 16);
+        write_varint(&mut data, kind);
+        write_varint(&mut data, payload.len() as u64);
+        data.put_slice(&payload);
+        data.freeze()
+    }
+}
+
+/// Header of a subgroup stream, followed by its objects.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SubgroupHeader {
+    pub subscribe_id: u64,
+    pub alias: u64,
+    pub group: u64,
+    pub subgroup: u64,
+    pub priority: u8,
+}
+
+impl SubgroupHeader {
+    pub fn decode(buf: &mut &[u8]) -> Result<Self, Error> {
+        if read_varint(buf)? != STREAM_HEADER_SUBGROUP {
+            return Err(Error::Invalid("stream type"));
+        }
+        Ok(Self {
+            subscribe_id: read_varint(buf)?,
+            alias: read_varint(buf)?,
+            group: read_varint(buf)?,
+            subgroup: read_varint(buf)?,
+            priority: read_u8(buf)?,
+        })
+    }
+
+    pub fn encode(&self, buf: &mut BytesMut) {
+        write_varint(buf, STREAM_HEADER_SUBGROUP);
+        write_varint(buf, self.subscribe_id);
+        write_varint(buf, self.alias);
+        write_varint(buf, self.group);
+        write_varint(buf, self.subgroup);
+        buf.put_u8(self.priority);
+    }
+}
+
+/// Object of a subgroup stream, the payload length and the status when it
+/// is empty.
+pub fn decode_stream_object(buf: &mut &[u8]) -> Result<(u64, u64, usize), Error> {
+    let id = read_varint(buf)?;
+    let length = read_varint(buf)? as usize;
+    let status = match length {
+        0 => read_varint(buf)?,
+        _ => NORMAL,
+    };
+    Ok((id, status, length))
+}
+
+pub fn encode_stream_object(buf: &mut BytesMut, id: u64, status: u64, payload: &[u8]) {
+    write_varint(buf, id);
+    write_varint(buf, payload.len() as u64);
+    if payload.is_empty() {
+        write_varint(buf, status);
+    }
+    buf.put_slice(payload);
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Datagram {
+    pub subscribe_id: u64,
+    pub alias: u64,
+    pub group: u64,
+    pub object: u64,
+    pub priority: u8,
+    pub status: u64,
+    pub payload: Bytes,
+}
+
+impl Datagram {
+    pub fn decode(data: &Bytes) -> Result<Self, Error> {
+        let mut buf = &data[..];
+        if read_varint(&mut buf)? != OBJECT_DATAGRAM {
+            return Err(Error::Invalid("datagram type"));
+        }
+        let subscribe_id = read_varint(&mut buf)?;
+        let alias = read_varint(&mut buf)?;
+        let group = read_varint(&mut buf)?;
+        let object = read_varint(&mut buf)?;
+        let priority = read_u8(&mut buf)?;
+        let length = read_varint(&mut buf)? as usize;
+        let status = match length {
+            0 => read_varint(&mut buf)?,
+            _ => NORMAL,
+        };
+        if buf.len() < length {
+            return Err(Error::Short);
+        }
+        let start = data.len() - buf.len();
+        Ok(Self {
+            subscribe_id,
+            alias,
+            group,
+            object,
+            priority,
+            status,
+            payload: data.slice(start..start + length),
+        })
+    }
+
+    pub fn encode(&self) -> Bytes {
+        let mut buf = BytesMut::with_capacity(self.payload.len() + 32);
+        write_varint(&mut buf, OBJECT_DATAGRAM);
+        write_varint(&mut buf, self.subscribe_id);
+        write_varint(&mut buf, self.alias);
+        write_varint(&mut buf, self.group);
+        write_varint(&mut buf, self.object);
+        buf.put_u8(self.priority);
+        write_varint(&mut buf, self.payload.len() as u64);
+        if self.payload.is_empty() {
+            write_varint(&mut buf, self.status);
+        }
+        buf.put_slice(&self.payload);
+        buf.freeze()
+    }
+}
+
+fn read_varint(buf: &mut &[u8]) -> Result<u64, Error> {
+    util::read_varint(buf).ok_or(Error::Short)
+}
+
+fn read_u8(buf: &mut &[u8]) -> Result<u8, Error> {
+    if buf.is_empty() {
+        return Err(Error::Short);
+    }
+    Ok(buf.get_u8())
+}
+
+fn read_bytes<'a>(buf: &mut &'a [u8]) -> Result<&'a [u8], Error> {
+    let length = read_varint(buf)? as usize;
+    if buf.len() < length {
+        return Err(Error::Short);
+    }
+    let (bytes, rest) = buf.split_at(length);
+    *buf = rest;
+    Ok(bytes)
+}
+
+fn read_string(buf: &mut &[u8]) -> Result<String, Error> {
+    String::from_utf8(read_bytes(buf)?.to_vec()).map_err(|_| Error::Invalid("string"))
+}
+
+fn write_string(buf: &mut BytesMut, value: &str) {
+    write_varint(buf, value.len() as u64);
+    buf.put_slice(value.as_bytes());
+}
+
+/// Namespaces are tuples of strings.
+fn read_namespace(buf: &mut &[u8]) -> Result<Vec<String>, Error> {
+    let count = read_varint(buf)?;
+    if count == 0 || count > 32 {
+        return Err(Error::Invalid("namespace"));
+    }
+    (0..count).map(|_| read_string(buf)).collect()
+}
+
+fn write_namespace(buf: &mut BytesMut, namespace: &[String]) {
+    write_varint(buf, namespace.len() as u64);
+    for field in namespace {
+        write_string(buf, field);
+    }
+}
+
+fn read_params(buf: &mut &[u8]) -> Result<Params, Error> {
+    let count = read_varint(buf)?;
+    (0..count)
+        .map(|_| Ok((read_varint(buf)?, read_bytes(buf)?.to_vec())))
+        .collect()
+}
+
+fn write_params(buf: &mut BytesMut, params: &Params) {
+    write_varint(buf, params.len() as u64);
+    for (key, value) in params {
+        write_varint(buf, *key);
+        write_varint(buf, value.len() as u64);
+        buf.put_slice(value);
+    }
+}
+
+fn write_position(buf: &mut BytesMut, position: Option<(u64, u64)>) {
+    match position {
+        Some((group, object)) => {
+            buf.put_u8(1);
+            write_varint(buf, group);
+            write_varint(buf, object);
+        }
+        None => buf.put_u8(0),
+    }
+}
+
+/// Varint parameter value, like the role or the maximum subscribe id.
+pub fn varint_param(value: u64) -> Vec<u8> {
+    let mut buf = BytesMut::new();
+    write_varint(&mut buf, value);
+    buf.to_vec()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn namespace(fields: &[&str]) -> Vec<String> {
+        fields.iter().map(|field| field.to_string()).collect()
+    }
+
+    fn messages() -> Vec<Message> {
+        let subscribe = Subscribe {
+            id: 1,
+            alias: 2,
+            namespace: namespace(&["live", "demo"]),
+            name: "video".to_string(),
+            priority: 128,
+            group_order: 1,
+            filter: Filter::LatestGroup,
+            params: vec![(0x02, b"token".to_vec())],
+        };
+        let filters = [
+            Filter::LatestObject,
+            Filter::AbsoluteStart {
+                group: 10,
+                object: 3,
+            },
+            Filter::AbsoluteRange {
+                group: 10,
+                object: 3,
+                end_group: 1 << 40,
+                end_object: 0,
+            },
+        ];
+        let mut messages = vec![
+            Message::ClientSetup {
+                versions: vec![VERSION, 0xff000006],
+                params: vec![
+                    (ROLE, varint_param(3)),
+                    (MAX_SUBSCRIBE_ID, varint_param(100)),
+                ],
+            },
+            Message::ServerSetup {
+                version: VERSION,
+                params: Vec::new(),
+            },
+            Message::Subscribe(subscribe.clone()),
+            Message::SubscribeOk {
+                id: 1,
+                expires: 0,
+                group_order: 1,
+                largest: None,
+            },
+            Message::SubscribeOk {
+                id: 1,
+                expires: 1000,
+                group_order: 2,
+                largest: Some((7, 300)),
+            },
+            Message::SubscribeError {
+                id: 1,
+                code: INTERNAL_ERROR,
+                reason: "no such track".to_string(),
+                alias: 2,
+            },
+            Message::Announce {
+                namespace: namespace(&["live"]),
+                params: vec![(0x02, Vec::new())],
+            },
+            Message::AnnounceOk {
+                namespace: namespace(&["live"]),
+            },
+            Message::AnnounceError {
+                namespace: namespace(&["live"]),
+                code: 1,
+                reason: "taken".to_string(),
+            },
+            Message::Unannounce {
+                namespace: namespace(&["live", "é"]),
+            },
+            Message::Unsubscribe { id: 1 << 20 },
+            Message::SubscribeDone {
+                id: 1,
+                code: TRACK_ENDED,
+                reason: String::new(),
+                last: Some((7, 301)),
+            },
+            Message::SubscribeDone {
+                id: 1,
+                code: UNSUBSCRIBED,
+                reason: "bye".to_string(),
+                last: None,
+            },
+            Message::AnnounceCancel {
+                namespace: namespace(&["live"]),
+                code: 0,
+                reason: "gone".to_string(),
+            },
+            Message::GoAway {
+                uri: "https://example.com/moq".to_string(),
+            },
+            Message::Other(0x0D),
+        ];
+        messages.extend(filters.into_iter().map(|filter| {
+            Message::Subscribe(Subscribe {
+                filter,
+                ..subscribe.clone()
+            })
+        }));
+        messages
+    }
+
+    #[test]
+    fn message_round_trip() {
+        for message in messages() {
+            let data = message.encode();
+            assert_eq!(Message::decode(&data), Ok((message, data.len())));
+        }
+    }
+
+    #[test]
+    fn messages_back_to_back() {
+        let mut data = BytesMut::new();
+        for message in messages() {
+            data.put_slice(&message.encode());
+        }
+        let mut buf = &data[..];
+        for message in messages() {
+            let (decoded, size) = Message::decode(buf).unwrap();
+            assert_eq!(decoded, message);
+            buf = &buf[size..];
+        }
+        assert!(buf.is_empty());
+    }
+
+    #[test]
+    fn unknown_message_skipped() {
+        let data = [0x20, 0x03, 1, 2, 3, 0x0A, 0x01, 0x05];
+        assert_eq!(Message::decode(&data), Ok((Message::Other(0x20), 5)));
+        assert_eq!(
+            Message::decode(&data[5..]),
+            Ok((Message::Unsubscribe { id: 5 }, 3))
+        );
+    }
+
+    #[test]
+    fn truncated_messages() {
+        for message in messages() {
+            let data = message.encode();
+            for end in 0..data.len() {
+                assert_eq!(Message::decode(&data[..end]), Err(Error::Short));
+            }
+        }
+    }
+
+    #[test]
+    fn invalid_messages() {
+        // Payload shorter than the fields of the message
+        assert_eq!(
+            Message::decode(&[0x0A, 0x00]),
+            Err(Error::Invalid("message length"))
+        );
+        // Refused from the length, before the payload is received
+        let mut data = BytesMut::new();
+        write_varint(&mut data, 0x06);
+        write_varint(&mut data, MAX_MESSAGE_SIZE as u64 + 1);
+        assert_eq!(
+            Message::decode(&data),
+            Err(Error::Invalid("message length"))
+        );
+        let mut data = BytesMut::new();
+        write_varint(&mut data, 0x06);
+        write_varint(&mut data, MAX_MESSAGE_SIZE as u64);
+        assert_eq!(Message::decode(&data), Err(Error::Short));
+
+        assert_eq!(
+            Message::decode(&[0x07, 0x01, 0x00]),
+            Err(Error::Invalid("namespace"))
+        );
+        let message = Message::AnnounceOk {
+            namespace: vec!["a".to_string(); 33],
+        };
+        assert_eq!(
+            Message::decode(&message.encode()),
+            Err(Error::Invalid("namespace"))
+        );
+        assert_eq!(
+            Message::decode(&[0x10, 0x03, 0x02, 0xC3, 0x28]),
+            Err(Error::Invalid("string"))
+        );
+
+        let mut subscribe = Message::Subscribe(Subscribe {
+            id: 1,
+            alias: 1,
+            namespace: namespace(&["live"]),
+            name: "video".to_string(),
+            priority: 0,
+            group_order: 0,
+            filter: Filter::LatestObject,
+            params: Vec::new(),
+        })
+        .encode()
+        .to_vec();
+        // Filter type right before the empty parameters
+        let filter = subscribe.len() - 2;
+        subscribe[filter] = 0x05;
+        assert_eq!(
+            Message::decode(&subscribe),
+            Err(Error::Invalid("filter type"))
+        );
+    }
+
+    #[test]
+    fn subgroup_header() {
+        let header = SubgroupHeader {
+            subscribe_id: 1,
+            alias: 2,
+            group: 1 << 33,
+            subgroup: 0,
+            priority: 255,
+        };
+        let mut data = BytesMut::new();
+        header.encode(&mut data);
+        encode_stream_object(&mut data, 0, NORMAL, b"frame");
+        encode_stream_object(&mut data, 1, END_OF_GROUP, b"");
+
+        let mut buf = &data[..];
+        assert_eq!(SubgroupHeader::decode(&mut buf), Ok(header));
+        assert_eq!(decode_stream_object(&mut buf), Ok((0, NORMAL, 5)));
+        assert_eq!(&buf[..5], b"frame");
+        buf = &buf[5..];
+        assert_eq!(decode_stream_object(&mut buf), Ok((1, END_OF_GROUP, 0)));
+        assert!(buf.is_empty());
+
+        let mut data = BytesMut::new();
+        header.encode(&mut data);
+        for end in 0..data.len() {
+            assert_eq!(SubgroupHeader::decode(&mut &data[..end]), Err(Error::Short));
+        }
+        data[0] = 0x05;
+        assert_eq!(
+            SubgroupHeader::decode(&mut &data[..]),
+            Err(Error::Invalid("stream type"))
+        );
+    }
+
+    #[test]
+    fn datagram() {
+        let datagrams = [
+            Datagram {
+                subscribe_id: 1,
+                alias: 2,
+                group: 3,
+                object: 4,
+                priority: 5,
+                status: NORMAL,
+                payload: Bytes::from_static(b"object"),
+            },
+            Datagram {
+                subscribe_id: 1,
+                alias: 2,
+                group: 3,
+                object: 5,
+                priority: 5,
+                status: END_OF_GROUP,
+                payload: Bytes::new(),
+            },
+        ];
+        for datagram in &datagrams {
+            let data = datagram.encode();
+            assert_eq!(Datagram::decode(&data).as_ref(), Ok(datagram));
+            for end in 0..data.len() {
+                assert_eq!(Datagram::decode(&data.slice(..end)), Err(Error::Short));
+            }
+        }
+
+        let mut data = datagrams[0].encode().to_vec();
+        data[0] = STREAM_HEADER_SUBGROUP as u8;
+        assert_eq!(
+            Datagram::decode(&Bytes::from(data)),
+            Err(Error::Invalid("datagram type"))
+        );
+    }
+}
@@ -0,0 +1,489 @@
+use std::collections::{HashMap, HashSet};
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::Context;
+use async_trait::async_trait;
+use bytes::{Buf, Bytes, BytesMut};
+use futures_util::future;
+use futures_util::select;
+use futures_util::FutureExt;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadHalf, WriteHalf};
+use tokio::sync::{broadcast, watch};
+use tokio::time::{self, Instant};
+use tracing::*;
+
+use crate::auth;
+use crate::channel;
+use crate::server;
+use crate::session;
+use crate::stats;
+use crate::transport;
+
+pub mod topic;
+pub mod wire;
+
+/// WebSocket subprotocol of MQTT over WebSocket.
+pub const WS_PROTOCOL: &str = "mqtt";
+/// Time given to the client to send CONNECT.
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+/// Highest QoS supported, QoS 2 is not.
+const MAX_QOS: u8 = 1;
+
+#[derive(Clone)]
+pub struct Config {
+    pub auth: auth::Config,
+}
+
+/// MQTT 3.1.1 and 5 client over TCP, TLS or WebSocket, whose topics are
+/// channels:
+///
+/// - PUBLISH sends its payload to the channel named by the topic, and
+///   retains it with the retain flag
+/// - SUBSCRIBE joins the channels matching the filter, including the ones
+///   created later, and sends their retained messages
+/// - the messages of the joined channels are sent with the highest QoS of
+///   the subscriptions matching them
+///
+/// The password is the token checked for every topic and filter. Sessions
+/// are never persisted and will messages are ignored.
+pub struct MqttTransport<S> {
+    server: server::ServerPtr,
+    stream: S,
+    remote: SocketAddr,
+    config: Config,
+    closed_tx: watch::Sender<bool>,
+    closed: watch::Receiver<bool>,
+}
+
+impl<S> MqttTransport<S> {
+    pub fn new(server: server::ServerPtr, stream: S, remote: SocketAddr, config: Config) -> Self {
+        let (closed_tx, closed) = watch::channel(false);
+        Self {
+            server,
+            stream,
+            remote,
+            config,
+            closed_tx,
+            closed,
+        }
+    }
+}
+
+#[async_trait]
+impl<S> transport::Transport for MqttTransport<S>
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    fn close(&self) {
+        let _ = self.closed_tx.send(true);
+    }
+
+    async fn process(self) -> Result<(), anyhow::Error> {
+        info!("mqtt connection established from {}", self.remote);
+        let mut closed = self.closed.clone();
+
+        let (read, mut write) = tokio::io::split(self.stream);
+        let mut reader = Reader {
+            read,
+            buffer: BytesMut::new(),
+        };
+        let connect = time::timeout(CONNECT_TIMEOUT, reader.recv(wire::V3_1_1))
+            .await
+            .context("connect timed out")
+            .and_then(|res| res);
+        let connect = match connect {
+            Ok(Some(wire::Packet::Connect(connect))) => connect,
+            Ok(_) => {
+                info!("mqtt connection from {} without connect", self.remote);
+                return Ok(());
+            }
+            Err(err) => {
+                info!("mqtt connect from {} failed: {}", self.remote, err);
+                return Ok(());
+            }
+        };
+
+        let version = connect.version;
+        let (code, assigned_client_id) = accept(&connect, &self.config);
+        let connack = wire::Packet::ConnAck {
+            session_present: false,
+            code,
+            assigned_client_id,
+        };
+        // Unknown protocol levels are answered like MQTT 3.1.1
+        let connack_version = match version {
+            wire::V5 => wire::V5,
+            _ => wire::V3_1_1,
+        };
+        write.write_all(&connack.encode(connack_version)).await?;
+        if code != wire::SUCCESS {
+            info!("mqtt connect from {} refused with {:#x}", self.remote, code);
+            return Ok(());
+        }
+
+        let (stats, retained, mut created) = {
+            let mut server = self.server.lock().unwrap();
+            (
+                server.stats.clone(),
+                server.mqtt.clone(),
+                server.watch_channels(),
+            )
+        };
+        stats.record_connection();
+        let session = session::Session::unbound(self.server.clone(), self.remote);
+        info!(
+            "mqtt connection request accepted: {:?} version {}",
+            connect.client_id, version
+        );
+
+        let mut connection = Connection {
+            server: self.server,
+            config: self.config,
+            retained,
+            write,
+            version,
+            token: connect
+                .password
+                .and_then(|password| String::from_utf8(password).ok()),
+            session,
+            subscriptions: Vec::new(),
+            joined: HashSet::new(),
+            publishers: HashMap::new(),
+            next_id: 0,
+        };
+
+        let keep_alive = Duration::from_secs(connect.keep_alive as u64) * 3 / 2;
+        let mut last_received = Instant::now();
+        let (initiator, code) = loop {
+            let deadline = (!keep_alive.is_zero()).then(|| last_received + keep_alive);
+            select! {
+                _ = closed.changed().fuse() => {
+                    break (stats::Initiator::Local, Some(wire::SERVER_SHUTTING_DOWN));
+                },
+                packet = reader.recv(version).fuse() => {
+                    last_received = Instant::now();
+                    match packet {
+                        Ok(Some(wire::Packet::Disconnect { .. })) | Ok(None) => {
+                            break (stats::Initiator::Remote, None);
+                        },
+                        Ok(Some(packet)) => {
+                            if let Some(code) = connection.handle(packet).await {
+                                break (stats::Initiator::Local, Some(code));
+                            }
+                        },
+                        Err(err) => {
+                            info!("mqtt connection from {} failed: {}", self.remote, err);
+                            break (stats::Initiator::Local, Some(wire::PROTOCOL_ERROR));
+                        }
+                    }
+                },
+                event = connection.session.recv().fuse() => {
+                    match event {
+                        Some(session::Event::Message { channel, data, .. }) => {
+                            connection.deliver(&channel, data.into(), false).await;
+                        },
+                        Some(session::Event::Control(_)) => {},
+                        None => break (stats::Initiator::Local, None),
+                    }
+                },
+                created = created.recv().fuse() => {
+                    if let Some(created) = created {
+                        connection.created(created).await;
+                    }
+                },
+                _ = sleep_until(deadline).fuse() => {
+                    break (stats::Initiator::Local, Some(wire::KEEP_ALIVE_TIMEOUT));
+                }
+            }
+        };
+
+        // Only MQTT 5 servers can send DISCONNECT
+        if let (wire::V5, Some(code)) = (version, code) {
+            connection.send(&wire::Packet::Disconnect { code }).await;
+        }
+        info!(
+            "mqtt connection from {} closed by {:?} with {:?}",
+            self.remote, initiator, code
+        );
+        stats.record_close("mqtt", initiator, stats::CLOSE_ABNORMAL);
+
+        connection.session.close().await;
+        Ok(())
+    }
+}
+
+/// CONNACK code of a CONNECT, with the client identifier assigned to a
+/// MQTT 5 client that sent none.
+fn accept(connect: &wire::Connect, config: &Config) -> (u8, Option<String>) {
+    let v5 = connect.version == wire::V5;
+    if connect.version != wire::V3_1_1 && !v5 {
+        return (wire::UNACCEPTABLE_PROTOCOL_VERSION, None);
+    }
+    if config.auth.secret.is_some() && connect.password.is_none() {
+        let code = match v5 {
+            true => wire::BAD_USERNAME_OR_PASSWORD,
+            false => wire::BAD_CREDENTIALS,
+        };
+        return (code, None);
+    }
+    match (connect.client_id.is_empty(), v5) {
+        (true, true) => {
+            let client_id = format!("prism-{:016x}", rand::random::<u64>());
+            (wire::SUCCESS, Some(client_id))
+        }
+        // Sessions are not persisted, a client without identifier can't resume one
+        (true, false) if !connect.clean_start => (wire::IDENTIFIER_REJECTED, None),
+        _ => (wire::SUCCESS, None),
+    }
+}
+
+/// State of an accepted connection.
+struct Connection<S> {
+    server: server::ServerPtr,
+    config: Config,
+    retained: Arc<topic::Retained>,
+    write: WriteHalf<S>,
+    version: u8,
+    /// Password of the CONNECT, checked for every topic and filter
+    token: Option<String>,
+    session: session::Session,
+    /// Topic filters with their granted QoS
+    subscriptions: Vec<(String, u8)>,
+    joined: HashSet<String>,
+    publishers: HashMap<String, broadcast::Sender<Vec<u8>>>,
+    next_id: u16,
+}
+
+impl<S> Connection<S>
+where
+    S: AsyncWrite,
+{
+    /// Handles a packet of the client, returning the reason to close the
+    /// connection if any.
+    async fn handle(&mut self, packet: wire::Packet) -> Option<u8> {
+        match packet {
+            wire::Packet::Publish(publish) => return self.publish(publish).await,
+            wire::Packet::Subscribe { id, filters } => self.subscribe(id, filters).await,
+            wire::Packet::Unsubscribe { id, filters } => self.unsubscribe(id, filters).await,
+            wire::Packet::PingReq => self.send(&wire::Packet::PingResp).await,
+            wire::Packet::PubAck { .. } => {}
+            wire::Packet::Connect(_) => return Some(wire::PROTOCOL_ERROR),
+            packet => debug!("mqtt packet ignored: {:?}", packet),
+        }
+        None
+    }
+
+    async fn publish(&mut self, publish: wire::Publish) -> Option<u8> {
+        if publish.qos > MAX_QOS {
+            return Some(wire::QOS_NOT_SUPPORTED);
+        }
+        if !topic::valid_topic(&publish.topic) {
+            return Some(wire::TOPIC_NAME_INVALID);
+        }
+
+        let code = match self
+            .config
+            .auth
+            .verify(self.token.as_deref(), &publish.topic)
+        {
+            Ok(()) => {
+                debug!("received: {:#?}", publish.payload.len());
+
+                if publish.retain {
+                    self.retained.set(&publish.topic, publish.payload.clone());
+                }
+                let _ = self
+                    .publisher(&publish.topic)
+                    .await
+                    .send(publish.payload.to_vec());
+                wire::SUCCESS
+            }
+            Err(err) => {
+                debug!("mqtt publish to {} refused: {}", publish.topic, err);
+                wire::NOT_AUTHORIZED
+            }
+        };
+        if let Some(id) = publish.id {
+            self.send(&wire::Packet::PubAck { id, code }).await;
+        }
+        None
+    }
+
+    async fn subscribe(&mut self, id: u16, filters: Vec<(String, u8)>) {
+        let mut codes = Vec::with_capacity(filters.len());
+        let mut added = Vec::new();
+        for (filter, options) in filters {
+            let code = if !topic::valid_filter(&filter) {
+                self.failure(wire::TOPIC_FILTER_INVALID)
+            } else if filter.starts_with("$share/") {
+                self.failure(wire::SHARED_SUBSCRIPTIONS_NOT_SUPPORTED)
+            } else if let Err(err) = self.config.auth.verify(self.token.as_deref(), &filter) {
+                debug!("mqtt subscribe to {} refused: {}", filter, err);
+                self.failure(wire::NOT_AUTHORIZED)
+            } else {
+                let qos = (options & 0x3).min(MAX_QOS);
+                self.subscriptions
+                    .retain(|(existing, _)| *existing != filter);
+                self.subscriptions.push((filter.clone(), qos));
+                added.push((filter, options));
+                qos
+            };
+            codes.push(code);
+        }
+        self.send(&wire::Packet::SubAck { id, codes }).await;
+
+        let names = self.server.lock().unwrap().channel_names();
+        for (filter, options) in added {
+            if topic::valid_topic(&filter) {
+                self.join(&filter).await;
+            }
+            for name in names.iter().filter(|name| topic::matches(&filter, name)) {
+                self.join(name).await;
+            }
+            // Retain handling 2 of MQTT 5 asks for no retained messages
+            if (options >> 4) & 0x3 != 2 {
+                for (topic, payload) in self.retained.matching(&filter) {
+                    self.deliver(&topic, payload, true).await;
+                }
+            }
+        }
+    }
+
+    async fn unsubscribe(&mut self, id: u16, filters: Vec<String>) {
+        let codes = filters
+            .iter()
+            .map(|filter| {
+                let count = self.subscriptions.len();
+                self.subscriptions
+                    .retain(|(existing, _)| existing != filter);
+                match self.subscriptions.len() < count {
+                    true => wire::SUCCESS,
+                    false => wire::NO_SUBSCRIPTION_EXISTED,
+                }
+            })
+            .collect();
+        self.send(&wire::Packet::UnsubAck { id, codes }).await;
+
+        let stale: Vec<String> = self
+            .joined
+            .iter()
+            .filter(|name| self.qos(name).is_none())
+            .cloned()
+            .collect();
+        for name in stale {
+            self.joined.remove(&name);
+            self.session.leave(&name).await;
+        }
+    }
+
+    /// Joins a channel created after the subscriptions matching it, before
+    /// its first message is sent.
+    async fn created(&mut self, created: channel::Created) {
+        if self.qos(&created.name).is_some() && self.joined.insert(created.name.clone()) {
+            self.session.join_created(created).await;
+        }
+    }
+
+    async fn join(&mut self, name: &str) {
+        if self.joined.insert(name.to_string()) {
+            self.session.join(name).await;
+        }
+    }
+
+    /// Sends a message of a channel if it is still subscribed.
+    async fn deliver(&mut self, topic: &str, payload: Bytes, retain: bool) {
+        let qos = match self.qos(topic) {
+            Some(qos) => qos,
+            None => return,
+        };
+        debug!("sent: {:#?}", payload.len());
+
+        let id = (qos > 0).then(|| self.next_id());
+        let publish = wire::Publish {
+            topic: topic.to_string(),
+            qos,
+            retain,
+            dup: false,
+            id,
+            payload,
+        };
+        self.send(&wire::Packet::Publish(publish)).await;
+    }
+
+    /// Highest QoS of the subscriptions matching a topic.
+    fn qos(&self, topic: &str) -> Option<u8> {
+        self.subscriptions
+            .iter()
+            .filter(|(filter, _)| topic::matches(filter, topic))
+            .map(|(_, qos)| *qos)
+            .max()
+    }
+
+    fn next_id(&mut self) -> u16 {
+        // Packet identifiers are non-zero
+        self.next_id = self.next_id.checked_add(1).unwrap_or(1);
+        self.next_id
+    }
+
+    /// SUBACK code of a refused subscription, MQTT 3.1.1 having a single one.
+    fn failure(&self, code: u8) -> u8 {
+        match self.version {
+            wire::V5 => code,
+            _ => wire::SUBSCRIBE_FAILURE,
+        }
+    }
+
+    async fn publisher(&mut self, topic: &str) -> broadcast::Sender<Vec<u8>> {
+        if let Some(tx) = self.publishers.get(topic) {
+            return tx.clone();
+        }
+        let channel = self.server.lock().unwrap().find_or_create_channel(topic);
+        let tx = channel.lock().await.broadcast.clone();
+        self.publishers.insert(topic.to_string(), tx.clone());
+        tx
+    }
+
+    async fn send(&mut self, packet: &wire::Packet) {
+        // A broken connection is noticed when reading
+        if let Err(err) = self.write.write_all(&packet.encode(self.version)).await {
+            debug!("mqtt write failed: {}", err);
+        }
+    }
+}
+
+/// Reads packets, keeping the bytes of incomplete ones so that reading is
+/// cancel safe.
+struct Reader<S> {
+    read: ReadHalf<S>,
+    buffer: BytesMut,
+}
+
+impl<S> Reader<S>
+where
+    S: AsyncRead,
+{
+    async fn recv(&mut self, version: u8) -> Result<Option<wire::Packet>, anyhow::Error> {
+        loop {
+            match wire::Packet::decode(&self.buffer, version) {
+                Ok((packet, size)) => {
+                    self.buffer.advance(size);
+                    return Ok(Some(packet));
+                }
+                Err(wire::Error::Short) => {}
+                Err(err) => return Err(err.into()),
+            }
+            if self.read.read_buf(&mut self.buffer).await? == 0 {
+                return Ok(None);
+            }
+        }
+    }
+}
+
+async fn sleep_until(deadline: Option<Instant>) {
+    match deadline {
+        Some(deadline) => time::sleep_until(deadline).await,
+        None => future::pending().await,
+    }
+}
@@ -0,0 +1,140 @@
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+use bytes::Bytes;
+
+/// Topic names are channel names, they can't contain wildcards.
+pub fn valid_topic(topic: &str) -> bool {
+    !topic.is_empty() && !topic.contains(['+', '#'])
+}
+
+/// Wildcards of a filter must be whole levels, `#` being the last one.
+pub fn valid_filter(filter: &str) -> bool {
+    if filter.is_empty() {
+        return false;
+    }
+    let levels: Vec<&str> = filter.split('/').collect();
+    levels.iter().enumerate().all(|(i, level)| match *level {
+        "+" => true,
+        "#" => i == levels.len() - 1,
+        level => !level.contains(['+', '#']),
+    })
+}
+
+/// Whether a filter matches a topic, `+` matching one level and `#` any
+/// number of them. Wildcards at the first level don't match the topics
+/// starting with `$`.
+pub fn matches(filter: &str, topic: &str) -> bool {
+    if topic.starts_with('$') && filter.starts_with(['+', '#']) {
+        return false;
+    }
+    let mut levels = topic.split('/');
+    for pattern in filter.split('/') {
+        match (pattern, levels.next()) {
+            ("#", _) => return true,
+            ("+", Some(_)) => {}
+            (pattern, Some(level)) if pattern == level => {}
+            _ => return false,
+        }
+    }
+    levels.next().is_none()
+}
+
+/// Retained messages, the last one published with the retain flag on each
+/// topic.
+#[derive(Debug, Default)]
+pub struct Retained {
+    messages: Mutex<HashMap<String, Bytes>>,
+}
+
+impl Retained {
+    /// Retains a message, an empty one removing the retained message.
+    pub fn set(&self, topic: &str, payload: Bytes) {
+        let mut messages = self.messages.lock().unwrap();
+        match payload.is_empty() {
+            true => messages.remove(topic),
+            false => messages.insert(topic.to_string(), payload),
+        };
+    }
+
+    pub fn matching(&self, filter: &str) -> Vec<(String, Bytes)> {
+        self.messages
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|(topic, _)| matches(filter, topic))
+            .map(|(topic, payload)| (topic.clone(), payload.clone()))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn matching() {
+        let table = [
+            ("live/demo", "live/demo", true),
+            ("live/demo", "live/other", false),
+            ("live/demo", "live/demo/video", false),
+            ("live/+", "live/demo", true),
+            ("live/+", "live/demo/video", false),
+            ("live/+", "live", false),
+            ("live/+", "live/", true),
+            ("+/demo", "live/demo", true),
+            ("+/+", "/demo", true),
+            ("live/+/video", "live/demo/video", true),
+            ("live/#", "live/demo/video", true),
+            ("live/#", "live", true),
+            ("live/#", "other/demo", false),
+            ("#", "live/demo", true),
+            ("#", "/", true),
+            ("#", "live", true),
+            ("+", "live", true),
+            ("+", "live/demo", false),
+            // Wildcards at the root never match the `$` topics
+            ("#", "$SYS/uptime", false),
+            ("+/uptime", "$SYS/uptime", false),
+            ("$SYS/#", "$SYS/uptime", true),
+            ("$SYS/+", "$SYS/uptime", true),
+            ("live/#", "live/$demo", true),
+            ("live/+", "live/$demo", true),
+        ];
+        for (filter, topic, expected) in table {
+            assert_eq!(matches(filter, topic), expected, "{} {}", filter, topic);
+        }
+    }
+
+    #[test]
+    fn validity() {
+        assert!(valid_topic("live/demo"));
+        assert!(!valid_topic(""));
+        assert!(!valid_topic("live/+"));
+        assert!(!valid_topic("live/#"));
+
+        for filter in ["#", "+", "live/#", "live/+/video", "+/+", "/"] {
+            assert!(valid_filter(filter), "{}", filter);
+        }
+        for filter in ["", "live/#/video", "live#", "live/vid+", "##"] {
+            assert!(!valid_filter(filter), "{}", filter);
+        }
+    }
+
+    #[test]
+    fn retained() {
+        let retained = Retained::default();
+        retained.set("live/demo", Bytes::from_static(b"first"));
+        retained.set("live/demo", Bytes::from_static(b"last"));
+        retained.set("live/other", Bytes::from_static(b"other"));
+        retained.set("$SYS/uptime", Bytes::from_static(b"1"));
+
+        assert_eq!(
+            retained.matching("live/demo"),
+            [("live/demo".to_string(), Bytes::from_static(b"last"))]
+        );
+        assert_eq!(retained.matching("#").len(), 2);
+        retained.set("live/demo", Bytes::new());
+        assert_eq!(retained.matching("live/+").len(), 1);
+    }
+}
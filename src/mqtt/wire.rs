@@ -0,0 +1,787 @@
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+
+/// Protocol levels of MQTT 3.1.1 and 5.
+pub const V3_1_1: u8 = 4;
+pub const V5: u8 = 5;
+
+/// Largest packet accepted.
+pub const MAX_PACKET_SIZE: usize = 16 * 1024 * 1024;
+
+/// CONNACK return codes of MQTT 3.1.1.
+pub const UNACCEPTABLE_PROTOCOL_VERSION: u8 = 0x01;
+pub const IDENTIFIER_REJECTED: u8 = 0x02;
+pub const BAD_CREDENTIALS: u8 = 0x04;
+
+/// SUBACK failure of MQTT 3.1.1.
+pub const SUBSCRIBE_FAILURE: u8 = 0x80;
+
+/// Reason codes of MQTT 5.
+pub const SUCCESS: u8 = 0x00;
+pub const NO_SUBSCRIPTION_EXISTED: u8 = 0x11;
+pub const PROTOCOL_ERROR: u8 = 0x82;
+pub const BAD_USERNAME_OR_PASSWORD: u8 = 0x86;
+pub const NOT_AUTHORIZED: u8 = 0x87;
+pub const SERVER_SHUTTING_DOWN: u8 = 0x8B;
+pub const KEEP_ALIVE_TIMEOUT: u8 = 0x8D;
+pub const TOPIC_FILTER_INVALID: u8 = 0x8F;
+pub const TOPIC_NAME_INVALID: u8 = 0x90;
+pub const QOS_NOT_SUPPORTED: u8 = 0x9B;
+pub const SHARED_SUBSCRIPTIONS_NOT_SUPPORTED: u8 = 0x9E;
+
+/// Properties of MQTT 5 sent by prism.
+const ASSIGNED_CLIENT_IDENTIFIER: u8 = 0x12;
+const MAXIMUM_QOS: u8 = 0x24;
+const SHARED_SUBSCRIPTION_AVAILABLE: u8 = 0x2A;
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum Error {
+    /// More bytes are needed to decode
+    Short,
+    Invalid(&'static str),
+}
+
+impl std::fmt::Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Error::Short => write!(f, "truncated packet"),
+            Error::Invalid(what) => write!(f, "invalid {}", what),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Connect {
+    /// Protocol level
+    pub version: u8,
+    pub client_id: String,
+    pub clean_start: bool,
+    /// Seconds, zero disables it
+    pub keep_alive: u16,
+    pub username: Option<String>,
+    pub password: Option<Vec<u8>>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Publish {
+    pub topic: String,
+    pub qos: u8,
+    pub retain: bool,
+    pub dup: bool,
+    /// Packet identifier, only with QoS 1 and 2
+    pub id: Option<u16>,
+    pub payload: Bytes,
+}
+
+/// Control packets, the ones prism does not use are skipped. MQTT 5
+/// properties are skipped too, besides the few sent by prism.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Packet {
+    Connect(Connect),
+    ConnAck {
+        session_present: bool,
+        code: u8,
+        /// Client identifier assigned to a MQTT 5 client that sent none
+        assigned_client_id: Option<String>,
+    },
+    Publish(Publish),
+    PubAck {
+        id: u16,
+        code: u8,
+    },
+    Subscribe {
+        id: u16,
+        /// Topic filters with their subscription options, the QoS in the
+        /// lowest bits
+        filters: Vec<(String, u8)>,
+    },
+    SubAck {
+        id: u16,
+        codes: Vec<u8>,
+    },
+    Unsubscribe {
+        id: u16,
+        filters: Vec<String>,
+    },
+    UnsubAck {
+        id: u16,
+        codes: Vec<u8>,
+    },
+    PingReq,
+    PingResp,
+    Disconnect {
+        code: u8,
+    },
+    Other(u8),
+}
+
+impl Packet {
+    /// Decodes a packet from the start of a buffer, returning it with its
+    /// size or `Error::Short` when incomplete. The protocol level selects
+    /// the MQTT 5 fields, CONNECT carrying its own.
+    pub fn decode(data: &[u8], version: u8) -> Result<(Self, usize), Error> {
+        let mut buf = data;
+        let header = read_u8(&mut buf)?;
+        let length = read_length(&mut buf)?;
+        if length > MAX_PACKET_SIZE {
+            return Err(Error::Invalid("packet size"));
+        }
+        if buf.len() < length {
+            return Err(Error::Short);
+        }
+        let size = data.len() - buf.len() + length;
+        let mut payload = &buf[..length];
+        let packet =
+            Self::decode_payload(header, version, &mut payload).map_err(|err| match err {
+                // The length is known, a short payload is malformed
+                Error::Short => Error::Invalid("packet length"),
+                err => err,
+            })?;
+        Ok((packet, size))
+    }
+
+    fn decode_payload(header: u8, version: u8, buf: &mut &[u8]) -> Result<Self, Error> {
+        let flags = header & 0x0f;
+        let packet = match header >> 4 {
+            0x1 => {
+                if read_string(buf)? != "MQTT" {
+                    return Err(Error::Invalid("protocol name"));
+                }
+                let version = read_u8(buf)?;
+                let connect_flags = read_u8(buf)?;
+                let keep_alive = read_u16(buf)?;
+                if version == V5 {
+                    skip_properties(buf)?;
+                }
+                let client_id = read_string(buf)?;
+                if connect_flags & 0x04 != 0 {
+                    if version == V5 {
+                        skip_properties(buf)?;
+                    }
+                    read_string(buf)?;
+                    read_binary(buf)?;
+                }
+                let username = match connect_flags & 0x80 {
+                    0 => None,
+                    _ => Some(read_string(buf)?),
+                };
+                let password = match connect_flags & 0x40 {
+                    0 => None,
+                    _ => Some(read_binary(buf)?),
+                };
+                Packet::Connect(Connect {
+                    version,
+                    client_id,
+                    clean_start: connect_flags & 0x02 != 0,
+                    keep_alive,
+                    username,
+                    password,
+                })
+            }
+            0x2 => {
+                let session_present = read_u8(buf)? & 0x01 != 0;
+                let code = read_u8(buf)?;
+                if version == V5 {
+                    skip_properties(buf)?;
+                }
+                Packet::ConnAck {
+                    session_present,
+                    code,
+                    assigned_client_id: None,
+                }
+            }
+            0x3 => {
+                let qos = (flags >> 1) & 0x3;
+                if qos == 0x3 {
+                    return Err(Error::Invalid("qos"));
+                }
+                let topic = read_string(buf)?;
+                let id = match qos {
+                    0 => None,
+                    _ => Some(read_u16(buf)?),
+                };
+                if version == V5 {
+                    skip_properties(buf)?;
+                }
+                Packet::Publish(Publish {
+                    topic,
+                    qos,
+                    retain: flags & 0x1 != 0,
+                    dup: flags & 0x8 != 0,
+                    id,
+                    payload: Bytes::copy_from_slice(buf),
+                })
+            }
+            0x4 => Packet::PubAck {
+                id: read_u16(buf)?,
+                code: read_reason(buf)?,
+            },
+            0x8 | 0xA if flags != 0x2 => return Err(Error::Invalid("flags")),
+            0x8 => {
+                let id = read_u16(buf)?;
+                if version == V5 {
+                    skip_properties(buf)?;
+                }
+                let mut filters = Vec::new();
+                while !buf.is_empty() {
+                    filters.push((read_string(buf)?, read_u8(buf)?));
+                }
+                if filters.is_empty() {
+                    return Err(Error::Invalid("empty subscribe"));
+                }
+                Packet::Subscribe { id, filters }
+            }
+            0x9 => {
+                let id = read_u16(buf)?;
+                if version == V5 {
+                    skip_properties(buf)?;
+                }
+                let codes = buf.to_vec();
+                buf.advance(codes.len());
+                Packet::SubAck { id, codes }
+            }
+            0xA => {
+                let id = read_u16(buf)?;
+                if version == V5 {
+                    skip_properties(buf)?;
+                }
+                let mut filters = Vec::new();
+                while !buf.is_empty() {
+                    filters.push(read_string(buf)?);
+                }
+                if filters.is_empty() {
+                    return Err(Error::Invalid("empty unsubscribe"));
+                }
+                Packet::Unsubscribe { id, filters }
+            }
+            0xB => {
+                let id = read_u16(buf)?;
+                let codes = match version {
+                    V5 => {
+                        skip_properties(buf)?;
+                        let codes = buf.to_vec();
+                        buf.advance(codes.len());
+                        codes
+                    }
+                    _ => Vec::new(),
+                };
+                Packet::UnsubAck { id, codes }
+            }
+            0xC => Packet::PingReq,
+            0xD => Packet::PingResp,
+            0xE => Packet::Disconnect {
+                code: read_reason(buf)?,
+            },
+            kind => Packet::Other(kind),
+        };
+        Ok(packet)
+    }
+
+    pub fn encode(&self, version: u8) -> Bytes {
+        let mut payload = BytesMut::new();
+        let buf = &mut payload;
+        let header = match self {
+            Packet::Connect(connect) => {
+                write_string(buf, "MQTT");
+                buf.put_u8(connect.version);
+                let mut flags = 0;
+                if connect.clean_start {
+                    flags |= 0x02;
+                }
+                if connect.username.is_some() {
+                    flags |= 0x80;
+                }
+                if connect.password.is_some() {
+                    flags |= 0x40;
+                }
+                buf.put_u8(flags);
+                buf.put_u16(connect.keep_alive);
+                if connect.version == V5 {
+                    write_length(buf, 0);
+                }
+                write_string(buf, &connect.client_id);
+                if let Some(username) = &connect.username {
+                    write_string(buf, username);
+                }
+                if let Some(password) = &connect.password {
+                    write_binary(buf, password);
+                }
+                0x10
+            }
+            Packet::ConnAck {
+                session_present,
+                code,
+                assigned_client_id,
+            } => {
+                buf.put_u8(*session_present as u8);
+                buf.put_u8(*code);
+                if version == V5 {
+                    let mut properties = BytesMut::new();
+                    properties.put_u8(MAXIMUM_QOS);
+                    properties.put_u8(1);
+                    properties.put_u8(SHARED_SUBSCRIPTION_AVAILABLE);
+                    properties.put_u8(0);
+                    if let Some(client_id) = assigned_client_id {
+                        properties.put_u8(ASSIGNED_CLIENT_IDENTIFIER);
+                        write_string(&mut properties, client_id);
+                    }
+                    write_length(buf, properties.len());
+                    buf.put_slice(&properties);
+                }
+                0x20
+            }
+            Packet::Publish(publish) => {
+                write_string(buf, &publish.topic);
+                if let Some(id) = publish.id {
+                    buf.put_u16(id);
+                }
+                if version == V5 {
+                    write_length(buf, 0);
+                }
+                buf.put_slice(&publish.payload);
+                0x30 | (publish.dup as u8) << 3 | publish.qos << 1 | publish.retain as u8
+            }
+            Packet::PubAck { id, code } => {
+                buf.put_u16(*id);
+                // The reason code can be left out on success
+                if version == V5 && *code != SUCCESS {
+                    buf.put_u8(*code);
+                    write_length(buf, 0);
+                }
+                0x40
+            }
+            Packet::Subscribe { id, filters } => {
+                buf.put_u16(*id);
+                if version == V5 {
+                    write_length(buf, 0);
+                }
+                for (filter, options) in filters {
+                    write_string(buf, filter);
+                    buf.put_u8(*options);
+                }
+                0x82
+            }
+            Packet::SubAck { id, codes } => {
+                buf.put_u16(*id);
+                if version == V5 {
+                    write_length(buf, 0);
+                }
+                buf.put_slice(codes);
+                0x90
+            }
+            Packet::Unsubscribe { id, filters } => {
+                buf.put_u16(*id);
+                if version == V5 {
+                    write_length(buf, 0);
+                }
+                for filter in filters {
+                    write_string(buf, filter);
+                }
+                0xA2
+            }
+            Packet::UnsubAck { id, codes } => {
+                buf.put_u16(*id);
+                if version == V5 {
+                    write_length(buf, 0);
+                    buf.put_slice(codes);
+                }
+                0xB0
+            }
+            Packet::PingReq => 0xC0,
+            Packet::PingResp => 0xD0,
+            Packet::Disconnect { code } => {
+                if version == V5 {
+                    buf.put_u8(*code);
+                    write_length(buf, 0);
+                }
+                0xE0
+            }
+            Packet::Other(kind) => kind << 4,
+        };
+
+        let mut packet = BytesMut::with_capacity(payload.len() + 5);
+        packet.put_u8(header);
+        write_length(&mut packet, payload.len());
+        packet.put_slice(&payload);
+        packet.freeze()
+    }
+}
+
+fn read_u8(buf: &mut &[u8]) -> Result<u8, Error> {
+    match buf.first() {
+        Some(&byte) => {
+            buf.advance(1);
+            Ok(byte)
+        }
+        None => Err(Error::Short),
+    }
+}
+
+fn read_u16(buf: &mut &[u8]) -> Result<u16, Error> {
+    if buf.len() < 2 {
+        return Err(Error::Short);
+    }
+    Ok(buf.get_u16())
+}
+
+/// Reason code of the acknowledgements, left out on success.
+fn read_reason(buf: &mut &[u8]) -> Result<u8, Error> {
+    match buf.is_empty() {
+        true => Ok(SUCCESS),
+        false => read_u8(buf),
+    }
+}
+
+/// Reads a variable byte integer, 7 bits per byte over at most 4 bytes.
+fn read_length(buf: &mut &[u8]) -> Result<usize, Error> {
+    let mut length = 0;
+    for i in 0..4 {
+        let byte = read_u8(buf)?;
+        length |= ((byte & 0x7f) as usize) << (7 * i);
+        if byte & 0x80 == 0 {
+            return Ok(length);
+        }
+    }
+    Err(Error::Invalid("variable byte integer"))
+}
+
+fn write_length(buf: &mut BytesMut, mut length: usize) {
+    loop {
+        let byte = (length & 0x7f) as u8;
+        length >>= 7;
+        match length {
+            0 => {
+                buf.put_u8(byte);
+                return;
+            }
+            _ => buf.put_u8(byte | 0x80),
+        }
+    }
+}
+
+fn read_binary(buf: &mut &[u8]) -> Result<Vec<u8>, Error> {
+    let length = read_u16(buf)? as usize;
+    if buf.len() < length {
+        return Err(Error::Short);
+    }
+    let data = buf[..length].to_vec();
+    buf.advance(length);
+    Ok(data)
+}
+
+fn write_binary(buf: &mut BytesMut, data: &[u8]) {
+    buf.put_u16(data.len() as u16);
+    buf.put_slice(data);
+}
+
+fn read_string(buf: &mut &[u8]) -> Result<String, Error> {
+    String::from_utf8(read_binary(buf)?).map_err(|_| Error::Invalid("string"))
+}
+
+fn write_string(buf: &mut BytesMut, value: &str) {
+    write_binary(buf, value.as_bytes());
+}
+
+fn skip_properties(buf: &mut &[u8]) -> Result<(), Error> {
+    let length = read_length(buf)?;
+    if buf.len() < length {
+        return Err(Error::Short);
+    }
+    buf.advance(length);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn connect(version: u8) -> Packet {
+        Packet::Connect(Connect {
+            version,
+            client_id: "client".to_string(),
+            clean_start: true,
+            keep_alive: 60,
+            username: Some("user".to_string()),
+            password: Some(b"token".to_vec()),
+        })
+    }
+
+    fn packets(version: u8) -> Vec<Packet> {
+        let mut packets = vec![
+            connect(version),
+            Packet::Connect(Connect {
+                version,
+                client_id: String::new(),
+                clean_start: false,
+                keep_alive: 0,
+                username: None,
+                password: None,
+            }),
+            Packet::ConnAck {
+                session_present: false,
+                code: SUCCESS,
+                assigned_client_id: None,
+            },
+            Packet::Publish(Publish {
+                topic: "live/demo".to_string(),
+                qos: 0,
+                retain: true,
+                dup: false,
+                id: None,
+                payload: Bytes::from_static(b"payload"),
+            }),
+            Packet::Publish(Publish {
+                topic: "live/é".to_string(),
+                qos: 1,
+                retain: false,
+                dup: true,
+                id: Some(7),
+                payload: Bytes::from(vec![0; 200]),
+            }),
+            Packet::PubAck {
+                id: 7,
+                code: SUCCESS,
+            },
+            Packet::Subscribe {
+                id: 8,
+                filters: vec![("live/+".to_string(), 1), ("#".to_string(), 0)],
+            },
+            Packet::SubAck {
+                id: 8,
+                codes: vec![1, 0],
+            },
+            Packet::Unsubscribe {
+                id: 9,
+                filters: vec!["live/+".to_string()],
+            },
+            Packet::PingReq,
+            Packet::PingResp,
+            Packet::Disconnect { code: SUCCESS },
+        ];
+        if version == V5 {
+            packets.extend([
+                Packet::PubAck {
+                    id: 7,
+                    code: NOT_AUTHORIZED,
+                },
+                Packet::UnsubAck {
+                    id: 9,
+                    codes: vec![SUCCESS, NO_SUBSCRIPTION_EXISTED],
+                },
+                Packet::Disconnect {
+                    code: KEEP_ALIVE_TIMEOUT,
+                },
+            ]);
+        } else {
+            packets.push(Packet::UnsubAck {
+                id: 9,
+                codes: Vec::new(),
+            });
+        }
+        packets
+    }
+
+    #[test]
+    fn round_trip() {
+        for version in [V3_1_1, V5] {
+            for packet in packets(version) {
+                let data = packet.encode(version);
+                assert_eq!(
+                    Packet::decode(&data, version),
+                    Ok((packet, data.len())),
+                    "version {}",
+                    version
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn connack_properties() {
+        let connack = Packet::ConnAck {
+            session_present: false,
+            code: SUCCESS,
+            assigned_client_id: Some("prism-1".to_string()),
+        };
+        assert_eq!(&connack.encode(V3_1_1)[..], [0x20, 0x02, 0x00, 0x00]);
+        let data = connack.encode(V5);
+        // Header, flags, code and length of the properties
+        assert_eq!(&data[..5], [0x20, 0x11, 0x00, 0x00, 0x0e]);
+        let mut properties = vec![MAXIMUM_QOS, 1, SHARED_SUBSCRIPTION_AVAILABLE, 0];
+        properties.extend_from_slice(&[ASSIGNED_CLIENT_IDENTIFIER, 0x00, 0x07]);
+        properties.extend_from_slice(b"prism-1");
+        assert_eq!(&data[5..], properties);
+        let (decoded, _) = Packet::decode(&data, V5).unwrap();
+        assert!(matches!(decoded, Packet::ConnAck { code: SUCCESS, .. }));
+    }
+
+    #[test]
+    fn connect_properties_and_will() {
+        // MQTT 5 CONNECT with properties, a will and its properties
+        let mut payload = BytesMut::new();
+        write_string(&mut payload, "MQTT");
+        payload.put_u8(V5);
+        payload.put_u8(0x02 | 0x04 | 0x40);
+        payload.put_u16(30);
+        payload.put_slice(&[0x05, 0x11, 0, 0, 0, 10]);
+        write_string(&mut payload, "client");
+        payload.put_slice(&[0x02, 0x01, 0x01]);
+        write_string(&mut payload, "will/topic");
+        write_binary(&mut payload, b"will");
+        write_binary(&mut payload, b"token");
+        let mut data = BytesMut::new();
+        data.put_u8(0x10);
+        write_length(&mut data, payload.len());
+        data.put_slice(&payload);
+
+        let (packet, size) = Packet::decode(&data, V3_1_1).unwrap();
+        assert_eq!(size, data.len());
+        assert_eq!(
+            packet,
+            Packet::Connect(Connect {
+                version: V5,
+                client_id: "client".to_string(),
+                clean_start: true,
+                keep_alive: 30,
+                username: None,
+                password: Some(b"token".to_vec()),
+            })
+        );
+    }
+
+    #[test]
+    fn v5_acknowledgement_properties() {
+        // PUBACK with a reason string property, and the short forms
+        let data = [0x40, 0x08, 0x00, 0x07, 0x87, 0x04, 0x1f, 0x00, 0x01, b'x'];
+        assert_eq!(
+            Packet::decode(&data, V5),
+            Ok((
+                Packet::PubAck {
+                    id: 7,
+                    code: NOT_AUTHORIZED
+                },
+                data.len()
+            ))
+        );
+        assert_eq!(
+            Packet::decode(&[0x40, 0x02, 0x00, 0x07], V5),
+            Ok((
+                Packet::PubAck {
+                    id: 7,
+                    code: SUCCESS
+                },
+                4
+            ))
+        );
+        assert_eq!(
+            Packet::decode(&[0xE0, 0x00], V5),
+            Ok((Packet::Disconnect { code: SUCCESS }, 2))
+        );
+    }
+
+    #[test]
+    fn back_to_back() {
+        let mut data = BytesMut::new();
+        for packet in packets(V5) {
+            data.put_slice(&packet.encode(V5));
+        }
+        let mut buf = &data[..];
+        for packet in packets(V5) {
+            let (decoded, size) = Packet::decode(buf, V5).unwrap();
+            assert_eq!(decoded, packet);
+            buf = &buf[size..];
+        }
+        assert!(buf.is_empty());
+    }
+
+    #[test]
+    fn truncated() {
+        for version in [V3_1_1, V5] {
+            for packet in packets(version) {
+                let data = packet.encode(version);
+                for end in 0..data.len() {
+                    assert_eq!(Packet::decode(&data[..end], version), Err(Error::Short));
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn remaining_length() {
+        let mut data = BytesMut::new();
+        for length in [0, 127, 128, 16383, 16384, 2097151, 2097152, 268435455] {
+            data.clear();
+            write_length(&mut data, length);
+            assert_eq!(read_length(&mut &data[..]), Ok(length));
+        }
+        assert_eq!(data.len(), 4);
+
+        // Over 4 bytes
+        assert_eq!(
+            Packet::decode(&[0x30, 0xff, 0xff, 0xff, 0xff, 0x01], V3_1_1),
+            Err(Error::Invalid("variable byte integer"))
+        );
+        assert_eq!(
+            Packet::decode(&[0x30, 0xff, 0xff, 0xff], V3_1_1),
+            Err(Error::Short)
+        );
+        data.clear();
+        data.put_u8(0x30);
+        write_length(&mut data, MAX_PACKET_SIZE + 1);
+        assert_eq!(
+            Packet::decode(&data, V3_1_1),
+            Err(Error::Invalid("packet size"))
+        );
+    }
+
+    #[test]
+    fn malformed() {
+        // Property block longer than the packet
+        let data = [0x30, 0x07, 0x00, 0x01, b't', 0x05, 0x01, 0x01, b'x'];
+        assert_eq!(
+            Packet::decode(&data, V5),
+            Err(Error::Invalid("packet length"))
+        );
+        assert_eq!(
+            Packet::decode(&[0x90, 0x03, 0x00, 0x01, 0x02], V5),
+            Err(Error::Invalid("packet length"))
+        );
+        // The same PUBLISH is fine without properties
+        assert!(Packet::decode(&data, V3_1_1).is_ok());
+
+        // Invalid UTF-8 in the topic, the client id and a filter
+        assert_eq!(
+            Packet::decode(&[0x30, 0x05, 0x00, 0x02, 0xc3, 0x28, b'x'], V3_1_1),
+            Err(Error::Invalid("string"))
+        );
+        let mut data = connect(V3_1_1).encode(V3_1_1).to_vec();
+        // First byte of the client id, after the protocol name, level,
+        // flags and keep alive
+        data[14] = 0xff;
+        assert_eq!(Packet::decode(&data, V3_1_1), Err(Error::Invalid("string")));
+        assert_eq!(
+            Packet::decode(&[0x82, 0x06, 0x00, 0x01, 0x00, 0x01, 0xfe, 0x00], V3_1_1),
+            Err(Error::Invalid("string"))
+        );
+
+        assert_eq!(
+            Packet::decode(&[0x36, 0x03, 0x00, 0x01, b't'], V3_1_1),
+            Err(Error::Invalid("qos"))
+        );
+        assert_eq!(
+            Packet::decode(&[0x80, 0x06, 0x00, 0x01, 0x00, 0x01, b't', 0x00], V3_1_1),
+            Err(Error::Invalid("flags"))
+        );
+        assert_eq!(
+            Packet::decode(&[0x82, 0x02, 0x00, 0x01], V3_1_1),
+            Err(Error::Invalid("empty subscribe"))
+        );
+        let mut data = connect(V3_1_1).encode(V3_1_1).to_vec();
+        data[4..8].copy_from_slice(b"MQIs");
+        assert_eq!(
+            Packet::decode(&data, V3_1_1),
+            Err(Error::Invalid("protocol name"))
+        );
+    }
+}
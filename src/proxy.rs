@@ -0,0 +1,419 @@
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+
+use http::HeaderMap;
+use ipnet::IpNet;
+use tokio::io::{AsyncRead, AsyncReadExt};
+
+const V2_SIGNATURE: &[u8; 12] = b"\r\n\r\n\0\r\nQUIT\n";
+const V1_MAX_LEN: usize = 107;
+
+/// Proxies in front of prism allowed to report the real address of the
+/// clients, either with PROXY protocol or with forwarding headers.
+#[derive(Debug, Clone, Default)]
+pub struct Config {
+    pub trusted: Vec<IpNet>,
+    /// Expect a PROXY protocol v1/v2 header from trusted peers
+    pub proxy_protocol: bool,
+}
+
+impl Config {
+    pub fn is_trusted(&self, ip: IpAddr) -> bool {
+        let ip = canonical(ip);
+        self.trusted.iter().any(|net| net.contains(&ip))
+    }
+
+    /// Reads the PROXY protocol header sent by a trusted peer before any
+    /// other data, returning the address of the client.
+    pub async fn accept<S>(&self, stream: &mut S, peer: SocketAddr) -> anyhow::Result<SocketAddr>
+    where
+        S: AsyncRead + Unpin,
+    {
+        if !self.proxy_protocol || !self.is_trusted(peer.ip()) {
+            return Ok(peer);
+        }
+        let client = read_proxy_header(stream).await?;
+        Ok(client.unwrap_or(peer))
+    }
+
+    /// Resolves the client address from the `Forwarded` or `X-Forwarded-For`
+    /// headers when the request comes through a trusted proxy.
+    pub fn client_addr(&self, peer: SocketAddr, headers: &HeaderMap) -> SocketAddr {
+        if self.proxy_protocol || !self.is_trusted(peer.ip()) {
+            return peer;
+        }
+
+        let mut chain = forwarded_for(headers);
+        if chain.is_empty() {
+            chain = x_forwarded_for(headers);
+        }
+
+        // The right-most address not belonging to a trusted proxy is the client
+        let mut client = peer;
+        for addr in chain.into_iter().rev() {
+            client = addr;
+            if !self.is_trusted(addr.ip()) {
+                break;
+            }
+        }
+        client
+    }
+}
+
+pub fn parse_network(value: &str) -> Result<IpNet, String> {
+    if let Ok(ip) = value.parse::<IpAddr>() {
+        return Ok(IpNet::from(ip));
+    }
+    value
+        .parse::<IpNet>()
+        .map_err(|err| format!("invalid network {}: {}", value, err))
+}
+
+fn canonical(ip: IpAddr) -> IpAddr {
+    match ip {
+        IpAddr::V6(v6) => v6.to_ipv4_mapped().map_or(ip, IpAddr::V4),
+        IpAddr::V4(_) => ip,
+    }
+}
+
+fn forwarded_for(headers: &HeaderMap) -> Vec<SocketAddr> {
+    headers
+        .get_all(http::header::FORWARDED)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .flat_map(|element| element.split(';'))
+        .filter_map(|pair| {
+            let (key, value) = pair.trim().split_once('=')?;
+            if !key.eq_ignore_ascii_case("for") {
+                return None;
+            }
+            parse_node(value.trim().trim_matches('"'))
+        })
+        .collect()
+}
+
+fn x_forwarded_for(headers: &HeaderMap) -> Vec<SocketAddr> {
+    headers
+        .get_all("x-forwarded-for")
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .filter_map(|node| parse_node(node.trim()))
+        .collect()
+}
+
+/// Parses a node like `192.0.2.60`, `192.0.2.60:4711`, `2001:db8::17` or
+/// `[2001:db8::17]:4711`, ignoring obfuscated identifiers and `unknown`.
+fn parse_node(node: &str) -> Option<SocketAddr> {
+    if let Ok(addr) = node.parse::<SocketAddr>() {
+        return Some(addr);
+    }
+    let ip = node.trim_start_matches('[').trim_end_matches(']');
+    let ip = match ip.parse::<IpAddr>() {
+        Ok(ip) => ip,
+        Err(_) => node.rsplit_once(':')?.0.parse::<IpAddr>().ok()?,
+    };
+    Some(SocketAddr::new(ip, 0))
+}
+
+/// Reads a PROXY protocol v1 or v2 header, returning None for connections
+/// not proxied on behalf of a client (v1 UNKNOWN or v2 LOCAL).
+pub async fn read_proxy_header<S>(stream: &mut S) -> anyhow::Result<Option<SocketAddr>>
+where
+    S: AsyncRead + Unpin,
+{
+    // Both the v2 signature and the shortest v1 header have at least 12 bytes
+    let mut header = vec![0u8; V2_SIGNATURE.len()];
+    stream.read_exact(&mut header).await?;
+
+    if header == V2_SIGNATURE {
+        let mut fixed = [0u8; 4];
+        stream.read_exact(&mut fixed).await?;
+        let mut addresses = vec![0u8; u16::from_be_bytes([fixed[2], fixed[3]]) as usize];
+        stream.read_exact(&mut addresses).await?;
+        return parse_v2(fixed[0], fixed[1], &addresses);
+    }
+
+    if !header.starts_with(b"PROXY ") {
+        anyhow::bail!("missing proxy protocol header");
+    }
+    while !header.ends_with(b"\r\n") {
+        if header.len() >= V1_MAX_LEN {
+            anyhow::bail!("proxy protocol v1 header too long");
+        }
+        header.push(stream.read_u8().await?);
+    }
+    parse_v1(std::str::from_utf8(&header)?)
+}
+
+fn parse_v1(line: &str) -> anyhow::Result<Option<SocketAddr>> {
+    let tokens: Vec<&str> = line.trim_end().split(' ').collect();
+    match tokens.as_slice() {
+        ["PROXY", "UNKNOWN", ..] => Ok(None),
+        ["PROXY", "TCP4" | "TCP6", src, _dst, sport, _dport] => {
+            let ip: IpAddr = src.parse()?;
+            Ok(Some(SocketAddr::new(ip, sport.parse()?)))
+        }
+        _ => anyhow::bail!("invalid proxy protocol v1 header"),
+    }
+}
+
+fn parse_v2(
+    version_command: u8,
+    family: u8,
+    addresses: &[u8],
+) -> anyhow::Result<Option<SocketAddr>> {
+    if version_command >> 4 != 2 {
+        anyhow::bail!("invalid proxy protocol v2 version");
+    }
+    match version_command & 0x0f {
+        0x00 => return Ok(None),
+        0x01 => {}
+        _ => anyhow::bail!("invalid proxy protocol v2 command"),
+    }
+
+    match family >> 4 {
+        0x1 if addresses.len() >= 12 => {
+            let ip = Ipv4Addr::new(addresses[0], addresses[1], addresses[2], addresses[3]);
+            let port = u16::from_be_bytes([addresses[8], addresses[9]]);
+            Ok(Some(SocketAddr::new(IpAddr::V4(ip), port)))
+        }
+        0x2 if addresses.len() >= 36 => {
+            let mut octets = [0u8; 16];
+            octets.copy_from_slice(&addresses[0..16]);
+            let port = u16::from_be_bytes([addresses[32], addresses[33]]);
+            Ok(Some(SocketAddr::new(
+                IpAddr::V6(Ipv6Addr::from(octets)),
+                port,
+            )))
+        }
+        // AF_UNSPEC or AF_UNIX, nothing meaningful to report
+        0x0 | 0x3 => Ok(None),
+        _ => anyhow::bail!("invalid proxy protocol v2 addresses"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config(trusted: &[&str], proxy_protocol: bool) -> Config {
+        Config {
+            trusted: trusted
+                .iter()
+                .map(|net| parse_network(net).unwrap())
+                .collect(),
+            proxy_protocol,
+        }
+    }
+
+    fn headers(pairs: &[(&'static str, &str)]) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        for (name, value) in pairs {
+            headers.append(*name, value.parse().unwrap());
+        }
+        headers
+    }
+
+    fn v2(command: u8, family: u8, addresses: &[u8]) -> Vec<u8> {
+        let mut header = V2_SIGNATURE.to_vec();
+        header.push(0x20 | command);
+        header.push(family);
+        header.extend_from_slice(&(addresses.len() as u16).to_be_bytes());
+        header.extend_from_slice(addresses);
+        header
+    }
+
+    async fn read(data: &[u8]) -> (anyhow::Result<Option<SocketAddr>>, Vec<u8>) {
+        let mut stream = data;
+        let client = read_proxy_header(&mut stream).await;
+        (client, stream.to_vec())
+    }
+
+    #[tokio::test]
+    async fn v1() {
+        let (client, rest) = read(b"PROXY TCP4 192.0.2.1 198.51.100.1 56324 443\r\nGET").await;
+        assert_eq!(client.unwrap(), Some("192.0.2.1:56324".parse().unwrap()));
+        assert_eq!(rest, b"GET");
+
+        let (client, _) = read(b"PROXY TCP6 2001:db8::1 2001:db8::2 56324 443\r\n").await;
+        assert_eq!(
+            client.unwrap(),
+            Some("[2001:db8::1]:56324".parse().unwrap())
+        );
+
+        let (client, rest) = read(b"PROXY UNKNOWN\r\nGET").await;
+        assert_eq!(client.unwrap(), None);
+        assert_eq!(rest, b"GET");
+
+        assert!(read(b"PROXY TCP4 192.0.2.1\r\n").await.0.is_err());
+        assert!(read(b"GET / HTTP/1.1\r\n").await.0.is_err());
+        let long = format!("PROXY TCP4 {}\r\n", "1".repeat(V1_MAX_LEN));
+        assert!(read(long.as_bytes()).await.0.is_err());
+    }
+
+    #[tokio::test]
+    async fn v2_local() {
+        let (client, rest) = read(&[v2(0x0, 0x00, &[]), b"GET".to_vec()].concat()).await;
+        assert_eq!(client.unwrap(), None);
+        assert_eq!(rest, b"GET");
+    }
+
+    #[tokio::test]
+    async fn v2_proxy_with_tlvs() {
+        let mut addresses = vec![192, 0, 2, 1, 198, 51, 100, 1];
+        addresses.extend_from_slice(&56324u16.to_be_bytes());
+        addresses.extend_from_slice(&443u16.to_be_bytes());
+        // PP2_TYPE_AUTHORITY and PP2_TYPE_NOOP
+        addresses.extend_from_slice(&[0x02, 0x00, 0x0b]);
+        addresses.extend_from_slice(b"example.com");
+        addresses.extend_from_slice(&[0x04, 0x00, 0x02, 0x00, 0x00]);
+        let (client, rest) = read(&[v2(0x1, 0x11, &addresses), b"GET".to_vec()].concat()).await;
+        assert_eq!(client.unwrap(), Some("192.0.2.1:56324".parse().unwrap()));
+        assert_eq!(rest, b"GET");
+
+        let mut addresses = Ipv6Addr::new(0x2001, 0xdb8, 0, 0, 0, 0, 0, 1)
+            .octets()
+            .to_vec();
+        addresses.extend_from_slice(&Ipv6Addr::LOCALHOST.octets());
+        addresses.extend_from_slice(&56324u16.to_be_bytes());
+        addresses.extend_from_slice(&443u16.to_be_bytes());
+        let (client, _) = read(&v2(0x1, 0x21, &addresses)).await;
+        assert_eq!(
+            client.unwrap(),
+            Some("[2001:db8::1]:56324".parse().unwrap())
+        );
+
+        let (client, _) = read(&v2(0x1, 0x31, &[0; 216])).await;
+        assert_eq!(client.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn v2_invalid() {
+        // Version 1 in the version nibble
+        let mut header = v2(0x1, 0x11, &[0; 12]);
+        header[12] = 0x11;
+        assert!(read(&header).await.0.is_err());
+        // Unknown command
+        assert!(read(&v2(0x2, 0x11, &[0; 12])).await.0.is_err());
+        // Addresses shorter than their family
+        assert!(read(&v2(0x1, 0x11, &[0; 8])).await.0.is_err());
+        assert!(read(&v2(0x1, 0x21, &[0; 12])).await.0.is_err());
+    }
+
+    #[tokio::test]
+    async fn truncated() {
+        let header = v2(0x1, 0x11, &[0; 12]);
+        for len in [4, 13, 16, 20] {
+            assert!(read(&header[..len]).await.0.is_err(), "{} bytes", len);
+        }
+        assert!(read(b"PROXY TCP4 192.0.2.1 198.51.100.1 56324 443")
+            .await
+            .0
+            .is_err());
+    }
+
+    #[tokio::test]
+    async fn accept_only_from_trusted_peers() {
+        let peer: SocketAddr = "10.0.0.1:1234".parse().unwrap();
+        let data = b"PROXY TCP4 192.0.2.1 198.51.100.1 56324 443\r\n";
+
+        let mut stream = &data[..];
+        let client = config(&["10.0.0.0/8"], true)
+            .accept(&mut stream, peer)
+            .await;
+        assert_eq!(client.unwrap(), "192.0.2.1:56324".parse().unwrap());
+
+        // The header of an untrusted peer is left in the stream
+        let mut stream = &data[..];
+        let client = config(&["192.168.0.0/16"], true)
+            .accept(&mut stream, peer)
+            .await;
+        assert_eq!(client.unwrap(), peer);
+        assert_eq!(stream, data);
+    }
+
+    #[test]
+    fn forwarded_headers() {
+        let config = config(&["10.0.0.0/8"], false);
+        let proxy: SocketAddr = "10.0.0.1:1234".parse().unwrap();
+
+        let client = config.client_addr(proxy, &headers(&[("x-forwarded-for", "203.0.113.7")]));
+        assert_eq!(client, "203.0.113.7:0".parse().unwrap());
+
+        let client = config.client_addr(
+            proxy,
+            &headers(&[("forwarded", "for=\"[2001:db8::17]:4711\";proto=https")]),
+        );
+        assert_eq!(client, "[2001:db8::17]:4711".parse().unwrap());
+
+        // Forwarded wins over X-Forwarded-For
+        let client = config.client_addr(
+            proxy,
+            &headers(&[
+                ("forwarded", "for=192.0.2.60"),
+                ("x-forwarded-for", "203.0.113.7"),
+            ]),
+        );
+        assert_eq!(client, "192.0.2.60:0".parse().unwrap());
+
+        // Chains of trusted proxies are walked back to the client
+        let client = config.client_addr(
+            proxy,
+            &headers(&[
+                ("x-forwarded-for", "203.0.113.7, 10.0.0.3"),
+                ("x-forwarded-for", "10.0.0.2"),
+            ]),
+        );
+        assert_eq!(client, "203.0.113.7:0".parse().unwrap());
+
+        // Mapped addresses of trusted proxies are trusted too
+        let mapped: SocketAddr = "[::ffff:10.0.0.1]:1234".parse().unwrap();
+        let client = config.client_addr(mapped, &headers(&[("x-forwarded-for", "203.0.113.7")]));
+        assert_eq!(client, "203.0.113.7:0".parse().unwrap());
+
+        // Without headers, or with unparsable ones, the proxy is the client
+        assert_eq!(config.client_addr(proxy, &HeaderMap::new()), proxy);
+        let client = config.client_addr(proxy, &headers(&[("x-forwarded-for", "unknown")]));
+        assert_eq!(client, proxy);
+    }
+
+    #[test]
+    fn spoofed_forwarded_headers() {
+        let config = config(&["10.0.0.0/8"], false);
+        let proxy: SocketAddr = "10.0.0.1:1234".parse().unwrap();
+
+        // The client sent its own X-Forwarded-For, the proxy appended the
+        // address it saw
+        let client = config.client_addr(
+            proxy,
+            &headers(&[("x-forwarded-for", "10.0.0.9, 192.0.2.1, 198.51.100.9")]),
+        );
+        assert_eq!(client, "198.51.100.9:0".parse().unwrap());
+
+        // Headers of untrusted peers are ignored
+        let peer: SocketAddr = "198.51.100.9:1234".parse().unwrap();
+        let client = config.client_addr(peer, &headers(&[("x-forwarded-for", "192.0.2.1")]));
+        assert_eq!(client, peer);
+        let client = config.client_addr(peer, &headers(&[("forwarded", "for=192.0.2.1")]));
+        assert_eq!(client, peer);
+
+        // With PROXY protocol, the headers are never trusted
+        let config = Config {
+            proxy_protocol: true,
+            ..config
+        };
+        let client = config.client_addr(proxy, &headers(&[("x-forwarded-for", "192.0.2.1")]));
+        assert_eq!(client, proxy);
+    }
+
+    #[test]
+    fn networks() {
+        assert!(parse_network("10.0.0.1")
+            .unwrap()
+            .contains(&"10.0.0.1".parse::<IpAddr>().unwrap()));
+        assert!(parse_network("2001:db8::/32").is_ok());
+        assert!(parse_network("10.0.0.0/33").is_err());
+        assert!(parse_network("proxy").is_err());
+    }
+}
@@ -0,0 +1,505 @@
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::Context;
+use async_trait::async_trait;
+use futures_util::future;
+use futures_util::select;
+use futures_util::{FutureExt, StreamExt};
+use quinn::{Connecting, IncomingBiStreams, NewConnection, ReadExactError, RecvStream, SendStream};
+use serde::Deserialize;
+use tokio::sync::{broadcast, watch};
+
+use tracing::*;
+
+use crate::control;
+use crate::framing;
+use crate::rush;
+use crate::server;
+use crate::session;
+use crate::stats;
+use crate::transport;
+
+/// ALPN of the native RUSH protocol, the other ones are HTTP/3.
+pub const ALPN: &[u8] = b"rush";
+/// Time given to the client to name its channel.
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+/// Largest RUSH message accepted on a stream.
+const MAX_MESSAGE_SIZE: usize = 16 * 1024 * 1024;
+/// Longest line of the handshake and control stream.
+const MAX_LINE_SIZE: usize = 64 * 1024;
+/// Application error codes.
+const HANDSHAKE_FAILED: u32 = 0x1;
+const INVALID_MESSAGE: u32 = 0x2;
+
+/// First line of the handshake stream.
+#[derive(Deserialize)]
+struct Hello {
+    channel: String,
+}
+
+/// Native RUSH client over QUIC, for publishers and players without HTTP/3
+/// like native apps or media framework plugins:
+///
+/// - the first bidirectional stream opened by the client starts with a JSON
+///   line naming the channel, `{"channel": "name"}`, answered with a `joined`
+///   message, and then carries the control protocol
+/// - each unidirectional stream opened by the client carries RUSH messages
+///   back to back, a single frame or a group of them
+/// - each datagram of the client is a message
+/// - each message of the channels is sent on its own unidirectional stream
+pub struct QuicTransport {
+    server: Arc<std::sync::Mutex<server::Server>>,
+    connecting: Connecting,
+    closed_tx: watch::Sender<bool>,
+    closed: watch::Receiver<bool>,
+}
+
+impl QuicTransport {
+    pub fn new(server: Arc<std::sync::Mutex<server::Server>>, connecting: Connecting) -> Self {
+        let (closed_tx, closed) = watch::channel(false);
+        Self {
+            server,
+            connecting,
+            closed_tx,
+            closed,
+        }
+    }
+}
+
+/// Negotiated ALPN of a connection, once its handshake data is available.
+pub async fn alpn(connecting: &mut Connecting) -> Option<Vec<u8>> {
+    let data = connecting.handshake_data().await.ok()?;
+    data.downcast::<quinn::crypto::rustls::HandshakeData>()
+        .ok()?
+        .protocol
+}
+
+#[async_trait]
+impl transport::Transport for QuicTransport {
+    fn close(&self) {
+        let _ = self.closed_tx.send(true);
+    }
+
+    async fn process(self) -> Result<(), anyhow::Error> {
+        let mut closed = self.closed.clone();
+        let NewConnection {
+            connection,
+            mut uni_streams,
+            mut bi_streams,
+            mut datagrams,
+            ..
+        } = match self.connecting.await {
+            Ok(conn) => conn,
+            Err(err) => {
+                error!("accepting connection failed: {:?}", err);
+                return Ok(());
+            }
+        };
+        let remote = connection.remote_address();
+        info!("rush connection established from {}", remote);
+
+        let handshake = tokio::time::timeout(HANDSHAKE_TIMEOUT, handshake(&mut bi_streams))
+            .await
+            .context("handshake timed out")
+            .and_then(|res| res);
+        let (control, channel_name) = match handshake {
+            Ok(handshake) => handshake,
+            Err(err) => {
+                info!("rush handshake from {} failed: {}", remote, err);
+                connection.close(HANDSHAKE_FAILED.into(), b"handshake failed");
+                return Ok(());
+            }
+        };
+
+        let stats = self.server.lock().unwrap().stats.clone();
+        stats.record_connection();
+        let mut session = session::Session::new(self.server, &channel_name, remote).await;
+        info!("rush connection request accepted: {}", session.channel());
+        let members = session.join(&channel_name).await;
+        let mut control = Some(control);
+        let joined = control::Response::Joined {
+            id: None,
+            channel: channel_name,
+            members,
+        };
+        send_control(&mut control, &joined).await;
+
+        let initiator = loop {
+            select! {
+                _ = closed.changed().fuse() => break stats::Initiator::Local,
+                stream = uni_streams.next().fuse() => {
+                    match stream {
+                        Some(Ok(stream)) => {
+                            tokio::spawn(receive(stream, session.publisher()));
+                        },
+                        Some(Err(err)) => {
+                            info!("rush connection from {} closed: {}", remote, err);
+                            break stats::Initiator::Remote;
+                        },
+                        None => break stats::Initiator::Remote,
+                    }
+                },
+                datagram = datagrams.next().fuse() => {
+                    match datagram {
+                        Some(Ok(datagram)) => {
+                            debug!("received: {:#?}", datagram.len());
+
+                            session.publish(datagram.to_vec());
+                        },
+                        Some(Err(_)) | None => break stats::Initiator::Remote,
+                    }
+                },
+                line = recv_control(&mut control).fuse() => {
+                    match line {
+                        Ok(Some(line)) => {
+                            if let Some(response) = session.handle_control(&line).await {
+                                send_control(&mut control, &response).await;
+                            }
+                        },
+                        Ok(None) => {
+                            info!("rush control stream closed");
+                            control = None;
+                        },
+                        Err(err) => {
+                            error!("error on rush control stream {}", err);
+                            control = None;
+                        }
+                    }
+                },
+                event = session.recv().fuse() => {
+                    match event {
+                        Some(session::Event::Message { channel, data, .. }) if *channel != *session.channel() => {
+                            // Streams carry no channel, the messages of the
+                            // other joined channels go in envelopes
+                            send_control(&mut control, &framing::envelope(&channel, &data)).await;
+                        },
+                        Some(session::Event::Message { data, .. }) => {
+                            debug!("sent: {:#?}", data.len());
+
+                            // Streams are written concurrently, a large frame
+                            // does not hold the next ones back
+                            match connection.open_uni().await {
+                                Ok(stream) => {
+                                    tokio::spawn(send(stream, data));
+                                },
+                                Err(err) => {
+                                    info!("rush connection from {} closed: {}", remote, err);
+                                    break stats::Initiator::Remote;
+                                }
+                            }
+                        },
+                        Some(session::Event::Control(response)) => {
+                            send_control(&mut control, &response).await;
+                        },
+                        None => break stats::Initiator::Local,
+                    }
+                }
+            }
+        };
+
+        stats.record_close("rush", initiator, stats::CLOSE_ABNORMAL);
+        session.close().await;
+        info!("rush connection finished");
+        Ok(())
+    }
+}
+
+/// Waits for the handshake stream and the channel it names.
+async fn handshake(
+    bi_streams: &mut IncomingBiStreams,
+) -> Result<(ControlStream, String), anyhow::Error> {
+    let (send, recv) = bi_streams.next().await.context("connection closed")??;
+    let mut control = ControlStream::new(send, recv);
+    let line = control.recv().await?.context("handshake stream closed")?;
+    let hello: Hello = serde_json::from_str(&line)?;
+    if hello.channel.is_empty() {
+        anyhow::bail!("empty channel");
+    }
+    Ok((control, hello.channel))
+}
+
+/// Publishes the RUSH messages of a stream of the client as they complete.
+async fn receive(mut stream: RecvStream, tx: broadcast::Sender<Vec<u8>>) {
+    loop {
+        let mut header = [0u8; rush::HEADER_LEN];
+        match stream.read_exact(&mut header).await {
+            Ok(()) => {}
+            Err(ReadExactError::FinishedEarly) => return,
+            Err(ReadExactError::ReadError(err)) => {
+                debug!("rush stream failed: {}", err);
+                return;
+            }
+        }
+        let length = u32::from_be_bytes([header[0], header[1], header[2], header[3]]) as usize;
+        if !(rush::HEADER_LEN..=MAX_MESSAGE_SIZE).contains(&length) {
+            debug!("rush stream with invalid message length {}", length);
+            let _ = stream.stop(INVALID_MESSAGE.into());
+            return;
+        }
+
+        let mut message = vec![0u8; length];
+        message[..rush::HEADER_LEN].copy_from_slice(&header);
+        if let Err(err) = stream.read_exact(&mut message[rush::HEADER_LEN..]).await {
+            debug!("rush stream with truncated message: {}", err);
+            return;
+        }
+        debug!("received: {:#?}", message.len());
+        let _ = tx.send(message);
+    }
+}
+
+async fn send(mut stream: SendStream, data: Vec<u8>) {
+    let res = match stream.write_all(&data).await {
+        Ok(()) => stream.finish().await,
+        Err(err) => Err(err),
+    };
+    if let Err(err) = res {
+        debug!("rush stream write failed: {}", err);
+    }
+}
+
+/// Handshake stream of the client, carrying the control protocol as newline
+/// delimited JSON messages.
+struct ControlStream {
+    send: SendStream,
+    recv: RecvStream,
+    buffer: Vec<u8>,
+}
+
+impl ControlStream {
+    fn new(send: SendStream, recv: RecvStream) -> Self {
+        Self {
+            send,
+            recv,
+            buffer: Vec::new(),
+        }
+    }
+
+    async fn recv(&mut self) -> Result<Option<String>, anyhow::Error> {
+        let mut chunk = [0u8; 4096];
+        loop {
+            if let Some(pos) = self.buffer.iter().position(|&b| b == b'\n') {
+                let line: Vec<u8> = self.buffer.drain(..=pos).collect();
+                return Ok(Some(String::from_utf8(line)?.trim_end().to_string()));
+            }
+            if self.buffer.len() > MAX_LINE_SIZE {
+                anyhow::bail!("control line too long");
+            }
+            match self.recv.read(&mut chunk).await? {
+                Some(n) => self.buffer.extend_from_slice(&chunk[..n]),
+                None => return Ok(None),
+            }
+        }
+    }
+
+    async fn send(&mut self, response: &control::Response) -> Result<(), anyhow::Error> {
+        let mut line = control::encode(response);
+        line.push('\n');
+        self.send.write_all(line.as_bytes()).await?;
+        Ok(())
+    }
+}
+
+async fn recv_control(
+    control: &mut Option<ControlStream>,
+) -> Result<Option<String>, anyhow::Error> {
+    match control {
+        Some(control) => control.recv().await,
+        None => future::pending().await,
+    }
+}
+
+async fn send_control(control: &mut Option<ControlStream>, response: &control::Response) {
+    match control {
+        Some(stream) => {
+            if let Err(err) = stream.send(response).await {
+                error!("error writing rush control stream {}", err);
+            }
+        }
+        None => debug!(
+            "control response dropped without control stream: {:?}",
+            response
+        ),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use quinn::{ConnectionError, Endpoint, ReadError, VarInt};
+
+    /// Connects a client to a RUSH endpoint of the server.
+    async fn connect(server: server::ServerPtr) -> NewConnection {
+        let cert = rcgen::generate_simple_self_signed(vec!["localhost".to_string()]).unwrap();
+        let der = rustls::Certificate(cert.serialize_der().unwrap());
+        let key = rustls::PrivateKey(cert.serialize_private_key_der());
+
+        let mut server_tls = rustls::ServerConfig::builder()
+            .with_safe_defaults()
+            .with_no_client_auth()
+            .with_single_cert(vec![der.clone()], key)
+            .unwrap();
+        server_tls.alpn_protocols = vec![ALPN.to_vec()];
+        let config = quinn::ServerConfig::with_crypto(Arc::new(server_tls));
+        let (endpoint, mut incoming) =
+            Endpoint::server(config, "127.0.0.1:0".parse().unwrap()).unwrap();
+        let addr = endpoint.local_addr().unwrap();
+        tokio::spawn(async move {
+            let _endpoint = endpoint;
+            while let Some(connecting) = incoming.next().await {
+                let transport = QuicTransport::new(server.clone(), connecting);
+                tokio::spawn(transport::Transport::process(transport));
+            }
+        });
+
+        let mut roots = rustls::RootCertStore::empty();
+        roots.add(&der).unwrap();
+        let mut client_tls = rustls::ClientConfig::builder()
+            .with_safe_defaults()
+            .with_root_certificates(roots)
+            .with_no_client_auth();
+        client_tls.alpn_protocols = vec![ALPN.to_vec()];
+        let mut endpoint = Endpoint::client("127.0.0.1:0".parse().unwrap()).unwrap();
+        endpoint.set_default_client_config(quinn::ClientConfig::new(Arc::new(client_tls)));
+        endpoint.connect(addr, "localhost").unwrap().await.unwrap()
+    }
+
+    fn frame(seq: u32, payload: &[u8]) -> Vec<u8> {
+        rush::Header {
+            length: (rush::HEADER_LEN + payload.len()) as u32,
+            seq,
+            frame_type: rush::VIDEO,
+            codec: rush::VP8,
+            source: 0,
+            timestamp: seq * 3000,
+            track_id: 1,
+        }
+        .encode(payload)
+    }
+
+    /// Next control message of the server, skipping the presence
+    /// notifications.
+    async fn read_line(recv: &mut RecvStream) -> serde_json::Value {
+        loop {
+            let mut line = Vec::new();
+            let mut byte = [0u8; 1];
+            while byte[0] != b'\n' {
+                recv.read_exact(&mut byte).await.unwrap();
+                line.push(byte[0]);
+            }
+            let message: serde_json::Value = serde_json::from_slice(&line).unwrap();
+            if message["type"] != "presence" {
+                return message;
+            }
+        }
+    }
+
+    async fn next_message(session: &mut session::Session) -> Vec<u8> {
+        loop {
+            let event = tokio::time::timeout(Duration::from_secs(2), session.recv())
+                .await
+                .unwrap();
+            if let Some(session::Event::Message { data, .. }) = event {
+                return data;
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn streams() {
+        let server: server::ServerPtr = Default::default();
+        let mut conn = connect(server.clone()).await;
+        let (mut send, mut recv) = conn.connection.open_bi().await.unwrap();
+        send.write_all(b"{\"channel\": \"demo\"}\n").await.unwrap();
+        let joined = read_line(&mut recv).await;
+        assert_eq!(joined["type"], "joined");
+        assert_eq!(joined["channel"], "demo");
+
+        let remote = "127.0.0.1:1".parse().unwrap();
+        let mut player = session::Session::new(server.clone(), "demo", remote).await;
+
+        // Messages back to back on a stream, and split across writes
+        let first = frame(1, &[0x10, 0x00, 0x01]);
+        let second = frame(2, &[0x10; 3000]);
+        let mut stream = conn.connection.open_uni().await.unwrap();
+        stream.write_all(&first).await.unwrap();
+        stream.write_all(&second[..10]).await.unwrap();
+        stream.write_all(&second[10..]).await.unwrap();
+        stream.finish().await.unwrap();
+        assert_eq!(next_message(&mut player).await, first);
+        assert_eq!(next_message(&mut player).await, second);
+
+        // A datagram is a message
+        let third = frame(3, b"datagram");
+        conn.connection.send_datagram(third.clone().into()).unwrap();
+        assert_eq!(next_message(&mut player).await, third);
+
+        // Each message of the channel comes on its own stream, the ones of
+        // the client included
+        let fourth = frame(4, b"from the player");
+        player.publish(fourth.clone());
+        let mut received = Vec::new();
+        while received.len() < 4 {
+            let stream = conn.uni_streams.next().await.unwrap().unwrap();
+            received.push(stream.read_to_end(MAX_MESSAGE_SIZE).await.unwrap());
+        }
+        let mut expected = vec![first, second, third, fourth];
+        expected.sort();
+        received.sort();
+        assert!(received == expected);
+
+        // The handshake stream carries the control protocol
+        send.write_all(b"{\"type\": \"ping\", \"id\": 5}\n")
+            .await
+            .unwrap();
+        let pong = read_line(&mut recv).await;
+        assert_eq!(pong["type"], "pong");
+        assert_eq!(pong["id"], 5);
+    }
+
+    #[tokio::test]
+    async fn invalid_messages() {
+        let server: server::ServerPtr = Default::default();
+        let conn = connect(server.clone()).await;
+        let (mut send, mut recv) = conn.connection.open_bi().await.unwrap();
+        send.write_all(b"{\"channel\": \"demo\"}\n").await.unwrap();
+        read_line(&mut recv).await;
+
+        for length in [0, rush::HEADER_LEN as u32 - 1, MAX_MESSAGE_SIZE as u32 + 1] {
+            let mut message = frame(1, b"payload");
+            message[..4].copy_from_slice(&length.to_be_bytes());
+            let mut stream = conn.connection.open_uni().await.unwrap();
+            stream.write_all(&message).await.unwrap();
+            let code = tokio::time::timeout(Duration::from_secs(2), stream.stopped())
+                .await
+                .unwrap()
+                .unwrap();
+            assert_eq!(code, VarInt::from_u32(INVALID_MESSAGE), "{}", length);
+        }
+    }
+
+    #[tokio::test]
+    async fn invalid_handshakes() {
+        let long_line = vec![b'a'; MAX_LINE_SIZE + 4096];
+        let cases: [&[u8]; 4] = [
+            b"{\"name\": \"demo\"}\n",
+            b"{\"channel\": \"\"}\n",
+            b"not json\n",
+            &long_line,
+        ];
+        for hello in cases {
+            let server: server::ServerPtr = Default::default();
+            let conn = connect(server).await;
+            let (mut send, mut recv) = conn.connection.open_bi().await.unwrap();
+            send.write_all(hello).await.unwrap();
+            let mut buf = [0u8; 1];
+            match recv.read(&mut buf).await {
+                Err(ReadError::ConnectionLost(ConnectionError::ApplicationClosed(close))) => {
+                    assert_eq!(close.error_code, VarInt::from_u32(HANDSHAKE_FAILED))
+                }
+                result => panic!("unexpected {:?}", result),
+            }
+        }
+    }
+}
@@ -0,0 +1,137 @@
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::Mutex;
+use std::time::Instant;
+
+/// Number of clients tracked before the ones back to a full bucket are
+/// forgotten.
+const MAX_CLIENTS: usize = 4096;
+
+/// Limits the connections and requests of every client address with a token
+/// bucket refilled at `rate` per second up to `burst`.
+#[derive(Debug)]
+pub struct Limiter {
+    rate: f64,
+    burst: f64,
+    buckets: Mutex<HashMap<IpAddr, Bucket>>,
+}
+
+#[derive(Debug)]
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+impl Bucket {
+    fn refill(&mut self, rate: f64, burst: f64, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.updated).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * rate).min(burst);
+        self.updated = now;
+    }
+}
+
+impl Limiter {
+    /// Limiter allowing `rate` connections per second after a burst of
+    /// `burst`, a zero rate allows everything.
+    pub fn new(rate: f64, burst: u32) -> Self {
+        Self {
+            rate,
+            burst: f64::from(burst.max(1)),
+            buckets: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.rate > 0.0
+    }
+
+    /// Takes a token from the bucket of the client, false when it is empty.
+    pub fn check(&self, ip: IpAddr) -> bool {
+        self.check_at(ip.to_canonical(), Instant::now())
+    }
+
+    fn check_at(&self, ip: IpAddr, now: Instant) -> bool {
+        if !self.is_enabled() {
+            return true;
+        }
+        let mut buckets = self.buckets.lock().unwrap();
+        if buckets.len() >= MAX_CLIENTS && !buckets.contains_key(&ip) {
+            buckets.retain(|_, bucket| {
+                bucket.refill(self.rate, self.burst, now);
+                bucket.tokens < self.burst
+            });
+        }
+
+        let bucket = buckets.entry(ip).or_insert(Bucket {
+            tokens: self.burst,
+            updated: now,
+        });
+        bucket.refill(self.rate, self.burst, now);
+        if bucket.tokens < 1.0 {
+            return false;
+        }
+        bucket.tokens -= 1.0;
+        true
+    }
+}
+
+impl Default for Limiter {
+    fn default() -> Self {
+        Self::new(0.0, 1)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    const CLIENT: IpAddr = IpAddr::V4(std::net::Ipv4Addr::new(192, 0, 2, 1));
+    const OTHER: IpAddr = IpAddr::V4(std::net::Ipv4Addr::new(192, 0, 2, 2));
+
+    #[test]
+    fn buckets() {
+        let limiter = Limiter::new(2.0, 3);
+        let now = Instant::now();
+        assert!((0..3).all(|_| limiter.check_at(CLIENT, now)));
+        assert!(!limiter.check_at(CLIENT, now));
+        // Other clients have their own bucket
+        assert!(limiter.check_at(OTHER, now));
+
+        // Refilled at 2 per second
+        let later = now + Duration::from_millis(500);
+        assert!(limiter.check_at(CLIENT, later));
+        assert!(!limiter.check_at(CLIENT, later));
+        // Up to the burst
+        let much_later = now + Duration::from_secs(60);
+        assert!((0..3).all(|_| limiter.check_at(CLIENT, much_later)));
+        assert!(!limiter.check_at(CLIENT, much_later));
+    }
+
+    #[test]
+    fn disabled() {
+        let limiter = Limiter::default();
+        let now = Instant::now();
+        assert!((0..1000).all(|_| limiter.check_at(CLIENT, now)));
+        assert!(limiter.buckets.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn forgets_full_buckets() {
+        let limiter = Limiter::new(1.0, 2);
+        let now = Instant::now();
+        for i in 0..MAX_CLIENTS as u32 {
+            assert!(limiter.check_at(IpAddr::from(i.to_be_bytes()), now));
+        }
+        assert!(limiter.check_at(CLIENT, now));
+        assert!(limiter.check_at(CLIENT, now));
+
+        // Once refilled, the clients are the same as new ones
+        let later = now + Duration::from_secs(1);
+        assert!(limiter.check_at(OTHER, later));
+        assert_eq!(limiter.buckets.lock().unwrap().len(), 2);
+        // While the others keep their tokens
+        assert!(limiter.check_at(CLIENT, later));
+        assert!(!limiter.check_at(CLIENT, later));
+    }
+}
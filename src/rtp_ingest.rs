@@ -0,0 +1,537 @@
+use std::collections::hash_map::Entry;
+use std::collections::HashMap;
+use std::fmt;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use bytes::Bytes;
+use futures_util::select;
+use futures_util::FutureExt;
+use tokio::net::UdpSocket;
+use tokio::sync::{broadcast, watch};
+use tracing::*;
+use webrtc_util::Unmarshal;
+
+use crate::server;
+use crate::webrtc::router::Kind;
+use crate::webrtc::{sdp, transmux};
+
+/// Size of the receive buffer, the largest UDP payload.
+const MAX_DATAGRAM_SIZE: usize = 65535;
+/// Streams without packets for this long are unpublished.
+const STREAM_TIMEOUT: Duration = Duration::from_secs(5);
+/// Packets of a stream waiting to be depacketized.
+const STREAM_QUEUE: usize = 256;
+/// H.264 NAL unit types.
+const NALU_IDR: u8 = 5;
+const NALU_STAP_A: u8 = 24;
+const NALU_FU_A: u8 = 28;
+
+#[derive(Clone)]
+pub struct Config {
+    pub listen: SocketAddr,
+    pub ingest: Arc<Ingest>,
+}
+
+/// Publisher whose packets are accepted, from any port when none is given
+/// and with any SSRC when none is given.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Source {
+    pub ip: IpAddr,
+    pub port: Option<u16>,
+    pub ssrc: Option<u32>,
+}
+
+impl Source {
+    /// Parses `ip` or `ip:port`, IPv6 addresses with a port in brackets.
+    pub fn parse(address: &str) -> Result<Self, anyhow::Error> {
+        let (ip, port) = match address.parse::<SocketAddr>() {
+            Ok(address) => (address.ip(), Some(address.port())),
+            Err(_) => (address.parse::<IpAddr>()?, None),
+        };
+        Ok(Self {
+            ip: ip.to_canonical(),
+            port,
+            ssrc: None,
+        })
+    }
+
+    fn matches(&self, remote: SocketAddr, ssrc: u32) -> bool {
+        self.ip == remote.ip().to_canonical()
+            && self.port.is_none_or(|port| port == remote.port())
+            && self.ssrc.is_none_or(|own| own == ssrc)
+    }
+
+    /// Whether a packet could match both sources.
+    fn overlaps(&self, other: &Source) -> bool {
+        self.ip == other.ip
+            && (self.port.is_none() || other.port.is_none() || self.port == other.port)
+            && (self.ssrc.is_none() || other.ssrc.is_none() || self.ssrc == other.ssrc)
+    }
+}
+
+impl fmt::Display for Source {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.port {
+            Some(port) => write!(f, "{}", SocketAddr::new(self.ip, port))?,
+            None => write!(f, "{}", self.ip)?,
+        }
+        match self.ssrc {
+            Some(ssrc) => write!(f, " ssrc {}", ssrc),
+            None => Ok(()),
+        }
+    }
+}
+
+/// Channel the RTP streams of a source are published to, with the payload
+/// types of the session description they are sent with.
+#[derive(Debug, Clone)]
+pub struct Description {
+    pub channel: String,
+    pub source: Source,
+    codecs: Vec<(Kind, sdp::Codec)>,
+}
+
+impl Description {
+    /// Parses the session description of a publisher, like the one printed
+    /// by ffmpeg, keeping the payload types that can be published.
+    pub fn parse(channel: &str, source: Source, description: &str) -> Result<Self, anyhow::Error> {
+        let codecs: Vec<(Kind, sdp::Codec)> = sdp::media(description)?
+            .into_iter()
+            .filter_map(|media| Some((Kind::parse(&media.kind)?, media.codecs)))
+            .flat_map(|(kind, codecs)| codecs.into_iter().map(move |codec| (kind, codec)))
+            .filter(|(kind, codec)| transmux::rush_codec(*kind, codec).is_some())
+            .collect();
+        if codecs.is_empty() {
+            anyhow::bail!("no Opus, H.264, VP8, VP9 or AV1 stream");
+        }
+        Ok(Self {
+            channel: channel.to_string(),
+            source,
+            codecs,
+        })
+    }
+
+    fn codec(&self, payload_type: u8) -> Option<&(Kind, sdp::Codec)> {
+        self.codecs
+            .iter()
+            .find(|(_, codec)| codec.payload_type == payload_type)
+    }
+}
+
+/// Descriptions of the RTP ingest by channel, shared with the HTTP endpoint
+/// replacing them. The streams of a channel restart with its description.
+#[derive(Debug)]
+pub struct Ingest {
+    descriptions: watch::Sender<HashMap<String, Arc<Description>>>,
+}
+
+impl Ingest {
+    pub fn new(description: Option<Description>) -> Self {
+        let descriptions = description
+            .into_iter()
+            .map(|description| (description.channel.clone(), Arc::new(description)))
+            .collect();
+        Self {
+            descriptions: watch::channel(descriptions).0,
+        }
+    }
+
+    /// Publishes the streams of a description to its channel, replacing the
+    /// previous one, unless its source publishes to another channel.
+    pub fn set(&self, description: Description) -> Result<(), anyhow::Error> {
+        let mut result = Ok(());
+        self.descriptions.send_if_modified(|descriptions| {
+            let taken = descriptions.values().find(|other| {
+                other.channel != description.channel && other.source.overlaps(&description.source)
+            });
+            if let Some(other) = taken {
+                result = Err(anyhow::anyhow!(
+                    "source {} already publishing to {}",
+                    other.source,
+                    other.channel
+                ));
+                return false;
+            }
+            info!(
+                "rtp ingest from {} published to {} with {} payload types",
+                description.source,
+                description.channel,
+                description.codecs.len()
+            );
+            descriptions.insert(description.channel.clone(), Arc::new(description));
+            true
+        });
+        result
+    }
+
+    /// Stops publishing to a channel, returning whether the ingest was
+    /// publishing to it.
+    pub fn clear(&self, channel: &str) -> bool {
+        self.descriptions.send_if_modified(|descriptions| {
+            let publishing = descriptions.remove(channel).is_some();
+            if publishing {
+                info!("rtp ingest to {} stopped", channel);
+            }
+            publishing
+        })
+    }
+}
+
+/// Plain RTP over UDP, for publishers like ffmpeg and GStreamer. The streams
+/// are told apart by source address and SSRC, and belong to the description
+/// whose source they match. Their codecs are the ones of their payload types
+/// in that description, and their frames are published to its channel as
+/// RUSH messages with the SSRC as track id.
+pub struct RtpIngestModule {
+    server: server::ServerPtr,
+    config: Config,
+}
+
+/// Stream of a publisher, depacketized on its own task.
+struct Stream {
+    description: Arc<Description>,
+    packets: broadcast::Sender<rtp::packet::Packet>,
+    /// SPS and PPS sent in front of the H.264 key frames, as encoders keep
+    /// them out of band in the session description
+    parameter_sets: Vec<Bytes>,
+    /// Packets inserted so far, shifting the sequence numbers
+    inserted: u16,
+    timestamp: Option<u32>,
+    last: Instant,
+}
+
+impl RtpIngestModule {
+    pub fn new(server: server::ServerPtr, config: Config) -> Self {
+        Self { server, config }
+    }
+
+    pub async fn start(self) -> anyhow::Result<()> {
+        let socket = UdpSocket::bind(self.config.listen).await?;
+        info!("listening rtp on {}", socket.local_addr()?);
+
+        let mut descriptions = self.config.ingest.descriptions.subscribe();
+        let mut streams: HashMap<(SocketAddr, u32), Stream> = HashMap::new();
+        let mut expiry = tokio::time::interval(STREAM_TIMEOUT);
+        let mut buffer = vec![0; MAX_DATAGRAM_SIZE];
+        loop {
+            select! {
+                res = socket.recv_from(&mut buffer).fuse() => {
+                    let (len, remote) = match res {
+                        Ok(res) => res,
+                        Err(err) => {
+                            debug!("rtp receive failed: {}", err);
+                            continue;
+                        }
+                    };
+                    // RTCP multiplexed with RTP is not needed, nothing is sent back
+                    if len > 1 && (200..=206).contains(&buffer[1]) {
+                        continue;
+                    }
+                    let packet = match rtp::packet::Packet::unmarshal(&mut &buffer[..len]) {
+                        Ok(packet) => packet,
+                        Err(err) => {
+                            debug!("rtp invalid packet from {}: {}", remote, err);
+                            continue;
+                        }
+                    };
+
+                    let stream = match streams.entry((remote, packet.header.ssrc)) {
+                        Entry::Occupied(entry) => entry.into_mut(),
+                        Entry::Vacant(entry) => {
+                            let ssrc = packet.header.ssrc;
+                            let current = descriptions
+                                .borrow()
+                                .values()
+                                .find(|description| description.source.matches(remote, ssrc))
+                                .cloned();
+                            match current.and_then(|current| self.spawn(current, remote, &packet)) {
+                                Some(stream) => entry.insert(stream),
+                                None => continue,
+                            }
+                        }
+                    };
+                    stream.send(packet);
+                },
+                res = descriptions.changed().fuse() => {
+                    if res.is_err() {
+                        break;
+                    }
+                    let current = descriptions.borrow_and_update();
+                    streams.retain(|_, stream| {
+                        current
+                            .get(&stream.description.channel)
+                            .is_some_and(|description| Arc::ptr_eq(description, &stream.description))
+                    });
+                },
+                _ = expiry.tick().fuse() => {
+                    streams.retain(|(remote, ssrc), stream| {
+                        let active = stream.last.elapsed() < STREAM_TIMEOUT;
+                        if !active {
+                            info!("rtp stream {} from {} finished", ssrc, remote);
+                        }
+                        active
+                    });
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Starts publishing a stream with the codec of its payload type.
+    fn spawn(
+        &self,
+        description: Arc<Description>,
+        remote: SocketAddr,
+        packet: &rtp::packet::Packet,
+    ) -> Option<Stream> {
+        let header = &packet.header;
+        let (kind, codec) = match description.codec(header.payload_type) {
+            Some(codec) => codec.clone(),
+            None => {
+                debug!(
+                    "rtp stream {} from {} with unknown payload type {}",
+                    header.ssrc, remote, header.payload_type
+                );
+                return None;
+            }
+        };
+        info!(
+            "rtp stream {} from {} published to {} with {}",
+            header.ssrc, remote, description.channel, codec.name
+        );
+
+        let parameter_sets = match codec.name.eq_ignore_ascii_case("H264") {
+            true => parameter_sets(&codec),
+            false => Vec::new(),
+        };
+        let (packets, rx) = broadcast::channel(STREAM_QUEUE);
+        tokio::spawn(transmux::publish(
+            self.server.clone(),
+            description.channel.clone(),
+            header.ssrc,
+            kind,
+            codec,
+            0,
+            rx,
+        ));
+        Some(Stream {
+            description,
+            packets,
+            parameter_sets,
+            inserted: 0,
+            timestamp: None,
+            last: Instant::now(),
+        })
+    }
+}
+
+impl Stream {
+    fn send(&mut self, mut packet: rtp::packet::Packet) {
+        self.last = Instant::now();
+        let first = self.timestamp != Some(packet.header.timestamp);
+        self.timestamp = Some(packet.header.timestamp);
+
+        // Key frames starting without their parameter sets get the ones of
+        // the description, in packets of the same frame
+        if first && !self.parameter_sets.is_empty() && starts_key_frame(&packet.payload) {
+            for parameter_set in &self.parameter_sets {
+                let mut header = packet.header.clone();
+                header.marker = false;
+                header.sequence_number = header.sequence_number.wrapping_add(self.inserted);
+                self.inserted = self.inserted.wrapping_add(1);
+                let _ = self.packets.send(rtp::packet::Packet {
+                    header,
+                    payload: parameter_set.clone(),
+                });
+            }
+        }
+        packet.header.sequence_number = packet.header.sequence_number.wrapping_add(self.inserted);
+        let _ = self.packets.send(packet);
+    }
+}
+
+/// SPS and PPS of the `sprop-parameter-sets` of an H.264 codec.
+fn parameter_sets(codec: &sdp::Codec) -> Vec<Bytes> {
+    codec
+        .parameter("sprop-parameter-sets")
+        .map(|sets| {
+            sets.split(',')
+                .filter_map(|set| STANDARD.decode(set).ok())
+                .filter(|set| !set.is_empty())
+                .map(Bytes::from)
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+/// Whether an H.264 payload starts with an IDR slice.
+fn starts_key_frame(payload: &Bytes) -> bool {
+    match payload.first().map(|nalu| nalu & 0x1F) {
+        Some(NALU_IDR) => true,
+        Some(NALU_STAP_A) => payload.get(3).is_some_and(|nalu| nalu & 0x1F == NALU_IDR),
+        Some(NALU_FU_A) => payload
+            .get(1)
+            .is_some_and(|fu| fu & 0x80 != 0 && fu & 0x1F == NALU_IDR),
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SDP: &str = "v=0\r\n\
+        o=- 0 0 IN IP4 127.0.0.1\r\n\
+        s=No Name\r\n\
+        c=IN IP4 127.0.0.1\r\n\
+        t=0 0\r\n\
+        m=video 5004 RTP/AVP 96\r\n\
+        a=rtpmap:96 H264/90000\r\n\
+        a=fmtp:96 packetization-mode=1; sprop-parameter-sets=Z0LAHtkDxWhAAAADAEAAAAwDxYuS,aMuMsg==\r\n\
+        m=audio 5004 RTP/AVP 97\r\n\
+        a=rtpmap:97 opus/48000/2\r\n";
+
+    fn source(address: &str) -> Source {
+        Source::parse(address).unwrap()
+    }
+
+    fn description(channel: &str, source: Source) -> Description {
+        Description::parse(channel, source, SDP).unwrap()
+    }
+
+    fn packet(sequence_number: u16, timestamp: u32, payload: &'static [u8]) -> rtp::packet::Packet {
+        rtp::packet::Packet {
+            header: rtp::header::Header {
+                version: 2,
+                payload_type: 96,
+                sequence_number,
+                timestamp,
+                marker: true,
+                ..Default::default()
+            },
+            payload: Bytes::from_static(payload),
+        }
+    }
+
+    #[test]
+    fn sources() {
+        let remote: SocketAddr = "192.0.2.1:5000".parse().unwrap();
+        assert!(source("192.0.2.1").matches(remote, 1));
+        assert!(source("192.0.2.1:5000").matches(remote, 1));
+        assert!(source("::ffff:192.0.2.1").matches(remote, 1));
+        assert!(source("192.0.2.1").matches("[::ffff:192.0.2.1]:5000".parse().unwrap(), 1));
+        assert!(!source("192.0.2.1:5001").matches(remote, 1));
+        assert!(!source("192.0.2.2").matches(remote, 1));
+        let ssrc = Source {
+            ssrc: Some(1),
+            ..source("192.0.2.1")
+        };
+        assert!(ssrc.matches(remote, 1));
+        assert!(!ssrc.matches(remote, 2));
+
+        assert_eq!(source("[2001:db8::1]:5004").port, Some(5004));
+        assert_eq!(source("2001:db8::1").port, None);
+        assert!(Source::parse("prism:5004").is_err());
+
+        assert!(source("192.0.2.1").overlaps(&source("192.0.2.1:5000")));
+        assert!(!source("192.0.2.1:5001").overlaps(&source("192.0.2.1:5000")));
+        assert!(!ssrc.overlaps(&Source {
+            ssrc: Some(2),
+            ..source("192.0.2.1")
+        }));
+    }
+
+    #[test]
+    fn descriptions_by_channel() {
+        let ingest = Ingest::new(Some(description("a", source("192.0.2.1"))));
+        ingest.set(description("b", source("192.0.2.2"))).unwrap();
+        // A source publishes to a single channel
+        assert!(ingest
+            .set(description("c", source("192.0.2.1:5000")))
+            .is_err());
+        ingest.set(description("a", source("192.0.2.3"))).unwrap();
+        ingest
+            .set(description("c", source("192.0.2.1:5000")))
+            .unwrap();
+
+        assert!(ingest.clear("a"));
+        assert!(!ingest.clear("a"));
+        let descriptions = ingest.descriptions.borrow();
+        let mut channels: Vec<&String> = descriptions.keys().collect();
+        channels.sort();
+        assert_eq!(channels, ["b", "c"]);
+        assert_eq!(descriptions["b"].source, source("192.0.2.2"));
+    }
+
+    #[test]
+    fn key_frames() {
+        assert!(starts_key_frame(&Bytes::from_static(&[0x65, 0x88])));
+        assert!(!starts_key_frame(&Bytes::from_static(&[0x41, 0x9a])));
+        // STAP-A starting with an IDR slice
+        assert!(starts_key_frame(&Bytes::from_static(&[
+            0x78, 0x00, 0x02, 0x65, 0x88
+        ])));
+        // FU-A start and continuation of an IDR slice
+        assert!(starts_key_frame(&Bytes::from_static(&[0x7c, 0x85, 0x88])));
+        assert!(!starts_key_frame(&Bytes::from_static(&[0x7c, 0x05, 0x88])));
+        assert!(!starts_key_frame(&Bytes::new()));
+    }
+
+    #[test]
+    fn parameter_sets_inserted() {
+        let description = Arc::new(description("a", source("192.0.2.1")));
+        let (_, codec) = description.codec(96).unwrap();
+        let sets = parameter_sets(codec);
+        assert_eq!(sets.len(), 2);
+        assert_eq!(sets[0][0] & 0x1F, 7);
+        assert_eq!(sets[1][0] & 0x1F, 8);
+        let (_, opus) = description.codec(97).unwrap();
+        assert!(parameter_sets(opus).is_empty());
+
+        let (packets, mut rx) = broadcast::channel(STREAM_QUEUE);
+        let mut stream = Stream {
+            description,
+            packets,
+            parameter_sets: sets.clone(),
+            inserted: 0,
+            timestamp: None,
+            last: Instant::now(),
+        };
+        let mut received = || {
+            let mut received = Vec::new();
+            while let Ok(packet) = rx.try_recv() {
+                received.push(packet);
+            }
+            received
+        };
+
+        // A key frame split in two, with the parameter sets in front
+        stream.send(packet(65534, 0, &[0x7c, 0x85, 0x88]));
+        stream.send(packet(65535, 0, &[0x7c, 0x45, 0x88]));
+        let frame = received();
+        let sequence_numbers: Vec<u16> = frame.iter().map(|p| p.header.sequence_number).collect();
+        assert_eq!(sequence_numbers, [65534, 65535, 0, 1]);
+        assert_eq!(frame[0].payload, sets[0]);
+        assert_eq!(frame[1].payload, sets[1]);
+        assert!(frame[..2]
+            .iter()
+            .all(|p| !p.header.marker && p.header.timestamp == 0));
+
+        // Delta frames are shifted without insertion
+        stream.send(packet(0, 3000, &[0x41, 0x9a]));
+        let frame = received();
+        assert_eq!(frame.len(), 1);
+        assert_eq!(frame[0].header.sequence_number, 2);
+
+        stream.send(packet(1, 6000, &[0x65, 0x88]));
+        let sequence_numbers: Vec<u16> = received()
+            .iter()
+            .map(|p| p.header.sequence_number)
+            .collect();
+        assert_eq!(sequence_numbers, [3, 4, 5]);
+    }
+}
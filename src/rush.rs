@@ -0,0 +1,70 @@
+/// Size of the RUSH-like header used by the demo: length, sequence number,
+/// type, codec, source, timestamp and track id.
+pub const HEADER_LEN: usize = 20;
+
+/// Frame types.
+pub const AUDIO: u8 = 0x0C;
+pub const VIDEO: u8 = 0x0D;
+
+/// Codecs of the audio frames.
+pub const OPUS: u8 = 0x02;
+
+/// Codecs of the video frames.
+pub const H264: u8 = 0x01;
+pub const VP8: u8 = 0x03;
+pub const VP9: u8 = 0x04;
+pub const AV1: u8 = 0x05;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Header {
+    pub length: u32,
+    pub seq: u32,
+    pub frame_type: u8,
+    pub codec: u8,
+    /// Tracks with the same non-zero source are simulcast layers of it,
+    /// encoding the same media at different qualities
+    pub source: u16,
+    pub timestamp: u32,
+    pub track_id: u32,
+}
+
+impl Header {
+    /// Parses the header of a message, returning None when the message is
+    /// not RUSH framed (too short or with a length not matching the payload).
+    pub fn parse(data: &[u8]) -> Option<Self> {
+        if data.len() < HEADER_LEN {
+            return None;
+        }
+        let header = Self {
+            length: read_u32(&data[0..4]),
+            seq: read_u32(&data[4..8]),
+            frame_type: data[8],
+            codec: data[9],
+            source: u16::from_be_bytes([data[10], data[11]]),
+            timestamp: read_u32(&data[12..16]),
+            track_id: read_u32(&data[16..20]),
+        };
+        if header.length as usize != data.len() {
+            return None;
+        }
+        Some(header)
+    }
+
+    /// Frames a payload, the length of the header has to be the one of
+    /// the whole message.
+    pub fn encode(&self, payload: &[u8]) -> Vec<u8> {
+        let mut data = Vec::with_capacity(HEADER_LEN + payload.len());
+        data.extend_from_slice(&self.length.to_be_bytes());
+        data.extend_from_slice(&self.seq.to_be_bytes());
+        data.extend_from_slice(&[self.frame_type, self.codec]);
+        data.extend_from_slice(&self.source.to_be_bytes());
+        data.extend_from_slice(&self.timestamp.to_be_bytes());
+        data.extend_from_slice(&self.track_id.to_be_bytes());
+        data.extend_from_slice(payload);
+        data
+    }
+}
+
+fn read_u32(bytes: &[u8]) -> u32 {
+    u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]])
+}
@@ -1,14 +1,35 @@
-use std::{collections::HashMap, sync::Arc};
-use tokio::sync::Mutex;
+use std::{
+    collections::{HashMap, HashSet},
+    sync::Arc,
+};
+use tokio::sync::{mpsc, Mutex};
 use tracing::*;
 
 use crate::channel;
 use crate::module;
+use crate::moq;
+use crate::mqtt;
+use crate::ratelimit;
+use crate::session;
+use crate::stats;
 
 #[derive(Debug)]
 pub struct Server {
     channels: HashMap<String, Arc<Mutex<channel::Channel>>>,
+    /// Open sessions of every transport, for the admin API
+    sessions: HashMap<u64, session::Info>,
     pub modules: HashMap<String, Arc<Mutex<module::Module>>>,
+    pub stats: Arc<stats::Stats>,
+    /// Connections and requests allowed per client address
+    pub limiter: Arc<ratelimit::Limiter>,
+    /// MoQT announcements and tracks
+    pub moq: Arc<moq::relay::Relay>,
+    /// MQTT retained messages
+    pub mqtt: Arc<mqtt::topic::Retained>,
+    /// Told about the channels created, for subscriptions matching channels by pattern
+    watchers: Vec<mpsc::UnboundedSender<channel::Created>>,
+    /// Channels whose messages are never compressed, like already compressed media
+    pub uncompressed_channels: HashSet<String>,
 }
 
 pub type ServerPtr = Arc<std::sync::Mutex<Server>>;
@@ -17,7 +38,14 @@ impl Server {
     pub fn new() -> Self {
         Self {
             channels: HashMap::new(),
+            sessions: HashMap::new(),
             modules: HashMap::new(),
+            stats: Arc::new(stats::Stats::new()),
+            limiter: Arc::new(ratelimit::Limiter::default()),
+            moq: Arc::new(moq::relay::Relay::default()),
+            mqtt: Arc::new(mqtt::topic::Retained::default()),
+            watchers: Vec::new(),
+            uncompressed_channels: HashSet::new(),
         }
     }
 
@@ -27,14 +55,55 @@ impl Server {
             Some(channel) => channel.clone(),
             None => {
                 info!("channel created {}", name);
-                let channel = Arc::new(Mutex::new(channel::Channel::new(name)));
+                let mut channel = channel::Channel::new(name);
+                channel.compression = !self.uncompressed_channels.contains(name);
+                self.watchers.retain(|watcher| {
+                    let created = channel::Created {
+                        name: name.to_string(),
+                        subscription: channel.subscribe(),
+                    };
+                    watcher.send(created).is_ok()
+                });
+                let channel = Arc::new(Mutex::new(channel));
                 self.channels.insert(name.to_string(), channel.clone());
                 channel
             }
         }
     }
 
+    /// Receives the channels created from now on.
+    pub fn watch_channels(&mut self) -> mpsc::UnboundedReceiver<channel::Created> {
+        let (tx, rx) = mpsc::unbounded_channel();
+        self.watchers.push(tx);
+        rx
+    }
+
+    pub fn channel_names(&self) -> Vec<String> {
+        self.channels.keys().cloned().collect()
+    }
+
+    pub fn add_session(&mut self, info: session::Info) {
+        self.sessions.insert(info.id, info);
+    }
+
+    pub fn remove_session(&mut self, id: u64) {
+        self.sessions.remove(&id);
+    }
+
+    /// Open sessions, oldest first.
+    pub fn sessions(&self) -> Vec<session::Info> {
+        let mut sessions: Vec<session::Info> = self.sessions.values().cloned().collect();
+        sessions.sort_by_key(|session| session.id);
+        sessions
+    }
+
     // pub fn destroy_channel(&mut self, name: &str) -> void {
     //     let channel = self.channels.delete(name);
     // }
 }
+
+impl Default for Server {
+    fn default() -> Self {
+        Self::new()
+    }
+}
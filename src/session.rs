@@ -0,0 +1,351 @@
+use std::collections::{HashMap, HashSet};
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use futures_util::select;
+use futures_util::FutureExt;
+use serde::Serialize;
+use tokio::sync::{broadcast, mpsc, watch};
+use tokio::task::JoinHandle;
+use tracing::*;
+
+use crate::channel;
+use crate::control;
+use crate::framing;
+use crate::rush;
+use crate::server;
+
+static NEXT_SESSION_ID: AtomicU64 = AtomicU64::new(1);
+
+/// Something a transport has to deliver to its peer.
+#[derive(Debug)]
+pub enum Event {
+    Message {
+        channel: Arc<str>,
+        data: Vec<u8>,
+        compress: bool,
+    },
+    Control(control::Response),
+}
+
+/// Session as listed by the admin API.
+#[derive(Debug, Clone, Serialize)]
+pub struct Info {
+    pub id: u64,
+    pub channel: String,
+    /// Address of the client, or how it is told apart when it has none
+    pub remote: String,
+    /// Unix time the session was opened at, in seconds
+    pub since: u64,
+}
+
+type TrackFilter = Option<HashSet<u32>>;
+
+struct Membership {
+    tracks: watch::Sender<TrackFilter>,
+    forwarder: JoinHandle<()>,
+}
+
+/// Channel routing state of a connection, shared by all the transports.
+///
+/// A session publishes into the channel it was opened for and receives from
+/// every channel it joined, including the additional ones requested through
+/// the control protocol.
+pub struct Session {
+    pub id: u64,
+    /// Address of the client, as reported by trusted proxies if any
+    pub remote: SocketAddr,
+    /// Client as named in the logs, its address unless it has none
+    peer: String,
+    server: server::ServerPtr,
+    channel: String,
+    tx: broadcast::Sender<Vec<u8>>,
+    memberships: HashMap<String, Membership>,
+    events_tx: mpsc::Sender<Event>,
+    events: mpsc::Receiver<Event>,
+}
+
+impl Session {
+    pub async fn new(server: server::ServerPtr, channel_name: &str, remote: SocketAddr) -> Self {
+        Self::with_peer(server, channel_name, remote, remote.to_string()).await
+    }
+
+    /// Session of a client without an address, like a local process, named
+    /// by `peer` instead.
+    pub async fn with_peer(
+        server: server::ServerPtr,
+        channel_name: &str,
+        remote: SocketAddr,
+        peer: String,
+    ) -> Self {
+        let channel = server.lock().unwrap().find_or_create_channel(channel_name);
+        let tx = channel.lock().await.broadcast.clone();
+        let (events_tx, events) = mpsc::channel(64);
+
+        let mut session = Self {
+            id: NEXT_SESSION_ID.fetch_add(1, Ordering::Relaxed),
+            remote,
+            peer,
+            server,
+            channel: channel_name.to_string(),
+            tx,
+            memberships: HashMap::new(),
+            events_tx,
+            events,
+        };
+        session.register();
+        session.join(channel_name).await;
+        session
+    }
+
+    /// Session without a connection channel, for protocols naming the channel
+    /// of every message like MQTT. Publishing into it drops the messages.
+    pub fn unbound(server: server::ServerPtr, remote: SocketAddr) -> Self {
+        let (tx, _rx) = broadcast::channel(1);
+        let (events_tx, events) = mpsc::channel(64);
+        let session = Self {
+            id: NEXT_SESSION_ID.fetch_add(1, Ordering::Relaxed),
+            remote,
+            peer: remote.to_string(),
+            server,
+            channel: String::new(),
+            tx,
+            memberships: HashMap::new(),
+            events_tx,
+            events,
+        };
+        session.register();
+        session
+    }
+
+    fn register(&self) {
+        let since = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |time| time.as_secs());
+        self.server.lock().unwrap().add_session(Info {
+            id: self.id,
+            channel: self.channel.clone(),
+            remote: self.peer.clone(),
+            since,
+        });
+    }
+
+    pub fn channel(&self) -> &str {
+        &self.channel
+    }
+
+    pub fn publish(&self, data: Vec<u8>) {
+        let _ = self.tx.send(data);
+    }
+
+    /// Sender into the connection channel, for messages published outside
+    /// of the transport loop.
+    pub fn publisher(&self) -> broadcast::Sender<Vec<u8>> {
+        self.tx.clone()
+    }
+
+    pub async fn recv(&mut self) -> Option<Event> {
+        self.events.recv().await
+    }
+
+    /// Handles a control request, returning the response to send back if any.
+    pub async fn handle_control(&mut self, text: &str) -> Option<control::Response> {
+        let request = match control::parse(text) {
+            Ok(request) => request,
+            Err(err) => {
+                return Some(control::Response::Error {
+                    id: None,
+                    message: format!("invalid control message: {}", err),
+                })
+            }
+        };
+        debug!("session {} control: {:?}", self.id, request);
+
+        let id = request.id;
+        let response = match request.command {
+            control::Command::Join { channel } => {
+                let members = self.join(&channel).await;
+                control::Response::Joined {
+                    id,
+                    channel,
+                    members,
+                }
+            }
+            control::Command::Leave { channel } => {
+                if channel == self.channel {
+                    return Some(control::Response::Error {
+                        id,
+                        message: "cannot leave the connection channel".to_string(),
+                    });
+                }
+                match self.leave(&channel).await {
+                    true => control::Response::Left { id, channel },
+                    false => control::Response::Error {
+                        id,
+                        message: format!("not joined to {}", channel),
+                    },
+                }
+            }
+            control::Command::Subscribe { channel, tracks } => {
+                if !self.memberships.contains_key(&channel) {
+                    self.join(&channel).await;
+                }
+                let filter = tracks.clone().map(|tracks| tracks.into_iter().collect());
+                let _ = self.memberships[&channel].tracks.send(filter);
+                control::Response::Subscribed {
+                    id,
+                    channel,
+                    tracks,
+                }
+            }
+            control::Command::Ping => control::Response::Pong {
+                id,
+                time: SystemTime::now()
+                    .duration_since(UNIX_EPOCH)
+                    .map_or(0, |time| time.as_millis() as u64),
+            },
+            control::Command::Message { encoding, data } => {
+                match framing::payload(encoding, &data) {
+                    Ok(payload) => {
+                        self.publish(payload);
+                        return None;
+                    }
+                    Err(err) => control::Response::Error {
+                        id,
+                        message: format!("invalid message: {}", err),
+                    },
+                }
+            }
+        };
+        Some(response)
+    }
+
+    /// Joins a channel returning the number of members, joining twice is a no-op.
+    pub async fn join(&mut self, name: &str) -> usize {
+        self.join_with(name, None).await
+    }
+
+    /// Joins a channel with the receivers subscribed when it was created, so
+    /// that none of its messages are missed.
+    pub async fn join_created(&mut self, created: channel::Created) -> usize {
+        self.join_with(&created.name, Some(created.subscription))
+            .await
+    }
+
+    async fn join_with(
+        &mut self,
+        name: &str,
+        subscription: Option<channel::Subscription>,
+    ) -> usize {
+        let channel = self.server.lock().unwrap().find_or_create_channel(name);
+        let mut guard = channel.lock().await;
+        if self.memberships.contains_key(name) {
+            return guard.members;
+        }
+
+        let channel::Subscription {
+            messages: rx,
+            presence,
+        } = subscription.unwrap_or_else(|| guard.subscribe());
+        let compress = guard.compression;
+        let members = guard.join(self.id);
+        drop(guard);
+
+        let (tracks, filter) = watch::channel(None);
+        let forwarder = tokio::spawn(forward(
+            self.id,
+            Arc::from(name),
+            rx,
+            presence,
+            filter,
+            compress,
+            self.events_tx.clone(),
+        ));
+        self.memberships
+            .insert(name.to_string(), Membership { tracks, forwarder });
+
+        info!("session {} from {} joined {}", self.id, self.peer, name);
+        members
+    }
+
+    pub async fn leave(&mut self, name: &str) -> bool {
+        let membership = match self.memberships.remove(name) {
+            Some(membership) => membership,
+            None => return false,
+        };
+        membership.forwarder.abort();
+
+        let channel = self.server.lock().unwrap().find_or_create_channel(name);
+        channel.lock().await.leave(self.id);
+
+        info!("session {} from {} left {}", self.id, self.peer, name);
+        true
+    }
+
+    pub async fn close(mut self) {
+        let names: Vec<String> = self.memberships.keys().cloned().collect();
+        for name in names {
+            self.leave(&name).await;
+        }
+    }
+}
+
+impl Drop for Session {
+    fn drop(&mut self) {
+        self.server.lock().unwrap().remove_session(self.id);
+    }
+}
+
+async fn forward(
+    id: u64,
+    channel: Arc<str>,
+    mut rx: broadcast::Receiver<Vec<u8>>,
+    mut presence: broadcast::Receiver<control::Presence>,
+    filter: watch::Receiver<TrackFilter>,
+    compress: bool,
+    events: mpsc::Sender<Event>,
+) {
+    loop {
+        let event = select! {
+            res = rx.recv().fuse() => {
+                match res {
+                    Ok(data) if accepts(&filter.borrow(), &data) => Event::Message {
+                        channel: channel.clone(),
+                        data,
+                        compress,
+                    },
+                    Ok(_) => continue,
+                    Err(broadcast::error::RecvError::Lagged(skipped)) => {
+                        warn!("session {} lagged {} messages", id, skipped);
+                        continue;
+                    }
+                    Err(broadcast::error::RecvError::Closed) => break,
+                }
+            },
+            res = presence.recv().fuse() => {
+                match res {
+                    Ok(presence) if presence.session != id => {
+                        Event::Control(control::Response::Presence(presence))
+                    }
+                    Ok(_) | Err(broadcast::error::RecvError::Lagged(_)) => continue,
+                    Err(broadcast::error::RecvError::Closed) => break,
+                }
+            }
+        };
+
+        if events.send(event).await.is_err() {
+            break;
+        }
+    }
+}
+
+/// Track filters only apply to RUSH framed messages, anything else is
+/// always forwarded.
+fn accepts(filter: &TrackFilter, data: &[u8]) -> bool {
+    match (filter, rush::Header::parse(data)) {
+        (Some(tracks), Some(header)) => tracks.contains(&header.track_id),
+        _ => true,
+    }
+}
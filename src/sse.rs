@@ -0,0 +1,240 @@
+use std::borrow::Cow;
+use std::net::SocketAddr;
+use std::time::Duration;
+
+use bytes::{BufMut, Bytes, BytesMut};
+use futures_util::select;
+use futures_util::FutureExt;
+use http::{header, Method, Request, Response, StatusCode};
+use hyper::body::{HttpBody, Sender};
+use hyper::Body;
+use tokio::time;
+use tracing::*;
+
+use crate::auth;
+use crate::control;
+use crate::framing;
+use crate::server;
+use crate::session;
+use crate::stats;
+
+const EVENT_STREAM: &str = "text/event-stream";
+/// Largest message accepted in a POST body.
+const MAX_MESSAGE_SIZE: usize = 1024 * 1024;
+/// Interval of the comments keeping idle event streams open, also detecting
+/// the clients that went away.
+const KEEPALIVE_INTERVAL: Duration = Duration::from_secs(15);
+
+/// Resources of the HTTP fallback, for clients that can't hold a session.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Route {
+    /// `POST /channels/{channel}/messages` publishes the body as a message
+    Messages,
+    /// `GET /channels/{channel}/events` streams the channel as Server-Sent Events
+    Events,
+}
+
+/// Channel and resource of a path.
+pub fn parse_path(path: &str) -> Option<(String, Route)> {
+    let tokens: Vec<&str> = path.split('/').filter(|s| !s.is_empty()).collect();
+    match tokens.as_slice() {
+        ["channels", channel, "messages"] => Some((channel.to_string(), Route::Messages)),
+        ["channels", channel, "events"] => Some((channel.to_string(), Route::Events)),
+        _ => None,
+    }
+}
+
+pub async fn handle(
+    server: server::ServerPtr,
+    auth: &auth::Config,
+    remote: SocketAddr,
+    channel: String,
+    route: Route,
+    req: Request<Body>,
+) -> Response<Body> {
+    if req.method() == Method::OPTIONS {
+        return status(StatusCode::NO_CONTENT);
+    }
+    match (route, req.method()) {
+        (Route::Messages, &Method::POST) | (Route::Events, &Method::GET) => {}
+        _ => return status(StatusCode::METHOD_NOT_ALLOWED),
+    }
+
+    if let Err(err) = auth.verify(token(&req).as_deref(), &channel) {
+        debug!("http unauthorized for {}: {}", channel, err);
+        return status(StatusCode::UNAUTHORIZED);
+    }
+
+    match route {
+        Route::Messages => publish(server, channel, req).await,
+        Route::Events => subscribe(server, remote, channel).await,
+    }
+}
+
+/// The token of the `Authorization` header, or of the `token` query parameter
+/// as `EventSource` can't set headers.
+fn token(req: &Request<Body>) -> Option<Cow<'_, str>> {
+    let authorization = req
+        .headers()
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok());
+    match auth::bearer(authorization) {
+        Some(token) => Some(Cow::Borrowed(token)),
+        None => form_urlencoded::parse(req.uri().query()?.as_bytes())
+            .find_map(|(name, value)| (name == "token").then_some(value)),
+    }
+}
+
+async fn publish(server: server::ServerPtr, channel: String, req: Request<Body>) -> Response<Body> {
+    let length = req
+        .headers()
+        .get(header::CONTENT_LENGTH)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.parse::<u64>().ok());
+    if length.is_some_and(|length| length > MAX_MESSAGE_SIZE as u64) {
+        return status(StatusCode::PAYLOAD_TOO_LARGE);
+    }
+    let body = match read_body(req.into_body(), MAX_MESSAGE_SIZE).await {
+        Ok(Some(body)) => body,
+        Ok(None) => return status(StatusCode::PAYLOAD_TOO_LARGE),
+        Err(err) => {
+            debug!("http body failed: {}", err);
+            return status(StatusCode::BAD_REQUEST);
+        }
+    };
+
+    let (stats, channel) = {
+        let mut server = server.lock().unwrap();
+        (
+            server.stats.clone(),
+            server.find_or_create_channel(&channel),
+        )
+    };
+    stats.record_connection();
+    let tx = channel.lock().await.broadcast.clone();
+    debug!("http published: {:#?}", body.len());
+    let _ = tx.send(body.to_vec());
+    status(StatusCode::NO_CONTENT)
+}
+
+/// Reads a body of at most `limit` bytes, `None` when it is larger.
+pub async fn read_body(mut body: Body, limit: usize) -> Result<Option<Bytes>, hyper::Error> {
+    let mut data = BytesMut::new();
+    while let Some(chunk) = body.data().await {
+        let chunk = chunk?;
+        if data.len() + chunk.len() > limit {
+            return Ok(None);
+        }
+        data.put(chunk);
+    }
+    Ok(Some(data.freeze()))
+}
+
+async fn subscribe(
+    server: server::ServerPtr,
+    remote: SocketAddr,
+    channel: String,
+) -> Response<Body> {
+    let stats = server.lock().unwrap().stats.clone();
+    stats.record_connection();
+    let session = session::Session::new(server, &channel, remote).await;
+    info!("http event stream accepted: {:#?}", session.channel());
+
+    let (sender, body) = Body::channel();
+    tokio::spawn(async move {
+        let initiator = stream(session, sender).await;
+        info!(
+            "http event stream from {} closed by {:?}",
+            remote, initiator
+        );
+        stats.record_close("sse", initiator, stats::CLOSE_ABNORMAL);
+    });
+
+    Response::builder()
+        .status(StatusCode::OK)
+        .header(header::CONTENT_TYPE, EVENT_STREAM)
+        .header(header::CACHE_CONTROL, "no-cache")
+        .body(body)
+        .unwrap()
+}
+
+/// Sends the events of a session until the client goes away, channel
+/// messages being wrapped in the JSON envelope of the `prism.json` framing.
+async fn stream(mut session: session::Session, mut sender: Sender) -> stats::Initiator {
+    let mut keepalive = time::interval(KEEPALIVE_INTERVAL);
+    let initiator = loop {
+        let chunk = select! {
+            event = session.recv().fuse() => {
+                let response = match event {
+                    Some(session::Event::Message { channel, data, .. }) => {
+                        framing::envelope(&channel, &data)
+                    }
+                    Some(session::Event::Control(response)) => response,
+                    None => break stats::Initiator::Local,
+                };
+                format!("data: {}\n\n", control::encode(&response))
+            },
+            _ = keepalive.tick().fuse() => ":\n\n".to_string(),
+        };
+        if sender.send_data(Bytes::from(chunk)).await.is_err() {
+            break stats::Initiator::Remote;
+        }
+    };
+    session.close().await;
+    initiator
+}
+
+fn status(status: StatusCode) -> Response<Body> {
+    Response::builder()
+        .status(status)
+        .body(Body::empty())
+        .unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request(uri: &str, authorization: Option<&str>) -> Request<Body> {
+        let mut builder = Request::builder().uri(uri);
+        if let Some(authorization) = authorization {
+            builder = builder.header(header::AUTHORIZATION, authorization);
+        }
+        builder.body(Body::empty()).unwrap()
+    }
+
+    #[test]
+    fn tokens() {
+        let req = request("/channels/demo/events?token=a%2Bb%3D%3D&x=1", None);
+        assert_eq!(token(&req).as_deref(), Some("a+b=="));
+        let req = request("/channels/demo/events?x=1&token=a+b", None);
+        assert_eq!(token(&req).as_deref(), Some("a b"));
+        let req = request("/channels/demo/events?token=query", Some("Bearer header"));
+        assert_eq!(token(&req).as_deref(), Some("header"));
+        let req = request("/channels/demo/events?notoken=1", None);
+        assert_eq!(token(&req), None);
+        assert_eq!(token(&request("/channels/demo/events", None)), None);
+    }
+
+    #[tokio::test]
+    async fn limited_body() {
+        let (mut sender, body) = Body::channel();
+        tokio::spawn(async move {
+            for _ in 0..4 {
+                if sender.send_data(Bytes::from(vec![0; 10])).await.is_err() {
+                    return;
+                }
+            }
+        });
+        assert_eq!(
+            read_body(body, 40).await.unwrap().map(|body| body.len()),
+            Some(40)
+        );
+
+        let (mut sender, body) = Body::channel();
+        tokio::spawn(
+            async move { while sender.send_data(Bytes::from(vec![0; 10])).await.is_ok() {} },
+        );
+        assert_eq!(read_body(body, 25).await.unwrap(), None);
+    }
+}
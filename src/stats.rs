@@ -0,0 +1,109 @@
+use std::collections::HashMap;
+use std::fmt::Write;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Initiator {
+    Local,
+    Remote,
+}
+
+impl Initiator {
+    fn as_str(&self) -> &'static str {
+        match self {
+            Initiator::Local => "local",
+            Initiator::Remote => "remote",
+        }
+    }
+}
+
+/// Close code reported when a connection ends without a close handshake.
+pub const CLOSE_ABNORMAL: u16 = 1006;
+
+#[derive(Debug, Default)]
+pub struct Stats {
+    connections: AtomicU64,
+    closes: Mutex<HashMap<(&'static str, Initiator, u16), u64>>,
+}
+
+impl Stats {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    pub fn record_connection(&self) {
+        self.connections.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn record_close(&self, transport: &'static str, initiator: Initiator, code: u16) {
+        *self
+            .closes
+            .lock()
+            .unwrap()
+            .entry((transport, initiator, code))
+            .or_insert(0) += 1;
+    }
+
+    pub fn connections(&self) -> u64 {
+        self.connections.load(Ordering::Relaxed)
+    }
+
+    pub fn closes(&self) -> Vec<((&'static str, Initiator, u16), u64)> {
+        self.closes
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(key, count)| (*key, *count))
+            .collect()
+    }
+
+    /// Counters in the Prometheus text exposition format.
+    pub fn render(&self) -> String {
+        let mut closes = self.closes();
+        closes.sort();
+        let mut text = String::new();
+        text.push_str("# HELP prism_connections_total Connections accepted.\n");
+        text.push_str("# TYPE prism_connections_total counter\n");
+        let _ = writeln!(text, "prism_connections_total {}", self.connections());
+        text.push_str("# HELP prism_closes_total Connections closed, by transport, initiator and close code.\n");
+        text.push_str("# TYPE prism_closes_total counter\n");
+        for ((transport, initiator, code), count) in closes {
+            let _ = writeln!(
+                text,
+                "prism_closes_total{{transport=\"{}\",initiator=\"{}\",code=\"{}\"}} {}",
+                transport,
+                initiator.as_str(),
+                code,
+                count
+            );
+        }
+        text
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn render() {
+        let stats = Stats::new();
+        stats.record_connection();
+        stats.record_connection();
+        stats.record_close("websocket", Initiator::Remote, 1000);
+        stats.record_close("udp", Initiator::Local, CLOSE_ABNORMAL);
+        stats.record_close("websocket", Initiator::Remote, 1000);
+
+        let text = stats.render();
+        let samples: Vec<&str> = text.lines().filter(|line| !line.starts_with('#')).collect();
+        assert_eq!(
+            samples,
+            [
+                "prism_connections_total 2",
+                "prism_closes_total{transport=\"udp\",initiator=\"local\",code=\"1006\"} 1",
+                "prism_closes_total{transport=\"websocket\",initiator=\"remote\",code=\"1000\"} 2",
+            ]
+        );
+    }
+}
@@ -0,0 +1,540 @@
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
+use futures_util::future;
+use futures_util::select;
+use futures_util::FutureExt;
+use hmac::{Hmac, Mac};
+use serde::Deserialize;
+use sha2::Sha256;
+use tokio::net::UdpSocket;
+use tokio::sync::mpsc;
+use tokio::time::{self, Instant};
+use tracing::*;
+
+use crate::auth;
+use crate::control;
+use crate::server;
+use crate::session;
+use crate::stats;
+
+/// Prefix of the registration datagrams and of their answers, anything else
+/// is a message.
+pub const REGISTER_PREFIX: &[u8] = b"prism-register ";
+/// Size of the receive buffer, the largest UDP payload.
+const MAX_DATAGRAM_SIZE: usize = 65535;
+/// Datagrams of an endpoint waiting to be published.
+const ENDPOINT_QUEUE: usize = 64;
+/// Time a source has to register again with the nonce of its challenge.
+const NONCE_LIFETIME: Duration = Duration::from_secs(30);
+/// Bytes of the HMAC kept in a nonce.
+const NONCE_MAC_SIZE: usize = 16;
+
+#[derive(Clone)]
+pub struct Config {
+    pub listen: SocketAddr,
+    /// Source addresses bound to a channel without registration, they never expire
+    pub endpoints: Vec<(SocketAddr, String)>,
+    /// Time without datagrams before a registration expires
+    pub idle_timeout: Duration,
+    pub auth: auth::Config,
+    /// Registrations are accepted without a token when there is no secret
+    pub anonymous: bool,
+}
+
+/// Registration datagram, `prism-register {"channel": "other", "token": "..."}`,
+/// with the `nonce` of the challenge sent back to the source.
+#[derive(Deserialize)]
+struct Register {
+    channel: String,
+    token: Option<String>,
+    nonce: Option<String>,
+}
+
+/// Parses a static endpoint, `address=channel`.
+pub fn parse_endpoint(value: &str) -> Result<(SocketAddr, String), anyhow::Error> {
+    let (address, channel) = match value.split_once('=') {
+        Some((address, channel)) if !channel.is_empty() => (address, channel),
+        _ => anyhow::bail!("expected address=channel"),
+    };
+    Ok((address.parse()?, channel.to_string()))
+}
+
+/// Raw UDP datagrams, for game servers and devices without a session
+/// protocol. A source address is bound to a channel by a registration
+/// datagram or statically, its datagrams are published to the channel and the
+/// messages of the channel are sent back to it.
+///
+/// Source addresses can be forged, so a registration is first answered with
+/// a challenge whose nonce the source sends back to be bound, and answers are
+/// never larger than the registration they answer.
+pub struct UdpModule {
+    server: server::ServerPtr,
+    config: Config,
+    /// Key of the nonces, they are not kept
+    nonce_key: [u8; 32],
+}
+
+/// Endpoint bound to a channel, its session runs on its own task.
+struct Endpoint {
+    channel: String,
+    datagrams: mpsc::Sender<Vec<u8>>,
+}
+
+impl Endpoint {
+    fn expired(&self) -> bool {
+        self.datagrams.is_closed()
+    }
+}
+
+impl UdpModule {
+    pub fn new(server: server::ServerPtr, config: Config) -> Self {
+        Self {
+            server,
+            config,
+            nonce_key: rand::random(),
+        }
+    }
+
+    pub async fn start(self) -> anyhow::Result<()> {
+        let socket = Arc::new(UdpSocket::bind(self.config.listen).await?);
+        info!("listening udp on {}", socket.local_addr()?);
+
+        let (expired_tx, mut expired) = mpsc::channel(ENDPOINT_QUEUE);
+        let mut endpoints = HashMap::new();
+        for (remote, channel) in &self.config.endpoints {
+            let endpoint = self
+                .spawn(&socket, &expired_tx, *remote, channel, None)
+                .await;
+            endpoints.insert(*remote, endpoint);
+        }
+
+        let mut buffer = vec![0; MAX_DATAGRAM_SIZE];
+        loop {
+            select! {
+                res = socket.recv_from(&mut buffer).fuse() => {
+                    let (len, remote) = match res {
+                        Ok(res) => res,
+                        Err(err) => {
+                            // ICMP errors of the endpoints that went away are reported here
+                            debug!("udp receive failed: {}", err);
+                            continue;
+                        }
+                    };
+                    let datagram = &buffer[..len];
+
+                    if let Some(register) = datagram.strip_prefix(REGISTER_PREFIX) {
+                        let response = self
+                            .register(&socket, &expired_tx, &mut endpoints, remote, register)
+                            .await;
+                        let mut answer = REGISTER_PREFIX.to_vec();
+                        answer.extend_from_slice(control::encode(&response).as_bytes());
+                        if answer.len() > len {
+                            debug!("udp registration from {} shorter than its answer", remote);
+                            continue;
+                        }
+                        let _ = socket.send_to(&answer, remote).await;
+                        continue;
+                    }
+
+                    match endpoints.get(&remote) {
+                        Some(endpoint) => {
+                            if endpoint.datagrams.try_send(datagram.to_vec()).is_err() {
+                                debug!("udp endpoint {} lagging, datagram dropped", remote);
+                            }
+                        }
+                        None => debug!("udp datagram from unregistered {} dropped", remote),
+                    }
+                },
+                remote = expired.recv().fuse() => {
+                    // The endpoint may have registered again in the meantime
+                    if let Some(remote) = remote {
+                        if endpoints.get(&remote).is_some_and(Endpoint::expired) {
+                            endpoints.remove(&remote);
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    /// Binds an endpoint to the channel of a registration echoing the nonce
+    /// of its challenge, registering again to the same channel only
+    /// refreshes it.
+    async fn register(
+        &self,
+        socket: &Arc<UdpSocket>,
+        expired: &mpsc::Sender<SocketAddr>,
+        endpoints: &mut HashMap<SocketAddr, Endpoint>,
+        remote: SocketAddr,
+        register: &[u8],
+    ) -> control::Response {
+        let Register { channel, nonce, .. } = match self.verify(register) {
+            Ok(register) => register,
+            Err(err) => {
+                debug!("udp registration from {} failed: {}", remote, err);
+                return control::Response::Error {
+                    id: None,
+                    message: err.to_string(),
+                };
+            }
+        };
+        if !nonce.is_some_and(|nonce| self.valid_nonce(&nonce, remote, &channel)) {
+            return control::Response::Challenge {
+                nonce: self.nonce(remote, &channel, unix_time()),
+            };
+        }
+
+        match endpoints
+            .get(&remote)
+            .filter(|endpoint| !endpoint.expired())
+        {
+            Some(endpoint) if endpoint.channel != channel => {
+                return control::Response::Error {
+                    id: None,
+                    message: format!("already registered to {}", endpoint.channel),
+                };
+            }
+            Some(endpoint) => {
+                let _ = endpoint.datagrams.try_send(Vec::new());
+            }
+            None => {
+                let idle_timeout = Some(self.config.idle_timeout);
+                let endpoint = self
+                    .spawn(socket, expired, remote, &channel, idle_timeout)
+                    .await;
+                endpoints.insert(remote, endpoint);
+            }
+        }
+
+        let state = self.server.lock().unwrap().find_or_create_channel(&channel);
+        let members = state.lock().await.members;
+        control::Response::Joined {
+            id: None,
+            channel,
+            members,
+        }
+    }
+
+    /// Checks the token of a registration.
+    fn verify(&self, register: &[u8]) -> Result<Register, anyhow::Error> {
+        let register: Register = serde_json::from_slice(register)?;
+        if self.config.auth.secret.is_none() && !self.config.anonymous {
+            anyhow::bail!("registration needs a token");
+        }
+        self.config
+            .auth
+            .verify(register.token.as_deref(), &register.channel)?;
+        Ok(register)
+    }
+
+    /// Nonce of a source and channel, `issued.mac` with the time it was
+    /// issued at in seconds.
+    fn nonce(&self, remote: SocketAddr, channel: &str, issued: u64) -> String {
+        let mac = self
+            .nonce_mac(remote, channel, issued)
+            .finalize()
+            .into_bytes();
+        format!(
+            "{}.{}",
+            issued,
+            URL_SAFE_NO_PAD.encode(&mac[..NONCE_MAC_SIZE])
+        )
+    }
+
+    fn valid_nonce(&self, nonce: &str, remote: SocketAddr, channel: &str) -> bool {
+        let (issued, mac) = match nonce.split_once('.') {
+            Some(nonce) => nonce,
+            None => return false,
+        };
+        let (issued, mac) = match (issued.parse::<u64>(), URL_SAFE_NO_PAD.decode(mac)) {
+            (Ok(issued), Ok(mac)) if mac.len() == NONCE_MAC_SIZE => (issued, mac),
+            _ => return false,
+        };
+        let age = unix_time().checked_sub(issued);
+        age.is_some_and(|age| age <= NONCE_LIFETIME.as_secs())
+            && self
+                .nonce_mac(remote, channel, issued)
+                .verify_truncated_left(&mac)
+                .is_ok()
+    }
+
+    fn nonce_mac(&self, remote: SocketAddr, channel: &str, issued: u64) -> Hmac<Sha256> {
+        let mut mac =
+            Hmac::<Sha256>::new_from_slice(&self.nonce_key).expect("hmac accepts any key");
+        mac.update(format!("{} {} {}", issued, remote, channel).as_bytes());
+        mac
+    }
+
+    /// Starts the session of an endpoint on its own task.
+    async fn spawn(
+        &self,
+        socket: &Arc<UdpSocket>,
+        expired: &mpsc::Sender<SocketAddr>,
+        remote: SocketAddr,
+        channel: &str,
+        idle_timeout: Option<Duration>,
+    ) -> Endpoint {
+        let stats = self.server.lock().unwrap().stats.clone();
+        stats.record_connection();
+        let session = session::Session::new(self.server.clone(), channel, remote).await;
+        info!("udp endpoint {} bound to {:#?}", remote, session.channel());
+
+        let (datagrams, rx) = mpsc::channel(ENDPOINT_QUEUE);
+        let socket = socket.clone();
+        let expired = expired.clone();
+        tokio::spawn(async move {
+            serve(session, socket, rx, idle_timeout).await;
+            info!("udp endpoint {} expired", remote);
+            stats.record_close("udp", stats::Initiator::Local, stats::CLOSE_ABNORMAL);
+            let _ = expired.send(remote).await;
+        });
+        Endpoint {
+            channel: channel.to_string(),
+            datagrams,
+        }
+    }
+}
+
+/// Publishes the datagrams of an endpoint until it expires, empty ones only
+/// keeping it alive.
+async fn serve(
+    mut session: session::Session,
+    socket: Arc<UdpSocket>,
+    mut datagrams: mpsc::Receiver<Vec<u8>>,
+    idle_timeout: Option<Duration>,
+) {
+    let remote = session.remote;
+    let mut last_received = Instant::now();
+    loop {
+        let idle_deadline = idle_timeout.map(|timeout| last_received + timeout);
+        select! {
+            datagram = datagrams.recv().fuse() => {
+                match datagram {
+                    Some(datagram) => {
+                        debug!("received: {:#?}", datagram.len());
+
+                        last_received = Instant::now();
+                        if !datagram.is_empty() {
+                            session.publish(datagram);
+                        }
+                    },
+                    None => break,
+                }
+            },
+            event = session.recv().fuse() => {
+                match event {
+                    Some(session::Event::Message { data, .. }) => {
+                        debug!("sent: {:#?}", data.len());
+
+                        if let Err(err) = socket.send_to(&data, remote).await {
+                            debug!("udp send to {} failed: {}", remote, err);
+                        }
+                    },
+                    Some(session::Event::Control(_)) => {},
+                    None => break,
+                }
+            },
+            _ = sleep_until(idle_deadline).fuse() => break,
+        }
+    }
+    session.close().await;
+}
+
+fn unix_time() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|time| time.as_secs())
+        .unwrap_or_default()
+}
+
+async fn sleep_until(deadline: Option<Instant>) {
+    match deadline {
+        Some(deadline) => time::sleep_until(deadline).await,
+        None => future::pending().await,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn module() -> UdpModule {
+        let server = Arc::new(std::sync::Mutex::new(server::Server::new()));
+        let config = Config {
+            listen: "127.0.0.1:0".parse().unwrap(),
+            endpoints: Vec::new(),
+            idle_timeout: Duration::from_secs(30),
+            auth: auth::Config::default(),
+            anonymous: true,
+        };
+        UdpModule::new(server, config)
+    }
+
+    /// Registration echoing a valid nonce.
+    fn registration(module: &UdpModule, remote: SocketAddr, channel: &str) -> Vec<u8> {
+        let nonce = module.nonce(remote, channel, unix_time());
+        format!(r#"{{"channel": "{}", "nonce": "{}"}}"#, channel, nonce).into_bytes()
+    }
+
+    async fn recv(socket: &UdpSocket) -> Vec<u8> {
+        let mut buffer = vec![0; MAX_DATAGRAM_SIZE];
+        let len = time::timeout(Duration::from_secs(1), socket.recv(&mut buffer))
+            .await
+            .unwrap()
+            .unwrap();
+        buffer.truncate(len);
+        buffer
+    }
+
+    #[test]
+    fn endpoints() {
+        assert_eq!(
+            parse_endpoint("192.0.2.1:5000=demo").unwrap(),
+            ("192.0.2.1:5000".parse().unwrap(), "demo".to_string())
+        );
+        assert!(parse_endpoint("192.0.2.1:5000=").is_err());
+        assert!(parse_endpoint("192.0.2.1:5000").is_err());
+        assert!(parse_endpoint("demo=192.0.2.1:5000").is_err());
+    }
+
+    #[tokio::test]
+    async fn binding() {
+        let module = module();
+        let socket = Arc::new(UdpSocket::bind("127.0.0.1:0").await.unwrap());
+        let client = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        client.connect(socket.local_addr().unwrap()).await.unwrap();
+        let remote = client.local_addr().unwrap();
+        let (expired, _) = mpsc::channel(ENDPOINT_QUEUE);
+        let mut endpoints = HashMap::new();
+
+        // Sources are only bound once they echo the nonce of their challenge
+        let response = module
+            .register(
+                &socket,
+                &expired,
+                &mut endpoints,
+                remote,
+                br#"{"channel": "demo"}"#,
+            )
+            .await;
+        let nonce = match response {
+            control::Response::Challenge { nonce } => nonce,
+            response => panic!("unexpected {:?}", response),
+        };
+        assert!(endpoints.is_empty());
+        let register = format!(r#"{{"channel": "demo", "nonce": "{}"}}"#, nonce);
+        let response = module
+            .register(
+                &socket,
+                &expired,
+                &mut endpoints,
+                remote,
+                register.as_bytes(),
+            )
+            .await;
+        assert!(matches!(response, control::Response::Joined { channel, .. } if channel == "demo"));
+        assert_eq!(endpoints[&remote].channel, "demo");
+
+        // The datagrams of the source are published to the channel, and its
+        // messages are sent back to the source
+        let mut other = session::Session::new(module.server.clone(), "demo", remote).await;
+        endpoints[&remote]
+            .datagrams
+            .try_send(b"hello".to_vec())
+            .unwrap();
+        match time::timeout(Duration::from_secs(1), other.recv()).await {
+            Ok(Some(session::Event::Message { data, .. })) => assert_eq!(data, b"hello"),
+            event => panic!("unexpected {:?}", event),
+        }
+        other.publish(b"world".to_vec());
+        let mut received = recv(&client).await;
+        if received == b"hello" {
+            received = recv(&client).await;
+        }
+        assert_eq!(received, b"world");
+
+        // Registering again only refreshes the binding to the same channel
+        let response = module
+            .register(
+                &socket,
+                &expired,
+                &mut endpoints,
+                remote,
+                register.as_bytes(),
+            )
+            .await;
+        assert!(matches!(response, control::Response::Joined { .. }));
+        let register = registration(&module, remote, "other");
+        let response = module
+            .register(&socket, &expired, &mut endpoints, remote, &register)
+            .await;
+        assert!(matches!(response, control::Response::Error { .. }));
+        assert_eq!(endpoints[&remote].channel, "demo");
+
+        let response = module
+            .register(&socket, &expired, &mut endpoints, remote, b"demo")
+            .await;
+        assert!(matches!(response, control::Response::Error { .. }));
+        assert_eq!(endpoints.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn registration_token() {
+        let mut module = module();
+        module.config.auth = auth::Config::new(Some("secret".to_string()));
+        let socket = Arc::new(UdpSocket::bind("127.0.0.1:0").await.unwrap());
+        let remote = "127.0.0.1:9".parse().unwrap();
+        let (expired, _) = mpsc::channel(ENDPOINT_QUEUE);
+        let mut endpoints = HashMap::new();
+
+        let register = br#"{"channel": "demo", "token": "invalid"}"#;
+        let response = module
+            .register(&socket, &expired, &mut endpoints, remote, register)
+            .await;
+        assert!(matches!(response, control::Response::Error { .. }));
+        assert!(endpoints.is_empty());
+    }
+
+    #[test]
+    fn nonces() {
+        let other = module();
+        let module = module();
+        let remote = "192.0.2.1:5000".parse().unwrap();
+        let now = unix_time();
+        let nonce = module.nonce(remote, "demo", now);
+        assert!(module.valid_nonce(&nonce, remote, "demo"));
+        assert!(!module.valid_nonce(&nonce, "192.0.2.1:5001".parse().unwrap(), "demo"));
+        assert!(!module.valid_nonce(&nonce, remote, "other"));
+        assert!(!module.valid_nonce(&nonce[..nonce.len() - 2], remote, "demo"));
+        assert!(!module.valid_nonce(&nonce.replacen('.', "0.", 1), remote, "demo"));
+        assert!(!module.valid_nonce("", remote, "demo"));
+
+        let issued = now - NONCE_LIFETIME.as_secs() - 1;
+        assert!(!module.valid_nonce(&module.nonce(remote, "demo", issued), remote, "demo"));
+        assert!(!module.valid_nonce(&module.nonce(remote, "demo", now + 60), remote, "demo"));
+        // Nonces are only valid for the module that issued them
+        assert!(!other.valid_nonce(&nonce, remote, "demo"));
+    }
+
+    #[tokio::test]
+    async fn registration_needs_token() {
+        let mut module = module();
+        module.config.anonymous = false;
+        let socket = Arc::new(UdpSocket::bind("127.0.0.1:0").await.unwrap());
+        let remote = "127.0.0.1:9".parse().unwrap();
+        let (expired, _) = mpsc::channel(ENDPOINT_QUEUE);
+        let mut endpoints = HashMap::new();
+
+        let register = registration(&module, remote, "demo");
+        let response = module
+            .register(&socket, &expired, &mut endpoints, remote, &register)
+            .await;
+        assert!(matches!(response, control::Response::Error { .. }));
+        assert!(endpoints.is_empty());
+    }
+}
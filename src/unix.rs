@@ -0,0 +1,460 @@
+use std::ffi::CString;
+use std::io::ErrorKind;
+use std::net::SocketAddr;
+use std::os::unix::fs::{FileTypeExt, PermissionsExt};
+use std::path::PathBuf;
+use std::time::Duration;
+
+use anyhow::Context;
+use async_trait::async_trait;
+use futures_util::select;
+use futures_util::FutureExt;
+use nix::unistd::{getgrouplist, Gid, Uid, User};
+use serde::Deserialize;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWriteExt};
+use tokio::net::unix::{OwnedReadHalf, OwnedWriteHalf, UCred};
+use tokio::net::{UnixListener, UnixStream};
+use tokio::sync::{mpsc, watch};
+use tracing::*;
+
+use crate::control;
+use crate::framing;
+use crate::server;
+use crate::session;
+use crate::stats;
+use crate::transport;
+
+/// Frame types. A frame is its type, the length of its payload as a 32 bits
+/// big endian integer and the payload.
+pub const MESSAGE: u8 = 0x0;
+pub const CONTROL: u8 = 0x1;
+/// Time given to the client to name its channel.
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+/// Largest frame payload accepted.
+const MAX_FRAME_SIZE: usize = 16 * 1024 * 1024;
+/// Frames read ahead of the session.
+const FRAME_QUEUE: usize = 64;
+
+#[derive(Debug, Clone)]
+pub struct Config {
+    pub path: PathBuf,
+    /// Permissions of the socket file
+    pub mode: u32,
+    /// Users allowed to connect, checked with the peer credentials
+    pub uids: Vec<u32>,
+    /// Groups allowed to connect, anyone the socket file lets in when there
+    /// are no users nor groups. The supplementary groups of a peer are the
+    /// ones of its user in the group database, not the ones of its process
+    pub gids: Vec<u32>,
+}
+
+impl Config {
+    async fn allows(&self, cred: &UCred) -> bool {
+        if (self.uids.is_empty() && self.gids.is_empty())
+            || self.uids.contains(&cred.uid())
+            || self.gids.contains(&cred.gid())
+        {
+            return true;
+        }
+        if self.gids.is_empty() {
+            return false;
+        }
+        // The group database may be remote, like LDAP through NSS
+        let (uid, gid) = (cred.uid(), cred.gid());
+        let groups = tokio::task::spawn_blocking(move || groups(uid, gid))
+            .await
+            .unwrap_or_default();
+        groups.iter().any(|gid| self.gids.contains(gid))
+    }
+}
+
+/// Primary and supplementary groups of a user.
+fn groups(uid: u32, gid: u32) -> Vec<u32> {
+    let name = match User::from_uid(Uid::from_raw(uid)) {
+        Ok(Some(user)) => user.name,
+        Ok(None) => return vec![gid],
+        Err(err) => {
+            error!("unix user {} lookup failed: {}", uid, err);
+            return vec![gid];
+        }
+    };
+    let groups = CString::new(name)
+        .map_err(anyhow::Error::from)
+        .and_then(|name| Ok(getgrouplist(&name, Gid::from_raw(gid))?));
+    match groups {
+        Ok(groups) => groups.into_iter().map(Gid::as_raw).collect(),
+        Err(err) => {
+            error!("unix groups of user {} failed: {}", uid, err);
+            vec![gid]
+        }
+    }
+}
+
+/// Names a peer by its credentials in the logs, Unix peers have no address.
+fn peer(cred: &UCred) -> String {
+    match cred.pid() {
+        Some(pid) => format!("uid {} pid {}", cred.uid(), pid),
+        None => format!("uid {}", cred.uid()),
+    }
+}
+
+/// First frame of the client, a control frame.
+#[derive(Deserialize)]
+struct Hello {
+    channel: String,
+}
+
+#[derive(Debug)]
+enum Frame {
+    Message(Vec<u8>),
+    Control(String),
+}
+
+/// Parses the permissions of the socket file, in octal.
+pub fn parse_mode(value: &str) -> Result<u32, anyhow::Error> {
+    Ok(u32::from_str_radix(value, 8)?)
+}
+
+/// Binds the socket with the permissions of the config, replacing the one
+/// left by a previous run.
+pub fn bind(config: &Config) -> Result<UnixListener, anyhow::Error> {
+    if let Ok(metadata) = std::fs::symlink_metadata(&config.path) {
+        if !metadata.file_type().is_socket() {
+            anyhow::bail!("{} exists and is not a socket", config.path.display());
+        }
+        std::fs::remove_file(&config.path)?;
+    }
+    let listener = UnixListener::bind(&config.path)?;
+    std::fs::set_permissions(&config.path, std::fs::Permissions::from_mode(config.mode))?;
+    Ok(listener)
+}
+
+/// Client on the same host, like backend services, over a Unix socket:
+///
+/// - the first frame is a control frame naming the channel,
+///   `{"channel": "name"}`, answered with a `joined` control frame
+/// - message frames are published to the channel, and the messages of the
+///   joined channels are sent as message frames
+/// - control frames carry the control protocol
+pub struct UnixTransport {
+    server: server::ServerPtr,
+    stream: UnixStream,
+    config: Config,
+    closed_tx: watch::Sender<bool>,
+    closed: watch::Receiver<bool>,
+}
+
+impl UnixTransport {
+    pub fn new(server: server::ServerPtr, stream: UnixStream, config: Config) -> Self {
+        let (closed_tx, closed) = watch::channel(false);
+        Self {
+            server,
+            stream,
+            config,
+            closed_tx,
+            closed,
+        }
+    }
+}
+
+#[async_trait]
+impl transport::Transport for UnixTransport {
+    fn close(&self) {
+        let _ = self.closed_tx.send(true);
+    }
+
+    async fn process(self) -> Result<(), anyhow::Error> {
+        let mut closed = self.closed.clone();
+        let cred = match self.stream.peer_cred() {
+            Ok(cred) => cred,
+            Err(err) => {
+                error!("unix peer credentials failed: {}", err);
+                return Ok(());
+            }
+        };
+        let peer = peer(&cred);
+        if !self.config.allows(&cred).await {
+            info!("unix connection from {} gid {} refused", peer, cred.gid());
+            return Ok(());
+        }
+        info!("unix connection established from {}", peer);
+
+        let (mut read, mut write) = self.stream.into_split();
+        let handshake = tokio::time::timeout(HANDSHAKE_TIMEOUT, handshake(&mut read))
+            .await
+            .context("handshake timed out")
+            .and_then(|res| res);
+        let channel_name = match handshake {
+            Ok(channel_name) => channel_name,
+            Err(err) => {
+                info!("unix handshake from {} failed: {}", peer, err);
+                let response = control::Response::Error {
+                    id: None,
+                    message: err.to_string(),
+                };
+                let _ = send_control(&mut write, &response).await;
+                return Ok(());
+            }
+        };
+
+        let stats = self.server.lock().unwrap().stats.clone();
+        stats.record_connection();
+        // Unix peers have no address, they are told apart by their credentials
+        let remote = SocketAddr::from(([0, 0, 0, 0], 0));
+        let mut session =
+            session::Session::with_peer(self.server, &channel_name, remote, peer.clone()).await;
+        info!("unix connection request accepted: {}", session.channel());
+        let members = session.join(&channel_name).await;
+        let joined = control::Response::Joined {
+            id: None,
+            channel: channel_name,
+            members,
+        };
+
+        let (frames_tx, mut frames) = mpsc::channel(FRAME_QUEUE);
+        let reader = tokio::spawn(receive(read, frames_tx));
+        let initiator = match send_control(&mut write, &joined).await {
+            Ok(()) => loop {
+                select! {
+                    _ = closed.changed().fuse() => break stats::Initiator::Local,
+                    frame = frames.recv().fuse() => {
+                        match frame {
+                            Some(Frame::Message(data)) => {
+                                debug!("received: {:#?}", data.len());
+
+                                session.publish(data);
+                            },
+                            Some(Frame::Control(text)) => {
+                                if let Some(response) = session.handle_control(&text).await {
+                                    if send_control(&mut write, &response).await.is_err() {
+                                        break stats::Initiator::Remote;
+                                    }
+                                }
+                            },
+                            None => break stats::Initiator::Remote,
+                        }
+                    },
+                    event = session.recv().fuse() => {
+                        let res = match event {
+                            // Message frames carry no channel, the messages of
+                            // the other joined channels go in envelopes
+                            Some(session::Event::Message { channel, data, .. }) if *channel != *session.channel() => {
+                                send_control(&mut write, &framing::envelope(&channel, &data)).await
+                            },
+                            Some(session::Event::Message { data, .. }) => {
+                                debug!("sent: {:#?}", data.len());
+
+                                send(&mut write, MESSAGE, &data).await
+                            },
+                            Some(session::Event::Control(response)) => {
+                                send_control(&mut write, &response).await
+                            },
+                            None => break stats::Initiator::Local,
+                        };
+                        if res.is_err() {
+                            break stats::Initiator::Remote;
+                        }
+                    }
+                }
+            },
+            Err(_) => stats::Initiator::Remote,
+        };
+        reader.abort();
+
+        info!("unix connection from {} closed by {:?}", peer, initiator);
+        stats.record_close("unix", initiator, stats::CLOSE_ABNORMAL);
+        session.close().await;
+        Ok(())
+    }
+}
+
+/// Waits for the first frame and the channel it names.
+async fn handshake(read: &mut OwnedReadHalf) -> Result<String, anyhow::Error> {
+    let text = match read_frame(read).await?.context("connection closed")? {
+        Frame::Control(text) => text,
+        Frame::Message(_) => anyhow::bail!("expected a control frame"),
+    };
+    let hello: Hello = serde_json::from_str(&text)?;
+    if hello.channel.is_empty() {
+        anyhow::bail!("empty channel");
+    }
+    Ok(hello.channel)
+}
+
+/// Reads the frames of the client ahead of the session, as reading one is
+/// not cancel safe.
+async fn receive(mut read: OwnedReadHalf, frames: mpsc::Sender<Frame>) {
+    loop {
+        match read_frame(&mut read).await {
+            Ok(Some(frame)) => {
+                if frames.send(frame).await.is_err() {
+                    return;
+                }
+            }
+            Ok(None) => return,
+            Err(err) => {
+                debug!("unix connection with invalid frame: {}", err);
+                return;
+            }
+        }
+    }
+}
+
+/// Reads the next frame, None when the connection ends between frames.
+async fn read_frame<R>(read: &mut R) -> Result<Option<Frame>, anyhow::Error>
+where
+    R: AsyncRead + Unpin,
+{
+    let kind = match read.read_u8().await {
+        Ok(kind) => kind,
+        Err(err) if err.kind() == ErrorKind::UnexpectedEof => return Ok(None),
+        Err(err) => return Err(err.into()),
+    };
+    let length = read.read_u32().await? as usize;
+    if length > MAX_FRAME_SIZE {
+        anyhow::bail!("frame too large {}", length);
+    }
+
+    let mut payload = vec![0u8; length];
+    read.read_exact(&mut payload).await?;
+    match kind {
+        MESSAGE => Ok(Some(Frame::Message(payload))),
+        CONTROL => Ok(Some(Frame::Control(String::from_utf8(payload)?))),
+        kind => anyhow::bail!("unknown frame type {}", kind),
+    }
+}
+
+async fn send(write: &mut OwnedWriteHalf, kind: u8, payload: &[u8]) -> std::io::Result<()> {
+    let mut frame = Vec::with_capacity(5 + payload.len());
+    frame.push(kind);
+    frame.extend_from_slice(&(payload.len() as u32).to_be_bytes());
+    frame.extend_from_slice(payload);
+    write.write_all(&frame).await
+}
+
+async fn send_control(
+    write: &mut OwnedWriteHalf,
+    response: &control::Response,
+) -> std::io::Result<()> {
+    send(write, CONTROL, control::encode(response).as_bytes()).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn read(data: &[u8]) -> Result<Option<Frame>, anyhow::Error> {
+        let mut data = data;
+        read_frame(&mut data).await
+    }
+
+    fn frame(kind: u8, length: u32, payload: &[u8]) -> Vec<u8> {
+        let mut frame = vec![kind];
+        frame.extend_from_slice(&length.to_be_bytes());
+        frame.extend_from_slice(payload);
+        frame
+    }
+
+    #[tokio::test]
+    async fn frames() {
+        let data = [
+            frame(MESSAGE, 5, b"hello"),
+            frame(CONTROL, 2, b"{}"),
+            frame(MESSAGE, 0, b""),
+        ]
+        .concat();
+        let mut buf = &data[..];
+        assert!(
+            matches!(read_frame(&mut buf).await, Ok(Some(Frame::Message(data))) if data == b"hello")
+        );
+        assert!(
+            matches!(read_frame(&mut buf).await, Ok(Some(Frame::Control(text))) if text == "{}")
+        );
+        assert!(
+            matches!(read_frame(&mut buf).await, Ok(Some(Frame::Message(data))) if data.is_empty())
+        );
+        assert!(matches!(read_frame(&mut buf).await, Ok(None)));
+    }
+
+    #[tokio::test]
+    async fn invalid_frames() {
+        let largest = frame(MESSAGE, MAX_FRAME_SIZE as u32, &vec![0; MAX_FRAME_SIZE]);
+        assert!(
+            matches!(read(&largest).await, Ok(Some(Frame::Message(data))) if data.len() == MAX_FRAME_SIZE)
+        );
+        assert!(read(&frame(MESSAGE, MAX_FRAME_SIZE as u32 + 1, b""))
+            .await
+            .is_err());
+        assert!(read(&frame(MESSAGE, u32::MAX, b"")).await.is_err());
+
+        // Truncated header and payload
+        assert!(read(&[MESSAGE, 0, 0]).await.is_err());
+        assert!(read(&frame(MESSAGE, 5, b"hel")).await.is_err());
+
+        assert!(read(&frame(0x2, 0, b"")).await.is_err());
+        assert!(read(&frame(CONTROL, 2, &[0xc3, 0x28])).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn handshake_frame() {
+        let (mut client, server) = UnixStream::pair().unwrap();
+        let (mut read, _write) = server.into_split();
+        let hello = frame(CONTROL, 19, br#"{"channel": "demo"}"#);
+        client.write_all(&hello).await.unwrap();
+        assert_eq!(handshake(&mut read).await.unwrap(), "demo");
+
+        client.write_all(&frame(MESSAGE, 2, b"{}")).await.unwrap();
+        assert!(handshake(&mut read).await.is_err());
+        client
+            .write_all(&frame(CONTROL, 14, br#"{"channel": ""}"#))
+            .await
+            .unwrap();
+        assert!(handshake(&mut read).await.is_err());
+        drop(client);
+        assert!(handshake(&mut read).await.is_err());
+    }
+
+    #[test]
+    fn modes() {
+        assert_eq!(parse_mode("660").unwrap(), 0o660);
+        assert!(parse_mode("rw").is_err());
+        assert!(parse_mode("9").is_err());
+    }
+
+    fn config(uids: &[u32], gids: &[u32]) -> Config {
+        Config {
+            path: PathBuf::new(),
+            mode: 0o660,
+            uids: uids.to_vec(),
+            gids: gids.to_vec(),
+        }
+    }
+
+    #[tokio::test]
+    async fn allowed_peers() {
+        let (stream, _peer) = UnixStream::pair().unwrap();
+        let cred = stream.peer_cred().unwrap();
+        let (uid, gid) = (cred.uid(), cred.gid());
+
+        assert!(config(&[], &[]).allows(&cred).await);
+        assert!(config(&[uid], &[]).allows(&cred).await);
+        assert!(config(&[uid.wrapping_add(1), uid], &[]).allows(&cred).await);
+        assert!(config(&[], &[gid]).allows(&cred).await);
+        assert!(!config(&[uid.wrapping_add(1)], &[]).allows(&cred).await);
+        assert!(!config(&[], &[gid.wrapping_add(1)]).allows(&cred).await);
+
+        // Supplementary groups of the user are allowed like its primary one
+        let all = groups(uid, gid);
+        assert!(all.contains(&gid));
+        for group in all {
+            assert!(config(&[], &[group]).allows(&cred).await);
+        }
+        // Users missing from the database only have their primary group
+        assert_eq!(groups(u32::MAX - 1, 4242), [4242]);
+
+        assert_eq!(
+            peer(&cred),
+            format!("uid {} pid {}", uid, std::process::id())
+        );
+    }
+}
@@ -1,3 +1,5 @@
+use bytes::{Buf, BufMut, BytesMut};
+
 pub fn parse_channel(path: &str) -> Result<String, anyhow::Error> {
     let tokens: Vec<&str> = path.split('/').filter(|s| !s.is_empty()).collect();
     if (tokens.len() != 2) || (tokens[0] != "channels") {
@@ -5,3 +7,28 @@ pub fn parse_channel(path: &str) -> Result<String, anyhow::Error> {
     }
     Ok(tokens[1].to_owned())
 }
+
+/// Reads a QUIC variable-length integer (RFC 9000 16), None when the buffer
+/// is too short.
+pub fn read_varint(buf: &mut &[u8]) -> Option<u64> {
+    let first = *buf.first()?;
+    let size = 1 << (first >> 6);
+    if buf.len() < size {
+        return None;
+    }
+    let mut value = (first & 0x3f) as u64;
+    for byte in &buf[1..size] {
+        value = (value << 8) | *byte as u64;
+    }
+    buf.advance(size);
+    Some(value)
+}
+
+pub fn write_varint(buf: &mut BytesMut, value: u64) {
+    match value {
+        0..=0x3f => buf.put_u8(value as u8),
+        0x40..=0x3fff => buf.put_u16(0x4000 | value as u16),
+        0x4000..=0x3fff_ffff => buf.put_u32(0x8000_0000 | value as u32),
+        _ => buf.put_u64(0xc000_0000_0000_0000 | value),
+    }
+}
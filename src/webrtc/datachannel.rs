@@ -0,0 +1,125 @@
+use std::net::SocketAddr;
+use std::sync::Arc;
+
+use bytes::Bytes;
+use futures_util::select;
+use futures_util::FutureExt;
+use tokio::sync::watch;
+use tracing::*;
+use webrtc_data::data_channel::{self, DataChannel};
+use webrtc_sctp::association::Association;
+
+use crate::control;
+use crate::framing;
+use crate::server;
+use crate::session;
+
+use super::sdp;
+
+/// Accepts the DataChannels opened by the peer until the association is
+/// closed, binding each of them to the prism channel named by its label.
+/// Channels without a label are bound to the channel of the transport.
+pub async fn serve(
+    server: server::ServerPtr,
+    association: Arc<Association>,
+    channel: String,
+    remote: SocketAddr,
+    mut closed: watch::Receiver<bool>,
+) {
+    let existing: &[DataChannel] = &[];
+    loop {
+        let data_channel = select! {
+            res = DataChannel::accept(&association, data_channel::Config::default(), existing).fuse() => {
+                match res {
+                    Ok(data_channel) => Arc::new(data_channel),
+                    Err(err) => {
+                        debug!("webrtc datachannels finished: {}", err);
+                        break;
+                    }
+                }
+            },
+            _ = closed.changed().fuse() => break,
+        };
+
+        let name = match data_channel.config.label.as_str() {
+            "" => channel.clone(),
+            label => label.to_string(),
+        };
+        info!(
+            "webrtc datachannel {} opened from {} for {} ({:?})",
+            data_channel.stream_identifier(),
+            remote,
+            name,
+            data_channel.config.channel_type
+        );
+        tokio::spawn(bind(server.clone(), data_channel, name, remote));
+    }
+}
+
+/// Forwards binary messages in both directions between a DataChannel and a
+/// prism channel, string messages carry the control protocol.
+async fn bind(
+    server: server::ServerPtr,
+    data_channel: Arc<DataChannel>,
+    name: String,
+    remote: SocketAddr,
+) {
+    let mut session = session::Session::new(server, &name, remote).await;
+    let mut buf = vec![0u8; sdp::MAX_MESSAGE_SIZE as usize];
+    loop {
+        select! {
+            res = data_channel.read_data_channel(&mut buf).fuse() => {
+                match res {
+                    Ok((0, _)) => break,
+                    Ok((n, true)) => {
+                        let text = String::from_utf8_lossy(&buf[..n]).into_owned();
+                        if let Some(response) = session.handle_control(&text).await {
+                            send_control(&data_channel, &response).await;
+                        }
+                    }
+                    Ok((n, false)) => {
+                        debug!("received: {:#?}", n);
+                        session.publish(buf[..n].to_vec());
+                    }
+                    Err(err) => {
+                        debug!("webrtc datachannel read failed: {}", err);
+                        break;
+                    }
+                }
+            },
+            event = session.recv().fuse() => {
+                match event {
+                    // Binary messages carry no channel, the messages of the
+                    // other joined channels go in envelopes
+                    Some(session::Event::Message { channel, data, .. }) if *channel != *session.channel() => {
+                        send_control(&data_channel, &framing::envelope(&channel, &data)).await;
+                    },
+                    Some(session::Event::Message { data, .. }) => {
+                        debug!("sent: {:#?}", data.len());
+                        if let Err(err) = data_channel.write_data_channel(&Bytes::from(data), false).await {
+                            debug!("webrtc datachannel write failed: {}", err);
+                            break;
+                        }
+                    },
+                    Some(session::Event::Control(response)) => {
+                        send_control(&data_channel, &response).await;
+                    },
+                    None => break,
+                }
+            }
+        }
+    }
+
+    info!(
+        "webrtc datachannel {} from {} closed",
+        data_channel.stream_identifier(),
+        remote
+    );
+    let _ = data_channel.close().await;
+    session.close().await;
+}
+
+async fn send_control(data_channel: &DataChannel, response: &control::Response) {
+    let text = Bytes::from(control::encode(response));
+    let _ = data_channel.write_data_channel(&text, true).await;
+}
@@ -0,0 +1,359 @@
+use std::sync::{Arc, Weak};
+use std::time::{Duration, Instant};
+
+use futures_util::select;
+use futures_util::FutureExt;
+use rtcp::payload_feedbacks::full_intra_request::FullIntraRequest;
+use rtcp::payload_feedbacks::picture_loss_indication::PictureLossIndication;
+use rtcp::receiver_report::ReceiverReport;
+use rtcp::sender_report::SenderReport;
+use rtcp::transport_feedbacks::transport_layer_nack::TransportLayerNack;
+use tokio::sync::broadcast::{self, error::RecvError};
+use tokio::sync::watch;
+use tracing::*;
+use webrtc_srtp::session::Session;
+
+use super::router::{Kind, Router, Track};
+use super::simulcast::{is_keyframe, Selector};
+use super::{sdp, RECEIVE_MTU};
+
+/// Interval the layers are chosen again at, as their bitrates change
+const SELECT_INTERVAL: Duration = Duration::from_secs(1);
+/// Time a first track is waited for a keyframe before forwarding it anyway
+const KEYFRAME_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Media section of a subscriber the tracks are forwarded to.
+pub struct Section {
+    pub kind: Kind,
+    pub codec: sdp::Codec,
+    pub ssrc: u32,
+    /// Simulcast layer asked for by the subscriber, automatic when `None`
+    pub layer: watch::Receiver<Option<String>>,
+}
+
+/// Track forwarded to a section, or switched to on its next keyframe.
+struct Forwarded {
+    // Only the router and the publisher keep the track, the packets are
+    // closed once it is removed
+    track: Weak<Track>,
+    id: u32,
+    packets: broadcast::Receiver<rtp::packet::Packet>,
+    since: Instant,
+}
+
+impl Forwarded {
+    fn new(track: &Arc<Track>) -> Self {
+        Self {
+            track: Arc::downgrade(track),
+            id: track.id,
+            packets: track.packets.subscribe(),
+            since: Instant::now(),
+        }
+    }
+}
+
+enum Event {
+    Current(Result<rtp::packet::Packet, RecvError>),
+    Next(Result<rtp::packet::Packet, RecvError>),
+    Feedback(usize),
+    Changed,
+    Layer,
+    Tick,
+}
+
+/// Forwards the tracks of a kind published in a channel to a media section
+/// of a subscriber, one after the other, until the transport is closed,
+/// answering the RTCP feedback of the subscriber about them. The layer of a
+/// simulcast source is chosen by the subscriber or by its losses, and only
+/// switched on a keyframe.
+pub async fn forward(
+    session: Arc<Session>,
+    rtcp: Arc<Session>,
+    router: Arc<Router>,
+    mut section: Section,
+    mut closed: watch::Receiver<bool>,
+) {
+    let (kind, ssrc) = (section.kind, section.ssrc);
+    let mut changes = router.subscribe();
+    let mut rewriter = Rewriter::new(ssrc, section.codec.payload_type);
+    let mut selector = Selector::default();
+    selector.prefer(section.layer.borrow_and_update().clone());
+    let feedback = rtcp.open(ssrc).await;
+    let mut buf = vec![0u8; RECEIVE_MTU];
+    let mut ticks = tokio::time::interval(SELECT_INTERVAL);
+    let mut current: Option<Forwarded> = None;
+    let mut next: Option<Forwarded> = None;
+    let mut layers = Vec::new();
+    let mut reselect = true;
+    loop {
+        if reselect {
+            reselect = false;
+            changes.borrow_and_update();
+            let sources = router.layers(kind, &section.codec.name);
+            let target = sources.get(selector.select(&sources));
+            let current_id = current.as_ref().map(|current| current.id);
+            let next_id = next.as_ref().map(|next| next.id);
+            match target {
+                Some(track) if Some(track.id) == current_id => next = None,
+                Some(track) if Some(track.id) == next_id => {}
+                Some(track) => {
+                    debug!("webrtc switching {} to track {}", ssrc, track.id);
+                    next = Some(Forwarded::new(track));
+                    if kind == Kind::Video {
+                        track.request_keyframe();
+                    }
+                }
+                None => next = None,
+            }
+            layers = sources.iter().map(|track| track.id).collect::<Vec<_>>();
+        }
+
+        let event = select! {
+            res = receive(&mut current).fuse() => Event::Current(res),
+            res = receive(&mut next).fuse() => Event::Next(res),
+            res = feedback.read(&mut buf).fuse() => match res {
+                Ok(n) => Event::Feedback(n),
+                Err(err) => {
+                    debug!("webrtc rtcp stream {} finished: {}", ssrc, err);
+                    return;
+                }
+            },
+            res = changes.changed().fuse() => match res {
+                Ok(()) => Event::Changed,
+                Err(_) => return,
+            },
+            res = section.layer.changed().fuse() => match res {
+                Ok(()) => Event::Layer,
+                Err(_) => return,
+            },
+            _ = ticks.tick().fuse() => Event::Tick,
+            _ = closed.changed().fuse() => return,
+        };
+
+        let mut packet = match event {
+            Event::Current(Ok(packet)) => packet,
+            Event::Next(Ok(packet)) => {
+                // Without any track forwarded yet, a publisher not answering
+                // keyframe requests is switched to anyway
+                let pending = next.as_ref().map(|next| next.since.elapsed());
+                if !is_keyframe(&section.codec.name, &packet.payload)
+                    && (current.is_some() || pending < Some(KEYFRAME_TIMEOUT))
+                {
+                    continue;
+                }
+                current = next.take();
+                rewriter.switch();
+                packet
+            }
+            Event::Current(Err(RecvError::Lagged(n))) => {
+                debug!("webrtc forwarding to {} lagged {} packets", ssrc, n);
+                continue;
+            }
+            Event::Next(Err(RecvError::Lagged(_))) => continue,
+            Event::Current(Err(RecvError::Closed)) => {
+                current = None;
+                reselect = true;
+                continue;
+            }
+            Event::Next(Err(RecvError::Closed)) => {
+                next = None;
+                reselect = true;
+                continue;
+            }
+            Event::Feedback(n) => {
+                let track = current
+                    .as_ref()
+                    .map_or_else(Weak::new, |current| current.track.clone());
+                let loss = handle_feedback(&session, &track, &rewriter, &buf[..n]).await;
+                let position = current
+                    .as_ref()
+                    .and_then(|current| layers.iter().position(|id| *id == current.id));
+                if let (Some(loss), Some(position)) = (loss, position) {
+                    if layers.len() > 1 {
+                        selector.report_loss(position, loss);
+                        reselect = true;
+                    }
+                }
+                continue;
+            }
+            Event::Changed => {
+                reselect = true;
+                continue;
+            }
+            Event::Layer => {
+                selector.prefer(section.layer.borrow_and_update().clone());
+                reselect = true;
+                continue;
+            }
+            Event::Tick => {
+                // Layers are ordered by bitrate until measured, and keyframe
+                // requests can be lost
+                if let Some(track) = next.as_ref().and_then(|next| next.track.upgrade()) {
+                    if kind == Kind::Video {
+                        track.request_keyframe();
+                    }
+                }
+                reselect = layers.len() > 1;
+                continue;
+            }
+        };
+
+        rewriter.rewrite(&mut packet);
+        if let Err(err) = session.write_rtp(&packet).await {
+            debug!("webrtc rtp write failed: {}", err);
+            return;
+        }
+    }
+}
+
+/// Next packet of a track, never ready without one.
+async fn receive(forwarded: &mut Option<Forwarded>) -> Result<rtp::packet::Packet, RecvError> {
+    match forwarded {
+        Some(forwarded) => forwarded.packets.recv().await,
+        None => std::future::pending().await,
+    }
+}
+
+/// Requests a keyframe from the publisher on PLI and FIR, retransmits the
+/// packets on NACK and passes the losses of the receiver reports on,
+/// returning the highest one.
+async fn handle_feedback(
+    session: &Session,
+    track: &Weak<Track>,
+    rewriter: &Rewriter,
+    data: &[u8],
+) -> Option<u8> {
+    let packets = match rtcp::packet::unmarshal(&mut &data[..]) {
+        Ok(packets) => packets,
+        Err(err) => {
+            debug!("webrtc invalid rtcp packet: {}", err);
+            return None;
+        }
+    };
+    let track = track.upgrade()?;
+
+    let mut loss = None;
+
+    let ssrc = rewriter.ssrc;
+    for packet in &packets {
+        let packet = packet.as_any();
+        if let Some(pli) = packet.downcast_ref::<PictureLossIndication>() {
+            if pli.media_ssrc == ssrc {
+                track.request_keyframe();
+            }
+        } else if let Some(fir) = packet.downcast_ref::<FullIntraRequest>() {
+            if fir.fir.iter().any(|entry| entry.ssrc == ssrc) {
+                track.request_keyframe();
+            }
+        } else if let Some(nack) = packet.downcast_ref::<TransportLayerNack>() {
+            if nack.media_ssrc != ssrc {
+                continue;
+            }
+            for sequence_number in nack.nacks.iter().flat_map(|pair| pair.packet_list()) {
+                let packet = rewriter
+                    .original(sequence_number)
+                    .and_then(|original| track.retransmission(original));
+                if let Some(mut packet) = packet {
+                    rewriter.apply(&mut packet);
+                    if let Err(err) = session.write_rtp(&packet).await {
+                        debug!("webrtc rtp retransmission failed: {}", err);
+                    }
+                }
+            }
+        } else {
+            let reports = match (
+                packet.downcast_ref::<ReceiverReport>(),
+                packet.downcast_ref::<SenderReport>(),
+            ) {
+                (Some(report), _) => &report.reports,
+                (_, Some(report)) => &report.reports,
+                _ => continue,
+            };
+            for report in reports.iter().filter(|report| report.ssrc == ssrc) {
+                track.report_loss(report.fraction_lost);
+                loss = loss.max(Some(report.fraction_lost));
+            }
+        }
+    }
+    loss
+}
+
+/// Maps the packets of the forwarded tracks to the source negotiated with
+/// the subscriber, keeping sequence numbers and timestamps continuous when
+/// the track changes.
+struct Rewriter {
+    ssrc: u32,
+    payload_type: u8,
+    /// Next sequence number sent
+    sequence_number: u16,
+    /// Last timestamp sent
+    timestamp: u32,
+    /// Offsets to the sequence numbers and timestamps of the current track
+    offsets: Option<(u16, u32)>,
+    /// First sequence number sent for the current track
+    start: u16,
+}
+
+impl Rewriter {
+    fn new(ssrc: u32, payload_type: u8) -> Self {
+        Self {
+            ssrc,
+            payload_type,
+            sequence_number: rand::random(),
+            timestamp: rand::random(),
+            offsets: None,
+            start: 0,
+        }
+    }
+
+    fn switch(&mut self) {
+        self.offsets = None;
+    }
+
+    fn rewrite(&mut self, packet: &mut rtp::packet::Packet) {
+        if self.offsets.is_none() {
+            self.start = self.sequence_number;
+            self.offsets = Some((
+                self.sequence_number
+                    .wrapping_sub(packet.header.sequence_number),
+                self.timestamp
+                    .wrapping_add(1)
+                    .wrapping_sub(packet.header.timestamp),
+            ));
+        }
+        self.apply(packet);
+
+        // Reordered packets do not move the source backwards
+        let header = &packet.header;
+        if header.sequence_number.wrapping_sub(self.sequence_number) < 0x8000 {
+            self.sequence_number = header.sequence_number.wrapping_add(1);
+            self.timestamp = header.timestamp;
+        }
+    }
+
+    /// Sequence number in the current track of a packet sent, for the ones
+    /// sent since the track was switched to.
+    fn original(&self, sequence_number: u16) -> Option<u16> {
+        let (sequence_offset, _) = self.offsets?;
+        let sent = sequence_number.wrapping_sub(self.start)
+            < self.sequence_number.wrapping_sub(self.start);
+        sent.then(|| sequence_number.wrapping_sub(sequence_offset))
+    }
+
+    /// Maps a packet of the current track, without moving the source.
+    fn apply(&self, packet: &mut rtp::packet::Packet) {
+        let (sequence_offset, timestamp_offset) = match self.offsets {
+            Some(offsets) => offsets,
+            None => return,
+        };
+        let header = &mut packet.header;
+        header.ssrc = self.ssrc;
+        header.payload_type = self.payload_type;
+        header.sequence_number = header.sequence_number.wrapping_add(sequence_offset);
+        header.timestamp = header.timestamp.wrapping_add(timestamp_offset);
+        // Extensions were negotiated with the publisher, not with the subscriber
+        header.extension = false;
+        header.extension_profile = 0;
+        header.extensions.clear();
+    }
+}
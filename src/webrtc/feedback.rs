@@ -0,0 +1,243 @@
+use std::time::{Duration, Instant};
+
+use rtcp::reception_report::ReceptionReport;
+
+/// Interval of the receiver reports sent to the publishers.
+pub const REPORT_INTERVAL: Duration = Duration::from_secs(1);
+/// Packets kept for retransmission, a power of two so that the history
+/// wraps with the sequence numbers
+const HISTORY_SIZE: usize = 1024;
+/// Longest gap of sequence numbers retransmissions are requested for, the
+/// publisher restarted or reordered its stream beyond it
+const MAX_NACKED: u16 = 64;
+
+/// Last packets of a track, answering the NACKs of the subscribers.
+pub struct History {
+    packets: Vec<Option<rtp::packet::Packet>>,
+}
+
+impl Default for History {
+    fn default() -> Self {
+        Self {
+            packets: vec![None; HISTORY_SIZE],
+        }
+    }
+}
+
+impl History {
+    pub fn push(&mut self, packet: &rtp::packet::Packet) {
+        let index = packet.header.sequence_number as usize % HISTORY_SIZE;
+        self.packets[index] = Some(packet.clone());
+    }
+
+    pub fn get(&self, sequence_number: u16) -> Option<&rtp::packet::Packet> {
+        self.packets[sequence_number as usize % HISTORY_SIZE]
+            .as_ref()
+            .filter(|packet| packet.header.sequence_number == sequence_number)
+    }
+}
+
+/// Reception statistics of a published stream, reported to its publisher
+/// instead of the reports of the subscribers (RFC 3550 6.4.2).
+pub struct Reception {
+    clock_rate: u32,
+    started: Instant,
+    /// First and highest sequence numbers received, extended with the cycles
+    base: u32,
+    max: Option<u32>,
+    received: u32,
+    expected_prior: u32,
+    received_prior: u32,
+    /// Interarrival jitter in timestamp units
+    jitter: f64,
+    transit: Option<u32>,
+    /// Middle bits of the NTP time of the last sender report, and when it
+    /// was received
+    last_sender_report: Option<(u32, Instant)>,
+}
+
+impl Reception {
+    pub fn new(clock_rate: u32) -> Self {
+        Self {
+            clock_rate,
+            started: Instant::now(),
+            base: 0,
+            max: None,
+            received: 0,
+            expected_prior: 0,
+            received_prior: 0,
+            jitter: 0.0,
+            transit: None,
+            last_sender_report: None,
+        }
+    }
+
+    /// Accounts a received packet, returning the sequence numbers skipped
+    /// right before it.
+    pub fn update(&mut self, header: &rtp::header::Header, now: Instant) -> Vec<u16> {
+        self.received = self.received.wrapping_add(1);
+
+        let arrival =
+            (now.duration_since(self.started).as_secs_f64() * self.clock_rate as f64) as u32;
+        let transit = arrival.wrapping_sub(header.timestamp);
+        if let Some(previous) = self.transit.replace(transit) {
+            let delta = (transit.wrapping_sub(previous) as i32).unsigned_abs() as f64;
+            self.jitter += (delta - self.jitter) / 16.0;
+        }
+
+        let max = match self.max {
+            Some(max) => max,
+            None => {
+                self.base = header.sequence_number as u32;
+                self.max = Some(self.base);
+                return Vec::new();
+            }
+        };
+        let delta = header.sequence_number.wrapping_sub(max as u16);
+        if delta == 0 || delta >= 0x8000 {
+            // Duplicated, reordered or retransmitted
+            return Vec::new();
+        }
+        self.max = Some(max.wrapping_add(delta as u32));
+        if delta > MAX_NACKED {
+            return Vec::new();
+        }
+        (1..delta).map(|i| (max as u16).wrapping_add(i)).collect()
+    }
+
+    pub fn sender_report(&mut self, ntp_time: u64, now: Instant) {
+        self.last_sender_report = Some(((ntp_time >> 16) as u32, now));
+    }
+
+    /// Report of the packets received since the previous one, losing at
+    /// least `fraction_lost` so that the publisher adapts to the worst
+    /// subscriber.
+    pub fn report(
+        &mut self,
+        ssrc: u32,
+        fraction_lost: u8,
+        now: Instant,
+    ) -> Option<ReceptionReport> {
+        let max = self.max?;
+        let expected = max.wrapping_sub(self.base).wrapping_add(1);
+        let expected_interval = expected.wrapping_sub(self.expected_prior);
+        let received_interval = self.received.wrapping_sub(self.received_prior);
+        self.expected_prior = expected;
+        self.received_prior = self.received;
+
+        let lost_interval = expected_interval.saturating_sub(received_interval);
+        let fraction = match expected_interval {
+            0 => 0,
+            expected_interval => ((lost_interval as u64) << 8) / expected_interval as u64,
+        };
+        let (last_sender_report, delay) = match self.last_sender_report {
+            Some((ntp_time, at)) => (
+                ntp_time,
+                (now.duration_since(at).as_secs_f64() * 65536.0) as u32,
+            ),
+            None => (0, 0),
+        };
+
+        Some(ReceptionReport {
+            ssrc,
+            fraction_lost: (fraction.min(255) as u8).max(fraction_lost),
+            total_lost: expected.saturating_sub(self.received).min(0x7fffff),
+            last_sequence_number: max,
+            jitter: self.jitter as u32,
+            last_sender_report,
+            delay,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn header(sequence_number: u16) -> rtp::header::Header {
+        rtp::header::Header {
+            sequence_number,
+            timestamp: sequence_number as u32 * 3000,
+            ..Default::default()
+        }
+    }
+
+    fn receive(reception: &mut Reception, sequence_numbers: &[u16]) -> Vec<u16> {
+        let now = Instant::now();
+        sequence_numbers
+            .iter()
+            .flat_map(|sequence_number| reception.update(&header(*sequence_number), now))
+            .collect()
+    }
+
+    #[test]
+    fn sequence_wrap() {
+        let mut reception = Reception::new(90000);
+        assert!(receive(&mut reception, &[65534, 65535, 0, 1]).is_empty());
+        let report = reception.report(1, 0, Instant::now()).unwrap();
+        assert_eq!(report.last_sequence_number, 0x1_0001);
+        assert_eq!(report.total_lost, 0);
+        assert_eq!(report.fraction_lost, 0);
+
+        // Skipped across the wrap
+        assert_eq!(receive(&mut reception, &[5]), [2, 3, 4]);
+        let mut reception = Reception::new(90000);
+        assert_eq!(receive(&mut reception, &[65534, 1]), [65535, 0]);
+        let report = reception.report(1, 0, Instant::now()).unwrap();
+        assert_eq!(report.last_sequence_number, 0x1_0001);
+        assert_eq!(report.total_lost, 2);
+        assert_eq!(report.fraction_lost, 128);
+    }
+
+    #[test]
+    fn reordering() {
+        let mut reception = Reception::new(90000);
+        assert_eq!(receive(&mut reception, &[10, 12]), [11]);
+        // Late and duplicated packets are neither lost nor the highest
+        assert!(receive(&mut reception, &[11, 12, 9]).is_empty());
+        assert!(receive(&mut reception, &[13]).is_empty());
+        let report = reception.report(1, 0, Instant::now()).unwrap();
+        assert_eq!(report.last_sequence_number, 13);
+        assert_eq!(report.total_lost, 0);
+        assert_eq!(report.fraction_lost, 0);
+    }
+
+    #[test]
+    fn report_intervals() {
+        let mut reception = Reception::new(90000);
+        assert!(reception.report(1, 0, Instant::now()).is_none());
+        receive(&mut reception, &[0, 1, 2, 3]);
+        let report = reception.report(1, 0, Instant::now()).unwrap();
+        assert_eq!(report.fraction_lost, 0);
+
+        // Only the packets since the previous report count, at least the
+        // loss of the subscribers is reported
+        receive(&mut reception, &[5, 6, 7]);
+        let report = reception.report(1, 0, Instant::now()).unwrap();
+        assert_eq!(report.fraction_lost, 64);
+        assert_eq!(report.total_lost, 1);
+        receive(&mut reception, &[8]);
+        let report = reception.report(1, 100, Instant::now()).unwrap();
+        assert_eq!(report.fraction_lost, 100);
+    }
+
+    #[test]
+    fn long_gaps_not_nacked() {
+        let mut reception = Reception::new(90000);
+        assert!(receive(&mut reception, &[0, 1000]).is_empty());
+        assert_eq!(receive(&mut reception, &[1002]), [1001]);
+    }
+
+    #[test]
+    fn history() {
+        let mut history = History::default();
+        let packet = rtp::packet::Packet {
+            header: header(7),
+            ..Default::default()
+        };
+        history.push(&packet);
+        assert_eq!(history.get(7), Some(&packet));
+        assert!(history.get(7 + HISTORY_SIZE as u16).is_none());
+        assert!(history.get(8).is_none());
+    }
+}
@@ -0,0 +1,74 @@
+use std::net::{IpAddr, SocketAddr};
+
+/// Addresses the candidates of a socket bound to `listen` are reachable on,
+/// of the families the socket serves: the public ones when given, otherwise
+/// the ones of the local interfaces.
+pub fn host_addresses(listen: SocketAddr, public_ips: &[String]) -> Vec<IpAddr> {
+    if !public_ips.is_empty() {
+        return public_ips
+            .iter()
+            .filter_map(|ip| ip.parse::<IpAddr>().ok())
+            .filter(|ip| listen.is_ipv6() || ip.is_ipv4())
+            .collect();
+    }
+    if !listen.ip().is_unspecified() {
+        return vec![listen.ip()];
+    }
+    // The interfaces listed by webrtc-util have mangled IPv6 addresses
+    let interfaces = match if_addrs::get_if_addrs() {
+        Ok(interfaces) => interfaces,
+        Err(_) => return Vec::new(),
+    };
+    let mut addresses: Vec<IpAddr> = interfaces
+        .into_iter()
+        .map(|interface| interface.ip())
+        .filter(|ip| reachable(*ip) && (listen.is_ipv6() || ip.is_ipv4()))
+        .collect();
+    addresses.sort();
+    addresses.dedup();
+    addresses
+}
+
+/// Whether remote peers may reach an address, leaving out the loopback and
+/// IPv6 link local ones that need a scope.
+fn reachable(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => !ip.is_loopback() && !ip.is_unspecified(),
+        IpAddr::V6(ip) => {
+            !ip.is_loopback() && !ip.is_unspecified() && (ip.segments()[0] & 0xffc0) != 0xfe80
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn addresses() {
+        let public_ips = ["203.0.113.1".to_string(), "2001:db8::1".to_string()];
+        assert_eq!(
+            host_addresses("[::]:4435".parse().unwrap(), &public_ips),
+            [
+                "203.0.113.1".parse::<IpAddr>().unwrap(),
+                "2001:db8::1".parse().unwrap()
+            ]
+        );
+        assert_eq!(
+            host_addresses("0.0.0.0:4435".parse().unwrap(), &public_ips),
+            ["203.0.113.1".parse::<IpAddr>().unwrap()]
+        );
+        assert_eq!(
+            host_addresses("192.0.2.1:4435".parse().unwrap(), &[]),
+            ["192.0.2.1".parse::<IpAddr>().unwrap()]
+        );
+        assert!(host_addresses("0.0.0.0:4435".parse().unwrap(), &[])
+            .iter()
+            .all(|ip| ip.is_ipv4() && !ip.is_loopback()));
+
+        assert!(reachable("2001:db8::1".parse().unwrap()));
+        assert!(!reachable("fe80::1".parse().unwrap()));
+        assert!(!reachable("::1".parse().unwrap()));
+        assert!(!reachable("127.0.0.1".parse().unwrap()));
+    }
+}
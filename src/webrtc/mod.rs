@@ -1,45 +1,154 @@
+use std::collections::HashMap;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::sync::Arc;
-use tokio::sync::Mutex;
+use std::time::{Duration, Instant};
+use tokio::sync::{mpsc, watch, Mutex};
 
 use async_trait::async_trait;
 
+use futures_util::select;
+use futures_util::FutureExt;
+use rtcp::payload_feedbacks::picture_loss_indication::PictureLossIndication;
+use rtcp::receiver_report::ReceiverReport;
+use rtcp::sender_report::SenderReport;
+use rtcp::transport_feedbacks::transport_layer_nack::{
+    nack_pairs_from_sequence_numbers, TransportLayerNack,
+};
+use sha2::{Digest, Sha256};
 use tokio::net::UdpSocket;
 use tracing::*;
+use webrtc_dtls::config::{ClientAuthType, ExtendedMasterSecretType};
+use webrtc_dtls::conn::DTLSConn;
+use webrtc_dtls::crypto::Certificate;
+use webrtc_dtls::extension::extension_use_srtp::SrtpProtectionProfile;
 use webrtc_ice::{
     agent::{agent_config::AgentConfig, Agent},
+    candidate::{candidate_base::unmarshal_candidate, Candidate, CandidateType},
     network_type::NetworkType,
     state::ConnectionState,
     udp_mux::{UDPMuxDefault, UDPMuxParams},
     udp_network::UDPNetwork,
 };
+use webrtc_sctp::association::{self, Association};
+use webrtc_srtp::protection_profile::ProtectionProfile;
+use webrtc_srtp::session::Session;
+use webrtc_srtp::stream::Stream;
+use webrtc_util::{Conn, Unmarshal};
 
-use crate::transport;
+use crate::transport::{self, Transport};
 use crate::{module, server};
 
+pub mod datachannel;
+pub mod egress;
+pub mod feedback;
+pub mod interfaces;
+pub mod mux;
+pub mod router;
 pub mod rpc;
+pub mod sdp;
+pub mod simulcast;
+pub mod tcp;
+pub mod transmux;
+pub mod turn;
+
+const GATHER_TIMEOUT: Duration = Duration::from_secs(5);
+const RECEIVE_MTU: usize = 1500;
+/// Longest wait for the tracks of a channel before answering its first
+/// subscriber.
+const TRACK_DISCOVERY: Duration = Duration::from_secs(1);
+const AUDIO_CODECS: &[&str] = &["opus"];
+const VIDEO_CODECS: &[&str] = &["H264", "VP8", "VP9", "AV1"];
+
+static NEXT_TRANSPORT_ID: AtomicU64 = AtomicU64::new(1);
+
+#[derive(Clone)]
+pub struct Config {
+    pub listen: SocketAddr,
+    /// ICE-TCP is disabled without it
+    pub tcp_listen: Option<SocketAddr>,
+    /// Addresses announced instead of the local ones behind a 1:1 NAT
+    pub public_ips: Vec<IpAddr>,
+    pub turn: Option<turn::Config>,
+}
 
 pub struct WebRtcModule {
     name: String,
     server: Arc<std::sync::Mutex<server::Server>>,
+    config: Config,
+}
+
+/// State shared by all the transports of the module.
+struct Context {
+    server: server::ServerPtr,
+    udp_mux: Arc<UDPMuxDefault>,
+    /// Local address of the UDP mux socket
+    udp_listen: SocketAddr,
+    tcp: Option<Arc<tcp::Listener>>,
+    public_ips: Vec<String>,
+    certificate: Certificate,
+    /// SHA-256 fingerprint of the certificate, announced in the answers
+    fingerprint: String,
+    transports: std::sync::Mutex<HashMap<u64, Handle>>,
+    routers: std::sync::Mutex<HashMap<String, Arc<router::Router>>>,
+}
+
+/// What the commands need to control a running transport.
+#[derive(Clone)]
+struct Handle {
+    channel: String,
+    agent: Arc<Agent>,
+    offer: sdp::Offer,
+    state: Arc<std::sync::Mutex<ConnectionState>>,
+    closed: Arc<watch::Sender<bool>>,
+    /// Simulcast layers asked for by the subscriber, by mid
+    layers: Arc<HashMap<String, watch::Sender<Option<String>>>>,
 }
 
 impl WebRtcModule {
-    pub fn new(server: Arc<std::sync::Mutex<server::Server>>) -> Self {
+    pub fn new(server: Arc<std::sync::Mutex<server::Server>>, config: Config) -> Self {
         Self {
             name: "webrtc".to_string(),
             server,
+            config,
         }
     }
 
     pub async fn start(self: WebRtcModule) -> anyhow::Result<()> {
         info!("webrtc start");
 
-        let udp_socket = UdpSocket::bind("[::]:4435").await.unwrap();
+        let udp_socket = UdpSocket::bind(self.config.listen).await?;
+        let udp_listen = udp_socket.local_addr()?;
+        info!("listening webrtc on {}", udp_listen);
         let udp_mux = UDPMuxDefault::new(UDPMuxParams::new(udp_socket));
-        let udp_network = UDPNetwork::Muxed(udp_mux);
+        if let Some(turn) = &self.config.turn {
+            turn::start(turn.clone()).await?;
+        }
+        let tcp = match self.config.tcp_listen {
+            Some(addr) => Some(tcp::Listener::bind(addr).await?),
+            None => None,
+        };
 
-        let module = module::Module::new();
-        let mut commands = module.commands.subscribe();
+        let certificate = Certificate::generate_self_signed(vec!["prism".to_string()])?;
+        let fingerprint = fingerprint(&certificate.certificate[0].0);
+        let context = Arc::new(Context {
+            server: self.server.clone(),
+            udp_mux,
+            udp_listen,
+            tcp,
+            public_ips: self
+                .config
+                .public_ips
+                .iter()
+                .map(|ip| ip.to_string())
+                .collect(),
+            certificate,
+            fingerprint,
+            transports: Default::default(),
+            routers: Default::default(),
+        });
+
+        let (module, mut commands) = module::Module::new();
         self.server
             .lock()
             .unwrap()
@@ -47,73 +156,997 @@ impl WebRtcModule {
             .insert(self.name, Arc::new(Mutex::new(module)));
 
         tokio::spawn(async move {
-            while let Ok(msg) = commands.recv().await {
-                info!("webrtc command: {:#?}", msg);
-                msg.reply.send(msg.data).unwrap();
+            while let Some(msg) = commands.recv().await {
+                let context = context.clone();
+                tokio::spawn(async move {
+                    let response = match rpc::parse_message(&msg.data) {
+                        Ok(request) => context.handle(request).await,
+                        Err(err) => rpc::Response::Error {
+                            message: format!("invalid command: {}", err),
+                        },
+                    };
+                    let _ = msg.reply.send(rpc::encode(&response));
+                });
             }
         });
 
-        // CreateIceConnection, Input, Output
-        // DestroyIceConnection
+        Ok(())
+    }
+
+    pub async fn stop() -> anyhow::Result<()> {
+        info!("webrtc stop");
+        Ok(())
+    }
+
+    pub async fn exec(command: &str) -> anyhow::Result<()> {
+        info!("webrtc exec {}", command);
+        Ok(())
+    }
+}
+
+impl Context {
+    async fn handle(self: &Arc<Self>, request: rpc::Request) -> rpc::Response {
+        debug!("webrtc command: {:?}", request);
+        let res = match request {
+            rpc::Request::CreateTransport {
+                channel,
+                mode,
+                offer,
+            } => self.create_transport(channel, mode, &offer).await,
+            rpc::Request::DestroyTransport { channel, id } => self.destroy_transport(&channel, id),
+            rpc::Request::AddRemoteCandidates {
+                channel,
+                id,
+                ice_ufrag,
+                candidates,
+            } => {
+                self.add_remote_candidates(&channel, id, ice_ufrag, &candidates)
+                    .await
+            }
+            rpc::Request::RestartIce {
+                channel,
+                id,
+                ice_ufrag,
+                ice_pwd,
+                candidates,
+            } => {
+                self.restart_ice(&channel, id, ice_ufrag, ice_pwd, &candidates)
+                    .await
+            }
+            rpc::Request::GetStats { channel, id } => Ok(self.get_stats(&channel, id).await),
+            rpc::Request::SelectLayer {
+                channel,
+                id,
+                mid,
+                encoding,
+            } => self.select_layer(&channel, id, mid, encoding),
+        };
+        res.unwrap_or_else(|err| rpc::Response::Error {
+            message: err.to_string(),
+        })
+    }
+
+    async fn create_transport(
+        self: &Arc<Self>,
+        channel: String,
+        mode: rpc::Mode,
+        offer: &str,
+    ) -> anyhow::Result<rpc::Response> {
+        let offer = sdp::Offer::parse(offer)?;
+        let router = self.router(&channel);
+        let mut changes = router.subscribe();
+        if mode == rpc::Mode::Subscribe && router.is_empty() {
+            // RUSH tracks are only known once their frames are seen
+            let _ = tokio::time::timeout(TRACK_DISCOVERY, changes.changed()).await;
+        }
+        let accepts: Vec<sdp::Accept> = offer
+            .media
+            .iter()
+            .map(|media| accept(media, mode, &router))
+            .collect();
+        if accepts
+            .iter()
+            .all(|accept| matches!(accept, sdp::Accept::Reject))
+        {
+            anyhow::bail!("offer without supported media");
+        }
 
-        // when ready to create a connection, create an agent
-        let ice_agent = Arc::new(
+        let addresses = interfaces::host_addresses(self.udp_listen, &self.public_ips);
+        let agent = Arc::new(
             Agent::new(AgentConfig {
-                network_types: vec![NetworkType::Udp4, NetworkType::Udp6],
-                udp_network,
+                network_types: network_types(self.udp_listen),
+                udp_network: UDPNetwork::Muxed(self.udp_mux.clone()),
+                nat_1to1_ips: self.public_ips.clone(),
+                nat_1to1_ip_candidate_type: CandidateType::Host,
                 ..Default::default()
             })
-            .await
-            .unwrap(),
+            .await?,
+        );
+        let candidates = match self.gather_candidates(&agent, &addresses).await {
+            Ok(candidates) => candidates,
+            Err(err) => {
+                let _ = agent.close().await;
+                return Err(err);
+            }
+        };
+        let (ice_ufrag, ice_pwd) = agent.get_local_user_credentials().await;
+
+        let id = NEXT_TRANSPORT_ID.fetch_add(1, Ordering::Relaxed);
+        let tcp_conns = self
+            .tcp
+            .as_ref()
+            .map(|tcp| tcp.register(id, ice_ufrag.clone(), ice_pwd.clone()));
+        let answer = sdp::answer(
+            id,
+            &offer,
+            &sdp::Local {
+                ice_ufrag: ice_ufrag.clone(),
+                ice_pwd,
+                fingerprint: self.fingerprint.clone(),
+                candidates,
+            },
+            &accepts,
         );
-        let _ = ice_agent.gather_candidates();
 
-        // Get the local auth details and send to remote peer
-        let (local_ufrag, local_pwd) = ice_agent.get_local_user_credentials().await;
+        let (closed_tx, closed) = watch::channel(false);
+        let closed_tx = Arc::new(closed_tx);
+        let state = Arc::new(std::sync::Mutex::new(ConnectionState::New));
+        let layers: HashMap<String, watch::Sender<Option<String>>> = offer
+            .media
+            .iter()
+            .zip(&accepts)
+            .filter(|(_, accept)| matches!(accept, sdp::Accept::Media { ssrc: Some(_), .. }))
+            .map(|(media, _)| (media.mid.clone(), watch::channel(None).0))
+            .collect();
+        let layers = Arc::new(layers);
+        self.transports.lock().unwrap().insert(
+            id,
+            Handle {
+                channel: channel.clone(),
+                agent: agent.clone(),
+                offer: offer.clone(),
+                state: state.clone(),
+                closed: closed_tx.clone(),
+                layers: layers.clone(),
+            },
+        );
 
+        let transport = WebRtcTransport {
+            id,
+            context: self.clone(),
+            agent,
+            offer,
+            accepts,
+            channel,
+            state,
+            tcp_conns,
+            layers,
+            closed_tx,
+            closed,
+        };
         tokio::spawn(async move {
-            ice_agent.on_connection_state_change(Box::new(move |c: ConnectionState| {
-                info!("incoming connection ice");
-                if c == ConnectionState::Failed {
-                    // let _ = ice_done_tx.try_send(());
-                }
-                Box::pin(async move {})
-            }));
+            if let Err(err) = transport.process().await {
+                error!("webrtc transport failed: {}", err);
+            }
         });
-        Ok(())
+
+        Ok(rpc::Response::TransportCreated {
+            id,
+            ice_ufrag,
+            answer,
+        })
     }
 
-    pub async fn stop() -> anyhow::Result<()> {
-        info!("webrtc stop");
-        Ok(())
+    /// Running transport, only visible with the channel it was created for.
+    fn find(&self, channel: &str, id: u64) -> Option<Handle> {
+        self.transports
+            .lock()
+            .unwrap()
+            .get(&id)
+            .filter(|handle| handle.channel == channel)
+            .cloned()
     }
 
-    pub async fn exec(command: &str) -> anyhow::Result<()> {
-        info!("webrtc exec {}", command);
-        Ok(())
+    fn select_layer(
+        &self,
+        channel: &str,
+        id: u64,
+        mid: Option<String>,
+        encoding: Option<String>,
+    ) -> anyhow::Result<rpc::Response> {
+        let handle = match self.find(channel, id) {
+            Some(handle) => handle,
+            None => return Ok(rpc::Response::UnknownTransport),
+        };
+        let mut layers = handle
+            .layers
+            .iter()
+            .filter(|(layer_mid, _)| mid.as_ref().is_none_or(|mid| mid == *layer_mid))
+            .peekable();
+        if layers.peek().is_none() {
+            anyhow::bail!("no media sent with mid {:?}", mid);
+        }
+        for (_, layer) in layers {
+            layer.send_replace(encoding.clone());
+        }
+        Ok(rpc::Response::Done)
+    }
+
+    fn destroy_transport(&self, channel: &str, id: u64) -> anyhow::Result<rpc::Response> {
+        let handle = match self.find(channel, id) {
+            Some(handle) => handle,
+            None => return Ok(rpc::Response::UnknownTransport),
+        };
+        let _ = handle.closed.send(true);
+        Ok(rpc::Response::Done)
+    }
+
+    async fn add_remote_candidates(
+        &self,
+        channel: &str,
+        id: u64,
+        ice_ufrag: Option<String>,
+        candidates: &[String],
+    ) -> anyhow::Result<rpc::Response> {
+        let handle = match self.find(channel, id) {
+            Some(handle) => handle,
+            None => return Ok(rpc::Response::UnknownTransport),
+        };
+        if let Some(ice_ufrag) = ice_ufrag {
+            if handle.agent.get_local_user_credentials().await.0 != ice_ufrag {
+                return Ok(rpc::Response::IceMismatch);
+            }
+        }
+        add_remote_candidates(&handle.agent, candidates)?;
+        Ok(rpc::Response::Done)
+    }
+
+    async fn restart_ice(
+        &self,
+        channel: &str,
+        id: u64,
+        ice_ufrag: String,
+        ice_pwd: String,
+        candidates: &[String],
+    ) -> anyhow::Result<rpc::Response> {
+        let handle = match self.find(channel, id) {
+            Some(handle) => handle,
+            None => return Ok(rpc::Response::UnknownTransport),
+        };
+
+        // Empty credentials are generated by the agent
+        handle.agent.restart(String::new(), String::new()).await?;
+        let addresses = interfaces::host_addresses(self.udp_listen, &self.public_ips);
+        let local_candidates = self.gather_candidates(&handle.agent, &addresses).await?;
+        handle
+            .agent
+            .set_remote_credentials(ice_ufrag, ice_pwd)
+            .await?;
+        add_remote_candidates(&handle.agent, candidates)?;
+
+        let (ice_ufrag, ice_pwd) = handle.agent.get_local_user_credentials().await;
+        if let Some(tcp) = &self.tcp {
+            tcp.update(id, ice_ufrag.clone(), ice_pwd.clone());
+        }
+        info!("webrtc transport {} ice restarted", id);
+        let fragment = sdp::fragment(
+            &handle.offer,
+            &sdp::Local {
+                ice_ufrag: ice_ufrag.clone(),
+                ice_pwd,
+                fingerprint: self.fingerprint.clone(),
+                candidates: local_candidates,
+            },
+        );
+        Ok(rpc::Response::IceRestarted {
+            ice_ufrag,
+            fragment,
+        })
+    }
+
+    async fn get_stats(&self, channel: &str, id: u64) -> rpc::Response {
+        let handle = match self.find(channel, id) {
+            Some(handle) => handle,
+            None => return rpc::Response::UnknownTransport,
+        };
+        let state = handle.state.lock().unwrap().to_string().to_lowercase();
+        let pair = handle.agent.get_selected_candidate_pair();
+        let address = |candidate: &Arc<dyn Candidate + Send + Sync>| {
+            let ip = candidate.address().parse().ok()?;
+            Some(SocketAddr::new(ip, candidate.port()).to_string())
+        };
+        rpc::Response::Stats(rpc::Stats {
+            state,
+            ice_ufrag: handle.agent.get_local_user_credentials().await.0,
+            local_address: pair.as_ref().and_then(|pair| address(&pair.local)),
+            remote_address: pair.as_ref().and_then(|pair| address(&pair.remote)),
+            bytes_sent: handle.agent.get_bytes_sent() as u64,
+            bytes_received: handle.agent.get_bytes_received() as u64,
+        })
+    }
+
+    /// Gathers the local candidates, a host one of the UDP mux and a passive
+    /// ICE-TCP one on each of its addresses. The mux only gathers one, checks
+    /// received on the others are paired with it as peer reflexive ones.
+    async fn gather_candidates(
+        &self,
+        agent: &Agent,
+        addresses: &[IpAddr],
+    ) -> anyhow::Result<Vec<String>> {
+        let gathered = gather_candidates(agent).await?;
+        let port = match gathered
+            .iter()
+            .find_map(|candidate| unmarshal_candidate(candidate).ok())
+        {
+            Some(candidate) => candidate.port(),
+            None => anyhow::bail!("no local candidates"),
+        };
+        // The gathered address may be a mangled IPv6 one
+        let mut candidates: Vec<String> = gathered
+            .into_iter()
+            .filter(|candidate| {
+                addresses.is_empty()
+                    || unmarshal_candidate(candidate).is_ok_and(|candidate| {
+                        addresses
+                            .iter()
+                            .any(|ip| candidate.address() == ip.to_string())
+                    })
+            })
+            .collect();
+        for (index, ip) in addresses.iter().enumerate() {
+            let announced = candidates.iter().any(|candidate| {
+                unmarshal_candidate(candidate)
+                    .is_ok_and(|candidate| candidate.address() == ip.to_string())
+            });
+            if !announced {
+                candidates.push(host_candidate(index, *ip, port));
+            }
+        }
+        if let Some(tcp) = &self.tcp {
+            let addresses: Vec<String> = candidates
+                .iter()
+                .filter_map(|candidate| unmarshal_candidate(candidate).ok())
+                .map(|candidate| candidate.address())
+                .collect();
+            for (index, address) in addresses.iter().enumerate() {
+                candidates.push(tcp.candidate(index, address));
+            }
+        }
+        Ok(candidates)
+    }
+
+    /// Router of a channel, created with the packetizing of its RUSH tracks.
+    fn router(&self, channel: &str) -> Arc<router::Router> {
+        let mut routers = self.routers.lock().unwrap();
+        if let Some(router) = routers.get(channel) {
+            return router.clone();
+        }
+        let router = Arc::new(router::Router::default());
+        routers.insert(channel.to_string(), router.clone());
+        tokio::spawn(transmux::packetize(
+            self.server.clone(),
+            channel.to_string(),
+            router.clone(),
+        ));
+        router
     }
 }
 
-pub struct WebRtcTransport {
-    server: Arc<std::sync::Mutex<server::Server>>,
+/// Accepts DataChannels and the media flowing in the direction of the mode,
+/// with the first supported codec. Subscribers prefer the codec of the
+/// track already published, which is forwarded without transcoding.
+fn accept(media: &sdp::Media, mode: rpc::Mode, router: &router::Router) -> sdp::Accept {
+    if media.is_data_channel() {
+        return sdp::Accept::DataChannel;
+    }
+    let (kind, supported) = match router::Kind::parse(&media.kind) {
+        Some(router::Kind::Audio) => (router::Kind::Audio, AUDIO_CODECS),
+        Some(router::Kind::Video) => (router::Kind::Video, VIDEO_CODECS),
+        None => return sdp::Accept::Reject,
+    };
+    let accept = match mode {
+        rpc::Mode::Publish if media.direction.sends() => {
+            media
+                .select_codec(supported)
+                .map(|codec| sdp::Accept::Media {
+                    codec: codec.clone(),
+                    direction: sdp::Direction::RecvOnly,
+                    ssrc: None,
+                    // Packets of the layers are told apart by their RID
+                    rids: match media.rid_extension() {
+                        Some(_) if media.rids.len() > 1 => media.rids.clone(),
+                        _ => Vec::new(),
+                    },
+                })
+        }
+        rpc::Mode::Subscribe if media.direction.receives() => router
+            .track(kind, None)
+            .and_then(|track| media.select_codec(&[track.codec.name.as_str()]).cloned())
+            .or_else(|| media.select_codec(supported).cloned())
+            .map(|codec| sdp::Accept::Media {
+                codec,
+                direction: sdp::Direction::SendOnly,
+                ssrc: Some(rand::random()),
+                rids: Vec::new(),
+            }),
+        _ => None,
+    };
+    accept.unwrap_or(sdp::Accept::Reject)
 }
 
-impl WebRtcTransport {
-    pub fn new(server: Arc<std::sync::Mutex<server::Server>>) -> Self {
-        Self { server }
+/// Gathers the candidates of the agent, a single host one on the UDP mux.
+async fn gather_candidates(agent: &Agent) -> anyhow::Result<Vec<String>> {
+    let (candidates_tx, mut candidates_rx) = mpsc::unbounded_channel();
+    agent.on_candidate(Box::new(
+        move |candidate: Option<Arc<dyn Candidate + Send + Sync>>| {
+            let _ = candidates_tx.send(candidate.map(|candidate| candidate.marshal()));
+            Box::pin(async {})
+        },
+    ));
+    agent.gather_candidates()?;
+
+    let mut candidates = Vec::new();
+    let gathered = tokio::time::timeout(GATHER_TIMEOUT, async {
+        while let Some(Some(candidate)) = candidates_rx.recv().await {
+            candidates.push(candidate);
+        }
+    })
+    .await;
+    if gathered.is_err() {
+        warn!("webrtc candidates gathering timed out");
+    }
+    if candidates.is_empty() {
+        anyhow::bail!("no local candidates");
+    }
+    Ok(candidates)
+}
+
+/// Network type of the agents on the UDP mux. The agents pair the checks
+/// with the single candidate gathered on the mux only when they are of its
+/// type, and a dual stack socket receives the IPv4 ones on mapped IPv6
+/// addresses, so its candidate is an IPv6 one.
+fn network_types(udp_listen: SocketAddr) -> Vec<NetworkType> {
+    match udp_listen {
+        SocketAddr::V4(_) => vec![NetworkType::Udp4],
+        SocketAddr::V6(_) => vec![NetworkType::Udp6],
+    }
+}
+
+/// UDP host candidate on the mux, below the gathered one.
+fn host_candidate(index: usize, ip: IpAddr, port: u16) -> String {
+    let local_preference = 65534 - index.min(1024) as u32;
+    let priority: u32 = (126 << 24) | (local_preference << 8) | 255;
+    format!(
+        "{} 1 udp {} {} {} typ host",
+        1000 + index,
+        priority,
+        ip,
+        port
+    )
+}
+
+fn add_remote_candidates(agent: &Agent, candidates: &[String]) -> anyhow::Result<()> {
+    for candidate in candidates {
+        match unmarshal_candidate(candidate) {
+            Ok(candidate) => {
+                let candidate: Arc<dyn Candidate + Send + Sync> = Arc::new(candidate);
+                agent.add_remote_candidate(&candidate)?;
+            }
+            Err(err) => warn!("webrtc invalid candidate {:?}: {}", candidate, err),
+        }
     }
+    Ok(())
+}
+
+fn fingerprint(certificate: &[u8]) -> String {
+    let digest = Sha256::digest(certificate);
+    let bytes: Vec<String> = digest.iter().map(|byte| format!("{:02X}", byte)).collect();
+    bytes.join(":")
+}
+
+/// Peer connection answered by prism, carrying DataChannels over ICE,
+/// DTLS and SCTP and media over SRTP, published into the channel or
+/// forwarded from it.
+pub struct WebRtcTransport {
+    id: u64,
+    context: Arc<Context>,
+    agent: Arc<Agent>,
+    offer: sdp::Offer,
+    accepts: Vec<sdp::Accept>,
+    channel: String,
+    state: Arc<std::sync::Mutex<ConnectionState>>,
+    /// Connections nominated on the ICE-TCP candidates, when enabled
+    tcp_conns: Option<mpsc::Receiver<Arc<tcp::TcpConn>>>,
+    layers: Arc<HashMap<String, watch::Sender<Option<String>>>>,
+    closed_tx: Arc<watch::Sender<bool>>,
+    closed: watch::Receiver<bool>,
 }
 
 #[async_trait]
 impl transport::Transport for WebRtcTransport {
     fn close(&self) {
-        unimplemented!()
+        let _ = self.closed_tx.send(true);
+    }
+
+    async fn process(mut self) -> Result<(), anyhow::Error> {
+        let tcp_conns = self.tcp_conns.take();
+        let res = self.run(tcp_conns).await;
+        self.context.transports.lock().unwrap().remove(&self.id);
+        if let Some(tcp) = &self.context.tcp {
+            tcp.unregister(self.id);
+        }
+        let _ = self.agent.close().await;
+        info!("webrtc connection {} finished", self.id);
+        res
     }
+}
+
+impl WebRtcTransport {
+    async fn run(
+        &self,
+        mut tcp_conns: Option<mpsc::Receiver<Arc<tcp::TcpConn>>>,
+    ) -> Result<(), anyhow::Error> {
+        let (cancel_tx, cancel_rx) = mpsc::channel(1);
+        let closed_tx = self.closed_tx.clone();
+        let current = self.state.clone();
+        self.agent
+            .on_connection_state_change(Box::new(move |state: ConnectionState| {
+                info!("webrtc ice connection state {}", state);
+                *current.lock().unwrap() = state;
+                if matches!(state, ConnectionState::Failed | ConnectionState::Closed) {
+                    let _ = cancel_tx.try_send(());
+                    let _ = closed_tx.send(true);
+                }
+                Box::pin(async {})
+            }));
+
+        add_remote_candidates(&self.agent, &self.offer.candidates)?;
+
+        let mut closed = self.closed.clone();
+        let tcp_conn = async {
+            match tcp_conns.as_mut() {
+                Some(tcp_conns) => tcp_conns.recv().await,
+                None => std::future::pending().await,
+            }
+        };
+        let conn: Arc<dyn Conn + Send + Sync> = select! {
+            res = self.agent.accept(
+                cancel_rx,
+                self.offer.ice_ufrag.clone(),
+                self.offer.ice_pwd.clone(),
+            ).fuse() => res?,
+            conn = tcp_conn.fuse() => match conn {
+                Some(conn) => self.use_tcp(conn).await,
+                None => return Ok(()),
+            },
+            _ = closed.changed().fuse() => return Ok(()),
+        };
+        let remote = conn
+            .remote_addr()
+            .unwrap_or_else(|| SocketAddr::from(([0, 0, 0, 0], 0)));
+        info!("webrtc connection {} established from {}", self.id, remote);
+        self.context
+            .server
+            .lock()
+            .unwrap()
+            .stats
+            .record_connection();
+
+        let mux = mux::Mux::new(conn);
+        let expected = self.offer.fingerprint.clone();
+        let config = webrtc_dtls::config::Config {
+            certificates: vec![self.context.certificate.clone()],
+            srtp_protection_profiles: vec![
+                SrtpProtectionProfile::Srtp_Aead_Aes_128_Gcm,
+                SrtpProtectionProfile::Srtp_Aes128_Cm_Hmac_Sha1_80,
+            ],
+            client_auth: ClientAuthType::RequireAnyClientCert,
+            extended_master_secret: ExtendedMasterSecretType::Require,
+            // The certificate is self signed, it is verified with the fingerprint of the offer
+            insecure_skip_verify: true,
+            verify_peer_certificate: Some(Arc::new(move |certificates: &[Vec<u8>], _| {
+                match certificates.first() {
+                    Some(certificate) if fingerprint(certificate) == expected => Ok(()),
+                    _ => Err(webrtc_dtls::Error::ErrInvalidCertificate),
+                }
+            })),
+            ..Default::default()
+        };
+        let dtls_conn = select! {
+            res = DTLSConn::new(
+                mux.dtls.clone() as Arc<dyn Conn + Send + Sync>,
+                config,
+                self.offer.is_dtls_client(),
+                None,
+            ).fuse() => res?,
+            _ = closed.changed().fuse() => return Ok(()),
+        };
+        let dtls_conn = Arc::new(dtls_conn);
+        debug!("webrtc connection {} dtls handshaked", self.id);
+
+        let media: Vec<_> = self
+            .offer
+            .media
+            .iter()
+            .zip(&self.accepts)
+            .filter_map(|(media, accept)| match accept {
+                sdp::Accept::Media {
+                    codec, ssrc, rids, ..
+                } => Some((
+                    media,
+                    router::Kind::parse(&media.kind)?,
+                    codec,
+                    *ssrc,
+                    rids.as_slice(),
+                )),
+                _ => None,
+            })
+            .collect();
+        let srtp = if media.is_empty() {
+            None
+        } else {
+            let config = self.srtp_config(&dtls_conn).await?;
+            let session = Arc::new(Session::new(mux.srtp.clone(), config, true).await?);
+            let config = self.srtp_config(&dtls_conn).await?;
+            let rtcp = Arc::new(Session::new(mux.srtcp.clone(), config, false).await?);
+            // Streams are opened by SSRC, the others are left unread
+            let accepted = rtcp.clone();
+            tokio::spawn(async move { while accepted.accept().await.is_ok() {} });
+            let router = self.context.router(&self.channel);
+
+            // Sections with a local source are sent, the others received
+            let mut formats = HashMap::new();
+            for (media, kind, codec, ssrc, rids) in media {
+                match ssrc {
+                    Some(ssrc) => {
+                        let section = egress::Section {
+                            kind,
+                            codec: codec.clone(),
+                            ssrc,
+                            layer: self.layers[&media.mid].subscribe(),
+                        };
+                        tokio::spawn(egress::forward(
+                            session.clone(),
+                            rtcp.clone(),
+                            router.clone(),
+                            section,
+                            closed.clone(),
+                        ));
+                    }
+                    None => {
+                        let simulcast = media.rid_extension().filter(|_| !rids.is_empty());
+                        let format = Format {
+                            kind,
+                            codec: codec.clone(),
+                            simulcast: simulcast.map(|extension| Simulcast {
+                                source: rand::random::<u16>().max(1),
+                                rids: rids.to_vec(),
+                                extension,
+                            }),
+                        };
+                        formats.insert(codec.payload_type, Arc::new(format));
+                    }
+                }
+            }
+            if !formats.is_empty() {
+                tokio::spawn(ingest(
+                    session.clone(),
+                    rtcp.clone(),
+                    self.context.server.clone(),
+                    self.channel.clone(),
+                    router,
+                    formats,
+                    closed.clone(),
+                ));
+            }
+            Some((session, rtcp))
+        };
+
+        if self
+            .accepts
+            .iter()
+            .any(|accept| matches!(accept, sdp::Accept::DataChannel))
+        {
+            let association = Association::server(association::Config {
+                net_conn: dtls_conn.clone(),
+                max_receive_buffer_size: 0,
+                max_message_size: sdp::MAX_MESSAGE_SIZE,
+                name: format!("webrtc-{}", self.id),
+            })
+            .await?;
+            let association = Arc::new(association);
 
-    async fn process(self) -> Result<(), anyhow::Error> {
-        info!("webrtc connection established");
+            datachannel::serve(
+                self.context.server.clone(),
+                association.clone(),
+                self.channel.clone(),
+                remote,
+                closed,
+            )
+            .await;
+            let _ = association.close().await;
+        } else {
+            while !*closed.borrow() {
+                if closed.changed().await.is_err() {
+                    break;
+                }
+            }
+        }
 
-        info!("webrtc connection finished");
+        if let Some((srtp, srtcp)) = srtp {
+            let _ = srtp.close().await;
+            let _ = srtcp.close().await;
+        }
+        let _ = dtls_conn.close().await;
         Ok(())
     }
+
+    /// Gives up the UDP checks for a connection nominated on ICE-TCP, the
+    /// transport then lasts as long as the TCP connection.
+    async fn use_tcp(&self, conn: Arc<tcp::TcpConn>) -> Arc<dyn Conn + Send + Sync> {
+        self.agent
+            .on_connection_state_change(Box::new(|_: ConnectionState| Box::pin(async {})));
+        let _ = self.agent.close().await;
+        *self.state.lock().unwrap() = ConnectionState::Connected;
+
+        let finished = conn.clone();
+        let closed_tx = self.closed_tx.clone();
+        tokio::spawn(async move {
+            finished.finished().await;
+            let _ = closed_tx.send(true);
+        });
+        conn
+    }
+
+    /// SRTP keys of the DTLS handshake (RFC 5764), a session is keyed for
+    /// either RTP or RTCP.
+    async fn srtp_config(
+        &self,
+        dtls_conn: &DTLSConn,
+    ) -> Result<webrtc_srtp::config::Config, anyhow::Error> {
+        let profile = match dtls_conn.selected_srtpprotection_profile() {
+            SrtpProtectionProfile::Srtp_Aead_Aes_128_Gcm => ProtectionProfile::AeadAes128Gcm,
+            SrtpProtectionProfile::Srtp_Aes128_Cm_Hmac_Sha1_80 => {
+                ProtectionProfile::Aes128CmHmacSha1_80
+            }
+            profile => anyhow::bail!("unsupported srtp profile {:?}", profile),
+        };
+        let mut config = webrtc_srtp::config::Config {
+            profile,
+            ..Default::default()
+        };
+        config
+            .extract_session_keys_from_dtls(
+                dtls_conn.connection_state().await,
+                self.offer.is_dtls_client(),
+            )
+            .await?;
+        Ok(config)
+    }
+}
+
+/// Publishes each RTP stream received from the peer as a track of the
+/// channel router, and as RUSH messages of the channel, until the transport
+/// is closed.
+async fn ingest(
+    session: Arc<Session>,
+    rtcp: Arc<Session>,
+    server: server::ServerPtr,
+    channel: String,
+    router: Arc<router::Router>,
+    formats: HashMap<u8, Arc<Format>>,
+    mut closed: watch::Receiver<bool>,
+) {
+    loop {
+        // Streams are not closed with the session, the transport has to stop them
+        let stream = select! {
+            res = session.accept().fuse() => match res {
+                Ok(stream) => stream,
+                Err(_) => break,
+            },
+            _ = closed.changed().fuse() => break,
+        };
+        tokio::spawn(receive(
+            stream,
+            rtcp.clone(),
+            server.clone(),
+            channel.clone(),
+            router.clone(),
+            formats.clone(),
+            closed.clone(),
+        ));
+    }
+}
+
+/// Media received in a section of the peer.
+struct Format {
+    kind: router::Kind,
+    codec: sdp::Codec,
+    simulcast: Option<Simulcast>,
+}
+
+/// Simulcast layers of a received section, told apart by the RID header
+/// extension of their packets.
+struct Simulcast {
+    source: u16,
+    rids: Vec<String>,
+    extension: u8,
+}
+
+/// What a stream of the peer is woken up by.
+enum Event {
+    Rtp(usize),
+    Rtcp(usize),
+    KeyframeRequest,
+    Report,
+}
+
+/// Publishes an RTP stream of the peer, requesting the retransmission of
+/// its lost packets and keyframes for the subscribers, and reporting its
+/// reception.
+async fn receive(
+    stream: Arc<Stream>,
+    rtcp: Arc<Session>,
+    server: server::ServerPtr,
+    channel: String,
+    router: Arc<router::Router>,
+    formats: HashMap<u8, Arc<Format>>,
+    mut closed: watch::Receiver<bool>,
+) {
+    let id = stream.get_ssrc();
+    let feedback = rtcp.open(id).await;
+    // Source of the RTCP packets about the stream
+    let ssrc = rand::random::<u32>();
+    let mut track: Option<(Arc<router::Track>, feedback::Reception)> = None;
+    let mut reports = tokio::time::interval(feedback::REPORT_INTERVAL);
+    let mut buf = vec![0u8; RECEIVE_MTU];
+    let mut rtcp_buf = vec![0u8; RECEIVE_MTU];
+    loop {
+        let requested = track.as_ref().map(|(track, _)| track.clone());
+        let keyframe_requested = async move {
+            match requested {
+                Some(track) => track.keyframe_requested().await,
+                None => std::future::pending().await,
+            }
+        };
+        let event = select! {
+            res = stream.read(&mut buf).fuse() => match res {
+                Ok(n) => Event::Rtp(n),
+                Err(err) => {
+                    debug!("webrtc rtp stream {} finished: {}", id, err);
+                    break;
+                }
+            },
+            res = feedback.read(&mut rtcp_buf).fuse() => match res {
+                Ok(n) => Event::Rtcp(n),
+                Err(err) => {
+                    debug!("webrtc rtcp stream {} finished: {}", id, err);
+                    break;
+                }
+            },
+            _ = keyframe_requested.fuse() => Event::KeyframeRequest,
+            _ = reports.tick().fuse() => Event::Report,
+            _ = closed.changed().fuse() => break,
+        };
+
+        let feedback: Box<dyn rtcp::packet::Packet + Send + Sync> = match event {
+            Event::Rtp(n) => {
+                let packet = match rtp::packet::Packet::unmarshal(&mut &buf[..n]) {
+                    Ok(packet) => packet,
+                    Err(err) => {
+                        debug!("webrtc invalid rtp packet: {}", err);
+                        continue;
+                    }
+                };
+
+                // The track is published with the codec of its first packet,
+                // and the layer of the first one with a RID
+                let (track, reception) = match &mut track {
+                    Some(track) => track,
+                    None => match formats.get(&packet.header.payload_type) {
+                        Some(format) => {
+                            let layer = match &format.simulcast {
+                                Some(simulcast) => match layer(simulcast, &packet) {
+                                    Some(layer) => Some(layer),
+                                    None => continue,
+                                },
+                                None => None,
+                            };
+                            let source = layer.as_ref().map_or(0, |layer| layer.source as u16);
+                            let (kind, codec) = (format.kind, &format.codec);
+                            let added = router.add_track(id, kind, codec.clone(), layer);
+                            tokio::spawn(transmux::publish(
+                                server.clone(),
+                                channel.clone(),
+                                id,
+                                kind,
+                                codec.clone(),
+                                source,
+                                added.packets.subscribe(),
+                            ));
+                            track.insert((added, feedback::Reception::new(codec.clock_rate)))
+                        }
+                        None => {
+                            debug!(
+                                "webrtc rtp stream {} with unknown payload type {}",
+                                id, packet.header.payload_type
+                            );
+                            continue;
+                        }
+                    },
+                };
+                let lost = reception.update(&packet.header, Instant::now());
+                track.send(packet);
+                if lost.is_empty()
+                    || !track
+                        .codec
+                        .feedback
+                        .iter()
+                        .any(|feedback| feedback == "nack")
+                {
+                    continue;
+                }
+                Box::new(TransportLayerNack {
+                    sender_ssrc: ssrc,
+                    media_ssrc: id,
+                    nacks: nack_pairs_from_sequence_numbers(&lost),
+                })
+            }
+            Event::Rtcp(n) => {
+                let (reception, packets) =
+                    match (&mut track, rtcp::packet::unmarshal(&mut &rtcp_buf[..n])) {
+                        (Some((_, reception)), Ok(packets)) => (reception, packets),
+                        _ => continue,
+                    };
+                for packet in &packets {
+                    if let Some(report) = packet.as_any().downcast_ref::<SenderReport>() {
+                        if report.ssrc == id {
+                            reception.sender_report(report.ntp_time, Instant::now());
+                        }
+                    }
+                }
+                continue;
+            }
+            Event::KeyframeRequest => Box::new(PictureLossIndication {
+                sender_ssrc: ssrc,
+                media_ssrc: id,
+            }),
+            Event::Report => {
+                let report = match &mut track {
+                    Some((track, reception)) => {
+                        reception.report(id, track.take_loss(), Instant::now())
+                    }
+                    None => None,
+                };
+                let report = match report {
+                    Some(report) => report,
+                    None => continue,
+                };
+                Box::new(ReceiverReport {
+                    ssrc,
+                    reports: vec![report],
+                    ..Default::default()
+                })
+            }
+        };
+        if let Err(err) = rtcp.write_rtcp(feedback.as_ref()).await {
+            debug!("webrtc rtcp write failed: {}", err);
+        }
+    }
+    if track.is_some() {
+        router.remove_track(id);
+    }
+}
+
+/// Layer of a simulcast stream, from the RID of its packet.
+fn layer(simulcast: &Simulcast, packet: &rtp::packet::Packet) -> Option<router::Layer> {
+    let rid = packet.header.get_extension(simulcast.extension)?;
+    let rid = String::from_utf8_lossy(&rid).into_owned();
+    if !simulcast.rids.contains(&rid) {
+        return None;
+    }
+    Some(router::Layer {
+        source: simulcast.source as u32,
+        rid: Some(rid),
+    })
 }
@@ -0,0 +1,132 @@
+use std::io;
+use std::net::SocketAddr;
+use std::sync::Arc;
+
+use async_trait::async_trait;
+use tokio::sync::{mpsc, Mutex};
+use tracing::*;
+use webrtc_util::Conn;
+
+const RECEIVE_MTU: usize = 8192;
+const QUEUE_SIZE: usize = 256;
+
+/// Demultiplexes the packets received on an ICE connection by their first
+/// byte as described in RFC 7983.
+pub struct Mux {
+    pub dtls: Arc<Endpoint>,
+    pub srtp: Arc<Endpoint>,
+    pub srtcp: Arc<Endpoint>,
+}
+
+impl Mux {
+    pub fn new(conn: Arc<dyn Conn + Send + Sync>) -> Self {
+        let (dtls_tx, dtls_rx) = mpsc::channel(QUEUE_SIZE);
+        let (srtp_tx, srtp_rx) = mpsc::channel(QUEUE_SIZE);
+        let (srtcp_tx, srtcp_rx) = mpsc::channel(QUEUE_SIZE);
+        tokio::spawn(read_loop(conn.clone(), dtls_tx, srtp_tx, srtcp_tx));
+
+        Self {
+            dtls: Arc::new(Endpoint::new(conn.clone(), dtls_rx)),
+            srtp: Arc::new(Endpoint::new(conn.clone(), srtp_rx)),
+            srtcp: Arc::new(Endpoint::new(conn, srtcp_rx)),
+        }
+    }
+}
+
+async fn read_loop(
+    conn: Arc<dyn Conn + Send + Sync>,
+    dtls: mpsc::Sender<Vec<u8>>,
+    srtp: mpsc::Sender<Vec<u8>>,
+    srtcp: mpsc::Sender<Vec<u8>>,
+) {
+    let mut buf = vec![0u8; RECEIVE_MTU];
+    loop {
+        let n = match conn.recv(&mut buf).await {
+            Ok(n) => n,
+            Err(err) => {
+                debug!("webrtc mux finished: {}", err);
+                break;
+            }
+        };
+
+        let packet = buf[..n].to_vec();
+        // Packets are dropped instead of blocking the other endpoints
+        match packet[..] {
+            [20..=63, ..] => {
+                let _ = dtls.try_send(packet);
+            }
+            [128..=191, 192..=223, ..] => {
+                let _ = srtcp.try_send(packet);
+            }
+            [128..=191, ..] => {
+                let _ = srtp.try_send(packet);
+            }
+            [first, ..] => trace!("webrtc mux dropped packet starting with {}", first),
+            [] => {}
+        }
+    }
+}
+
+/// Connection delivering the packets of one of the demultiplexed protocols,
+/// writing directly to the ICE connection.
+pub struct Endpoint {
+    conn: Arc<dyn Conn + Send + Sync>,
+    packets: Mutex<mpsc::Receiver<Vec<u8>>>,
+}
+
+impl Endpoint {
+    fn new(conn: Arc<dyn Conn + Send + Sync>, packets: mpsc::Receiver<Vec<u8>>) -> Self {
+        Self {
+            conn,
+            packets: Mutex::new(packets),
+        }
+    }
+}
+
+type Result<T> = std::result::Result<T, webrtc_util::Error>;
+
+#[async_trait]
+impl Conn for Endpoint {
+    async fn connect(&self, _addr: SocketAddr) -> Result<()> {
+        Err(io::Error::new(io::ErrorKind::Unsupported, "not applicable").into())
+    }
+
+    async fn recv(&self, buf: &mut [u8]) -> Result<usize> {
+        match self.packets.lock().await.recv().await {
+            Some(packet) => {
+                let n = packet.len().min(buf.len());
+                buf[..n].copy_from_slice(&packet[..n]);
+                Ok(n)
+            }
+            None => Err(io::Error::new(io::ErrorKind::ConnectionAborted, "mux closed").into()),
+        }
+    }
+
+    async fn recv_from(&self, buf: &mut [u8]) -> Result<(usize, SocketAddr)> {
+        let n = self.recv(buf).await?;
+        let addr = self
+            .remote_addr()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::NotConnected, "no remote address"))?;
+        Ok((n, addr))
+    }
+
+    async fn send(&self, buf: &[u8]) -> Result<usize> {
+        self.conn.send(buf).await
+    }
+
+    async fn send_to(&self, _buf: &[u8], _target: SocketAddr) -> Result<usize> {
+        Err(io::Error::new(io::ErrorKind::Unsupported, "not applicable").into())
+    }
+
+    fn local_addr(&self) -> Result<SocketAddr> {
+        self.conn.local_addr()
+    }
+
+    fn remote_addr(&self) -> Option<SocketAddr> {
+        self.conn.remote_addr()
+    }
+
+    async fn close(&self) -> Result<()> {
+        self.conn.close().await
+    }
+}
@@ -0,0 +1,226 @@
+use std::sync::atomic::{AtomicU8, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use tokio::sync::{broadcast, watch, Notify};
+use tracing::*;
+
+use super::{feedback, sdp};
+
+const PACKETS_CAPACITY: usize = 512;
+/// Keyframe requests of the subscribers merged into a single one
+const KEYFRAME_REQUEST_INTERVAL: Duration = Duration::from_millis(500);
+const BITRATE_PERIOD: Duration = Duration::from_secs(1);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Kind {
+    Audio,
+    Video,
+}
+
+impl Kind {
+    /// Kind of a media section, `None` for the ones without RTP.
+    pub fn parse(media: &str) -> Option<Self> {
+        match media {
+            "audio" => Some(Kind::Audio),
+            "video" => Some(Kind::Video),
+            _ => None,
+        }
+    }
+}
+
+/// Simulcast layer a track is, among the tracks of the same source. The
+/// layers are ordered by bitrate, the order of `a=simulcast` being a
+/// preference rather than a quality.
+#[derive(Debug, Clone)]
+pub struct Layer {
+    pub source: u32,
+    pub rid: Option<String>,
+}
+
+/// RTP stream published into a channel.
+pub struct Track {
+    /// SSRC of the publisher
+    pub id: u32,
+    pub kind: Kind,
+    pub codec: sdp::Codec,
+    pub layer: Option<Layer>,
+    pub packets: broadcast::Sender<rtp::packet::Packet>,
+    /// Bytes sent since the start of the measure, and bitrate of the last one
+    meter: Mutex<(Instant, u64, u64)>,
+    history: Mutex<feedback::History>,
+    keyframe_requests: Notify,
+    last_keyframe_request: Mutex<Option<Instant>>,
+    /// Highest fraction of packets lost reported by the subscribers since
+    /// the last report to the publisher
+    fraction_lost: AtomicU8,
+}
+
+impl Track {
+    /// Forwards a packet to the subscribers, keeping it for retransmission.
+    pub fn send(&self, packet: rtp::packet::Packet) {
+        {
+            let mut meter = self.meter.lock().unwrap();
+            let (since, bytes, bitrate) = &mut *meter;
+            *bytes += packet.payload.len() as u64;
+            let elapsed = since.elapsed();
+            if elapsed >= BITRATE_PERIOD {
+                *bitrate = *bytes * 8 * 1000 / elapsed.as_millis() as u64;
+                *since = Instant::now();
+                *bytes = 0;
+            }
+        }
+        self.history.lock().unwrap().push(&packet);
+        let _ = self.packets.send(packet);
+    }
+
+    /// Bits per second of the payloads.
+    pub fn bitrate(&self) -> u64 {
+        self.meter.lock().unwrap().2
+    }
+
+    pub fn retransmission(&self, sequence_number: u16) -> Option<rtp::packet::Packet> {
+        self.history.lock().unwrap().get(sequence_number).cloned()
+    }
+
+    /// Asks the publisher for a keyframe, unless it was just asked.
+    pub fn request_keyframe(&self) {
+        let now = Instant::now();
+        let mut last = self.last_keyframe_request.lock().unwrap();
+        if last.is_some_and(|last| now.duration_since(last) < KEYFRAME_REQUEST_INTERVAL) {
+            return;
+        }
+        *last = Some(now);
+        self.keyframe_requests.notify_one();
+    }
+
+    pub async fn keyframe_requested(&self) {
+        self.keyframe_requests.notified().await
+    }
+
+    pub fn report_loss(&self, fraction_lost: u8) {
+        self.fraction_lost
+            .fetch_max(fraction_lost, Ordering::Relaxed);
+    }
+
+    /// Worst loss reported by the subscribers since the previous call.
+    pub fn take_loss(&self) -> u8 {
+        self.fraction_lost.swap(0, Ordering::Relaxed)
+    }
+}
+
+/// RTP tracks published into a channel by WHIP clients, or packetized from
+/// its RUSH tracks.
+pub struct Router {
+    /// Tracks in the order they were published
+    tracks: Mutex<Vec<Arc<Track>>>,
+    changes: watch::Sender<()>,
+}
+
+impl Default for Router {
+    fn default() -> Self {
+        Self {
+            tracks: Mutex::new(Vec::new()),
+            changes: watch::channel(()).0,
+        }
+    }
+}
+
+impl Router {
+    pub fn add_track(
+        &self,
+        id: u32,
+        kind: Kind,
+        codec: sdp::Codec,
+        layer: Option<Layer>,
+    ) -> Arc<Track> {
+        let (packets, _) = broadcast::channel(PACKETS_CAPACITY);
+        let track = Arc::new(Track {
+            id,
+            kind,
+            codec,
+            layer,
+            packets,
+            meter: Mutex::new((Instant::now(), 0, 0)),
+            history: Default::default(),
+            keyframe_requests: Notify::new(),
+            last_keyframe_request: Mutex::new(None),
+            fraction_lost: AtomicU8::new(0),
+        });
+        info!(
+            "webrtc track {} added ({:?} {})",
+            id, track.kind, track.codec.name
+        );
+        self.tracks.lock().unwrap().push(track.clone());
+        self.changes.send_replace(());
+        track
+    }
+
+    pub fn remove_track(&self, id: u32) {
+        let mut tracks = self.tracks.lock().unwrap();
+        let len = tracks.len();
+        tracks.retain(|track| track.id != id);
+        if tracks.len() != len {
+            info!("webrtc track {} removed", id);
+            self.changes.send_replace(());
+        }
+    }
+
+    pub fn has_track(&self, id: u32) -> bool {
+        self.tracks
+            .lock()
+            .unwrap()
+            .iter()
+            .any(|track| track.id == id)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.tracks.lock().unwrap().is_empty()
+    }
+
+    /// Oldest published track of a kind, with the given codec if any.
+    pub fn track(&self, kind: Kind, codec: Option<&str>) -> Option<Arc<Track>> {
+        self.tracks
+            .lock()
+            .unwrap()
+            .iter()
+            .find(|track| {
+                track.kind == kind
+                    && codec.is_none_or(|codec| track.codec.name.eq_ignore_ascii_case(codec))
+            })
+            .cloned()
+    }
+
+    /// Layers of the oldest published source of a kind with a codec, lowest
+    /// quality first, a single track when it is not simulcast.
+    pub fn layers(&self, kind: Kind, codec: &str) -> Vec<Arc<Track>> {
+        let tracks = self.tracks.lock().unwrap();
+        let mut tracks = tracks
+            .iter()
+            .filter(|track| track.kind == kind && track.codec.name.eq_ignore_ascii_case(codec));
+        let first = match tracks.next() {
+            Some(first) => first,
+            None => return Vec::new(),
+        };
+        let source = match &first.layer {
+            Some(layer) => layer.source,
+            None => return vec![first.clone()],
+        };
+        let mut layers: Vec<Arc<Track>> = std::iter::once(first)
+            .chain(tracks.filter(|track| {
+                track
+                    .layer
+                    .as_ref()
+                    .is_some_and(|layer| layer.source == source)
+            }))
+            .cloned()
+            .collect();
+        layers.sort_by_key(|track| track.bitrate());
+        layers
+    }
+
+    /// Notifies every time a track is added or removed.
+    pub fn subscribe(&self) -> watch::Receiver<()> {
+        self.changes.subscribe()
+    }
+}
@@ -1,11 +1,180 @@
-pub struct CreateTransportRequest {}
+use std::time::Duration;
 
-pub struct CreateConnectionResponse {}
+use serde::{Deserialize, Serialize};
+use tokio::sync::{mpsc, oneshot};
 
-pub struct DestroyConnectionRequest {}
+use crate::module;
 
-pub struct DestroyConnectionResponse {}
+/// Longest wait for a response, above the ICE gathering of a new transport.
+const CALL_TIMEOUT: Duration = Duration::from_secs(10);
 
-pub fn parse_message() -> Result<(), anyhow::Error> {
-    Ok(())
+/// Whether the media of a transport flows into the channel or out of it.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Mode {
+    #[default]
+    Publish,
+    Subscribe,
+}
+
+/// Command sent to the webrtc module through its command bus, as JSON.
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Request {
+    /// Answers an SDP offer with a new transport bound to `channel`
+    CreateTransport {
+        channel: String,
+        #[serde(default)]
+        mode: Mode,
+        offer: String,
+    },
+    DestroyTransport {
+        channel: String,
+        id: u64,
+    },
+    /// Adds trickled candidates of the peer, `ice_ufrag` is the local one the
+    /// peer expects when known
+    AddRemoteCandidates {
+        channel: String,
+        id: u64,
+        ice_ufrag: Option<String>,
+        candidates: Vec<String>,
+    },
+    /// Restarts ICE with new credentials of the peer
+    RestartIce {
+        channel: String,
+        id: u64,
+        ice_ufrag: String,
+        ice_pwd: String,
+        candidates: Vec<String>,
+    },
+    GetStats {
+        channel: String,
+        id: u64,
+    },
+    /// Chooses the simulcast layer sent in the media section `mid`, or in
+    /// every section, by RID or index from the lowest, automatic when
+    /// `encoding` is `None`
+    SelectLayer {
+        channel: String,
+        id: u64,
+        mid: Option<String>,
+        encoding: Option<String>,
+    },
+}
+
+/// State and counters of a transport.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Stats {
+    /// ICE connection state, like `checking`, `connected` or `failed`
+    pub state: String,
+    pub ice_ufrag: String,
+    /// Addresses of the selected candidate pair, once connected
+    pub local_address: Option<String>,
+    pub remote_address: Option<String>,
+    pub bytes_sent: u64,
+    pub bytes_received: u64,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Response {
+    /// `ice_ufrag` is the local one, identifying the current ICE session
+    TransportCreated {
+        id: u64,
+        ice_ufrag: String,
+        answer: String,
+    },
+    IceRestarted {
+        ice_ufrag: String,
+        fragment: String,
+    },
+    Stats(Stats),
+    Done,
+    UnknownTransport,
+    /// The ICE session was restarted since the request was sent
+    IceMismatch,
+    Error {
+        message: String,
+    },
+}
+
+pub fn parse_message(data: &[u8]) -> Result<Request, anyhow::Error> {
+    Ok(serde_json::from_slice(data)?)
+}
+
+pub fn encode(response: &Response) -> Vec<u8> {
+    serde_json::to_vec(response).expect("rpc responses are always serializable")
+}
+
+/// Sends a request to the webrtc module and waits for its response, failing
+/// when the module is not running or takes longer than `CALL_TIMEOUT`.
+pub async fn call(
+    commands: &mpsc::Sender<module::Message>,
+    request: &Request,
+) -> Result<Response, anyhow::Error> {
+    let data = serde_json::to_vec(request)?;
+    let call = async {
+        let (reply, response) = oneshot::channel();
+        commands
+            .send(module::Message { data, reply })
+            .await
+            .map_err(|_| anyhow::anyhow!("webrtc module not running"))?;
+        response
+            .await
+            .map_err(|_| anyhow::anyhow!("webrtc module dropped the request"))
+    };
+    let data = tokio::time::timeout(CALL_TIMEOUT, call)
+        .await
+        .map_err(|_| anyhow::anyhow!("webrtc module timed out"))??;
+    Ok(serde_json::from_slice(&data)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn requests() {
+        let request =
+            parse_message(br#"{"type": "create_transport", "channel": "demo", "offer": "v=0"}"#)
+                .unwrap();
+        assert!(matches!(
+            request,
+            Request::CreateTransport {
+                mode: Mode::Publish,
+                ..
+            }
+        ));
+        let request =
+            parse_message(br#"{"type": "get_stats", "channel": "demo", "id": 3}"#).unwrap();
+        assert!(matches!(request, Request::GetStats { id: 3, .. }));
+        assert!(parse_message(br#"{"type": "get_stats", "channel": "demo"}"#).is_err());
+        assert!(parse_message(br#"{"type": "unknown"}"#).is_err());
+    }
+
+    #[tokio::test]
+    async fn calls() {
+        let (module, mut commands) = module::Module::new();
+        tokio::spawn(async move {
+            while let Some(msg) = commands.recv().await {
+                // Other requests are dropped without a response
+                if let Ok(Request::DestroyTransport { id: 1, .. }) = parse_message(&msg.data) {
+                    let _ = msg.reply.send(encode(&Response::Done));
+                }
+            }
+        });
+
+        let destroy = |id| Request::DestroyTransport {
+            channel: "demo".to_string(),
+            id,
+        };
+        let response = call(&module.commands, &destroy(1)).await.unwrap();
+        assert!(matches!(response, Response::Done));
+        assert!(call(&module.commands, &destroy(2)).await.is_err());
+
+        let (module, commands) = module::Module::new();
+        drop(commands);
+        assert!(call(&module.commands, &destroy(1)).await.is_err());
+    }
 }
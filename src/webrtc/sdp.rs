@@ -0,0 +1,1069 @@
+use std::fmt::Write;
+use std::io::Cursor;
+
+use ::sdp::description::common::Attribute;
+use ::sdp::SessionDescription;
+
+/// Maximum size of the DataChannel messages announced to the peer.
+pub const MAX_MESSAGE_SIZE: u32 = 262144;
+const SCTP_PORT: u16 = 5000;
+/// RTCP feedback kept in the answers, the rest is not implemented
+const FEEDBACK: &[&str] = &["nack", "nack pli", "ccm fir"];
+/// Header extensions identifying the simulcast encodings (RFC 8852)
+const MID_EXTENSION: &str = "urn:ietf:params:rtp-hdrext:sdes:mid";
+const RID_EXTENSION: &str = "urn:ietf:params:rtp-hdrext:sdes:rtp-stream-id";
+
+/// DTLS role requested by the `a=setup` attribute.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Setup {
+    Active,
+    Passive,
+    ActPass,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Direction {
+    SendRecv,
+    SendOnly,
+    RecvOnly,
+    Inactive,
+}
+
+impl Direction {
+    fn parse(attributes: &[Attribute]) -> Self {
+        attributes
+            .iter()
+            .find_map(|attribute| match attribute.key.as_str() {
+                "sendrecv" => Some(Direction::SendRecv),
+                "sendonly" => Some(Direction::SendOnly),
+                "recvonly" => Some(Direction::RecvOnly),
+                "inactive" => Some(Direction::Inactive),
+                _ => None,
+            })
+            .unwrap_or(Direction::SendRecv)
+    }
+
+    fn as_str(&self) -> &'static str {
+        match self {
+            Direction::SendRecv => "sendrecv",
+            Direction::SendOnly => "sendonly",
+            Direction::RecvOnly => "recvonly",
+            Direction::Inactive => "inactive",
+        }
+    }
+
+    /// Whether media flows from the peer in a section with this direction.
+    pub fn sends(&self) -> bool {
+        matches!(self, Direction::SendRecv | Direction::SendOnly)
+    }
+
+    /// Whether media flows to the peer in a section with this direction.
+    pub fn receives(&self) -> bool {
+        matches!(self, Direction::SendRecv | Direction::RecvOnly)
+    }
+}
+
+/// RTP payload format of a media section.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Codec {
+    pub payload_type: u8,
+    pub name: String,
+    pub clock_rate: u32,
+    pub channels: u16,
+    /// Format parameters, empty when there are none
+    pub fmtp: String,
+    pub feedback: Vec<String>,
+}
+
+impl Codec {
+    fn parse(attributes: &[Attribute], payload_type: u8) -> Option<Self> {
+        let values = |key: &'static str| {
+            attributes
+                .iter()
+                .filter(move |attribute| attribute.key == key)
+                .filter_map(move |attribute| {
+                    let (format, value) = attribute.value.as_deref()?.split_once(' ')?;
+                    (format.parse::<u8>().ok()? == payload_type).then(|| value.trim().to_string())
+                })
+        };
+
+        let rtpmap = values("rtpmap").next()?;
+        let mut encoding = rtpmap.split('/');
+        let name = encoding.next()?.to_string();
+        let clock_rate = encoding.next()?.parse().ok()?;
+        let channels = encoding
+            .next()
+            .and_then(|channels| channels.parse().ok())
+            .unwrap_or(1);
+
+        Some(Self {
+            payload_type,
+            name,
+            clock_rate,
+            channels,
+            fmtp: values("fmtp").next().unwrap_or_default(),
+            feedback: values("rtcp-fb").collect(),
+        })
+    }
+
+    /// Value of a `key=value` format parameter.
+    pub fn parameter(&self, key: &str) -> Option<&str> {
+        self.fmtp.split(';').find_map(|parameter| {
+            let (name, value) = parameter.trim().split_once('=')?;
+            (name == key).then_some(value)
+        })
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Media {
+    pub kind: String,
+    pub mid: String,
+    pub protocol: String,
+    pub formats: Vec<String>,
+    pub direction: Direction,
+    pub codecs: Vec<Codec>,
+    /// Simulcast encodings sent by the peer, in the order of `a=simulcast`
+    pub rids: Vec<String>,
+    /// Header extensions by id
+    pub extensions: Vec<(u8, String)>,
+}
+
+impl Media {
+    pub fn is_data_channel(&self) -> bool {
+        self.kind == "application" && self.protocol.ends_with("SCTP")
+    }
+
+    fn extension(&self, uri: &str) -> Option<u8> {
+        self.extensions
+            .iter()
+            .find(|(_, extension)| extension == uri)
+            .map(|(id, _)| *id)
+    }
+
+    /// Id of the header extension carrying the RID of the packets.
+    pub fn rid_extension(&self) -> Option<u8> {
+        self.extension(RID_EXTENSION)
+    }
+
+    /// First offered codec among the supported ones. Non interleaved H.264
+    /// is preferred as it is the mode every decoder implements.
+    pub fn select_codec(&self, supported: &[&str]) -> Option<&Codec> {
+        let is_supported = |codec: &&Codec| {
+            supported
+                .iter()
+                .any(|name| name.eq_ignore_ascii_case(&codec.name))
+        };
+        self.codecs
+            .iter()
+            .filter(is_supported)
+            .find(|codec| {
+                !codec.name.eq_ignore_ascii_case("H264")
+                    || codec.parameter("packetization-mode") == Some("1")
+            })
+            .or_else(|| self.codecs.iter().find(is_supported))
+    }
+}
+
+/// The parts of a remote offer prism needs to answer it, bundling every
+/// media section on a single transport.
+#[derive(Debug, Clone)]
+pub struct Offer {
+    pub ice_ufrag: String,
+    pub ice_pwd: String,
+    /// SHA-256 fingerprint of the DTLS certificate of the peer
+    pub fingerprint: String,
+    pub setup: Setup,
+    pub media: Vec<Media>,
+    /// Candidates of the RTP component, without the `candidate:` prefix
+    pub candidates: Vec<String>,
+}
+
+impl Offer {
+    pub fn parse(offer: &str) -> Result<Self, anyhow::Error> {
+        let description = SessionDescription::unmarshal(&mut Cursor::new(offer.as_bytes()))?;
+
+        // Transport attributes may be in the session or in the first media section
+        let attribute = |key: &str| {
+            find(&description.attributes, key).or_else(|| {
+                description
+                    .media_descriptions
+                    .first()
+                    .and_then(|media| find(&media.attributes, key))
+            })
+        };
+
+        let ice_ufrag =
+            attribute("ice-ufrag").ok_or_else(|| anyhow::anyhow!("missing ice-ufrag"))?;
+        let ice_pwd = attribute("ice-pwd").ok_or_else(|| anyhow::anyhow!("missing ice-pwd"))?;
+        let fingerprint = match attribute("fingerprint").and_then(|value| {
+            value
+                .split_once(' ')
+                .map(|(algorithm, value)| (algorithm.to_string(), value.to_string()))
+        }) {
+            Some((algorithm, value)) if algorithm.eq_ignore_ascii_case("sha-256") => {
+                value.to_uppercase()
+            }
+            Some((algorithm, _)) => anyhow::bail!("unsupported fingerprint {}", algorithm),
+            None => anyhow::bail!("missing fingerprint"),
+        };
+        let setup = match attribute("setup").as_deref() {
+            Some("active") => Setup::Active,
+            Some("passive") => Setup::Passive,
+            _ => Setup::ActPass,
+        };
+
+        let media = parse_media(&description);
+
+        // Every section shares the same transport, RTCP is always multiplexed
+        let candidates = description
+            .attributes
+            .iter()
+            .chain(
+                description
+                    .media_descriptions
+                    .iter()
+                    .flat_map(|media| media.attributes.iter()),
+            )
+            .filter(|attribute| attribute.key == "candidate")
+            .filter_map(|attribute| attribute.value.clone())
+            .filter(|candidate| is_rtp_candidate(candidate))
+            .collect();
+
+        Ok(Self {
+            ice_ufrag,
+            ice_pwd,
+            fingerprint,
+            setup,
+            media,
+            candidates,
+        })
+    }
+
+    /// Whether prism has to start the DTLS handshake.
+    pub fn is_dtls_client(&self) -> bool {
+        self.setup == Setup::Passive
+    }
+}
+
+/// Media sections of a description, the only part of the plain RTP ones
+/// sent by publishers like ffmpeg.
+pub fn media(description: &str) -> Result<Vec<Media>, anyhow::Error> {
+    let description = SessionDescription::unmarshal(&mut Cursor::new(description.as_bytes()))?;
+    Ok(parse_media(&description))
+}
+
+fn parse_media(description: &SessionDescription) -> Vec<Media> {
+    description
+        .media_descriptions
+        .iter()
+        .enumerate()
+        .map(|(index, media)| Media {
+            kind: media.media_name.media.clone(),
+            mid: find(&media.attributes, "mid").unwrap_or_else(|| index.to_string()),
+            protocol: media.media_name.protos.join("/"),
+            formats: media.media_name.formats.clone(),
+            direction: Direction::parse(&media.attributes),
+            codecs: media
+                .media_name
+                .formats
+                .iter()
+                .filter_map(|format| format.parse().ok())
+                .filter_map(|payload_type| Codec::parse(&media.attributes, payload_type))
+                .collect(),
+            rids: parse_rids(&media.attributes),
+            extensions: media
+                .attributes
+                .iter()
+                .filter(|attribute| attribute.key == "extmap")
+                .filter_map(|attribute| {
+                    let (id, uri) = attribute.value.as_deref()?.split_once(' ')?;
+                    let id = id.split('/').next()?.parse().ok()?;
+                    Some((id, uri.split(' ').next()?.to_string()))
+                })
+                .collect(),
+        })
+        .collect()
+}
+
+fn find(attributes: &[Attribute], key: &str) -> Option<String> {
+    attributes
+        .iter()
+        .find(|attribute| attribute.key == key)
+        .and_then(|attribute| attribute.value.clone())
+}
+
+/// RIDs the peer sends (RFC 8853), the alternatives of a stream of
+/// `a=simulcast` are not supported and only the first one is kept.
+fn parse_rids(attributes: &[Attribute]) -> Vec<String> {
+    let sent: Vec<String> = attributes
+        .iter()
+        .filter(|attribute| attribute.key == "rid")
+        .filter_map(|attribute| {
+            let mut value = attribute.value.as_deref()?.split(' ');
+            let rid = value.next()?;
+            (value.next()? == "send").then(|| rid.to_string())
+        })
+        .collect();
+    let simulcast = find(attributes, "simulcast").and_then(|simulcast| {
+        let mut tokens = simulcast.split_whitespace();
+        while let Some(direction) = tokens.next() {
+            let streams = tokens.next()?;
+            if direction == "send" {
+                return Some(
+                    streams
+                        .split(';')
+                        .filter_map(|alternatives| alternatives.split(',').next())
+                        .map(|rid| rid.trim_start_matches('~').to_string())
+                        .filter(|rid| sent.contains(rid))
+                        .collect::<Vec<_>>(),
+                );
+            }
+        }
+        None
+    });
+    simulcast.unwrap_or(sent)
+}
+
+fn is_rtp_candidate(candidate: &str) -> bool {
+    candidate.split(' ').nth(1) == Some("1")
+}
+
+/// Trickle ICE SDP fragment (RFC 8840), as sent in WHIP PATCH requests.
+#[derive(Debug, Clone, Default)]
+pub struct Fragment {
+    pub ice_ufrag: Option<String>,
+    pub ice_pwd: Option<String>,
+    /// Candidates of the RTP component, without the `candidate:` prefix
+    pub candidates: Vec<String>,
+}
+
+impl Fragment {
+    pub fn parse(fragment: &str) -> Self {
+        let mut parsed = Self::default();
+        for line in fragment.lines().map(str::trim) {
+            if let Some(ufrag) = line.strip_prefix("a=ice-ufrag:") {
+                parsed.ice_ufrag = Some(ufrag.to_string());
+            } else if let Some(pwd) = line.strip_prefix("a=ice-pwd:") {
+                parsed.ice_pwd = Some(pwd.to_string());
+            } else if let Some(candidate) = line.strip_prefix("a=candidate:") {
+                if is_rtp_candidate(candidate) {
+                    parsed.candidates.push(candidate.to_string());
+                }
+            }
+        }
+        parsed
+    }
+}
+
+/// Local transport parameters announced in the answer.
+#[derive(Debug, Clone)]
+pub struct Local {
+    pub ice_ufrag: String,
+    pub ice_pwd: String,
+    pub fingerprint: String,
+    pub candidates: Vec<String>,
+}
+
+/// How prism answers a media section of the offer.
+#[derive(Debug, Clone)]
+pub enum Accept {
+    Reject,
+    DataChannel,
+    Media {
+        codec: Codec,
+        direction: Direction,
+        /// Source of the media sent by prism
+        ssrc: Option<u32>,
+        /// Simulcast encodings received
+        rids: Vec<String>,
+    },
+}
+
+/// Builds the answer to an offer, with one decision per media section.
+pub fn answer(session_id: u64, offer: &Offer, local: &Local, accepts: &[Accept]) -> String {
+    let setup = match offer.setup {
+        Setup::Passive => "active",
+        Setup::Active | Setup::ActPass => "passive",
+    };
+    let mids: Vec<&str> = offer
+        .media
+        .iter()
+        .zip(accepts)
+        .filter(|(_, accept)| !matches!(accept, Accept::Reject))
+        .map(|(media, _)| media.mid.as_str())
+        .collect();
+
+    let mut sdp = String::new();
+    let _ = write!(
+        sdp,
+        "v=0\r\no=prism {} 2 IN IP4 0.0.0.0\r\ns=-\r\nt=0 0\r\n",
+        session_id
+    );
+    if !mids.is_empty() {
+        let _ = write!(sdp, "a=group:BUNDLE {}\r\n", mids.join(" "));
+    }
+
+    for (media, accept) in offer.media.iter().zip(accepts) {
+        let formats = match accept {
+            Accept::Reject => {
+                let _ = write!(
+                    sdp,
+                    "m={} 0 {} {}\r\nc=IN IP4 0.0.0.0\r\na=mid:{}\r\na=inactive\r\n",
+                    media.kind,
+                    media.protocol,
+                    media.formats.join(" "),
+                    media.mid
+                );
+                continue;
+            }
+            Accept::DataChannel => media.formats.join(" "),
+            Accept::Media { codec, .. } => codec.payload_type.to_string(),
+        };
+
+        let _ = write!(
+            sdp,
+            "m={} 9 {} {}\r\nc=IN IP4 0.0.0.0\r\na=mid:{}\r\n",
+            media.kind, media.protocol, formats, media.mid
+        );
+        let _ = write!(
+            sdp,
+            "a=ice-ufrag:{}\r\na=ice-pwd:{}\r\na=fingerprint:sha-256 {}\r\na=setup:{}\r\n",
+            local.ice_ufrag, local.ice_pwd, local.fingerprint, setup
+        );
+        match accept {
+            Accept::Reject => {}
+            Accept::DataChannel => {
+                let _ = write!(
+                    sdp,
+                    "a=sctp-port:{}\r\na=max-message-size:{}\r\n",
+                    SCTP_PORT, MAX_MESSAGE_SIZE
+                );
+            }
+            Accept::Media {
+                codec,
+                direction,
+                ssrc,
+                rids,
+            } => {
+                write_codec(&mut sdp, codec, *direction);
+                if !rids.is_empty() {
+                    write_simulcast(&mut sdp, media, rids);
+                }
+                if let Some(ssrc) = ssrc {
+                    let _ = write!(
+                        sdp,
+                        "a=msid:prism {}\r\na=ssrc:{} cname:prism\r\n",
+                        media.mid, ssrc
+                    );
+                }
+            }
+        }
+        write_candidates(&mut sdp, &local.candidates);
+    }
+    sdp
+}
+
+/// Builds the answer fragment of an ICE restart, with the new local
+/// credentials and candidates.
+pub fn fragment(offer: &Offer, local: &Local) -> String {
+    let mut sdp = String::new();
+    let _ = write!(
+        sdp,
+        "a=ice-ufrag:{}\r\na=ice-pwd:{}\r\n",
+        local.ice_ufrag, local.ice_pwd
+    );
+    if let Some(media) = offer.media.first() {
+        let _ = write!(
+            sdp,
+            "m={} 9 {} {}\r\na=mid:{}\r\n",
+            media.kind,
+            media.protocol,
+            media.formats.join(" "),
+            media.mid
+        );
+    }
+    write_candidates(&mut sdp, &local.candidates);
+    sdp
+}
+
+fn write_codec(sdp: &mut String, codec: &Codec, direction: Direction) {
+    let _ = write!(sdp, "a={}\r\na=rtcp-mux\r\n", direction.as_str());
+    let _ = write!(
+        sdp,
+        "a=rtpmap:{} {}/{}",
+        codec.payload_type, codec.name, codec.clock_rate
+    );
+    if codec.channels > 1 {
+        let _ = write!(sdp, "/{}", codec.channels);
+    }
+    *sdp += "\r\n";
+    if !codec.fmtp.is_empty() {
+        let _ = write!(sdp, "a=fmtp:{} {}\r\n", codec.payload_type, codec.fmtp);
+    }
+    for feedback in codec
+        .feedback
+        .iter()
+        .filter(|feedback| FEEDBACK.contains(&feedback.as_str()))
+    {
+        let _ = write!(sdp, "a=rtcp-fb:{} {}\r\n", codec.payload_type, feedback);
+    }
+}
+
+fn write_simulcast(sdp: &mut String, media: &Media, rids: &[String]) {
+    for uri in [MID_EXTENSION, RID_EXTENSION] {
+        if let Some(id) = media.extension(uri) {
+            let _ = write!(sdp, "a=extmap:{} {}\r\n", id, uri);
+        }
+    }
+    for rid in rids {
+        let _ = write!(sdp, "a=rid:{} recv\r\n", rid);
+    }
+    let _ = write!(sdp, "a=simulcast:recv {}\r\n", rids.join(";"));
+}
+
+fn write_candidates(sdp: &mut String, candidates: &[String]) {
+    for candidate in candidates {
+        let _ = write!(sdp, "a=candidate:{}\r\n", candidate);
+    }
+    *sdp += "a=end-of-candidates\r\n";
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// WHIP offer of Chrome publishing a camera with simulcast.
+    const CHROME_SIMULCAST: &str = "v=0\r
+o=- 4215775240449105457 2 IN IP4 127.0.0.1\r
+s=-\r
+t=0 0\r
+a=group:BUNDLE 0 1\r
+a=extmap-allow-mixed\r
+a=msid-semantic: WMS\r
+m=audio 9 UDP/TLS/RTP/SAVPF 111 63 9 0 8 13 110 126\r
+c=IN IP4 0.0.0.0\r
+a=rtcp:9 IN IP4 0.0.0.0\r
+a=ice-ufrag:EsAw\r
+a=ice-pwd:bP+XJMM09aR8AiX1jdukzR6Y\r
+a=ice-options:trickle\r
+a=fingerprint:sha-256 da:7b:57:dc:28:ce:04:4f:31:79:85:c4:31:67:eb:27:58:29:ed:77:2a:0d:24:ae:ed:88:30:c5:a5:39:f5:2b\r
+a=setup:actpass\r
+a=mid:0\r
+a=extmap:1 urn:ietf:params:rtp-hdrext:ssrc-audio-level\r
+a=extmap:2 http://www.webrtc.org/experiments/rtp-hdrext/abs-send-time\r
+a=extmap:3 http://www.ietf.org/id/draft-holmer-rmcat-transport-wide-cc-extensions-01\r
+a=extmap:4 urn:ietf:params:rtp-hdrext:sdes:mid\r
+a=sendonly\r
+a=msid:- 6e8b4a1c-4c1a-4d9a-a0d6-0c3f9b7f4b1e\r
+a=rtcp-mux\r
+a=rtpmap:111 opus/48000/2\r
+a=rtcp-fb:111 transport-cc\r
+a=fmtp:111 minptime=10;useinbandfec=1\r
+a=rtpmap:63 red/48000/2\r
+a=fmtp:63 111/111\r
+a=rtpmap:9 G722/8000\r
+a=rtpmap:0 PCMU/8000\r
+a=rtpmap:8 PCMA/8000\r
+a=rtpmap:13 CN/8000\r
+a=rtpmap:110 telephone-event/48000\r
+a=rtpmap:126 telephone-event/8000\r
+a=ssrc:1129554364 cname:kS1xZ0mBZQnYPaWx\r
+a=ssrc:1129554364 msid:- 6e8b4a1c-4c1a-4d9a-a0d6-0c3f9b7f4b1e\r
+m=video 9 UDP/TLS/RTP/SAVPF 96 97 102 103 104 105 39 40 98 99 45 46\r
+c=IN IP4 0.0.0.0\r
+a=rtcp:9 IN IP4 0.0.0.0\r
+a=ice-ufrag:EsAw\r
+a=ice-pwd:bP+XJMM09aR8AiX1jdukzR6Y\r
+a=ice-options:trickle\r
+a=fingerprint:sha-256 da:7b:57:dc:28:ce:04:4f:31:79:85:c4:31:67:eb:27:58:29:ed:77:2a:0d:24:ae:ed:88:30:c5:a5:39:f5:2b\r
+a=setup:actpass\r
+a=mid:1\r
+a=extmap:14 urn:ietf:params:rtp-hdrext:toffset\r
+a=extmap:2 http://www.webrtc.org/experiments/rtp-hdrext/abs-send-time\r
+a=extmap:13 urn:3gpp:video-orientation\r
+a=extmap:3 http://www.ietf.org/id/draft-holmer-rmcat-transport-wide-cc-extensions-01\r
+a=extmap:4 urn:ietf:params:rtp-hdrext:sdes:mid\r
+a=extmap:10 urn:ietf:params:rtp-hdrext:sdes:rtp-stream-id\r
+a=extmap:11 urn:ietf:params:rtp-hdrext:sdes:repaired-rtp-stream-id\r
+a=sendonly\r
+a=msid:- 0f4d3a5e-7b8b-4c33-9c55-5d1c3e0c2b47\r
+a=rtcp-mux\r
+a=rtcp-rsize\r
+a=rtpmap:96 VP8/90000\r
+a=rtcp-fb:96 goog-remb\r
+a=rtcp-fb:96 transport-cc\r
+a=rtcp-fb:96 ccm fir\r
+a=rtcp-fb:96 nack\r
+a=rtcp-fb:96 nack pli\r
+a=rtpmap:97 rtx/90000\r
+a=fmtp:97 apt=96\r
+a=rtpmap:102 H264/90000\r
+a=rtcp-fb:102 goog-remb\r
+a=rtcp-fb:102 transport-cc\r
+a=rtcp-fb:102 ccm fir\r
+a=rtcp-fb:102 nack\r
+a=rtcp-fb:102 nack pli\r
+a=fmtp:102 level-asymmetry-allowed=1;packetization-mode=1;profile-level-id=42001f\r
+a=rtpmap:103 rtx/90000\r
+a=fmtp:103 apt=102\r
+a=rtpmap:104 H264/90000\r
+a=rtcp-fb:104 nack\r
+a=fmtp:104 level-asymmetry-allowed=1;packetization-mode=0;profile-level-id=42001f\r
+a=rtpmap:105 rtx/90000\r
+a=fmtp:105 apt=104\r
+a=rtpmap:39 AV1/90000\r
+a=rtcp-fb:39 nack\r
+a=rtpmap:40 rtx/90000\r
+a=fmtp:40 apt=39\r
+a=rtpmap:98 VP9/90000\r
+a=rtcp-fb:98 nack\r
+a=fmtp:98 profile-id=0\r
+a=rtpmap:99 rtx/90000\r
+a=fmtp:99 apt=98\r
+a=rtpmap:45 ulpfec/90000\r
+a=rtpmap:46 red/90000\r
+a=rid:q send\r
+a=rid:h send\r
+a=rid:f send\r
+a=simulcast:send q;h;f\r
+";
+
+    /// WHIP offer of OBS, with the transport in the session section and its
+    /// candidates gathered.
+    const OBS: &str = "v=0\r
+o=rtc 3116325374 0 IN IP4 127.0.0.1\r
+s=-\r
+t=0 0\r
+a=group:BUNDLE 0 1\r
+a=group:LS 0 1\r
+a=msid-semantic:WMS *\r
+a=setup:actpass\r
+a=ice-ufrag:V3AW\r
+a=ice-pwd:4XSqGg4b0JqXU8rXx1SNDr\r
+a=ice-options:ice2,trickle\r
+a=fingerprint:sha-256 8C:A7:1F:0B:5E:55:6A:7C:2D:93:29:1D:F4:0C:B2:D2:6F:43:22:A4:5C:DE:13:5B:06:C2:37:10:4A:51:2B:EA\r
+m=audio 56413 UDP/TLS/RTP/SAVPF 111\r
+c=IN IP4 192.168.1.20\r
+a=mid:0\r
+a=sendonly\r
+a=ssrc:3412345678 cname:obs\r
+a=msid:obs obs-audio\r
+a=rtcp-mux\r
+a=rtpmap:111 opus/48000/2\r
+a=fmtp:111 minptime=10;maxaveragebitrate=96000;stereo=1;sprop-stereo=1;useinbandfec=1\r
+a=candidate:1 1 UDP 2122317823 192.168.1.20 56413 typ host\r
+a=candidate:2 1 UDP 1686052607 203.0.113.7 56413 typ srflx raddr 192.168.1.20 rport 56413\r
+a=end-of-candidates\r
+m=video 56413 UDP/TLS/RTP/SAVPF 96\r
+c=IN IP4 192.168.1.20\r
+a=mid:1\r
+a=sendonly\r
+a=ssrc:2234567890 cname:obs\r
+a=msid:obs obs-video\r
+a=rtcp-mux\r
+a=rtpmap:96 H264/90000\r
+a=rtcp-fb:96 nack\r
+a=rtcp-fb:96 nack pli\r
+a=rtcp-fb:96 goog-remb\r
+a=fmtp:96 profile-level-id=42e01f;packetization-mode=1;level-asymmetry-allowed=1\r
+";
+
+    /// WHEP offer of Firefox, receiving only, with a passive DTLS role.
+    const FIREFOX_WHEP: &str = "v=0\r
+o=mozilla...THIS_IS_SDPARTA-99.0 6410339224916128183 0 IN IP4 0.0.0.0\r
+s=-\r
+t=0 0\r
+a=fingerprint:sha-256 3F:1B:AD:6A:52:E1:1E:19:75:02:5E:8F:C5:74:86:58:E9:4F:0A:77:95:5D:8E:2C:0E:AC:5A:7A:54:D9:52:34\r
+a=group:BUNDLE 0 1\r
+a=ice-options:trickle\r
+a=msid-semantic:WMS *\r
+m=audio 9 UDP/TLS/RTP/SAVPF 109 9 0 8 101\r
+c=IN IP4 0.0.0.0\r
+a=recvonly\r
+a=extmap:1 urn:ietf:params:rtp-hdrext:ssrc-audio-level\r
+a=extmap:3 urn:ietf:params:rtp-hdrext:sdes:mid\r
+a=fmtp:109 maxplaybackrate=48000;stereo=1;useinbandfec=1\r
+a=fmtp:101 0-15\r
+a=ice-pwd:e7a4b3c2d1f0e9a8b7c6d5e4f3a2b1c0\r
+a=ice-ufrag:0a1b2c3d\r
+a=mid:0\r
+a=rtcp-mux\r
+a=rtpmap:109 opus/48000/2\r
+a=rtpmap:9 G722/8000/1\r
+a=rtpmap:0 PCMU/8000\r
+a=rtpmap:8 PCMA/8000\r
+a=rtpmap:101 telephone-event/8000\r
+a=setup:passive\r
+a=ssrc:2853624791 cname:{5f0f1c2a-3b4c-4d5e-8f60-718293a4b5c6}\r
+a=candidate:0 1 UDP 2122252543 10.0.0.2 53211 typ host\r
+a=candidate:0 2 UDP 2122252542 10.0.0.2 53212 typ host\r
+m=video 9 UDP/TLS/RTP/SAVPF 120 124 121 125 126 127 97 98\r
+c=IN IP4 0.0.0.0\r
+a=recvonly\r
+a=extmap:3 urn:ietf:params:rtp-hdrext:sdes:mid\r
+a=fmtp:126 profile-level-id=42e01f;level-asymmetry-allowed=1;packetization-mode=1\r
+a=fmtp:97 profile-level-id=42e01f;level-asymmetry-allowed=1\r
+a=fmtp:120 max-fs=12288;max-fr=60\r
+a=fmtp:124 apt=120\r
+a=fmtp:121 max-fs=12288;max-fr=60\r
+a=fmtp:125 apt=121\r
+a=fmtp:127 apt=126\r
+a=fmtp:98 apt=97\r
+a=ice-pwd:e7a4b3c2d1f0e9a8b7c6d5e4f3a2b1c0\r
+a=ice-ufrag:0a1b2c3d\r
+a=mid:1\r
+a=rtcp-fb:120 nack\r
+a=rtcp-fb:120 nack pli\r
+a=rtcp-fb:120 ccm fir\r
+a=rtcp-fb:120 goog-remb\r
+a=rtcp-fb:120 transport-cc\r
+a=rtcp-fb:126 nack\r
+a=rtcp-fb:126 nack pli\r
+a=rtcp-mux\r
+a=rtcp-rsize\r
+a=rtpmap:120 VP8/90000\r
+a=rtpmap:124 rtx/90000\r
+a=rtpmap:121 VP9/90000\r
+a=rtpmap:125 rtx/90000\r
+a=rtpmap:126 H264/90000\r
+a=rtpmap:127 rtx/90000\r
+a=rtpmap:97 H264/90000\r
+a=rtpmap:98 rtx/90000\r
+a=setup:passive\r
+";
+
+    /// Offer of Chrome opening a DataChannel.
+    const CHROME_DATACHANNEL: &str = "v=0\r
+o=- 7356024157624437447 2 IN IP4 127.0.0.1\r
+s=-\r
+t=0 0\r
+a=group:BUNDLE 0\r
+a=extmap-allow-mixed\r
+a=msid-semantic: WMS\r
+m=application 9 UDP/DTLS/SCTP webrtc-datachannel\r
+c=IN IP4 0.0.0.0\r
+a=ice-ufrag:Hb6R\r
+a=ice-pwd:p1Xb7nWz3e8VqfSk+DcQ2m0Z\r
+a=ice-options:trickle\r
+a=fingerprint:sha-256 1E:57:6C:23:9A:B3:54:AF:3D:4E:BC:C3:46:2B:9D:73:2E:DE:29:6F:47:0D:6D:B7:0F:AE:55:1E:46:C5:6E:FE\r
+a=setup:actpass\r
+a=mid:0\r
+a=sctp-port:5000\r
+a=max-message-size:262144\r
+";
+
+    struct Expected {
+        offer: &'static str,
+        ice_ufrag: &'static str,
+        fingerprint_start: &'static str,
+        setup: Setup,
+        /// Kind, mid and direction of every section
+        media: &'static [(&'static str, &'static str, Direction)],
+        /// Codec chosen with every video codec, then with only H.264
+        video: Option<(u8, u8)>,
+        rids: &'static [&'static str],
+        candidates: usize,
+    }
+
+    const OFFERS: &[Expected] = &[
+        Expected {
+            offer: CHROME_SIMULCAST,
+            ice_ufrag: "EsAw",
+            fingerprint_start: "DA:7B:57",
+            setup: Setup::ActPass,
+            media: &[
+                ("audio", "0", Direction::SendOnly),
+                ("video", "1", Direction::SendOnly),
+            ],
+            video: Some((96, 102)),
+            rids: &["q", "h", "f"],
+            candidates: 0,
+        },
+        Expected {
+            offer: OBS,
+            ice_ufrag: "V3AW",
+            fingerprint_start: "8C:A7:1F",
+            setup: Setup::ActPass,
+            media: &[
+                ("audio", "0", Direction::SendOnly),
+                ("video", "1", Direction::SendOnly),
+            ],
+            video: Some((96, 96)),
+            rids: &[],
+            candidates: 2,
+        },
+        Expected {
+            offer: FIREFOX_WHEP,
+            ice_ufrag: "0a1b2c3d",
+            fingerprint_start: "3F:1B:AD",
+            setup: Setup::Passive,
+            media: &[
+                ("audio", "0", Direction::RecvOnly),
+                ("video", "1", Direction::RecvOnly),
+            ],
+            video: Some((120, 126)),
+            rids: &[],
+            candidates: 1,
+        },
+        Expected {
+            offer: CHROME_DATACHANNEL,
+            ice_ufrag: "Hb6R",
+            fingerprint_start: "1E:57:6C",
+            setup: Setup::ActPass,
+            media: &[("application", "0", Direction::SendRecv)],
+            video: None,
+            rids: &[],
+            candidates: 0,
+        },
+    ];
+
+    const VIDEO: &[&str] = &["H264", "VP8", "VP9", "AV1"];
+
+    fn local() -> Local {
+        Local {
+            ice_ufrag: "prsm".to_string(),
+            ice_pwd: "0123456789abcdef01234567".to_string(),
+            fingerprint: "AA:BB".to_string(),
+            candidates: vec!["1 1 udp 2130706431 192.0.2.1 4435 typ host".to_string()],
+        }
+    }
+
+    #[test]
+    fn offers() {
+        for expected in OFFERS {
+            let offer = Offer::parse(expected.offer).unwrap();
+            assert_eq!(offer.ice_ufrag, expected.ice_ufrag);
+            assert!(offer.fingerprint.starts_with(expected.fingerprint_start));
+            assert_eq!(offer.fingerprint.len(), 95);
+            assert_eq!(offer.setup, expected.setup);
+            assert_eq!(offer.is_dtls_client(), expected.setup == Setup::Passive);
+            assert_eq!(offer.candidates.len(), expected.candidates);
+
+            let media: Vec<(&str, &str, Direction)> = offer
+                .media
+                .iter()
+                .map(|media| (media.kind.as_str(), media.mid.as_str(), media.direction))
+                .collect();
+            assert_eq!(media, expected.media);
+
+            let video = offer.media.iter().find(|media| media.kind == "video");
+            let chosen = video.map(|video| {
+                (
+                    video.select_codec(VIDEO).unwrap().payload_type,
+                    video.select_codec(&["H264"]).unwrap().payload_type,
+                )
+            });
+            assert_eq!(chosen, expected.video);
+            let rids = video.map(|video| video.rids.clone()).unwrap_or_default();
+            assert_eq!(rids, expected.rids);
+
+            if let Some(audio) = offer.media.iter().find(|media| media.kind == "audio") {
+                let opus = audio.select_codec(&["opus"]).unwrap();
+                assert_eq!((opus.clock_rate, opus.channels), (48000, 2));
+                assert_eq!(opus.parameter("useinbandfec"), Some("1"));
+            }
+            let data_channel = offer.media.iter().any(Media::is_data_channel);
+            assert_eq!(data_channel, expected.offer == CHROME_DATACHANNEL);
+        }
+    }
+
+    #[test]
+    fn simulcast_offer() {
+        let offer = Offer::parse(CHROME_SIMULCAST).unwrap();
+        let video = &offer.media[1];
+        assert_eq!(video.rid_extension(), Some(10));
+        let h264 = video.select_codec(&["H264"]).unwrap();
+        assert_eq!(h264.parameter("profile-level-id"), Some("42001f"));
+        assert_eq!(
+            h264.feedback,
+            ["goog-remb", "transport-cc", "ccm fir", "nack", "nack pli"]
+        );
+        let rtx = video
+            .codecs
+            .iter()
+            .find(|codec| codec.payload_type == 103)
+            .unwrap();
+        assert_eq!(rtx.parameter("apt"), Some("102"));
+    }
+
+    #[test]
+    fn invalid_offers() {
+        let without = |key: &str| {
+            CHROME_DATACHANNEL
+                .lines()
+                .filter(|line| !line.starts_with(key))
+                .map(|line| format!("{}\n", line))
+                .collect::<String>()
+        };
+        assert!(Offer::parse(&without("a=ice-ufrag")).is_err());
+        assert!(Offer::parse(&without("a=ice-pwd")).is_err());
+        assert!(Offer::parse(&without("a=fingerprint")).is_err());
+        let sha1 = CHROME_DATACHANNEL.replace("sha-256 1E:57", "sha-1 1E:57");
+        assert!(Offer::parse(&sha1).is_err());
+        assert!(Offer::parse("not an offer").is_err());
+    }
+
+    #[test]
+    fn publish_answer() {
+        let offer = Offer::parse(CHROME_SIMULCAST).unwrap();
+        let accepts = [
+            Accept::Media {
+                codec: offer.media[0].select_codec(&["opus"]).unwrap().clone(),
+                direction: Direction::RecvOnly,
+                ssrc: None,
+                rids: Vec::new(),
+            },
+            Accept::Media {
+                codec: offer.media[1].select_codec(VIDEO).unwrap().clone(),
+                direction: Direction::RecvOnly,
+                ssrc: None,
+                rids: offer.media[1].rids.clone(),
+            },
+        ];
+        let answer = answer(7, &offer, &local(), &accepts);
+        let lines: Vec<&str> = answer.lines().collect();
+
+        assert!(lines.contains(&"o=prism 7 2 IN IP4 0.0.0.0"));
+        assert!(lines.contains(&"a=group:BUNDLE 0 1"));
+        assert!(lines.contains(&"m=audio 9 UDP/TLS/RTP/SAVPF 111"));
+        assert!(lines.contains(&"m=video 9 UDP/TLS/RTP/SAVPF 96"));
+        assert!(lines.contains(&"a=rtpmap:111 opus/48000/2"));
+        assert!(lines.contains(&"a=fmtp:111 minptime=10;useinbandfec=1"));
+        assert!(lines.contains(&"a=rtpmap:96 VP8/90000"));
+        assert!(lines.contains(&"a=setup:passive"));
+        assert!(lines.contains(&"a=fingerprint:sha-256 AA:BB"));
+        assert_eq!(
+            lines.iter().filter(|line| **line == "a=recvonly").count(),
+            2
+        );
+
+        // Only the implemented feedback is kept
+        let feedback: Vec<&&str> = lines
+            .iter()
+            .filter(|line| line.starts_with("a=rtcp-fb:96"))
+            .collect();
+        assert_eq!(
+            feedback,
+            [
+                &"a=rtcp-fb:96 ccm fir",
+                &"a=rtcp-fb:96 nack",
+                &"a=rtcp-fb:96 nack pli"
+            ]
+        );
+        assert!(!answer.contains("transport-cc"));
+
+        assert!(lines.contains(&"a=extmap:4 urn:ietf:params:rtp-hdrext:sdes:mid"));
+        assert!(lines.contains(&"a=extmap:10 urn:ietf:params:rtp-hdrext:sdes:rtp-stream-id"));
+        assert!(lines.contains(&"a=rid:q recv"));
+        assert!(lines.contains(&"a=simulcast:recv q;h;f"));
+        assert_eq!(
+            lines
+                .iter()
+                .filter(|line| **line == "a=end-of-candidates")
+                .count(),
+            2
+        );
+
+        // The answer is itself a valid description
+        let parsed = Offer::parse(&answer).unwrap();
+        assert_eq!(parsed.ice_ufrag, "prsm");
+        assert_eq!(parsed.setup, Setup::Passive);
+        assert_eq!(parsed.candidates.len(), 2);
+        assert_eq!(parsed.media[1].rids, Vec::<String>::new());
+    }
+
+    #[test]
+    fn subscribe_answer() {
+        let offer = Offer::parse(FIREFOX_WHEP).unwrap();
+        let accepts = [
+            Accept::Reject,
+            Accept::Media {
+                codec: offer.media[1].select_codec(&["H264"]).unwrap().clone(),
+                direction: Direction::SendOnly,
+                ssrc: Some(1234),
+                rids: Vec::new(),
+            },
+        ];
+        let answer = answer(8, &offer, &local(), &accepts);
+        let lines: Vec<&str> = answer.lines().collect();
+
+        assert!(lines.contains(&"a=group:BUNDLE 1"));
+        assert!(lines.contains(&"m=audio 0 UDP/TLS/RTP/SAVPF 109 9 0 8 101"));
+        assert!(lines.contains(&"a=inactive"));
+        assert!(lines.contains(&"m=video 9 UDP/TLS/RTP/SAVPF 126"));
+        assert!(lines.contains(&"a=sendonly"));
+        assert!(lines.contains(
+            &"a=fmtp:126 profile-level-id=42e01f;level-asymmetry-allowed=1;packetization-mode=1"
+        ));
+        assert!(lines.contains(&"a=msid:prism 1"));
+        assert!(lines.contains(&"a=ssrc:1234 cname:prism"));
+        // Firefox is passive, prism starts the DTLS handshake
+        assert!(lines.contains(&"a=setup:active"));
+        assert_eq!(
+            lines
+                .iter()
+                .filter(|line| line.starts_with("a=candidate"))
+                .count(),
+            1
+        );
+    }
+
+    #[test]
+    fn data_channel_answer() {
+        let offer = Offer::parse(CHROME_DATACHANNEL).unwrap();
+        let answer = answer(9, &offer, &local(), &[Accept::DataChannel]);
+        let lines: Vec<&str> = answer.lines().collect();
+        assert!(lines.contains(&"a=group:BUNDLE 0"));
+        assert!(lines.contains(&"m=application 9 UDP/DTLS/SCTP webrtc-datachannel"));
+        assert!(lines.contains(&"a=sctp-port:5000"));
+        assert!(lines.contains(&"a=max-message-size:262144"));
+        assert!(!answer.contains("a=rtpmap"));
+    }
+
+    #[test]
+    fn fragments() {
+        let fragment = Fragment::parse(
+            "a=ice-ufrag:EsAw\r\na=ice-pwd:bP+XJMM09aR8AiX1jdukzR6Y\r\nm=audio 9 UDP/TLS/RTP/SAVPF 0\r\na=mid:0\r\n\
+             a=candidate:1 1 udp 2113937151 192.0.2.2 54400 typ host\r\n\
+             a=candidate:1 2 udp 2113937150 192.0.2.2 54401 typ host\r\na=end-of-candidates\r\n",
+        );
+        assert_eq!(fragment.ice_ufrag.as_deref(), Some("EsAw"));
+        assert_eq!(
+            fragment.ice_pwd.as_deref(),
+            Some("bP+XJMM09aR8AiX1jdukzR6Y")
+        );
+        assert_eq!(
+            fragment.candidates,
+            ["1 1 udp 2113937151 192.0.2.2 54400 typ host"]
+        );
+        assert!(Fragment::parse("").candidates.is_empty());
+
+        let offer = Offer::parse(OBS).unwrap();
+        let restart = super::fragment(&offer, &local());
+        assert_eq!(
+            restart,
+            "a=ice-ufrag:prsm\r\na=ice-pwd:0123456789abcdef01234567\r\n\
+             m=audio 9 UDP/TLS/RTP/SAVPF 111\r\na=mid:0\r\n\
+             a=candidate:1 1 udp 2130706431 192.0.2.1 4435 typ host\r\na=end-of-candidates\r\n"
+        );
+    }
+
+    #[test]
+    fn plain_rtp_media() {
+        let media = media(
+            "v=0\r\no=- 0 0 IN IP4 127.0.0.1\r\ns=No Name\r\nc=IN IP4 127.0.0.1\r\nt=0 0\r\n\
+             a=tool:libavformat 60.16.100\r\nm=video 5004 RTP/AVP 96\r\nb=AS:200\r\n\
+             a=rtpmap:96 VP9/90000\r\nm=audio 5004 RTP/AVP 97\r\nb=AS:64\r\n\
+             a=rtpmap:97 opus/48000/2\r\na=fmtp:97 sprop-stereo=1\r\n",
+        )
+        .unwrap();
+        assert_eq!(media.len(), 2);
+        assert_eq!(
+            (media[0].mid.as_str(), media[0].protocol.as_str()),
+            ("0", "RTP/AVP")
+        );
+        assert_eq!(media[0].codecs[0].name, "VP9");
+        assert_eq!(media[1].codecs[0].parameter("sprop-stereo"), Some("1"));
+    }
+}
@@ -0,0 +1,305 @@
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use super::router::Track;
+
+/// Fractions of packets lost (over 256) reported by a subscriber above which
+/// a lower layer is forwarded, and below which a higher one is tried
+const HIGH_LOSS: u8 = 25;
+const LOW_LOSS: u8 = 5;
+/// Time without losses before trying a higher layer, doubled every time the
+/// higher layer is given up soon after
+const MIN_HOLD: Duration = Duration::from_secs(5);
+const MAX_HOLD: Duration = Duration::from_secs(60);
+
+/// Whether a packet starts a keyframe, the point where a subscriber can
+/// switch to a layer. Audio packets always are.
+pub fn is_keyframe(codec: &str, payload: &[u8]) -> bool {
+    match codec.to_ascii_uppercase().as_str() {
+        "VP8" => is_vp8_keyframe(payload),
+        "VP9" => payload
+            .first()
+            .is_some_and(|descriptor| descriptor & 0x40 == 0 && descriptor & 0x08 != 0),
+        "H264" => is_h264_keyframe(payload),
+        // N bit of the aggregation header, first packet of a coded video sequence
+        "AV1" => payload.first().is_some_and(|header| header & 0x08 != 0),
+        _ => true,
+    }
+}
+
+/// Start of partition 0 of a frame whose P bit is not set (RFC 7741).
+fn is_vp8_keyframe(payload: &[u8]) -> bool {
+    let descriptor = match payload.first() {
+        Some(descriptor) => *descriptor,
+        None => return false,
+    };
+    if descriptor & 0x10 == 0 || descriptor & 0x07 != 0 {
+        return false;
+    }
+    let mut offset = 1;
+    if descriptor & 0x80 != 0 {
+        let extensions = match payload.get(offset) {
+            Some(extensions) => *extensions,
+            None => return false,
+        };
+        offset += 1;
+        if extensions & 0x80 != 0 {
+            // Picture id on 7 or 15 bits
+            match payload.get(offset) {
+                Some(picture_id) if picture_id & 0x80 != 0 => offset += 2,
+                Some(_) => offset += 1,
+                None => return false,
+            }
+        }
+        if extensions & 0x40 != 0 {
+            offset += 1;
+        }
+        if extensions & 0x30 != 0 {
+            offset += 1;
+        }
+    }
+    payload.get(offset).is_some_and(|header| header & 0x01 == 0)
+}
+
+/// IDR or parameter sets, alone, aggregated or starting a fragmented unit
+/// (RFC 6184).
+fn is_h264_keyframe(payload: &[u8]) -> bool {
+    let is_key = |nal_type: u8| nal_type == 5 || nal_type == 7;
+    match payload.first().map(|header| header & 0x1f) {
+        Some(24) => {
+            let mut units = &payload[1..];
+            while units.len() > 2 {
+                let size = u16::from_be_bytes([units[0], units[1]]) as usize;
+                if is_key(units[2] & 0x1f) {
+                    return true;
+                }
+                units = units.get(2 + size..).unwrap_or_default();
+            }
+            false
+        }
+        Some(28) => payload
+            .get(1)
+            .is_some_and(|header| header & 0x80 != 0 && is_key(header & 0x1f)),
+        Some(nal_type) => is_key(nal_type),
+        None => false,
+    }
+}
+
+/// Chooses the layer forwarded to a subscriber: the one it asked for, or the
+/// highest one its reception keeps up with.
+pub struct Selector {
+    /// Layer asked for, by RID or by index from the lowest
+    preference: Option<String>,
+    /// Highest layer the losses allow
+    limit: usize,
+    /// Since when the losses were low enough to try a higher layer
+    stable_since: Instant,
+    hold: Duration,
+    upgraded_at: Option<Instant>,
+}
+
+impl Default for Selector {
+    fn default() -> Self {
+        Self {
+            preference: None,
+            limit: usize::MAX,
+            stable_since: Instant::now(),
+            hold: MIN_HOLD,
+            upgraded_at: None,
+        }
+    }
+}
+
+impl Selector {
+    pub fn prefer(&mut self, preference: Option<String>) {
+        self.preference = preference;
+    }
+
+    /// Position of the layer to forward among the layers of a source.
+    pub fn select(&self, layers: &[Arc<Track>]) -> usize {
+        let highest = layers.len().saturating_sub(1);
+        let preferred = match &self.preference {
+            Some(preference) => layers
+                .iter()
+                .position(|track| {
+                    track
+                        .layer
+                        .as_ref()
+                        .and_then(|layer| layer.rid.as_ref())
+                        .is_some_and(|rid| rid == preference)
+                })
+                .or_else(|| preference.parse().ok())
+                .unwrap_or(highest)
+                .min(highest),
+            None => highest,
+        };
+        preferred.min(self.limit)
+    }
+
+    /// Adapts to the fraction of packets lost reported by the subscriber
+    /// for the layer at a position.
+    pub fn report_loss(&mut self, current: usize, fraction_lost: u8) {
+        let now = Instant::now();
+        if fraction_lost > HIGH_LOSS {
+            if current > 0 {
+                self.limit = current - 1;
+                // The higher layer did not hold, wait longer before the next try
+                if self
+                    .upgraded_at
+                    .is_some_and(|at| now.duration_since(at) < self.hold)
+                {
+                    self.hold = (self.hold * 2).min(MAX_HOLD);
+                }
+                self.upgraded_at = None;
+            }
+            self.stable_since = now;
+        } else if fraction_lost >= LOW_LOSS {
+            self.stable_since = now;
+        } else if self.limit <= current && now.duration_since(self.stable_since) >= self.hold {
+            self.limit = current + 1;
+            self.upgraded_at = Some(now);
+            self.stable_since = now;
+        } else if self
+            .upgraded_at
+            .is_some_and(|at| now.duration_since(at) >= MAX_HOLD)
+        {
+            // The layer held, the next losses are not its fault
+            self.hold = MIN_HOLD;
+            self.upgraded_at = None;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::router::{Kind, Layer, Router};
+    use super::super::sdp::Codec;
+    use super::*;
+
+    fn layers(rids: &[&str]) -> Vec<Arc<Track>> {
+        let router = Router::default();
+        let codec = Codec {
+            payload_type: 96,
+            name: "VP8".to_string(),
+            clock_rate: 90000,
+            channels: 0,
+            fmtp: String::new(),
+            feedback: Vec::new(),
+        };
+        rids.iter()
+            .enumerate()
+            .map(|(i, rid)| {
+                let layer = Layer {
+                    source: 1,
+                    rid: Some(rid.to_string()),
+                };
+                router.add_track(i as u32 + 1, Kind::Video, codec.clone(), Some(layer))
+            })
+            .collect()
+    }
+
+    #[test]
+    fn keyframes() {
+        let cases: &[(&str, &[u8], bool)] = &[
+            // VP8 start of partition 0, with and without the P bit
+            ("VP8", &[0x10, 0x00], true),
+            ("VP8", &[0x10, 0x01], false),
+            ("vp8", &[0x00, 0x00], false),
+            // Picture id on 15 bits, TL0PICIDX and TID/KEYIDX
+            ("VP8", &[0x90, 0xf0, 0x80, 0x01, 0x02, 0x03, 0x9c], true),
+            ("VP8", &[0x90, 0x80, 0x80], false),
+            // VP9 start of a frame which is not inter predicted
+            ("VP9", &[0x08], true),
+            ("VP9", &[0x48], false),
+            // H.264 IDR, SPS, STAP-A with an SPS, FU-A start of an IDR
+            ("H264", &[0x65], true),
+            ("H264", &[0x67], true),
+            ("H264", &[0x41], false),
+            (
+                "H264",
+                &[0x78, 0x00, 0x01, 0x09, 0x00, 0x02, 0x67, 0x42],
+                true,
+            ),
+            ("H264", &[0x78, 0x00, 0x01, 0x09, 0x00, 0x01, 0x41], false),
+            ("H264", &[0x7c, 0x85], true),
+            ("H264", &[0x7c, 0x05], false),
+            ("H264", &[], false),
+            ("AV1", &[0x08], true),
+            ("AV1", &[0x00], false),
+            ("opus", &[], true),
+        ];
+        for (codec, payload, expected) in cases {
+            assert_eq!(
+                is_keyframe(codec, payload),
+                *expected,
+                "{} {:02x?}",
+                codec,
+                payload
+            );
+        }
+    }
+
+    #[test]
+    fn preferred_layers() {
+        let layers = layers(&["q", "h", "f"]);
+        let mut selector = Selector::default();
+        assert_eq!(selector.select(&layers), 2);
+
+        selector.prefer(Some("h".to_string()));
+        assert_eq!(selector.select(&layers), 1);
+        // By index from the lowest when no RID matches
+        selector.prefer(Some("0".to_string()));
+        assert_eq!(selector.select(&layers), 0);
+        selector.prefer(Some("7".to_string()));
+        assert_eq!(selector.select(&layers), 2);
+        // Falls back to the highest layer
+        selector.prefer(Some("unknown".to_string()));
+        assert_eq!(selector.select(&layers), 2);
+        assert_eq!(selector.select(&layers[..1]), 0);
+        assert_eq!(selector.select(&[]), 0);
+    }
+
+    #[test]
+    fn losses() {
+        let layers = layers(&["q", "h", "f"]);
+        let mut selector = Selector::default();
+
+        // High losses step down one layer at a time, down to the lowest
+        selector.report_loss(2, HIGH_LOSS + 1);
+        assert_eq!(selector.select(&layers), 1);
+        selector.report_loss(1, 128);
+        assert_eq!(selector.select(&layers), 0);
+        selector.report_loss(0, 255);
+        assert_eq!(selector.select(&layers), 0);
+        // Even when a higher one is asked for
+        selector.prefer(Some("f".to_string()));
+        assert_eq!(selector.select(&layers), 0);
+
+        // No upgrade before the losses stayed low for the hold time
+        selector.report_loss(0, 0);
+        assert_eq!(selector.select(&layers), 0);
+        selector.stable_since -= MIN_HOLD;
+        selector.report_loss(0, LOW_LOSS);
+        assert_eq!(selector.select(&layers), 0);
+        selector.stable_since -= MIN_HOLD;
+        selector.report_loss(0, LOW_LOSS - 1);
+        assert_eq!(selector.select(&layers), 1);
+
+        // Giving the higher layer up soon after doubles the hold time
+        selector.report_loss(1, HIGH_LOSS + 1);
+        assert_eq!(selector.select(&layers), 0);
+        assert_eq!(selector.hold, MIN_HOLD * 2);
+        selector.stable_since -= MIN_HOLD;
+        selector.report_loss(0, 0);
+        assert_eq!(selector.select(&layers), 0);
+        selector.stable_since -= MIN_HOLD;
+        selector.report_loss(0, 0);
+        assert_eq!(selector.select(&layers), 1);
+
+        // Until the layer held long enough
+        selector.upgraded_at = selector.upgraded_at.map(|at| at - MAX_HOLD);
+        selector.report_loss(1, 0);
+        assert_eq!(selector.hold, MIN_HOLD);
+        assert_eq!(selector.upgraded_at, None);
+    }
+}
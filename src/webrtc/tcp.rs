@@ -0,0 +1,284 @@
+use std::collections::HashMap;
+use std::io;
+use std::net::SocketAddr;
+use std::sync::Arc;
+
+use async_trait::async_trait;
+use stun::attributes::{ATTR_USERNAME, ATTR_USE_CANDIDATE};
+use stun::fingerprint::FINGERPRINT;
+use stun::integrity::MessageIntegrity;
+use stun::message::{Getter, Message, BINDING_REQUEST, BINDING_SUCCESS};
+use stun::textattrs::Username;
+use stun::xoraddr::XorMappedAddress;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::tcp::{OwnedReadHalf, OwnedWriteHalf};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::{mpsc, watch, Mutex};
+use tracing::*;
+use webrtc_util::Conn;
+
+type Result<T> = std::result::Result<T, webrtc_util::Error>;
+
+/// Passive ICE-TCP (RFC 6544) candidates on a single port, the connections
+/// are matched to the transports by the local ufrag of their STUN checks.
+pub struct Listener {
+    port: u16,
+    sessions: std::sync::Mutex<HashMap<String, Session>>,
+}
+
+#[derive(Clone)]
+struct Session {
+    id: u64,
+    ice_pwd: String,
+    conns: mpsc::Sender<Arc<TcpConn>>,
+}
+
+impl Listener {
+    pub async fn bind(addr: SocketAddr) -> anyhow::Result<Arc<Self>> {
+        let listener = TcpListener::bind(addr).await?;
+        info!("listening webrtc ice-tcp on {}", listener.local_addr()?);
+        let this = Arc::new(Self {
+            port: listener.local_addr()?.port(),
+            sessions: Default::default(),
+        });
+
+        let cloned = this.clone();
+        tokio::spawn(async move {
+            loop {
+                let (stream, remote) = match listener.accept().await {
+                    Ok(conn) => conn,
+                    Err(err) => {
+                        error!("webrtc ice-tcp accept failed: {}", err);
+                        continue;
+                    }
+                };
+                let this = cloned.clone();
+                tokio::spawn(async move {
+                    if let Err(err) = this.handshake(stream, remote).await {
+                        debug!("webrtc ice-tcp from {} failed: {}", remote, err);
+                    }
+                });
+            }
+        });
+        Ok(this)
+    }
+
+    /// Passive candidate announced for each address of the UDP candidates.
+    pub fn candidate(&self, index: usize, address: &str) -> String {
+        // Type preference of host candidates, below UDP with the local
+        // preference of passive ones (RFC 6544 4.2)
+        let priority: u32 = (126 << 24) | ((4 * 8192 + 8191) << 8) | 255;
+        format!(
+            "{} 1 tcp {} {} {} typ host tcptype passive",
+            2000 + index,
+            priority,
+            address,
+            self.port
+        )
+    }
+
+    /// Delivers the connections nominated for the credentials of a transport.
+    pub fn register(
+        &self,
+        id: u64,
+        ice_ufrag: String,
+        ice_pwd: String,
+    ) -> mpsc::Receiver<Arc<TcpConn>> {
+        let (conns, rx) = mpsc::channel(1);
+        self.sessions
+            .lock()
+            .unwrap()
+            .insert(ice_ufrag, Session { id, ice_pwd, conns });
+        rx
+    }
+
+    /// Moves a transport to the credentials of its restarted ICE session.
+    pub fn update(&self, id: u64, ice_ufrag: String, ice_pwd: String) {
+        let mut sessions = self.sessions.lock().unwrap();
+        let session = sessions
+            .iter()
+            .find(|(_, session)| session.id == id)
+            .map(|(ufrag, session)| (ufrag.clone(), session.conns.clone()));
+        if let Some((ufrag, conns)) = session {
+            sessions.remove(&ufrag);
+            sessions.insert(ice_ufrag, Session { id, ice_pwd, conns });
+        }
+    }
+
+    pub fn unregister(&self, id: u64) {
+        self.sessions
+            .lock()
+            .unwrap()
+            .retain(|_, session| session.id != id);
+    }
+
+    /// Answers the connectivity checks of a connection until the peer
+    /// nominates it.
+    async fn handshake(&self, stream: TcpStream, remote: SocketAddr) -> anyhow::Result<()> {
+        let local = stream.local_addr()?;
+        let (mut reader, mut writer) = stream.into_split();
+        loop {
+            let packet = read_frame(&mut reader).await?;
+            let mut message = decode(&packet)?;
+            if message.typ != BINDING_REQUEST {
+                anyhow::bail!("unexpected stun message {}", message.typ);
+            }
+            let mut username = Username::new(ATTR_USERNAME, String::new());
+            username.get_from(&message)?;
+            let ice_ufrag = username.to_string();
+            let ice_ufrag = ice_ufrag.split(':').next().unwrap_or_default();
+            let session = match self.sessions.lock().unwrap().get(ice_ufrag) {
+                Some(session) => session.clone(),
+                None => anyhow::bail!("unknown ufrag {}", ice_ufrag),
+            };
+
+            let response = binding_success(&mut message, &session.ice_pwd, remote)?;
+            write_frame(&mut writer, &response).await?;
+            if message.contains(ATTR_USE_CANDIDATE) {
+                info!("webrtc ice-tcp nominated from {}", remote);
+                let conn = TcpConn {
+                    reader: Mutex::new(reader),
+                    writer: Mutex::new(writer),
+                    ice_pwd: session.ice_pwd,
+                    local,
+                    remote,
+                    finished: watch::channel(false).0,
+                };
+                let _ = session.conns.send(Arc::new(conn)).await;
+                return Ok(());
+            }
+        }
+    }
+}
+
+/// ICE-TCP connection, framing packets as in RFC 4571 and answering the
+/// consent checks of the peer.
+pub struct TcpConn {
+    reader: Mutex<OwnedReadHalf>,
+    writer: Mutex<OwnedWriteHalf>,
+    ice_pwd: String,
+    local: SocketAddr,
+    remote: SocketAddr,
+    finished: watch::Sender<bool>,
+}
+
+impl TcpConn {
+    /// Resolves once the peer closed the connection.
+    pub async fn finished(&self) {
+        let mut finished = self.finished.subscribe();
+        while !*finished.borrow_and_update() {
+            if finished.changed().await.is_err() {
+                return;
+            }
+        }
+    }
+}
+
+#[async_trait]
+impl Conn for TcpConn {
+    async fn connect(&self, _addr: SocketAddr) -> Result<()> {
+        Err(io::Error::new(io::ErrorKind::Unsupported, "not applicable").into())
+    }
+
+    async fn recv(&self, buf: &mut [u8]) -> Result<usize> {
+        let mut reader = self.reader.lock().await;
+        loop {
+            let packet = match read_frame(&mut reader).await {
+                Ok(packet) => packet,
+                Err(err) => {
+                    self.finished.send_replace(true);
+                    return Err(err.into());
+                }
+            };
+            if !stun::message::is_message(&packet) {
+                let n = packet.len().min(buf.len());
+                buf[..n].copy_from_slice(&packet[..n]);
+                return Ok(n);
+            }
+            let response = decode(&packet)
+                .and_then(|mut message| binding_success(&mut message, &self.ice_pwd, self.remote));
+            match response {
+                Ok(response) => {
+                    write_frame(&mut *self.writer.lock().await, &response).await?;
+                }
+                Err(err) => debug!("webrtc ice-tcp invalid stun message: {}", err),
+            }
+        }
+    }
+
+    async fn recv_from(&self, buf: &mut [u8]) -> Result<(usize, SocketAddr)> {
+        Ok((self.recv(buf).await?, self.remote))
+    }
+
+    async fn send(&self, buf: &[u8]) -> Result<usize> {
+        write_frame(&mut *self.writer.lock().await, buf).await?;
+        Ok(buf.len())
+    }
+
+    async fn send_to(&self, _buf: &[u8], _target: SocketAddr) -> Result<usize> {
+        Err(io::Error::new(io::ErrorKind::Unsupported, "not applicable").into())
+    }
+
+    fn local_addr(&self) -> Result<SocketAddr> {
+        Ok(self.local)
+    }
+
+    fn remote_addr(&self) -> Option<SocketAddr> {
+        Some(self.remote)
+    }
+
+    async fn close(&self) -> Result<()> {
+        self.finished.send_replace(true);
+        Ok(self.writer.lock().await.shutdown().await?)
+    }
+}
+
+fn decode(packet: &[u8]) -> anyhow::Result<Message> {
+    let mut message = Message::new();
+    message.raw = packet.to_vec();
+    message.decode()?;
+    Ok(message)
+}
+
+/// Success response to a binding request authenticated with the local
+/// password.
+fn binding_success(
+    request: &mut Message,
+    ice_pwd: &str,
+    remote: SocketAddr,
+) -> anyhow::Result<Vec<u8>> {
+    if request.typ != BINDING_REQUEST {
+        anyhow::bail!("unexpected stun message {}", request.typ);
+    }
+    let integrity = MessageIntegrity::new_short_term_integrity(ice_pwd.to_string());
+    integrity.check(request)?;
+
+    let mut response = Message::new();
+    response.build(&[
+        Box::new(request.clone()),
+        Box::new(BINDING_SUCCESS),
+        Box::new(XorMappedAddress {
+            ip: remote.ip(),
+            port: remote.port(),
+        }),
+        Box::new(integrity),
+        Box::new(FINGERPRINT),
+    ])?;
+    Ok(response.raw)
+}
+
+async fn read_frame(reader: &mut OwnedReadHalf) -> io::Result<Vec<u8>> {
+    let len = reader.read_u16().await? as usize;
+    let mut packet = vec![0u8; len];
+    reader.read_exact(&mut packet).await?;
+    Ok(packet)
+}
+
+async fn write_frame(writer: &mut OwnedWriteHalf, packet: &[u8]) -> io::Result<()> {
+    let len = u16::try_from(packet.len())
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "packet too large"))?;
+    let mut frame = Vec::with_capacity(2 + packet.len());
+    frame.extend_from_slice(&len.to_be_bytes());
+    frame.extend_from_slice(packet);
+    writer.write_all(&frame).await
+}
@@ -0,0 +1,666 @@
+use std::collections::{HashMap, HashSet};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use bytes::{BufMut, Bytes, BytesMut};
+use futures_util::select;
+use futures_util::FutureExt;
+use rtp::codecs::{
+    h264::{H264Packet, H264Payloader},
+    opus::{OpusPacket, OpusPayloader},
+    vp8::{Vp8Packet, Vp8Payloader},
+    vp9::{Vp9Packet, Vp9Payloader},
+};
+use rtp::packetizer::{Depacketizer, Payloader};
+use tokio::sync::broadcast::{self, error::RecvError};
+use tracing::*;
+
+use crate::rush;
+use crate::server;
+
+use super::router::{Kind, Layer, Router, Track};
+use super::sdp;
+
+/// RUSH tracks without frames for this long are unpublished.
+const TRACK_TIMEOUT: Duration = Duration::from_secs(3);
+/// Payload size of the packets, leaving room for the SRTP overhead.
+const PAYLOAD_MTU: usize = 1188;
+
+/// RUSH codec of an RTP codec, `None` when it can not be transmuxed.
+pub fn rush_codec(kind: Kind, codec: &sdp::Codec) -> Option<u8> {
+    match (kind, codec.name.to_ascii_uppercase().as_str()) {
+        (Kind::Audio, "OPUS") => Some(rush::OPUS),
+        (Kind::Video, "H264") => Some(rush::H264),
+        (Kind::Video, "VP8") => Some(rush::VP8),
+        (Kind::Video, "VP9") => Some(rush::VP9),
+        (Kind::Video, "AV1") => Some(rush::AV1),
+        _ => None,
+    }
+}
+
+fn depacketizer(codec: u8, kind: Kind) -> Box<dyn Depacketizer + Send> {
+    match (kind, codec) {
+        (Kind::Audio, _) => Box::<OpusPacket>::default(),
+        (Kind::Video, rush::H264) => Box::<H264Packet>::default(),
+        (Kind::Video, rush::VP8) => Box::<Vp8Packet>::default(),
+        (Kind::Video, rush::VP9) => Box::<Vp9Packet>::default(),
+        (Kind::Video, _) => Box::<Av1Packet>::default(),
+    }
+}
+
+/// RTP codec of a RUSH frame, `None` when it can not be packetized.
+fn rtp_codec(frame_type: u8, codec: u8) -> Option<(Kind, sdp::Codec)> {
+    let (kind, payload_type, name, clock_rate, channels, fmtp) = match (frame_type, codec) {
+        (rush::AUDIO, rush::OPUS) => (Kind::Audio, 111, "opus", 48000, 2, ""),
+        (rush::VIDEO, rush::H264) => (Kind::Video, 102, "H264", 90000, 0, "packetization-mode=1"),
+        (rush::VIDEO, rush::VP8) => (Kind::Video, 96, "VP8", 90000, 0, ""),
+        (rush::VIDEO, rush::VP9) => (Kind::Video, 98, "VP9", 90000, 0, ""),
+        (rush::VIDEO, rush::AV1) => (Kind::Video, 45, "AV1", 90000, 0, ""),
+        _ => return None,
+    };
+    let codec = sdp::Codec {
+        payload_type,
+        name: name.to_string(),
+        clock_rate,
+        channels,
+        fmtp: fmtp.to_string(),
+        feedback: Vec::new(),
+    };
+    Some((kind, codec))
+}
+
+fn payloader(codec: u8, kind: Kind) -> Box<dyn Payloader + Send + Sync> {
+    match (kind, codec) {
+        (Kind::Audio, _) => Box::new(OpusPayloader),
+        (Kind::Video, rush::H264) => Box::<H264Payloader>::default(),
+        (Kind::Video, rush::VP8) => {
+            let mut payloader = Vp8Payloader::default();
+            payloader.enable_picture_id = true;
+            Box::new(payloader)
+        }
+        (Kind::Video, rush::VP9) => Box::<Vp9Payloader>::default(),
+        (Kind::Video, _) => Box::<Av1Payloader>::default(),
+    }
+}
+
+/// Republishes the frames of an RTP track on a channel as RUSH messages,
+/// with the SSRC as track id and timestamps in milliseconds. The layers of
+/// a simulcast source share a non-zero source.
+pub async fn publish(
+    server: server::ServerPtr,
+    channel: String,
+    id: u32,
+    kind: Kind,
+    codec: sdp::Codec,
+    source: u16,
+    mut packets: broadcast::Receiver<rtp::packet::Packet>,
+) {
+    let rush_codec = match rush_codec(kind, &codec) {
+        Some(rush_codec) => rush_codec,
+        None => {
+            debug!("webrtc track {} with {} not transmuxed", id, codec.name);
+            return;
+        }
+    };
+    let channel = server.lock().unwrap().find_or_create_channel(&channel);
+    let tx = channel.lock().await.broadcast.clone();
+
+    let mut assembler = Assembler::new(kind, rush_codec);
+    let mut clock = Clock::new(codec.clock_rate);
+    let mut seq = 0u32;
+    loop {
+        let packet = match packets.recv().await {
+            Ok(packet) => packet,
+            Err(RecvError::Lagged(n)) => {
+                debug!("webrtc transmux of track {} lagged {} packets", id, n);
+                continue;
+            }
+            Err(RecvError::Closed) => break,
+        };
+        let (timestamp, frame) = match assembler.push(&packet) {
+            Some(frame) => frame,
+            None => continue,
+        };
+
+        let header = rush::Header {
+            length: (rush::HEADER_LEN + frame.len()) as u32,
+            seq,
+            frame_type: match kind {
+                Kind::Audio => rush::AUDIO,
+                Kind::Video => rush::VIDEO,
+            },
+            codec: rush_codec,
+            source,
+            timestamp: clock.millis(timestamp),
+            track_id: id,
+        };
+        seq = seq.wrapping_add(1);
+        let _ = tx.send(header.encode(&frame));
+    }
+}
+
+/// Publishes the RUSH tracks of a channel as RTP tracks of its router, for
+/// the WebRTC subscribers, the tracks of a non-zero source being its
+/// simulcast layers. The RTP tracks transmuxed into the channel are skipped,
+/// they are already in the router.
+pub async fn packetize(server: server::ServerPtr, channel: String, router: Arc<Router>) {
+    let channel = server.lock().unwrap().find_or_create_channel(&channel);
+    let mut messages = channel.lock().await.broadcast.subscribe();
+
+    let mut tracks: HashMap<u32, Packetizer> = HashMap::new();
+    let mut transmuxed = HashSet::new();
+    let mut expiry = tokio::time::interval(TRACK_TIMEOUT);
+    loop {
+        let message = select! {
+            res = messages.recv().fuse() => match res {
+                Ok(message) => message,
+                Err(RecvError::Lagged(n)) => {
+                    debug!("webrtc packetizing lagged {} messages", n);
+                    continue;
+                }
+                Err(RecvError::Closed) => break,
+            },
+            _ = expiry.tick().fuse() => {
+                tracks.retain(|id, track| {
+                    let active = track.last.elapsed() < TRACK_TIMEOUT;
+                    if !active {
+                        router.remove_track(*id);
+                    }
+                    active
+                });
+                continue;
+            }
+        };
+
+        let header = match rush::Header::parse(&message) {
+            Some(header) => header,
+            None => continue,
+        };
+        let id = header.track_id;
+        if transmuxed.contains(&id) {
+            continue;
+        }
+        let (kind, codec) = match rtp_codec(header.frame_type, header.codec) {
+            Some(codec) => codec,
+            None => continue,
+        };
+
+        // A track id reused with another codec is a new track
+        if tracks
+            .get(&id)
+            .is_some_and(|track| track.track.kind != kind || track.track.codec.name != codec.name)
+        {
+            tracks.remove(&id);
+            router.remove_track(id);
+        }
+        let track = match tracks.get_mut(&id) {
+            Some(track) => track,
+            None if router.has_track(id) => {
+                transmuxed.insert(id);
+                continue;
+            }
+            None => {
+                let rush_codec = header.codec;
+                let layer = (header.source != 0).then_some(Layer {
+                    source: header.source as u32,
+                    rid: None,
+                });
+                let track = router.add_track(id, kind, codec, layer);
+                tracks
+                    .entry(id)
+                    .or_insert_with(|| Packetizer::new(track, rush_codec))
+            }
+        };
+
+        let frame = Bytes::copy_from_slice(&message[rush::HEADER_LEN..]);
+        for packet in track.packetize(header.timestamp, &frame) {
+            track.track.send(packet);
+        }
+    }
+}
+
+/// Splits the frames of a RUSH track into RTP packets, with the track id
+/// as SSRC.
+struct Packetizer {
+    track: Arc<Track>,
+    payloader: Box<dyn Payloader + Send + Sync>,
+    sequence_number: u16,
+    /// RTP timestamp of the timestamp 0 of the track
+    timestamp: u32,
+    last: Instant,
+}
+
+impl Packetizer {
+    fn new(track: Arc<Track>, codec: u8) -> Self {
+        Self {
+            payloader: payloader(codec, track.kind),
+            track,
+            sequence_number: rand::random(),
+            timestamp: rand::random(),
+            last: Instant::now(),
+        }
+    }
+
+    fn packetize(&mut self, millis: u32, frame: &Bytes) -> Vec<rtp::packet::Packet> {
+        self.last = Instant::now();
+        let payloads = match self.payloader.payload(PAYLOAD_MTU, frame) {
+            Ok(payloads) => payloads,
+            Err(err) => {
+                debug!("webrtc packetizing track {} failed: {}", self.track.id, err);
+                return Vec::new();
+            }
+        };
+
+        let timestamp = self
+            .timestamp
+            .wrapping_add((millis as u64 * self.track.codec.clock_rate as u64 / 1000) as u32);
+        let count = payloads.len();
+        payloads
+            .into_iter()
+            .enumerate()
+            .map(|(i, payload)| {
+                let sequence_number = self.sequence_number;
+                self.sequence_number = self.sequence_number.wrapping_add(1);
+                rtp::packet::Packet {
+                    header: rtp::header::Header {
+                        version: 2,
+                        // Video frames end with the marker, audio has no talkspurts
+                        marker: self.track.kind == Kind::Video && i == count - 1,
+                        payload_type: self.track.codec.payload_type,
+                        sequence_number,
+                        timestamp,
+                        ssrc: self.track.id,
+                        ..Default::default()
+                    },
+                    payload,
+                }
+            })
+            .collect()
+    }
+}
+
+/// Converts RTP timestamps to milliseconds since the first frame.
+struct Clock {
+    rate: u64,
+    last: Option<u32>,
+    elapsed: i64,
+}
+
+impl Clock {
+    fn new(rate: u32) -> Self {
+        Self {
+            rate: rate.max(1) as u64,
+            last: None,
+            elapsed: 0,
+        }
+    }
+
+    fn millis(&mut self, timestamp: u32) -> u32 {
+        if let Some(last) = self.last {
+            // Signed difference, frames may go backwards with B-frames
+            self.elapsed += timestamp.wrapping_sub(last) as i32 as i64;
+        }
+        self.last = Some(timestamp);
+        (self.elapsed.max(0) as u64 * 1000 / self.rate) as u32
+    }
+}
+
+/// Reassembles the frames of a track, dropping the ones with lost packets.
+struct Assembler {
+    kind: Kind,
+    codec: u8,
+    depacketizer: Box<dyn Depacketizer + Send>,
+    frame: BytesMut,
+    timestamp: Option<u32>,
+    next_sequence_number: Option<u16>,
+    broken: bool,
+}
+
+impl Assembler {
+    fn new(kind: Kind, codec: u8) -> Self {
+        Self {
+            kind,
+            codec,
+            depacketizer: depacketizer(codec, kind),
+            frame: BytesMut::new(),
+            timestamp: None,
+            next_sequence_number: None,
+            broken: false,
+        }
+    }
+
+    /// Returns the RTP timestamp and the data of a frame when the packet
+    /// completes one.
+    fn push(&mut self, packet: &rtp::packet::Packet) -> Option<(u32, Bytes)> {
+        let header = &packet.header;
+        let lost = self
+            .next_sequence_number
+            .is_some_and(|next| next != header.sequence_number);
+        self.next_sequence_number = Some(header.sequence_number.wrapping_add(1));
+
+        if self.timestamp != Some(header.timestamp) {
+            // Whatever is left of the previous frame lost its tail, and the
+            // depacketizer is reset to forget its partial units
+            self.frame.clear();
+            self.depacketizer = depacketizer(self.codec, self.kind);
+            self.timestamp = Some(header.timestamp);
+            self.broken = !self.depacketizer.is_partition_head(&packet.payload);
+        } else if lost {
+            self.broken = true;
+        }
+
+        if !self.broken && !packet.payload.is_empty() {
+            match self.depacketizer.depacketize(&packet.payload) {
+                Ok(data) => self.frame.put(data),
+                Err(err) => {
+                    debug!("webrtc depacketizing failed: {}", err);
+                    self.broken = true;
+                }
+            }
+        }
+
+        if !self
+            .depacketizer
+            .is_partition_tail(header.marker, &packet.payload)
+        {
+            return None;
+        }
+        self.timestamp = None;
+        let frame = self.frame.split().freeze();
+        match self.broken || frame.is_empty() {
+            true => None,
+            false => Some((header.timestamp, frame)),
+        }
+    }
+}
+
+const OBU_SEQUENCE_HEADER: u8 = 1;
+const OBU_TEMPORAL_DELIMITER: u8 = 2;
+
+/// AV1 RTP payload depacketizer, converting the OBU elements to the low
+/// overhead bitstream format with size fields.
+#[derive(Default)]
+struct Av1Packet {
+    /// Start of an OBU continued in the next packet
+    fragment: Vec<u8>,
+}
+
+impl Depacketizer for Av1Packet {
+    fn depacketize(&mut self, packet: &Bytes) -> Result<Bytes, rtp::Error> {
+        let aggregation = *packet.first().ok_or(rtp::Error::ErrShortPacket)?;
+        let continued = aggregation & 0x80 != 0;
+        let continues = aggregation & 0x40 != 0;
+        let count = (aggregation >> 4) & 0x03;
+
+        let mut data = BytesMut::new();
+        let mut offset = 1;
+        let mut index = 0;
+        while offset < packet.len() {
+            index += 1;
+            let size = if index == count {
+                packet.len() - offset
+            } else {
+                let (size, n) = read_leb128(&packet[offset..]).ok_or(rtp::Error::ErrShortPacket)?;
+                offset += n;
+                size
+            };
+            let element = packet
+                .get(offset..offset + size)
+                .ok_or(rtp::Error::ErrShortPacket)?;
+            offset += size;
+
+            let mut obu = if index == 1 && continued {
+                std::mem::take(&mut self.fragment)
+            } else {
+                Vec::new()
+            };
+            obu.extend_from_slice(element);
+            if offset >= packet.len() && continues {
+                self.fragment = obu;
+                break;
+            }
+            write_obu(&mut data, &obu);
+        }
+        Ok(data.freeze())
+    }
+
+    fn is_partition_head(&self, payload: &Bytes) -> bool {
+        payload
+            .first()
+            .is_some_and(|aggregation| aggregation & 0x80 == 0)
+    }
+
+    fn is_partition_tail(&self, marker: bool, _payload: &Bytes) -> bool {
+        marker
+    }
+}
+
+/// AV1 RTP payloader of low overhead bitstream frames, every OBU element
+/// has a length field and the size fields of the OBUs are removed.
+#[derive(Debug, Default, Clone)]
+struct Av1Payloader;
+
+impl Payloader for Av1Payloader {
+    fn payload(&mut self, mtu: usize, payload: &Bytes) -> Result<Vec<Bytes>, rtp::Error> {
+        let mut elements = Vec::new();
+        let mut new_sequence = false;
+        let mut data = &payload[..];
+        while let Some(&header) = data.first() {
+            let header_len = if header & 0x04 != 0 { 2 } else { 1 };
+            let (size, n) = match header & 0x02 != 0 {
+                true => read_leb128(data.get(header_len..).ok_or(rtp::Error::ErrShortPacket)?)
+                    .ok_or(rtp::Error::ErrShortPacket)?,
+                false => (data.len().saturating_sub(header_len), 0),
+            };
+            let end = header_len + n + size;
+            let obu = data.get(..end).ok_or(rtp::Error::ErrShortPacket)?;
+            data = &data[end..];
+
+            match (header >> 3) & 0x0F {
+                OBU_TEMPORAL_DELIMITER => continue,
+                OBU_SEQUENCE_HEADER => new_sequence = true,
+                _ => {}
+            }
+            let mut element = Vec::with_capacity(header_len + size);
+            element.push(header & !0x02);
+            element.extend_from_slice(&obu[1..header_len]);
+            element.extend_from_slice(&obu[header_len + n..]);
+            elements.push(element);
+        }
+
+        let mut payloads = Vec::new();
+        let mut packet = BytesMut::new();
+        let mut aggregation = if new_sequence { 0x08 } else { 0 };
+        for element in elements {
+            let mut remaining = &element[..];
+            while !remaining.is_empty() {
+                if packet.is_empty() {
+                    packet.put_u8(0);
+                }
+                let available = mtu.saturating_sub(packet.len());
+                if available < 2 {
+                    packet[0] = aggregation;
+                    payloads.push(packet.split().freeze());
+                    aggregation = 0;
+                    continue;
+                }
+                let mut n = remaining.len().min(available - 1);
+                while leb128_len(n) + n > available {
+                    n -= 1;
+                }
+                write_leb128(&mut packet, n);
+                packet.put_slice(&remaining[..n]);
+                remaining = &remaining[n..];
+                if !remaining.is_empty() {
+                    packet[0] = aggregation | 0x40;
+                    payloads.push(packet.split().freeze());
+                    // The next packet continues the element, and is no new sequence
+                    aggregation = 0x80;
+                }
+            }
+        }
+        if !packet.is_empty() {
+            packet[0] = aggregation;
+            payloads.push(packet.freeze());
+        }
+        Ok(payloads)
+    }
+
+    fn clone_to(&self) -> Box<dyn Payloader + Send + Sync> {
+        Box::new(self.clone())
+    }
+}
+
+fn write_obu(data: &mut BytesMut, obu: &[u8]) {
+    let header = match obu.first() {
+        Some(header) => *header,
+        None => return,
+    };
+    if (header >> 3) & 0x0F == OBU_TEMPORAL_DELIMITER {
+        return;
+    }
+    if header & 0x02 != 0 {
+        data.put_slice(obu);
+        return;
+    }
+
+    let header_len = if header & 0x04 != 0 { 2 } else { 1 };
+    if obu.len() < header_len {
+        return;
+    }
+    data.put_u8(header | 0x02);
+    data.put_slice(&obu[1..header_len]);
+    write_leb128(data, obu.len() - header_len);
+    data.put_slice(&obu[header_len..]);
+}
+
+fn read_leb128(data: &[u8]) -> Option<(usize, usize)> {
+    let mut value = 0usize;
+    for (i, byte) in data.iter().take(8).enumerate() {
+        value |= ((byte & 0x7F) as usize) << (7 * i);
+        if byte & 0x80 == 0 {
+            return Some((value, i + 1));
+        }
+    }
+    None
+}
+
+fn leb128_len(value: usize) -> usize {
+    let mut len = 1;
+    while value >> (7 * len) != 0 {
+        len += 1;
+    }
+    len
+}
+
+fn write_leb128(data: &mut BytesMut, mut value: usize) {
+    loop {
+        let byte = (value & 0x7F) as u8;
+        value >>= 7;
+        if value == 0 {
+            data.put_u8(byte);
+            return;
+        }
+        data.put_u8(byte | 0x80);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn obu(obu_type: u8, payload: &[u8]) -> Vec<u8> {
+        let mut obu = BytesMut::new();
+        obu.put_u8(obu_type << 3 | 0x02);
+        write_leb128(&mut obu, payload.len());
+        obu.put_slice(payload);
+        obu.to_vec()
+    }
+
+    fn packets(
+        payloads: Vec<Bytes>,
+        sequence_number: u16,
+        timestamp: u32,
+    ) -> Vec<rtp::packet::Packet> {
+        let count = payloads.len();
+        payloads
+            .into_iter()
+            .enumerate()
+            .map(|(i, payload)| rtp::packet::Packet {
+                header: rtp::header::Header {
+                    version: 2,
+                    marker: i == count - 1,
+                    payload_type: 45,
+                    sequence_number: sequence_number.wrapping_add(i as u16),
+                    timestamp,
+                    ..Default::default()
+                },
+                payload,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn av1_round_trip() {
+        let sequence_header = obu(OBU_SEQUENCE_HEADER, &[1, 2, 3, 4]);
+        let tile: Vec<u8> = (0..3000).map(|i| i as u8).collect();
+        let frame = obu(6, &tile);
+        let data = [
+            obu(OBU_TEMPORAL_DELIMITER, &[]),
+            sequence_header.clone(),
+            frame.clone(),
+        ]
+        .concat();
+
+        let payloads = Av1Payloader
+            .payload(PAYLOAD_MTU, &Bytes::from(data))
+            .unwrap();
+        assert!(payloads.len() > 2);
+        assert!(payloads.iter().all(|payload| payload.len() <= PAYLOAD_MTU));
+        // New coded video sequence on the first packet only
+        assert_eq!(payloads[0][0] & 0x08, 0x08);
+        assert!(payloads[1..].iter().all(|payload| payload[0] & 0x08 == 0));
+
+        let mut assembler = Assembler::new(Kind::Video, rush::AV1);
+        let packets = packets(payloads, 65534, 3000);
+        let (last, packets) = packets.split_last().unwrap();
+        for packet in packets {
+            assert!(assembler.push(packet).is_none());
+        }
+        // The temporal delimiter is left out
+        let expected = [sequence_header, frame].concat();
+        assert_eq!(assembler.push(last), Some((3000, Bytes::from(expected))));
+    }
+
+    #[test]
+    fn av1_lost_packet() {
+        let frame = obu(6, &[0x55; 4000]);
+        let payloads = Av1Payloader
+            .payload(PAYLOAD_MTU, &Bytes::from(frame.clone()))
+            .unwrap();
+        let mut assembler = Assembler::new(Kind::Video, rush::AV1);
+        let mut lost = packets(payloads.clone(), 100, 0);
+        lost.remove(1);
+        assert!(lost.iter().all(|packet| assembler.push(packet).is_none()));
+
+        // The next frame is assembled again
+        let count = payloads.len() as u16;
+        let packets = packets(payloads, 100 + count, 3000);
+        let frames: Vec<_> = packets
+            .iter()
+            .filter_map(|packet| assembler.push(packet))
+            .collect();
+        assert_eq!(frames, [(3000, Bytes::from(frame))]);
+    }
+
+    #[test]
+    fn av1_small_frames_aggregated() {
+        let data = [obu(OBU_SEQUENCE_HEADER, &[1]), obu(6, &[2, 3])].concat();
+        let payloads = Av1Payloader
+            .payload(PAYLOAD_MTU, &Bytes::from(data.clone()))
+            .unwrap();
+        assert_eq!(payloads.len(), 1);
+        let mut depacketizer = Av1Packet::default();
+        assert_eq!(depacketizer.depacketize(&payloads[0]).unwrap(), data);
+    }
+}
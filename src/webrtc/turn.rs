@@ -0,0 +1,239 @@
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Arc;
+
+use async_trait::async_trait;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::tcp::{OwnedReadHalf, OwnedWriteHalf};
+use tokio::net::{TcpListener, UdpSocket};
+use tokio::sync::{watch, Mutex};
+use tracing::*;
+use turn::auth::{generate_auth_key, AuthHandler};
+use turn::relay::relay_static::RelayAddressGeneratorStatic;
+use turn::server::config::{ConnConfig, ServerConfig};
+use turn::server::Server;
+use webrtc_util::vnet::net::Net;
+use webrtc_util::Conn;
+
+use crate::auth;
+
+type Result<T> = std::result::Result<T, webrtc_util::Error>;
+
+const REALM: &str = "prism";
+
+/// STUN and TURN (RFC 8656) server relaying over UDP, reachable over UDP
+/// and TCP, with the time-limited credentials of the token secret.
+///
+/// TCP allocations (RFC 6062) are not supported, their requests are
+/// answered with 442 Unsupported Transport Protocol.
+#[derive(Clone)]
+pub struct Config {
+    pub listen: SocketAddr,
+    pub tcp_listen: Option<SocketAddr>,
+    /// Address of the relayed candidates
+    pub relay_ip: IpAddr,
+    pub auth: auth::Config,
+}
+
+impl Config {
+    /// URLs of the server announced to the clients.
+    pub fn urls(&self) -> Vec<String> {
+        let mut urls = vec![format!(
+            "turn:{}?transport=udp",
+            SocketAddr::new(self.relay_ip, self.listen.port())
+        )];
+        if let Some(tcp_listen) = self.tcp_listen {
+            urls.push(format!(
+                "turn:{}?transport=tcp",
+                SocketAddr::new(self.relay_ip, tcp_listen.port())
+            ));
+        }
+        urls
+    }
+}
+
+struct Auth(auth::Config);
+
+impl AuthHandler for Auth {
+    fn auth_handle(
+        &self,
+        username: &str,
+        realm: &str,
+        src_addr: SocketAddr,
+    ) -> std::result::Result<Vec<u8>, turn::Error> {
+        match self.0.turn_password(username) {
+            Ok(password) => Ok(generate_auth_key(username, realm, &password)),
+            Err(err) => {
+                debug!("turn unauthorized {} from {}: {}", username, src_addr, err);
+                Err(turn::Error::Other(err.to_string()))
+            }
+        }
+    }
+}
+
+pub async fn start(config: Config) -> anyhow::Result<()> {
+    let udp_socket = UdpSocket::bind(config.listen).await?;
+    info!("listening turn on {}", udp_socket.local_addr()?);
+    let server = new_server(&config, Arc::new(udp_socket)).await?;
+    tokio::spawn(async move {
+        // The server stops once dropped
+        let _server = server;
+        std::future::pending::<()>().await;
+    });
+
+    if let Some(tcp_listen) = config.tcp_listen {
+        let listener = TcpListener::bind(tcp_listen).await?;
+        info!("listening turn tcp on {}", listener.local_addr()?);
+        tokio::spawn(async move {
+            loop {
+                let (stream, remote) = match listener.accept().await {
+                    Ok(conn) => conn,
+                    Err(err) => {
+                        error!("turn tcp accept failed: {}", err);
+                        continue;
+                    }
+                };
+                let config = config.clone();
+                tokio::spawn(async move {
+                    let local = match stream.local_addr() {
+                        Ok(local) => local,
+                        Err(_) => return,
+                    };
+                    let (reader, writer) = stream.into_split();
+                    let conn = Arc::new(TcpConn {
+                        reader: Mutex::new(reader),
+                        writer: Mutex::new(writer),
+                        local,
+                        remote,
+                        finished: watch::channel(false).0,
+                    });
+                    // Allocations are bound to the connection, a server per
+                    // connection closes them with it
+                    match new_server(&config, conn.clone()).await {
+                        Ok(server) => {
+                            conn.finished().await;
+                            let _ = server.close().await;
+                        }
+                        Err(err) => error!("turn tcp from {} failed: {}", remote, err),
+                    }
+                });
+            }
+        });
+    }
+    Ok(())
+}
+
+async fn new_server(config: &Config, conn: Arc<dyn Conn + Send + Sync>) -> anyhow::Result<Server> {
+    Ok(Server::new(ServerConfig {
+        conn_configs: vec![ConnConfig {
+            conn,
+            relay_addr_generator: Box::new(RelayAddressGeneratorStatic {
+                relay_address: config.relay_ip,
+                address: "0.0.0.0".to_string(),
+                net: Arc::new(Net::new(None)),
+            }),
+        }],
+        realm: REALM.to_string(),
+        auth_handler: Arc::new(Auth(config.auth.clone())),
+        channel_bind_timeout: Default::default(),
+    })
+    .await?)
+}
+
+/// TURN connection over TCP, where STUN messages and ChannelData (padded)
+/// follow each other on the stream.
+struct TcpConn {
+    reader: Mutex<OwnedReadHalf>,
+    writer: Mutex<OwnedWriteHalf>,
+    local: SocketAddr,
+    remote: SocketAddr,
+    finished: watch::Sender<bool>,
+}
+
+impl TcpConn {
+    async fn finished(&self) {
+        let mut finished = self.finished.subscribe();
+        while !*finished.borrow_and_update() {
+            if finished.changed().await.is_err() {
+                return;
+            }
+        }
+    }
+
+    async fn read_message(&self, buf: &mut [u8]) -> io::Result<usize> {
+        let mut reader = self.reader.lock().await;
+        let mut header = [0u8; 4];
+        reader.read_exact(&mut header).await?;
+        let len = u16::from_be_bytes([header[2], header[3]]) as usize;
+        let (total, padded) = match header[0] >> 6 {
+            // STUN header of 20 bytes
+            0 => (20 + len, 20 + len),
+            // ChannelData, padded to 4 bytes over TCP
+            1 => (4 + len, 4 + len.div_ceil(4) * 4),
+            _ => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "not a turn message",
+                ))
+            }
+        };
+        if total > buf.len() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "turn message too large",
+            ));
+        }
+        buf[..4].copy_from_slice(&header);
+        reader.read_exact(&mut buf[4..total]).await?;
+        let mut padding = vec![0u8; padded - total];
+        reader.read_exact(&mut padding).await?;
+        Ok(total)
+    }
+}
+
+#[async_trait]
+impl Conn for TcpConn {
+    async fn connect(&self, _addr: SocketAddr) -> Result<()> {
+        Err(io::Error::new(io::ErrorKind::Unsupported, "not applicable").into())
+    }
+
+    async fn recv(&self, buf: &mut [u8]) -> Result<usize> {
+        match self.read_message(buf).await {
+            Ok(n) => Ok(n),
+            Err(err) => {
+                self.finished.send_replace(true);
+                Err(err.into())
+            }
+        }
+    }
+
+    async fn recv_from(&self, buf: &mut [u8]) -> Result<(usize, SocketAddr)> {
+        Ok((self.recv(buf).await?, self.remote))
+    }
+
+    async fn send(&self, buf: &[u8]) -> Result<usize> {
+        let mut writer = self.writer.lock().await;
+        writer.write_all(buf).await?;
+        if buf.first().is_some_and(|byte| byte >> 6 == 1) && !buf.len().is_multiple_of(4) {
+            writer.write_all(&[0u8; 3][..4 - buf.len() % 4]).await?;
+        }
+        Ok(buf.len())
+    }
+
+    async fn send_to(&self, buf: &[u8], _target: SocketAddr) -> Result<usize> {
+        self.send(buf).await
+    }
+
+    fn local_addr(&self) -> Result<SocketAddr> {
+        Ok(self.local)
+    }
+
+    fn remote_addr(&self) -> Option<SocketAddr> {
+        Some(self.remote)
+    }
+
+    async fn close(&self) -> Result<()> {
+        self.finished.send_replace(true);
+        Ok(self.writer.lock().await.shutdown().await?)
+    }
+}
@@ -1,4 +1,6 @@
+use std::net::SocketAddr;
 use std::sync::Arc;
+use std::time::Duration;
 
 use async_trait::async_trait;
 
@@ -7,104 +9,146 @@ use futures_util::select;
 use futures_util::FutureExt;
 use futures_util::SinkExt;
 use futures_util::StreamExt;
-use futures_util::TryStreamExt;
-use http::Uri;
-use tokio::net::TcpStream;
-use tokio_rustls::server::TlsStream;
-use tokio_tungstenite::tungstenite::handshake::server::Request;
+use http::{header::HeaderName, HeaderMap, HeaderValue, StatusCode, Uri};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::time::{self, Instant, Interval};
+use tokio_tungstenite::tungstenite::handshake::server::{ErrorResponse, Request, Response};
+use tokio_tungstenite::tungstenite::protocol::frame::coding::{CloseCode, Data, OpCode};
+use tokio_tungstenite::tungstenite::protocol::frame::Frame;
+use tokio_tungstenite::tungstenite::protocol::CloseFrame;
 use tokio_tungstenite::tungstenite::Message;
+use tokio_tungstenite::WebSocketStream;
 use tracing::*;
 
+use crate::control;
+use crate::deflate;
+use crate::framing;
+use crate::mqtt;
+use crate::proxy;
 use crate::server;
-use crate::transport;
+use crate::session;
+use crate::stats;
+use crate::transport::{self, Transport};
 use crate::util;
 
-pub struct WebSocket {
+/// Buffer of the pipe between a WebSocket and the MQTT connection it carries.
+const MQTT_PIPE_SIZE: usize = 64 * 1024;
+
+#[derive(Clone)]
+pub struct Config {
+    /// Interval between pings sent to the peer, zero disables them
+    pub ping_interval: Duration,
+    /// Time to wait for the pong of a ping before closing, zero disables it
+    pub pong_timeout: Duration,
+    /// Time without receiving anything from the peer before closing, zero disables it
+    pub idle_timeout: Duration,
+    pub proxy: proxy::Config,
+    pub deflate: deflate::Config,
+    pub mqtt: mqtt::Config,
+}
+
+/// WebSocket session over any byte stream, TLS or plain TCP when behind a
+/// load balancer terminating TLS.
+pub struct WebSocket<S> {
     server: Arc<std::sync::Mutex<server::Server>>,
-    stream: TlsStream<TcpStream>,
+    stream: S,
+    remote: SocketAddr,
+    config: Config,
 }
 
-impl WebSocket {
+impl<S> WebSocket<S> {
     pub fn new(
         server: Arc<std::sync::Mutex<server::Server>>,
-        stream: TlsStream<TcpStream>,
+        stream: S,
+        remote: SocketAddr,
+        config: Config,
     ) -> Self {
-        Self { server, stream }
+        Self {
+            server,
+            stream,
+            remote,
+            config,
+        }
     }
 }
 
 #[async_trait]
-impl transport::Transport for WebSocket {
+impl<S> transport::Transport for WebSocket<S>
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
     fn close(&self) {
         unimplemented!()
     }
 
+    // The handshake callback has to return the response tungstenite rejects with
+    #[allow(clippy::result_large_err)]
     async fn process(self) -> Result<(), anyhow::Error> {
-        info!("connection established");
+        info!("connection established from {}", self.remote);
 
         let mut uri: Uri = Default::default();
-        let ws_stream = tokio_tungstenite::accept_hdr_async(self.stream, |req: &Request, res| {
-            uri = req.uri().clone();
-            Ok(res)
-        })
-        .await;
+        let mut headers = HeaderMap::new();
+        let mut negotiated = None;
+        let mut mqtt = false;
+        let mut remote = self.remote;
+        let limiter = self.server.lock().unwrap().limiter.clone();
+        let stream = deflate::DeflateStream::new(self.stream);
+        let ws_stream =
+            tokio_tungstenite::accept_hdr_async(stream, |req: &Request, mut res: Response| {
+                uri = req.uri().clone();
+                headers = req.headers().clone();
+                remote = self.config.proxy.client_addr(self.remote, &headers);
+                if !limiter.check(remote.ip()) {
+                    debug!("connection from {} rate limited", remote);
+                    return Err(reject(StatusCode::TOO_MANY_REQUESTS, "rate limited"));
+                }
+                let protocols = header_values(&headers, http::header::SEC_WEBSOCKET_PROTOCOL);
+                if protocols.split(',').any(|p| p.trim() == mqtt::WS_PROTOCOL) {
+                    res.headers_mut().insert(
+                        http::header::SEC_WEBSOCKET_PROTOCOL,
+                        HeaderValue::from_static(mqtt::WS_PROTOCOL),
+                    );
+                    mqtt = true;
+                    return Ok(res);
+                }
+                let channel_name = match util::parse_channel(uri.path()) {
+                    Ok(channel_name) => channel_name,
+                    Err(_) => return Err(reject(StatusCode::NOT_FOUND, "no channel found")),
+                };
+                match negotiate(&self.config.deflate, &headers, res.headers_mut()) {
+                    Ok(agreed) => {
+                        negotiated = Some((channel_name, agreed));
+                        Ok(res)
+                    }
+                    Err(reason) => Err(reject(StatusCode::BAD_REQUEST, reason)),
+                }
+            })
+            .await;
 
         match ws_stream {
+            Ok(ws_stream) if mqtt => {
+                debug!(
+                    "connection mqtt websocket handshaked {:?} from {}",
+                    uri, remote
+                );
+
+                serve_mqtt(self.server, ws_stream, remote, self.config.mqtt.clone()).await;
+            }
             Ok(ws_stream) => {
-                debug!("connection websocket handshaked {:?}", uri);
-
-                let channel_name =
-                    util::parse_channel(uri.path()).expect("invalid path, no channel found");
-                let channel = self
-                    .server
-                    .lock()
-                    .unwrap()
-                    .find_or_create_channel(&channel_name);
-
-                let guard = channel.lock().await;
-                let tx = guard.broadcast.clone();
-                let mut rx = tx.subscribe();
-                let channel_name = guard.name.clone();
-
-                info!("connection request accepted: {:#?}", channel_name);
-                drop(guard);
-
-                let (mut write, read) = ws_stream.split();
-                let mut ws_read = read.try_filter(|msg| future::ready(msg.is_binary()));
-                loop {
-                    select! {
-                        data = ws_read.next().fuse() => {
-                            match data {
-                                Some(Ok(Message::Binary(datagram))) => {
-                                    debug!("received: {:#?}", datagram.len());
-
-                                    let _ = tx.send(datagram.into());
-                                },
-                                Some(Err(err)) => {
-                                    error!("error on poll_datagrams {}", err);
-                                    break;
-                                }
-                                _ => {
-                                    warn!("no more datagrams");
-                                    break;
-                                }
-                            }
-                        },
-                        res = rx.recv().fuse() => {
-                            match res {
-                                Ok(datagram) => {
-                                    debug!("sent: {:#?}", datagram.len());
-
-                                    let _ = write.send(Message::Binary(datagram)).await;
-                                },
-                                Err(err) => {
-                                    error!("no more datagrams {}", err);
-                                    break;
-                                }
-                            }
-                        }
-                    }
-                }
+                debug!("connection websocket handshaked {:?} from {}", uri, remote);
+
+                let (channel_name, negotiated) =
+                    negotiated.expect("handshake accepted without negotiation");
+                serve(
+                    self.server,
+                    ws_stream,
+                    remote,
+                    &channel_name,
+                    negotiated,
+                    &self.config,
+                    "websocket",
+                )
+                .await;
             }
             Err(err) => {
                 error!("connection websocket handshaked failed: {}", err);
@@ -115,3 +159,506 @@ impl transport::Transport for WebSocket {
         Ok(())
     }
 }
+
+/// Subprotocol and extension agreed in the handshake.
+pub struct Negotiated {
+    pub framing: framing::Framing,
+    pub extension: Option<deflate::Params>,
+}
+
+/// Negotiates the subprotocol and permessage-deflate offered in the request
+/// headers, adding the agreed ones to the response headers.
+pub fn negotiate(
+    config: &deflate::Config,
+    headers: &HeaderMap,
+    response: &mut HeaderMap,
+) -> Result<Negotiated, &'static str> {
+    let mut framing = framing::Framing::Raw;
+    let protocols = header_values(headers, http::header::SEC_WEBSOCKET_PROTOCOL);
+    if !protocols.is_empty() {
+        framing = match framing::Framing::negotiate(&protocols) {
+            Some(framing) => framing,
+            None => return Err("unsupported subprotocol"),
+        };
+        response.insert(
+            http::header::SEC_WEBSOCKET_PROTOCOL,
+            HeaderValue::from_static(framing.protocol()),
+        );
+    }
+
+    let offers = header_values(headers, http::header::SEC_WEBSOCKET_EXTENSIONS);
+    let extension = deflate::negotiate(config, &offers);
+    if let Some(params) = &extension {
+        if let Ok(value) = HeaderValue::from_str(&params.header_value()) {
+            response.insert(http::header::SEC_WEBSOCKET_EXTENSIONS, value);
+        }
+    }
+    Ok(Negotiated { framing, extension })
+}
+
+/// Runs a WebSocket session once handshaked, over HTTP/1.1 or the request
+/// stream of an extended CONNECT.
+pub async fn serve<S>(
+    server: server::ServerPtr,
+    mut ws_stream: WebSocketStream<deflate::DeflateStream<S>>,
+    remote: SocketAddr,
+    channel_name: &str,
+    negotiated: Negotiated,
+    config: &Config,
+    transport: &'static str,
+) where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let Negotiated { framing, extension } = negotiated;
+    let stats = server.lock().unwrap().stats.clone();
+    stats.record_connection();
+    let mut session = session::Session::new(server, channel_name, remote).await;
+
+    info!(
+        "connection request accepted: {:#?} with {}",
+        session.channel(),
+        framing.protocol()
+    );
+
+    if let Some(params) = &extension {
+        debug!("connection permessage-deflate {:?}", params);
+        ws_stream.get_mut().enable(params);
+    }
+    let mut deflater = extension.as_ref().map(deflate::Deflater::new);
+
+    let (mut write, mut read) = ws_stream.split();
+    let mut ping = (!config.ping_interval.is_zero()).then(|| time::interval(config.ping_interval));
+    let mut ping_sent: Option<Instant> = None;
+    let mut last_received = Instant::now();
+    let (initiator, frame) = loop {
+        let pong_deadline = ping_sent
+            .filter(|_| !config.pong_timeout.is_zero())
+            .map(|sent| sent + config.pong_timeout);
+        let idle_deadline =
+            (!config.idle_timeout.is_zero()).then(|| last_received + config.idle_timeout);
+        select! {
+            data = read.next().fuse() => {
+                last_received = Instant::now();
+                match data {
+                    Some(Ok(Message::Binary(datagram))) => {
+                        debug!("received: {:#?}", datagram.len());
+
+                        match framing.validate(&datagram) {
+                            Ok(()) => session.publish(datagram),
+                            Err(err) => {
+                                let response = control::Response::Error {
+                                    id: None,
+                                    message: err.to_string(),
+                                };
+                                let _ = write.send(text_message(&mut deflater, &response)).await;
+                            }
+                        }
+                    },
+                    Some(Ok(Message::Text(text))) => {
+                        if let Some(response) = session.handle_control(&text).await {
+                            let _ = write.send(text_message(&mut deflater, &response)).await;
+                        }
+                    },
+                    Some(Ok(Message::Pong(_))) => {
+                        ping_sent = None;
+                    },
+                    Some(Ok(Message::Close(frame))) => {
+                        break (stats::Initiator::Remote, frame);
+                    },
+                    Some(Ok(_)) => {},
+                    Some(Err(err)) => {
+                        error!("error on poll_datagrams {}", err);
+                        break (stats::Initiator::Remote, None);
+                    }
+                    None => {
+                        warn!("no more datagrams");
+                        break (stats::Initiator::Remote, None);
+                    }
+                }
+            },
+            event = session.recv().fuse() => {
+                match event {
+                    Some(session::Event::Message { channel, data, compress }) => {
+                        debug!("sent: {:#?}", data.len());
+
+                        // Binary frames carry no channel, the messages of
+                        // the other joined channels go in envelopes
+                        let message = match framing {
+                            framing::Framing::Raw | framing::Framing::Rush
+                                if *channel == *session.channel() =>
+                            {
+                                binary_message(&mut deflater, data, compress)
+                            }
+                            _ => text_message(&mut deflater, &framing::envelope(&channel, &data)),
+                        };
+                        let _ = write.send(message).await;
+                    },
+                    Some(session::Event::Control(response)) => {
+                        let _ = write.send(text_message(&mut deflater, &response)).await;
+                    },
+                    None => {
+                        error!("no more datagrams");
+                        break (stats::Initiator::Local, None);
+                    }
+                }
+            },
+            _ = tick(&mut ping).fuse() => {
+                if ping_sent.is_none() {
+                    ping_sent = Some(Instant::now());
+                    let _ = write.send(Message::Ping(Vec::new())).await;
+                }
+            },
+            _ = sleep_until(pong_deadline).fuse() => {
+                break (stats::Initiator::Local, Some(close_frame("pong timeout")));
+            },
+            _ = sleep_until(idle_deadline).fuse() => {
+                break (stats::Initiator::Local, Some(close_frame("idle timeout")));
+            }
+        }
+    };
+
+    match (initiator, &frame) {
+        // Sending anything completes the close handshake started by the peer
+        (stats::Initiator::Remote, Some(_)) => {
+            let _ = write.close().await;
+        }
+        (stats::Initiator::Local, Some(frame)) => {
+            let _ = write.send(Message::Close(Some(frame.clone()))).await;
+        }
+        _ => {}
+    }
+
+    let (code, reason) = match frame {
+        Some(frame) => (u16::from(frame.code), frame.reason.into_owned()),
+        None => (stats::CLOSE_ABNORMAL, String::new()),
+    };
+    info!(
+        "connection from {} closed by {:?} with code {} {:?}",
+        remote, initiator, code, reason
+    );
+    stats.record_close(transport, initiator, code);
+
+    session.close().await;
+}
+
+async fn tick(interval: &mut Option<Interval>) {
+    match interval {
+        Some(interval) => {
+            interval.tick().await;
+        }
+        None => future::pending().await,
+    }
+}
+
+async fn sleep_until(deadline: Option<Instant>) {
+    match deadline {
+        Some(deadline) => time::sleep_until(deadline).await,
+        None => future::pending().await,
+    }
+}
+
+fn close_frame(reason: &'static str) -> CloseFrame<'static> {
+    CloseFrame {
+        code: CloseCode::Away,
+        reason: reason.into(),
+    }
+}
+
+/// Runs a MQTT connection over a WebSocket, whose binary messages carry the
+/// bytes of the packets.
+async fn serve_mqtt<S>(
+    server: server::ServerPtr,
+    ws_stream: WebSocketStream<S>,
+    remote: SocketAddr,
+    config: mqtt::Config,
+) where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let (local, pipe) = tokio::io::duplex(MQTT_PIPE_SIZE);
+    let transport = mqtt::MqttTransport::new(server, local, remote, config);
+    let _ = future::join(transport.process(), pump_mqtt(ws_stream, pipe)).await;
+}
+
+/// Copies the binary messages of a WebSocket to a pipe and back, until the
+/// MQTT end of the pipe is dropped. Each direction is copied on its own, a
+/// full pipe does not hold the other one back.
+async fn pump_mqtt<S>(ws_stream: WebSocketStream<S>, pipe: tokio::io::DuplexStream)
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let (mut write, mut read) = ws_stream.split();
+    let (mut pipe_read, mut pipe_write) = tokio::io::split(pipe);
+
+    let upload = async move {
+        loop {
+            match read.next().await {
+                Some(Ok(Message::Binary(data))) => {
+                    if pipe_write.write_all(&data).await.is_err() {
+                        return;
+                    }
+                }
+                Some(Ok(Message::Close(_))) | None => break,
+                Some(Ok(_)) => {}
+                Some(Err(err)) => {
+                    debug!("error on mqtt websocket {}", err);
+                    break;
+                }
+            }
+        }
+        // The client went away, the MQTT connection notices it when reading
+        let _ = pipe_write.shutdown().await;
+    };
+    let download = async move {
+        let mut buffer = vec![0; MQTT_PIPE_SIZE];
+        while let Ok(len) = pipe_read.read(&mut buffer).await {
+            if len == 0
+                || write
+                    .send(Message::Binary(buffer[..len].to_vec()))
+                    .await
+                    .is_err()
+            {
+                break;
+            }
+        }
+        let _ = write.close().await;
+    };
+
+    // The upload is dropped once the MQTT connection is done
+    tokio::pin!(upload, download);
+    if let future::Either::Left(((), download)) = future::select(upload, download).await {
+        download.await;
+    }
+}
+
+/// Joins the values of a comma separated header sent in several lines.
+fn header_values(headers: &HeaderMap, name: HeaderName) -> String {
+    let values: Vec<&str> = headers
+        .get_all(name)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .collect();
+    values.join(",")
+}
+
+fn reject(status: StatusCode, reason: &str) -> ErrorResponse {
+    http::Response::builder()
+        .status(status)
+        .body(Some(reason.to_string()))
+        .unwrap()
+}
+
+fn text_message(deflater: &mut Option<deflate::Deflater>, response: &control::Response) -> Message {
+    let text = control::encode(response);
+    compressed(deflater, Data::Text, text.as_bytes()).unwrap_or(Message::Text(text))
+}
+
+fn binary_message(
+    deflater: &mut Option<deflate::Deflater>,
+    data: Vec<u8>,
+    compress: bool,
+) -> Message {
+    let message = match compress {
+        true => compressed(deflater, Data::Binary, &data),
+        false => None,
+    };
+    message.unwrap_or(Message::Binary(data))
+}
+
+/// Compresses a message if permessage-deflate was negotiated, flagging the
+/// frame with RSV1.
+fn compressed(
+    deflater: &mut Option<deflate::Deflater>,
+    opcode: Data,
+    data: &[u8],
+) -> Option<Message> {
+    let compressed = match deflater.as_mut()?.compress(data) {
+        Ok(compressed) => compressed,
+        Err(err) => {
+            error!("compressing message failed {}", err);
+            return None;
+        }
+    };
+    let mut frame = Frame::message(compressed, OpCode::Data(opcode), true);
+    frame.header_mut().rsv1 = true;
+    Some(Message::Frame(frame))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio_tungstenite::tungstenite::protocol::Role;
+
+    fn config() -> Config {
+        Config {
+            ping_interval: Duration::ZERO,
+            pong_timeout: Duration::ZERO,
+            idle_timeout: Duration::ZERO,
+            proxy: proxy::Config::default(),
+            deflate: deflate::Config {
+                enabled: false,
+                server_max_window_bits: 15,
+                server_no_context_takeover: false,
+                client_no_context_takeover: false,
+            },
+            mqtt: mqtt::Config {
+                auth: Default::default(),
+            },
+        }
+    }
+
+    /// Next message of the client, skipping the presence notifications.
+    async fn next<S>(client: &mut WebSocketStream<S>) -> Message
+    where
+        S: AsyncRead + AsyncWrite + Unpin,
+    {
+        loop {
+            let message = time::timeout(Duration::from_secs(1), client.next())
+                .await
+                .unwrap()
+                .unwrap()
+                .unwrap();
+            if !matches!(&message, Message::Text(text) if text.contains("presence")) {
+                return message;
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn mqtt_pipe_both_ways() {
+        const SIZE: usize = 4 * MQTT_PIPE_SIZE;
+        let (client, stream) = tokio::io::duplex(MQTT_PIPE_SIZE);
+        let ws_stream = WebSocketStream::from_raw_socket(stream, Role::Server, None).await;
+        let (mut local, pipe) = tokio::io::duplex(MQTT_PIPE_SIZE);
+        tokio::spawn(pump_mqtt(ws_stream, pipe));
+        let client = WebSocketStream::from_raw_socket(client, Role::Client, None).await;
+        let (mut client_write, mut client_read) = client.split();
+
+        // The client uploads while the MQTT end writes without reading, like
+        // a connection sending to a busy subscriber
+        let upload = async move {
+            for _ in 0..SIZE / 1024 {
+                client_write
+                    .send(Message::Binary(vec![1; 1024]))
+                    .await
+                    .unwrap();
+            }
+        };
+        let download = async move {
+            let mut received = 0;
+            while received < SIZE {
+                match client_read.next().await {
+                    Some(Ok(Message::Binary(data))) => received += data.len(),
+                    message => panic!("unexpected {:?}", message),
+                }
+            }
+        };
+        let mqtt = async move {
+            // Let the upload fill the pipe first
+            time::sleep(Duration::from_millis(100)).await;
+            local.write_all(&vec![2; SIZE]).await.unwrap();
+            let mut data = vec![0; SIZE];
+            local.read_exact(&mut data).await.unwrap();
+            assert!(data.iter().all(|byte| *byte == 1));
+        };
+        let all = future::join3(upload, download, mqtt);
+        time::timeout(Duration::from_secs(5), all).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn other_channels() {
+        let server = Arc::new(std::sync::Mutex::new(server::Server::new()));
+        let remote: SocketAddr = "127.0.0.1:1".parse().unwrap();
+        let (client, stream) = tokio::io::duplex(64 * 1024);
+        let stream = deflate::DeflateStream::new(stream);
+        let ws_stream = WebSocketStream::from_raw_socket(stream, Role::Server, None).await;
+        let negotiated = Negotiated {
+            framing: framing::Framing::Raw,
+            extension: None,
+        };
+        let session = server.clone();
+        tokio::spawn(async move {
+            let config = config();
+            serve(
+                session,
+                ws_stream,
+                remote,
+                "demo",
+                negotiated,
+                &config,
+                "websocket",
+            )
+            .await
+        });
+        let mut client = WebSocketStream::from_raw_socket(client, Role::Client, None).await;
+
+        let join = r#"{"id": 1, "type": "join", "channel": "other"}"#;
+        client.send(Message::Text(join.to_string())).await.unwrap();
+        assert!(matches!(next(&mut client).await, Message::Text(text) if text.contains("joined")));
+
+        let demo = session::Session::new(server.clone(), "demo", remote).await;
+        demo.publish(b"own".to_vec());
+        assert_eq!(next(&mut client).await, Message::Binary(b"own".to_vec()));
+
+        let other = session::Session::new(server.clone(), "other", remote).await;
+        other.publish(b"\x00\x01".to_vec());
+        let text = match next(&mut client).await {
+            Message::Text(text) => text,
+            message => panic!("unexpected {:?}", message),
+        };
+        let envelope: serde_json::Value = serde_json::from_str(&text).unwrap();
+        assert_eq!(envelope["type"], "message");
+        assert_eq!(envelope["channel"], "other");
+        assert_eq!(envelope["encoding"], "base64");
+        assert_eq!(envelope["data"], "AAE=");
+    }
+
+    #[tokio::test]
+    async fn handshakes() {
+        use tokio_tungstenite::tungstenite::client::IntoClientRequest;
+        use tokio_tungstenite::tungstenite::Error;
+
+        let server = Arc::new(std::sync::Mutex::new(server::Server::new()));
+        let remote: SocketAddr = "127.0.0.1:1".parse().unwrap();
+        let cases = [
+            ("/channels/demo", None, Ok(None)),
+            ("/channels/demo", Some("prism.json"), Ok(Some("prism.json"))),
+            (
+                "/channels/demo",
+                Some("prism.xml"),
+                Err(StatusCode::BAD_REQUEST),
+            ),
+            ("/demo", None, Err(StatusCode::NOT_FOUND)),
+            ("/channels/demo/more", None, Err(StatusCode::NOT_FOUND)),
+            ("/", Some("prism.raw"), Err(StatusCode::NOT_FOUND)),
+        ];
+        for (path, protocol, expected) in cases {
+            let (client, stream) = tokio::io::duplex(64 * 1024);
+            let transport = WebSocket::new(server.clone(), stream, remote, config());
+            tokio::spawn(transport.process());
+
+            let mut request = format!("ws://localhost{}", path)
+                .into_client_request()
+                .unwrap();
+            if let Some(protocol) = protocol {
+                request.headers_mut().insert(
+                    http::header::SEC_WEBSOCKET_PROTOCOL,
+                    HeaderValue::from_static(protocol),
+                );
+            }
+            let result = match tokio_tungstenite::client_async(request, client).await {
+                Ok((_, response)) => Ok(response
+                    .headers()
+                    .get(http::header::SEC_WEBSOCKET_PROTOCOL)
+                    .map(|value| value.to_str().unwrap().to_string())),
+                Err(Error::Http(response)) => Err(response.status()),
+                Err(err) => panic!("{} failed: {}", path, err),
+            };
+            assert_eq!(
+                result,
+                expected.map(|protocol| protocol.map(String::from)),
+                "{}",
+                path
+            );
+        }
+    }
+}
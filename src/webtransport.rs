@@ -1,29 +1,57 @@
+use std::net::SocketAddr;
 use std::sync::Arc;
 
 use async_trait::async_trait;
 use quinn::Connecting;
 
+use futures_util::future;
 use futures_util::select;
 use futures_util::FutureExt;
 
 use tracing::*;
 
-use bytes::Bytes;
-use http::{Request, StatusCode};
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+use http::{header, HeaderMap, Method, Request, StatusCode};
+use tokio::io::{AsyncReadExt, AsyncWriteExt, DuplexStream};
+use tokio_tungstenite::tungstenite::protocol::Role;
+use tokio_tungstenite::WebSocketStream;
 
 use h3::{quic::BidiStream, server::RequestStream};
 
+use crate::control;
+use crate::deflate;
+use crate::framing;
 use crate::server;
+use crate::session;
+use crate::stats;
 use crate::transport;
+use crate::util;
+use crate::websocket;
+
+/// Size of the pipe between a request stream and its WebSocket framing.
+const WEBSOCKET_PIPE_SIZE: usize = 64 * 1024;
+
+/// Longest control line accepted before its newline.
+const MAX_LINE_SIZE: usize = 64 * 1024;
 
 pub struct WebTransport {
     server: Arc<std::sync::Mutex<server::Server>>,
     connecting: Connecting,
+    /// Settings of the WebSockets bootstrapped with extended CONNECT
+    ws_config: websocket::Config,
 }
 
 impl WebTransport {
-    pub fn new(server: Arc<std::sync::Mutex<server::Server>>, connecting: Connecting) -> Self {
-        Self { server, connecting }
+    pub fn new(
+        server: Arc<std::sync::Mutex<server::Server>>,
+        connecting: Connecting,
+        ws_config: websocket::Config,
+    ) -> Self {
+        Self {
+            server,
+            connecting,
+            ws_config,
+        }
     }
 }
 
@@ -36,51 +64,37 @@ impl transport::Transport for WebTransport {
     async fn process(self) -> Result<(), anyhow::Error> {
         match self.connecting.await {
             Ok(conn) => {
-                info!("connection established");
+                let remote = conn.connection.remote_address();
+                info!("connection established from {}", remote);
 
                 let mut h3_conn = h3::server::Connection::new(h3_quinn::Connection::new(conn))
                     .await
                     .unwrap();
 
-                let (channel, _stream) = match h3_conn.accept().await {
-                    Ok(Some((req, mut stream))) => {
-                        info!("connection new stream and request: {:#?}", req);
-
-                        match handle_request(req, &mut stream).await {
-                            Ok(channel_name) => {
-                                let channel = self
-                                    .server
-                                    .lock()
-                                    .unwrap()
-                                    .find_or_create_channel(&channel_name);
-                                (channel, stream)
-                            }
-                            Err(err) => {
-                                error!("handling request failed: {}", err);
-                                anyhow::bail!("invalid request")
-                            }
-                        }
+                let accepted =
+                    serve_requests(&self.server, &mut h3_conn, remote, &self.ws_config).await;
+                let (channel_name, _stream) = match accepted {
+                    Some(accepted) => accepted,
+                    None => {
+                        info!("connection finished");
+                        return Ok(());
                     }
-                    Ok(None) => anyhow::bail!("no request stream"),
-                    Err(err) => anyhow::bail!("invalid request {}", err),
                 };
 
-                let guard = channel.lock().await;
-                let tx = guard.broadcast.clone();
-                let mut rx = tx.subscribe();
-                let channel_name = guard.name.clone();
+                let mut session = session::Session::new(self.server, &channel_name, remote).await;
 
-                info!("connection request accepted: {:#?}", channel_name);
-                drop(guard);
+                info!("connection request accepted: {:#?}", session.channel());
 
+                let mut control: Option<ControlStream<_>> = None;
                 loop {
+                    let accepting = control.is_none();
                     select! {
                         data = h3_conn.poll_datagrams().fuse() => {
                             match data {
                                 Ok(Some(datagram)) => {
                                     debug!("received: {:#?}", datagram.len());
 
-                                    let _ = tx.send(datagram.into());
+                                    session.publish(datagram.into());
                                 },
                                 Ok(None) => {
                                     warn!("no more datagrams");
@@ -92,15 +106,52 @@ impl transport::Transport for WebTransport {
                                 }
                             }
                         },
-                        res = rx.recv().fuse() => {
-                            match res {
-                                Ok(datagram) => {
+                        stream = accept_control(&h3_conn, accepting).fuse() => {
+                            match stream {
+                                Ok(Some(stream)) => {
+                                    info!("connection control stream opened");
+                                    control = Some(ControlStream::new(stream));
+                                },
+                                Ok(None) => {},
+                                Err(err) => error!("error accepting control stream {}", err),
+                            }
+                        },
+                        line = recv_control(&mut control).fuse() => {
+                            match line {
+                                Ok(Some(line)) => {
+                                    if let Some(response) = session.handle_control(&line).await {
+                                        send_control(&mut control, &response).await;
+                                    }
+                                },
+                                Ok(None) => {
+                                    info!("connection control stream closed");
+                                    control = None;
+                                },
+                                Err(err) => {
+                                    error!("error on control stream {}", err);
+                                    control = None;
+                                }
+                            }
+                        },
+                        event = session.recv().fuse() => {
+                            match event {
+                                Some(session::Event::Message { channel, data: datagram, .. }) => {
                                     debug!("sent: {:#?}", datagram.len());
 
-                                    let _ = h3_conn.send_datagram(datagram.into()).await;
+                                    // Datagrams carry no channel, the messages of
+                                    // the other joined channels go in envelopes
+                                    if *channel == *session.channel() {
+                                        let _ = h3_conn.send_datagram(datagram.into()).await;
+                                    } else {
+                                        let envelope = framing::envelope(&channel, &datagram);
+                                        send_control(&mut control, &envelope).await;
+                                    }
                                 },
-                                Err(err) => {
-                                    error!("no more datagrams {}", err);
+                                Some(session::Event::Control(response)) => {
+                                    send_control(&mut control, &response).await;
+                                },
+                                None => {
+                                    error!("no more datagrams");
                                     break;
                                 }
                             }
@@ -108,6 +159,7 @@ impl transport::Transport for WebTransport {
                     }
                 }
 
+                session.close().await;
                 // server.lock().unwrap().destroy_channel(&channel_name);
             }
             Err(err) => {
@@ -127,15 +179,10 @@ async fn handle_request<T>(
 where
     T: BidiStream<Bytes>,
 {
-    // Only accept webtransport requests
-    if req.method() != "CONNECT" {
-        // TODO: Check webtransport protocol
-        return Err("invalid method".into());
-    }
-
     let path = req.uri().path();
     let tokens: Vec<&str> = path.split('/').filter(|s| !s.is_empty()).collect();
     if (tokens.len() != 2) || (tokens[0] != "channels") {
+        respond(stream, StatusCode::NOT_FOUND).await;
         return Err("invalid query".into());
     }
     let channel = tokens[1].to_owned();
@@ -151,3 +198,347 @@ where
         Err(err) => Err(err.into()),
     }
 }
+
+/// Serves the requests of a connection on their own tasks, each after its
+/// method, until the client closes it or opens a WebTransport session. The
+/// session then takes the connection over, its channel and request stream
+/// are returned.
+async fn serve_requests<C>(
+    server: &server::ServerPtr,
+    h3_conn: &mut h3::server::Connection<C, Bytes>,
+    remote: SocketAddr,
+    ws_config: &websocket::Config,
+) -> Option<(String, RequestStream<C::BidiStream, Bytes>)>
+where
+    C: h3::quic::Connection<Bytes>,
+    C::BidiStream: Send + 'static,
+    <C::BidiStream as BidiStream<Bytes>>::SendStream: Send,
+    <C::BidiStream as BidiStream<Bytes>>::RecvStream: Send,
+{
+    loop {
+        let (req, mut stream) = match h3_conn.accept().await {
+            Ok(Some(accepted)) => accepted,
+            Ok(None) => return None,
+            Err(err) => {
+                debug!("accepting request failed: {}", err);
+                return None;
+            }
+        };
+        if is_websocket(&req) {
+            info!("connection new websocket request: {:#?}", req);
+            tokio::spawn(serve_websocket(
+                server.clone(),
+                req,
+                stream,
+                remote,
+                ws_config.clone(),
+            ));
+        } else if req.method() == Method::CONNECT {
+            info!("connection new stream and request: {:#?}", req);
+            match handle_request(req, &mut stream).await {
+                Ok(channel_name) => return Some((channel_name, stream)),
+                Err(err) => error!("handling request failed: {}", err),
+            }
+        } else {
+            // Plain requests, the unknown methods are refused there
+            info!("connection new stream and request: {:#?}", req);
+            tokio::spawn(serve_request(server.clone(), req, stream, remote));
+        }
+    }
+}
+
+/// `GET /channels/{name}/stream` answers with an unbounded body carrying the
+/// messages of the channel, each prefixed by its length as a 32 bits big
+/// endian integer.
+async fn serve_request<T>(
+    server: server::ServerPtr,
+    req: Request<()>,
+    mut stream: RequestStream<T, Bytes>,
+    remote: SocketAddr,
+) where
+    T: BidiStream<Bytes>,
+{
+    let tokens: Vec<&str> = req
+        .uri()
+        .path()
+        .split('/')
+        .filter(|s| !s.is_empty())
+        .collect();
+    let channel_name = match (req.method(), tokens.as_slice()) {
+        (&Method::GET, ["channels", channel, "stream"]) => channel.to_string(),
+        (&Method::GET, _) => return respond(&mut stream, StatusCode::NOT_FOUND).await,
+        _ => return respond(&mut stream, StatusCode::METHOD_NOT_ALLOWED).await,
+    };
+
+    let resp = http::Response::builder()
+        .status(StatusCode::OK)
+        .header(header::CONTENT_TYPE, "application/octet-stream")
+        .header(header::CACHE_CONTROL, "no-cache")
+        .header(header::ACCESS_CONTROL_ALLOW_ORIGIN, "*")
+        .body(())
+        .unwrap();
+    if let Err(err) = stream.send_response(resp).await {
+        error!("connection stream response failed: {}", err);
+        return;
+    }
+
+    let stats = server.lock().unwrap().stats.clone();
+    stats.record_connection();
+    let mut session = session::Session::new(server, &channel_name, remote).await;
+    info!(
+        "connection stream request accepted: {:#?}",
+        session.channel()
+    );
+
+    let initiator = loop {
+        match session.recv().await {
+            Some(session::Event::Message { data, .. }) => {
+                debug!("sent: {:#?}", data.len());
+
+                let mut chunk = BytesMut::with_capacity(4 + data.len());
+                chunk.put_u32(data.len() as u32);
+                chunk.extend_from_slice(&data);
+                if let Err(err) = stream.send_data(chunk.freeze()).await {
+                    debug!("error on stream request {}", err);
+                    break stats::Initiator::Remote;
+                }
+            }
+            Some(session::Event::Control(_)) => {}
+            None => {
+                let _ = stream.finish().await;
+                break stats::Initiator::Local;
+            }
+        }
+    };
+    info!(
+        "connection stream from {} closed by {:?}",
+        remote, initiator
+    );
+    stats.record_close("http3-stream", initiator, stats::CLOSE_ABNORMAL);
+
+    session.close().await;
+}
+
+async fn respond<T>(stream: &mut RequestStream<T, Bytes>, status: StatusCode)
+where
+    T: BidiStream<Bytes>,
+{
+    let resp = http::Response::builder().status(status).body(()).unwrap();
+    if stream.send_response(resp).await.is_ok() {
+        let _ = stream.finish().await;
+    }
+}
+
+/// WebSockets over HTTP/3 (RFC 9220) are extended CONNECT requests with the
+/// `websocket` protocol, the fork does not expose `:protocol` but
+/// `sec-websocket-version` is mandatory for them and never sent by
+/// WebTransport.
+fn is_websocket(req: &Request<()>) -> bool {
+    req.method() == "CONNECT"
+        && req
+            .headers()
+            .contains_key(http::header::SEC_WEBSOCKET_VERSION)
+}
+
+/// Answers a WebSocket extended CONNECT and runs the session over the request
+/// stream, like a WebSocket upgraded from HTTP/1.1.
+async fn serve_websocket<T>(
+    server: server::ServerPtr,
+    req: Request<()>,
+    mut stream: RequestStream<T, Bytes>,
+    remote: SocketAddr,
+    config: websocket::Config,
+) where
+    T: BidiStream<Bytes>,
+{
+    let mut headers = HeaderMap::new();
+    let negotiated = match util::parse_channel(req.uri().path()) {
+        Ok(channel_name) => websocket::negotiate(&config.deflate, req.headers(), &mut headers)
+            .map(|negotiated| (channel_name, negotiated))
+            .map_err(|reason| (StatusCode::BAD_REQUEST, reason)),
+        Err(_) => Err((StatusCode::NOT_FOUND, "no channel found")),
+    };
+    let (channel_name, negotiated) = match negotiated {
+        Ok(negotiated) => negotiated,
+        Err((status, reason)) => {
+            info!("connection websocket rejected: {}", reason);
+            return respond(&mut stream, status).await;
+        }
+    };
+
+    let mut resp = http::Response::builder()
+        .status(StatusCode::OK)
+        .body(())
+        .unwrap();
+    *resp.headers_mut() = headers;
+    if let Err(err) = stream.send_response(resp).await {
+        error!("connection websocket response failed: {}", err);
+        return;
+    }
+    debug!(
+        "connection websocket handshaked {:?} from {}",
+        req.uri(),
+        remote
+    );
+
+    let (local, pipe) = tokio::io::duplex(WEBSOCKET_PIPE_SIZE);
+    let ws_stream =
+        WebSocketStream::from_raw_socket(deflate::DeflateStream::new(local), Role::Server, None)
+            .await;
+    let session = websocket::serve(
+        server,
+        ws_stream,
+        remote,
+        &channel_name,
+        negotiated,
+        &config,
+        "websocket-h3",
+    );
+    future::join(session, pump(stream, pipe)).await;
+}
+
+/// Copies the bytes of a request stream to a pipe and back, until the
+/// WebSocket end of the pipe is dropped. Each direction is copied on its own,
+/// a full pipe does not hold the other one back.
+async fn pump<T>(stream: RequestStream<T, Bytes>, pipe: DuplexStream)
+where
+    T: BidiStream<Bytes>,
+{
+    let (mut send, mut recv) = stream.split();
+    let (mut pipe_read, mut pipe_write) = tokio::io::split(pipe);
+
+    let upload = async move {
+        loop {
+            match recv.recv_data().await {
+                Ok(Some(mut chunk)) => {
+                    while chunk.has_remaining() {
+                        let bytes = chunk.chunk();
+                        let len = bytes.len();
+                        if pipe_write.write_all(bytes).await.is_err() {
+                            return;
+                        }
+                        chunk.advance(len);
+                    }
+                }
+                Ok(None) => break,
+                Err(err) => {
+                    debug!("error on websocket request stream {}", err);
+                    break;
+                }
+            }
+        }
+        // The client stopped sending, the WebSocket still gets to answer
+        let _ = pipe_write.shutdown().await;
+    };
+    let download = async move {
+        let mut buffer = vec![0; WEBSOCKET_PIPE_SIZE];
+        while let Ok(len) = pipe_read.read(&mut buffer).await {
+            if len == 0
+                || send
+                    .send_data(Bytes::copy_from_slice(&buffer[..len]))
+                    .await
+                    .is_err()
+            {
+                break;
+            }
+        }
+        let _ = send.finish().await;
+    };
+
+    // The upload is dropped once the WebSocket is done
+    tokio::pin!(upload, download);
+    if let future::Either::Left(((), download)) = future::select(upload, download).await {
+        download.await;
+    }
+}
+
+/// Bidirectional stream opened by the client to carry the control protocol as
+/// newline delimited JSON messages.
+struct ControlStream<T> {
+    stream: RequestStream<T, Bytes>,
+    buffer: Vec<u8>,
+}
+
+impl<T> ControlStream<T>
+where
+    T: BidiStream<Bytes>,
+{
+    fn new(stream: RequestStream<T, Bytes>) -> Self {
+        Self {
+            stream,
+            buffer: Vec::new(),
+        }
+    }
+
+    async fn recv(&mut self) -> Result<Option<String>, anyhow::Error> {
+        loop {
+            if let Some(pos) = self.buffer.iter().position(|&b| b == b'\n') {
+                let line: Vec<u8> = self.buffer.drain(..=pos).collect();
+                return Ok(Some(String::from_utf8(line)?.trim_end().to_string()));
+            }
+            if self.buffer.len() > MAX_LINE_SIZE {
+                anyhow::bail!("control line too long");
+            }
+            match self.stream.recv_data().await? {
+                Some(mut chunk) => {
+                    while chunk.has_remaining() {
+                        let bytes = chunk.chunk();
+                        let len = bytes.len();
+                        self.buffer.extend_from_slice(bytes);
+                        chunk.advance(len);
+                    }
+                }
+                None => return Ok(None),
+            }
+        }
+    }
+
+    async fn send(&mut self, response: &control::Response) -> Result<(), anyhow::Error> {
+        let mut line = control::encode(response);
+        line.push('\n');
+        self.stream.send_data(Bytes::from(line)).await?;
+        Ok(())
+    }
+}
+
+async fn accept_control<C>(
+    h3_conn: &h3::server::Connection<C, Bytes>,
+    accepting: bool,
+) -> Result<Option<RequestStream<C::BidiStream, Bytes>>, h3::Error>
+where
+    C: h3::quic::Connection<Bytes>,
+{
+    if !accepting {
+        return future::pending().await;
+    }
+    h3_conn.accept_bi().await
+}
+
+async fn recv_control<T>(
+    control: &mut Option<ControlStream<T>>,
+) -> Result<Option<String>, anyhow::Error>
+where
+    T: BidiStream<Bytes>,
+{
+    match control {
+        Some(control) => control.recv().await,
+        None => future::pending().await,
+    }
+}
+
+async fn send_control<T>(control: &mut Option<ControlStream<T>>, response: &control::Response)
+where
+    T: BidiStream<Bytes>,
+{
+    match control {
+        Some(stream) => {
+            if let Err(err) = stream.send(response).await {
+                error!("error writing control stream {}", err);
+            }
+        }
+        None => debug!(
+            "control response dropped without control stream: {:?}",
+            response
+        ),
+    }
+}
@@ -0,0 +1,626 @@
+use std::net::SocketAddr;
+use std::sync::Arc;
+
+use async_trait::async_trait;
+use bytes::{Bytes, BytesMut};
+use futures_util::future;
+use futures_util::select;
+use futures_util::FutureExt;
+use h2::server::SendResponse;
+use http::{Request, Response, StatusCode};
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::sync::{mpsc, oneshot, watch};
+use tracing::*;
+
+use crate::control;
+use crate::framing;
+use crate::server;
+use crate::session;
+use crate::stats;
+use crate::transport;
+use crate::util;
+
+/// Capsule types (RFC 9297 and draft-ietf-webtrans-http2).
+const DATAGRAM: u64 = 0x00;
+const CLOSE_SESSION: u64 = 0x2843;
+const WT_RESET_STREAM: u64 = 0x190b4d39;
+const WT_STOP_SENDING: u64 = 0x190b4d3a;
+const WT_STREAM: u64 = 0x190b4d3b;
+const WT_STREAM_FIN: u64 = 0x190b4d3c;
+const WT_MAX_DATA: u64 = 0x190b4d3d;
+const WT_MAX_STREAM_DATA: u64 = 0x190b4d3e;
+const WT_MAX_STREAMS_BIDI: u64 = 0x190b4d3f;
+const WT_DATA_BLOCKED: u64 = 0x190b4d41;
+const WT_STREAM_DATA_BLOCKED: u64 = 0x190b4d42;
+const WT_STREAMS_BLOCKED_BIDI: u64 = 0x190b4d43;
+/// Largest varint, the flow control credit given to the client.
+const UNLIMITED: u64 = (1 << 62) - 1;
+/// Bidirectional streams the client may open at once.
+const MAX_STREAMS: u64 = 16;
+/// Largest capsule accepted, and longest control line.
+const MAX_CAPSULE_SIZE: usize = 16 * 1024 * 1024;
+const MAX_LINE_SIZE: usize = 64 * 1024;
+/// Capsules queued for the client, messages are dropped beyond.
+const SEND_QUEUE: usize = 256;
+
+/// WebTransport session over HTTP/2 (draft-ietf-webtrans-http2), for
+/// clients on networks blocking UDP. The session is an extended CONNECT
+/// (RFC 8441) to `/channels/{name}` whose stream carries capsules:
+///
+/// - DATAGRAM capsules are the messages, in both directions, like the
+///   datagrams of WebTransport over HTTP/3
+/// - the first bidirectional stream opened by the client carries the control
+///   protocol, as with HTTP/3, and other streams are refused
+///
+/// Flow control is left to HTTP/2, the client is given unlimited credit.
+pub struct WebTransportH2<S> {
+    server: Arc<std::sync::Mutex<server::Server>>,
+    stream: S,
+    remote: SocketAddr,
+    closed_tx: watch::Sender<bool>,
+    closed: watch::Receiver<bool>,
+}
+
+impl<S> WebTransportH2<S> {
+    pub fn new(
+        server: Arc<std::sync::Mutex<server::Server>>,
+        stream: S,
+        remote: SocketAddr,
+    ) -> Self {
+        let (closed_tx, closed) = watch::channel(false);
+        Self {
+            server,
+            stream,
+            remote,
+            closed_tx,
+            closed,
+        }
+    }
+}
+
+#[async_trait]
+impl<S> transport::Transport for WebTransportH2<S>
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    fn close(&self) {
+        let _ = self.closed_tx.send(true);
+    }
+
+    async fn process(self) -> Result<(), anyhow::Error> {
+        let remote = self.remote;
+        let mut closed = self.closed.clone();
+        let mut builder = h2::server::Builder::new();
+        builder.enable_connect_protocol();
+        let mut conn = match builder.handshake::<_, Bytes>(self.stream).await {
+            Ok(conn) => conn,
+            Err(err) => {
+                info!("http2 handshake from {} failed: {}", remote, err);
+                return Ok(());
+            }
+        };
+        info!("http2 connection established from {}", remote);
+
+        let (channel_name, mut body, send) = loop {
+            match conn.accept().await {
+                Some(Ok((request, respond))) => match accept(request, respond) {
+                    Ok(accepted) => break accepted,
+                    Err(err) => info!("http2 request from {} refused: {}", remote, err),
+                },
+                Some(Err(err)) => {
+                    info!("http2 connection from {} failed: {}", remote, err);
+                    return Ok(());
+                }
+                None => return Ok(()),
+            }
+        };
+
+        // The connection makes progress in its own task, the session may
+        // wait for send capacity
+        let (closing, shutdown) = oneshot::channel();
+        tokio::spawn(drive(conn, shutdown));
+        let (capsules, queue) = mpsc::channel(SEND_QUEUE);
+        tokio::spawn(write(send, queue));
+
+        let stats = self.server.lock().unwrap().stats.clone();
+        stats.record_connection();
+        let mut session = session::Session::new(self.server, &channel_name, remote).await;
+        info!("webtransport over http2 accepted: {}", session.channel());
+
+        let mut credit = BytesMut::new();
+        encode(&mut credit, WT_MAX_DATA, &varint(UNLIMITED));
+        encode(&mut credit, WT_MAX_STREAMS_BIDI, &varint(MAX_STREAMS));
+        let _ = capsules.send(credit.freeze()).await;
+
+        let mut state = State {
+            control: None,
+            next_stream: 0,
+            opened: 0,
+            buffer: Vec::new(),
+        };
+        let mut buffer = BytesMut::new();
+        let initiator = loop {
+            select! {
+                _ = closed.changed().fuse() => break stats::Initiator::Local,
+                chunk = body.data().fuse() => {
+                    let chunk = match chunk {
+                        Some(Ok(chunk)) => chunk,
+                        Some(Err(err)) => {
+                            info!("webtransport over http2 from {} failed: {}", remote, err);
+                            break stats::Initiator::Remote;
+                        }
+                        None => break stats::Initiator::Remote,
+                    };
+                    let _ = body.flow_control().release_capacity(chunk.len());
+                    buffer.extend_from_slice(&chunk);
+
+                    let mut closed = false;
+                    loop {
+                        let (kind, value) = match decode(&mut buffer) {
+                            Ok(Some(capsule)) => capsule,
+                            Ok(None) => break,
+                            Err(err) => {
+                                info!("webtransport over http2 from {} failed: {}", remote, err);
+                                closed = true;
+                                break;
+                            }
+                        };
+                        if kind == CLOSE_SESSION {
+                            closed = true;
+                            break;
+                        }
+                        for response in state.handle(&mut session, kind, value).await {
+                            let _ = capsules.send(response).await;
+                        }
+                    }
+                    if closed {
+                        break stats::Initiator::Remote;
+                    }
+                },
+                event = session.recv().fuse() => {
+                    match event {
+                        Some(session::Event::Message { channel, data, .. }) if *channel != *session.channel() => {
+                            // Datagrams carry no channel, the messages of the
+                            // other joined channels go in envelopes
+                            let envelope = framing::envelope(&channel, &data);
+                            if let Some(capsule) = state.control_capsule(&envelope) {
+                                let _ = capsules.send(capsule).await;
+                            }
+                        },
+                        Some(session::Event::Message { data, .. }) => {
+                            debug!("sent: {:#?}", data.len());
+
+                            let mut capsule = BytesMut::with_capacity(data.len() + 16);
+                            encode(&mut capsule, DATAGRAM, &data);
+                            if capsules.try_send(capsule.freeze()).is_err() {
+                                debug!("webtransport over http2 message dropped");
+                            }
+                        },
+                        Some(session::Event::Control(response)) => {
+                            if let Some(capsule) = state.control_capsule(&response) {
+                                let _ = capsules.send(capsule).await;
+                            }
+                        },
+                        None => break stats::Initiator::Local,
+                    }
+                }
+            }
+        };
+
+        stats.record_close("webtransport-h2", initiator, stats::CLOSE_ABNORMAL);
+        session.close().await;
+        drop(capsules);
+        let _ = closing.send(());
+        info!("webtransport over http2 finished");
+        Ok(())
+    }
+}
+
+/// Accepts a WebTransport CONNECT, answering the other requests with an
+/// error.
+fn accept(
+    request: Request<h2::RecvStream>,
+    mut respond: SendResponse<Bytes>,
+) -> Result<(String, h2::RecvStream, h2::SendStream<Bytes>), anyhow::Error> {
+    let is_webtransport = request.method() == http::Method::CONNECT
+        && request
+            .extensions()
+            .get::<h2::ext::Protocol>()
+            .is_some_and(|protocol| protocol.as_str() == "webtransport");
+    let channel = match util::parse_channel(request.uri().path()) {
+        Ok(channel) if is_webtransport => channel,
+        Ok(_) => {
+            reject(&mut respond, StatusCode::BAD_REQUEST);
+            anyhow::bail!("not a webtransport request")
+        }
+        Err(err) => {
+            reject(&mut respond, StatusCode::NOT_FOUND);
+            return Err(err);
+        }
+    };
+
+    let response = Response::builder().status(StatusCode::OK).body(())?;
+    let send = respond.send_response(response, false)?;
+    Ok((channel, request.into_body(), send))
+}
+
+fn reject(respond: &mut SendResponse<Bytes>, status: StatusCode) {
+    let response = Response::builder().status(status).body(()).unwrap();
+    let _ = respond.send_response(response, true);
+}
+
+/// Drives the connection, refusing requests after the session one, until
+/// told to close.
+async fn drive<T>(mut conn: h2::server::Connection<T, Bytes>, closed: oneshot::Receiver<()>)
+where
+    T: AsyncRead + AsyncWrite + Unpin,
+{
+    let mut closed = closed.fuse();
+    loop {
+        select! {
+            request = conn.accept().fuse() => {
+                match request {
+                    Some(Ok((_, mut respond))) => reject(&mut respond, StatusCode::TOO_MANY_REQUESTS),
+                    Some(Err(err)) => {
+                        debug!("http2 connection failed: {}", err);
+                        break;
+                    }
+                    None => break,
+                }
+            },
+            _ = closed => conn.graceful_shutdown(),
+        }
+    }
+}
+
+/// Writes capsules on the session stream as HTTP/2 flow control allows,
+/// and ends it when the session is over.
+async fn write(mut send: h2::SendStream<Bytes>, mut queue: mpsc::Receiver<Bytes>) {
+    while let Some(mut data) = queue.recv().await {
+        while !data.is_empty() {
+            send.reserve_capacity(data.len());
+            let capacity = match future::poll_fn(|cx| send.poll_capacity(cx)).await {
+                Some(Ok(capacity)) => capacity,
+                Some(Err(err)) => {
+                    debug!("webtransport over http2 write failed: {}", err);
+                    return;
+                }
+                None => return,
+            };
+            let chunk = data.split_to(capacity.min(data.len()));
+            if let Err(err) = send.send_data(chunk, false) {
+                debug!("webtransport over http2 write failed: {}", err);
+                return;
+            }
+        }
+    }
+    let _ = send.send_data(Bytes::new(), true);
+}
+
+/// Streams of the session.
+struct State {
+    /// Stream carrying the control protocol
+    control: Option<u64>,
+    /// Lowest id of a stream not opened yet by the client
+    next_stream: u64,
+    opened: u64,
+    buffer: Vec<u8>,
+}
+
+impl State {
+    /// Handles a capsule of the client, returning the capsules to send back.
+    async fn handle(
+        &mut self,
+        session: &mut session::Session,
+        kind: u64,
+        value: Bytes,
+    ) -> Vec<Bytes> {
+        let mut responses = Vec::new();
+        match kind {
+            DATAGRAM => {
+                debug!("received: {:#?}", value.len());
+
+                session.publish(value.to_vec());
+            }
+            WT_STREAM | WT_STREAM_FIN => {
+                let mut buf = &value[..];
+                let id = match util::read_varint(&mut buf) {
+                    Some(id) => id,
+                    None => return responses,
+                };
+                if id >= self.next_stream {
+                    responses.extend(self.open(id));
+                }
+                if Some(id) != self.control {
+                    return responses;
+                }
+
+                self.buffer.extend_from_slice(buf);
+                while let Some(pos) = self.buffer.iter().position(|&b| b == b'\n') {
+                    let line: Vec<u8> = self.buffer.drain(..=pos).collect();
+                    let line = String::from_utf8_lossy(&line).trim_end().to_string();
+                    if let Some(response) = session.handle_control(&line).await {
+                        responses.extend(self.control_capsule(&response));
+                    }
+                }
+                if self.buffer.len() > MAX_LINE_SIZE {
+                    info!("control line too long");
+                    responses.extend(self.close_control(id));
+                } else if kind == WT_STREAM_FIN {
+                    info!("connection control stream closed");
+                    self.control = None;
+                }
+            }
+            WT_RESET_STREAM | WT_STOP_SENDING => {
+                let id = util::read_varint(&mut &value[..]);
+                if id.is_some() && id == self.control {
+                    info!("connection control stream closed");
+                    self.control = None;
+                }
+            }
+            WT_DATA_BLOCKED => responses.push(capsule(WT_MAX_DATA, &varint(UNLIMITED))),
+            WT_STREAM_DATA_BLOCKED => {
+                if let Some(id) = util::read_varint(&mut &value[..]) {
+                    responses.push(stream_credit(id));
+                }
+            }
+            WT_STREAMS_BLOCKED_BIDI => {
+                responses.push(capsule(
+                    WT_MAX_STREAMS_BIDI,
+                    &varint(self.opened + MAX_STREAMS),
+                ));
+            }
+            kind => debug!("capsule {:#x} ignored", kind),
+        }
+        responses
+    }
+
+    /// Binds a new stream of the client to the control protocol, or refuses
+    /// it when it is not the control one.
+    fn open(&mut self, id: u64) -> Vec<Bytes> {
+        self.next_stream = id + 4;
+        self.opened += 1;
+        let mut responses = vec![capsule(
+            WT_MAX_STREAMS_BIDI,
+            &varint(self.opened + MAX_STREAMS),
+        )];
+        // Client initiated bidirectional streams only
+        if id & 0x3 == 0 && self.control.is_none() {
+            info!("connection control stream opened");
+            self.control = Some(id);
+            self.buffer.clear();
+            responses.push(stream_credit(id));
+        } else {
+            responses.extend(self.refuse(id));
+        }
+        responses
+    }
+
+    fn close_control(&mut self, id: u64) -> Vec<Bytes> {
+        self.control = None;
+        self.buffer.clear();
+        self.refuse(id)
+    }
+
+    fn refuse(&self, id: u64) -> Vec<Bytes> {
+        let mut value = BytesMut::new();
+        util::write_varint(&mut value, id);
+        util::write_varint(&mut value, 0);
+        let mut responses = vec![capsule(WT_STOP_SENDING, &value)];
+        if id & 0x3 == 0 {
+            responses.push(capsule(WT_RESET_STREAM, &value));
+        }
+        responses
+    }
+
+    fn control_capsule(&self, response: &control::Response) -> Option<Bytes> {
+        let id = match self.control {
+            Some(id) => id,
+            None => {
+                debug!(
+                    "control response dropped without control stream: {:?}",
+                    response
+                );
+                return None;
+            }
+        };
+        let mut line = control::encode(response);
+        line.push('\n');
+        let mut value = BytesMut::with_capacity(line.len() + 8);
+        util::write_varint(&mut value, id);
+        value.extend_from_slice(line.as_bytes());
+        Some(capsule(WT_STREAM, &value))
+    }
+}
+
+fn stream_credit(id: u64) -> Bytes {
+    let mut value = BytesMut::new();
+    util::write_varint(&mut value, id);
+    util::write_varint(&mut value, UNLIMITED);
+    capsule(WT_MAX_STREAM_DATA, &value)
+}
+
+fn varint(value: u64) -> BytesMut {
+    let mut buf = BytesMut::new();
+    util::write_varint(&mut buf, value);
+    buf
+}
+
+fn encode(buf: &mut BytesMut, kind: u64, value: &[u8]) {
+    util::write_varint(buf, kind);
+    util::write_varint(buf, value.len() as u64);
+    buf.extend_from_slice(value);
+}
+
+fn capsule(kind: u64, value: &[u8]) -> Bytes {
+    let mut buf = BytesMut::with_capacity(value.len() + 16);
+    encode(&mut buf, kind, value);
+    buf.freeze()
+}
+
+/// Takes the next complete capsule out of the buffer.
+fn decode(buffer: &mut BytesMut) -> Result<Option<(u64, Bytes)>, anyhow::Error> {
+    let mut buf = &buffer[..];
+    let (kind, length) = match (util::read_varint(&mut buf), util::read_varint(&mut buf)) {
+        (Some(kind), Some(length)) => (kind, length as usize),
+        _ => return Ok(None),
+    };
+    if length > MAX_CAPSULE_SIZE {
+        anyhow::bail!("capsule of {} bytes", length);
+    }
+    if buf.len() < length {
+        return Ok(None);
+    }
+    let header = buffer.len() - buf.len();
+    let _ = buffer.split_to(header);
+    Ok(Some((kind, buffer.split_to(length).freeze())))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use h2::client::SendRequest;
+    use http::Method;
+
+    fn request(method: Method, path: &str, protocol: Option<&'static str>) -> Request<()> {
+        let mut request = Request::builder()
+            .method(method)
+            .uri(format!("https://localhost{}", path));
+        if let Some(protocol) = protocol {
+            request = request.extension(h2::ext::Protocol::from_static(protocol));
+        }
+        request.body(()).unwrap()
+    }
+
+    async fn status(client: &mut SendRequest<Bytes>, request: Request<()>) -> StatusCode {
+        let (response, _) = client.send_request(request, true).unwrap();
+        response.await.unwrap().status()
+    }
+
+    /// Reads capsules from the session stream until one of a kind.
+    async fn next_capsule(body: &mut h2::RecvStream, buffer: &mut BytesMut, kind: u64) -> Bytes {
+        loop {
+            while let Some((found, value)) = decode(buffer).unwrap() {
+                if found == kind {
+                    return value;
+                }
+            }
+            let chunk = tokio::time::timeout(std::time::Duration::from_secs(2), body.data())
+                .await
+                .unwrap()
+                .unwrap()
+                .unwrap();
+            let _ = body.flow_control().release_capacity(chunk.len());
+            buffer.extend_from_slice(&chunk);
+        }
+    }
+
+    fn stream_capsule(id: u64, data: &[u8]) -> Bytes {
+        let mut value = varint(id);
+        value.extend_from_slice(data);
+        capsule(WT_STREAM, &value)
+    }
+
+    #[test]
+    fn capsules() {
+        let mut buffer = BytesMut::new();
+        encode(&mut buffer, DATAGRAM, b"hello");
+        encode(&mut buffer, WT_MAX_DATA, &varint(UNLIMITED));
+        assert_eq!(buffer[..3], [0x00, 0x05, b'h']);
+
+        // Incomplete capsules wait for more data
+        let mut partial = BytesMut::from(&buffer[..4]);
+        assert!(decode(&mut partial).unwrap().is_none());
+        assert_eq!(partial.len(), 4);
+        assert!(decode(&mut BytesMut::new()).unwrap().is_none());
+
+        let (kind, value) = decode(&mut buffer).unwrap().unwrap();
+        assert_eq!((kind, &value[..]), (DATAGRAM, &b"hello"[..]));
+        let (kind, value) = decode(&mut buffer).unwrap().unwrap();
+        assert_eq!(kind, WT_MAX_DATA);
+        assert_eq!(util::read_varint(&mut &value[..]), Some(UNLIMITED));
+        assert!(buffer.is_empty());
+
+        // Oversized capsules fail before being buffered
+        let mut oversized = BytesMut::new();
+        util::write_varint(&mut oversized, DATAGRAM);
+        util::write_varint(&mut oversized, MAX_CAPSULE_SIZE as u64 + 1);
+        assert!(decode(&mut oversized).is_err());
+    }
+
+    #[tokio::test]
+    async fn extended_connect() {
+        let server: server::ServerPtr = Default::default();
+        let remote: SocketAddr = "127.0.0.1:1".parse().unwrap();
+        let (client, stream) = tokio::io::duplex(64 * 1024);
+        let transport = WebTransportH2::new(server.clone(), stream, remote);
+        tokio::spawn(transport::Transport::process(transport));
+        let (client, conn) = h2::client::handshake(client).await.unwrap();
+        tokio::spawn(conn);
+        let mut client = client.ready().await.unwrap();
+
+        // Requests before the session are refused
+        let get = request(Method::GET, "/channels/demo", None);
+        assert_eq!(status(&mut client, get).await, StatusCode::BAD_REQUEST);
+        assert!(client.is_extended_connect_protocol_enabled());
+        let websocket = request(Method::CONNECT, "/channels/demo", Some("websocket"));
+        assert_eq!(
+            status(&mut client, websocket).await,
+            StatusCode::BAD_REQUEST
+        );
+        let no_channel = request(Method::CONNECT, "/demo", Some("webtransport"));
+        assert_eq!(status(&mut client, no_channel).await, StatusCode::NOT_FOUND);
+
+        let connect = request(Method::CONNECT, "/channels/demo", Some("webtransport"));
+        let (response, mut send) = client.send_request(connect, false).unwrap();
+        let response = response.await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let mut body = response.into_body();
+        let mut buffer = BytesMut::new();
+        next_capsule(&mut body, &mut buffer, WT_MAX_STREAMS_BIDI).await;
+
+        // And so are the requests after it
+        let other = request(Method::CONNECT, "/channels/other", Some("webtransport"));
+        assert_eq!(
+            status(&mut client, other).await,
+            StatusCode::TOO_MANY_REQUESTS
+        );
+
+        // Datagram capsules are the messages
+        let mut player = session::Session::new(server.clone(), "demo", remote).await;
+        send.send_data(capsule(DATAGRAM, b"hello"), false).unwrap();
+        loop {
+            let event = tokio::time::timeout(std::time::Duration::from_secs(2), player.recv())
+                .await
+                .unwrap();
+            if let Some(session::Event::Message { data, .. }) = event {
+                assert_eq!(data, b"hello");
+                break;
+            }
+        }
+        player.publish(b"world".to_vec());
+        let mut datagram = next_capsule(&mut body, &mut buffer, DATAGRAM).await;
+        while datagram[..] != b"world"[..] {
+            datagram = next_capsule(&mut body, &mut buffer, DATAGRAM).await;
+        }
+
+        // The first stream carries the control protocol, the next are refused
+        let ping = stream_capsule(0, b"{\"type\": \"ping\", \"id\": 1}\n");
+        send.send_data(ping, false).unwrap();
+        loop {
+            let value = next_capsule(&mut body, &mut buffer, WT_STREAM).await;
+            let mut line = &value[..];
+            assert_eq!(util::read_varint(&mut line), Some(0));
+            let message: serde_json::Value = serde_json::from_slice(line).unwrap();
+            if message["type"] == "pong" {
+                assert_eq!(message["id"], 1);
+                break;
+            }
+        }
+        send.send_data(stream_capsule(4, b"{}\n"), false).unwrap();
+        let refused = next_capsule(&mut body, &mut buffer, WT_STOP_SENDING).await;
+        assert_eq!(util::read_varint(&mut &refused[..]), Some(4));
+        let reset = next_capsule(&mut body, &mut buffer, WT_RESET_STREAM).await;
+        assert_eq!(util::read_varint(&mut &reset[..]), Some(4));
+    }
+}
@@ -1,34 +1,75 @@
-use hyper::service::{make_service_fn, service_fn};
+use hyper::server::conn::Http;
+use hyper::service::service_fn;
 use std::net::SocketAddr;
 use std::sync::Arc;
-use tokio::sync::Mutex;
+use std::time::Duration;
 
-use tokio::sync::broadcast::{self, Sender};
+use tokio::net::TcpListener;
+use tokio::sync::mpsc::Sender;
 
-use http::{Request, Response};
-use hyper::{server::Server, Body, Error};
-use tower::{make::Shared, ServiceBuilder};
+use http::{header, HeaderValue, Method, Request, Response, StatusCode};
+use hyper::{Body, Error};
+use serde::Deserialize;
 
 use tracing::*;
 
+use crate::auth;
 use crate::module::Message;
-use crate::{module, server};
+use crate::proxy;
+use crate::rtp_ingest;
+use crate::server;
+use crate::sse;
+use crate::webrtc::{rpc, sdp};
+
+const SDP: &str = "application/sdp";
+const TRICKLE_ICE_SDPFRAG: &str = "application/trickle-ice-sdpfrag";
+const JSON: &str = "application/json";
+/// WHEP extension choosing the simulcast layer sent to the player
+const LAYER_REL: &str = "urn:ietf:params:whep:ext:core:layer";
+/// Lifetime of the TURN credentials handed out with the sessions.
+const TURN_CREDENTIALS_TTL: Duration = Duration::from_secs(24 * 3600);
+/// Largest body of an SDP offer, a trickled fragment or a layer request.
+const MAX_BODY_SIZE: usize = 64 * 1024;
+
+#[derive(Clone)]
+pub struct Config {
+    pub listen: SocketAddr,
+    pub auth: auth::Config,
+    /// Proxies reporting the address of the clients, as for the WebSocket
+    pub proxy: proxy::Config,
+    /// Bearer token of the admin API, disabled without it
+    pub admin_token: Option<String>,
+    /// TURN servers announced with the credentials of the token secret
+    pub ice_servers: Vec<String>,
+    /// RTP ingest whose session description is PUT at `/rtp/{channel}`
+    pub rtp_ingest: Option<Arc<rtp_ingest::Ingest>>,
+}
+
+/// Layer asked for by a WHEP player, automatic without `encodingId`.
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct LayerRequest {
+    media_id: Option<String>,
+    encoding_id: Option<String>,
+}
 
 pub struct WhipModule {
     name: String,
     server: server::ServerPtr,
+    config: Config,
 }
 
 impl WhipModule {
-    pub fn new(server: server::ServerPtr) -> Self {
+    pub fn new(server: server::ServerPtr, config: Config) -> Self {
         Self {
             name: "whip".to_string(),
             server,
+            config,
         }
     }
 
     pub async fn start(self: WhipModule) -> anyhow::Result<()> {
-        info!("whip start");
+        info!("{} start", self.name);
 
         let webrtc = self
             .server
@@ -40,36 +81,62 @@ impl WhipModule {
             .clone();
         let guard = webrtc.lock().await;
         let commands = guard.commands.clone();
+        drop(guard);
 
-        let state = Arc::new(Mutex::new(commands));
+        let listener = TcpListener::bind(self.config.listen).await?;
+        info!("listening whip on {}", listener.local_addr()?);
 
-        let service = make_service_fn(move |_| {
-            let state = state.clone();
-            async move {
-                Ok::<_, Error>(service_fn(move |req| {
-                    let state = state.clone();
+        while let Ok((mut stream, peer)) = listener.accept().await {
+            let commands = commands.clone();
+            let config = self.config.clone();
+            let server = self.server.clone();
+            tokio::spawn(async move {
+                let peer = match config.proxy.accept(&mut stream, peer).await {
+                    Ok(peer) => peer,
+                    Err(err) => {
+                        error!("proxy protocol from {} failed: {}", peer, err);
+                        return;
+                    }
+                };
+                let service = service_fn(move |req: Request<Body>| {
+                    let commands = commands.clone();
+                    let config = config.clone();
+                    let server = server.clone();
                     async move {
-                        if req.uri().path() != "/" {
-                            return Ok::<_, Error>(Response::new(Body::from("Hello World")));
+                        let remote = config.proxy.client_addr(peer, req.headers());
+                        debug!("http {} {} from {}", req.method(), req.uri().path(), remote);
+                        let limiter = server.lock().unwrap().limiter.clone();
+                        if !limiter.check(remote.ip()) {
+                            debug!("http from {} rate limited", remote);
+                            let mut response = status(StatusCode::TOO_MANY_REQUESTS);
+                            cors(response.headers_mut());
+                            return Ok::<_, Error>(response);
                         }
-
-                        let (reply, _) = broadcast::channel::<Vec<u8>>(1);
-                        let _ = state.lock().await.send(module::Message {
-                            data: [].to_vec(),
-                            reply: reply.clone(),
-                        });
-                        let res = reply.subscribe().recv().await.unwrap();
-                        Ok::<_, Error>(Response::new(Body::from("Hello World")))
+                        if req.uri().path() == "/metrics" {
+                            return Ok::<_, Error>(metrics(&server, &req));
+                        }
+                        if req.uri().path() == "/admin/sessions" {
+                            return Ok::<_, Error>(sessions(&server, &config, &req));
+                        }
+                        // The HTTP fallback of the channels is served alongside
+                        let mut response = match sse::parse_path(req.uri().path()) {
+                            Some((channel, route)) => {
+                                sse::handle(server, &config.auth, remote, channel, route, req).await
+                            }
+                            None => match parse_ingest_path(req.uri().path()) {
+                                Some(channel) => ingest(&config, remote, channel, req).await,
+                                None => handle(commands, &config, req).await,
+                            },
+                        };
+                        cors(response.headers_mut());
+                        Ok::<_, Error>(response)
                     }
-                }))
-            }
-        });
-
-        let addr = SocketAddr::from(([127, 0, 0, 1], 8080));
-        Server::bind(&addr)
-            .serve(service)
-            .await
-            .expect("server error");
+                });
+                if let Err(err) = Http::new().serve_connection(stream, service).await {
+                    debug!("http connection from {} failed: {}", peer, err);
+                }
+            });
+        }
 
         Ok(())
     }
@@ -84,3 +151,562 @@ impl WhipModule {
         Ok(())
     }
 }
+
+/// `GET /metrics` returns the connection counters for Prometheus.
+fn metrics(server: &server::ServerPtr, req: &Request<Body>) -> Response<Body> {
+    if req.method() != Method::GET {
+        return status(StatusCode::METHOD_NOT_ALLOWED);
+    }
+    let stats = server.lock().unwrap().stats.clone();
+    Response::builder()
+        .status(StatusCode::OK)
+        .header(header::CONTENT_TYPE, "text/plain; version=0.0.4")
+        .body(Body::from(stats.render()))
+        .unwrap()
+}
+
+/// `GET /admin/sessions` lists the open sessions of every transport with the
+/// address of their client as JSON.
+fn sessions(server: &server::ServerPtr, config: &Config, req: &Request<Body>) -> Response<Body> {
+    let admin_token = match &config.admin_token {
+        Some(admin_token) => admin_token,
+        None => return status(StatusCode::NOT_FOUND),
+    };
+    let token = auth::bearer(
+        req.headers()
+            .get(header::AUTHORIZATION)
+            .and_then(|value| value.to_str().ok()),
+    );
+    if !token.is_some_and(|token| same(token.as_bytes(), admin_token.as_bytes())) {
+        return status(StatusCode::UNAUTHORIZED);
+    }
+    if req.method() != Method::GET {
+        return status(StatusCode::METHOD_NOT_ALLOWED);
+    }
+    let sessions = server.lock().unwrap().sessions();
+    Response::builder()
+        .status(StatusCode::OK)
+        .header(header::CONTENT_TYPE, JSON)
+        .body(Body::from(serde_json::to_vec(&sessions).unwrap()))
+        .unwrap()
+}
+
+/// Compares secrets in a time independent of where they differ.
+fn same(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |diff, (a, b)| diff | (a ^ b)) == 0
+}
+
+/// Routes of the RTP ingest:
+///
+/// - `PUT /rtp/{channel}` publishes the streams of an `application/sdp`
+///   session description to the channel, replacing the previous one. They
+///   are sent from the address of the request unless the `source` query
+///   parameter gives another one, and from any SSRC unless `ssrc` gives one
+/// - `DELETE /rtp/{channel}` stops publishing to the channel
+async fn ingest(
+    config: &Config,
+    remote: SocketAddr,
+    channel: String,
+    req: Request<Body>,
+) -> Response<Body> {
+    let ingest = match &config.rtp_ingest {
+        Some(ingest) => ingest,
+        None => return status(StatusCode::NOT_FOUND),
+    };
+    if req.method() == Method::OPTIONS {
+        return status(StatusCode::NO_CONTENT);
+    }
+
+    let token = auth::bearer(
+        req.headers()
+            .get(header::AUTHORIZATION)
+            .and_then(|value| value.to_str().ok()),
+    );
+    if let Err(err) = config.auth.verify(token, &channel) {
+        debug!("rtp ingest unauthorized for {}: {}", channel, err);
+        return status(StatusCode::UNAUTHORIZED);
+    }
+
+    match req.method().clone() {
+        Method::PUT => {
+            if !has_content_type(&req, SDP) {
+                return status(StatusCode::UNSUPPORTED_MEDIA_TYPE);
+            }
+            let source = match ingest_source(&req, remote) {
+                Ok(source) => source,
+                Err(err) => return error(StatusCode::BAD_REQUEST, err),
+            };
+            let description = match read_body(req).await {
+                Ok(description) => description,
+                Err(response) => return response,
+            };
+            let description = match rtp_ingest::Description::parse(&channel, source, &description) {
+                Ok(description) => description,
+                Err(err) => return error(StatusCode::BAD_REQUEST, err),
+            };
+            match ingest.set(description) {
+                Ok(()) => status(StatusCode::NO_CONTENT),
+                Err(err) => error(StatusCode::CONFLICT, err),
+            }
+        }
+        Method::DELETE => match ingest.clear(&channel) {
+            true => status(StatusCode::NO_CONTENT),
+            false => status(StatusCode::NOT_FOUND),
+        },
+        _ => status(StatusCode::METHOD_NOT_ALLOWED),
+    }
+}
+
+/// Source of the streams of an RTP ingest, from its query parameters.
+fn ingest_source(
+    req: &Request<Body>,
+    remote: SocketAddr,
+) -> Result<rtp_ingest::Source, anyhow::Error> {
+    let mut source = rtp_ingest::Source {
+        ip: remote.ip().to_canonical(),
+        port: None,
+        ssrc: None,
+    };
+    let query = req.uri().query().unwrap_or_default();
+    for (name, value) in form_urlencoded::parse(query.as_bytes()) {
+        match name.as_ref() {
+            "source" => {
+                let address = rtp_ingest::Source::parse(&value)?;
+                source.ip = address.ip;
+                source.port = address.port;
+            }
+            "ssrc" => source.ssrc = Some(value.parse()?),
+            _ => {}
+        }
+    }
+    Ok(source)
+}
+
+fn error(code: StatusCode, err: anyhow::Error) -> Response<Body> {
+    Response::builder()
+        .status(code)
+        .body(Body::from(err.to_string()))
+        .unwrap()
+}
+
+fn parse_ingest_path(path: &str) -> Option<String> {
+    let tokens: Vec<&str> = path.split('/').filter(|s| !s.is_empty()).collect();
+    match tokens.as_slice() {
+        ["rtp", channel] => Some(channel.to_string()),
+        _ => None,
+    }
+}
+
+/// Routes of the WHIP (RFC 9725) and WHEP endpoints and of the DataChannels
+/// one, which share the same resources:
+///
+/// - `POST /whip/{channel}`, `POST /whep/{channel}` and `POST /webrtc/{channel}`
+///   answer an offer
+/// - `PATCH /whip/{channel}/{id}` trickles candidates or restarts ICE
+/// - `GET /whip/{channel}/{id}` returns the stats of the session as JSON
+/// - `DELETE /whip/{channel}/{id}` tears the session down
+/// - `POST /whep/{channel}/{id}/layer` chooses the simulcast layers sent
+async fn handle(commands: Sender<Message>, config: &Config, req: Request<Body>) -> Response<Body> {
+    let (mode, channel, id, layer) = match parse_path(req.uri().path()) {
+        Some(path) => path,
+        None => return status(StatusCode::NOT_FOUND),
+    };
+
+    if req.method() == Method::OPTIONS {
+        let mut response = status(StatusCode::NO_CONTENT);
+        if id.is_none() {
+            response
+                .headers_mut()
+                .insert("Accept-Post", HeaderValue::from_static(SDP));
+        }
+        return response;
+    }
+    if layer && mode != rpc::Mode::Subscribe {
+        return status(StatusCode::NOT_FOUND);
+    }
+
+    let token = auth::bearer(
+        req.headers()
+            .get(header::AUTHORIZATION)
+            .and_then(|value| value.to_str().ok()),
+    );
+    if let Err(err) = config.auth.verify(token, &channel) {
+        debug!("whip unauthorized for {}: {}", channel, err);
+        return status(StatusCode::UNAUTHORIZED);
+    }
+
+    match (req.method().clone(), id) {
+        (Method::POST, Some(id)) if layer => select_layer(&commands, channel, id, req).await,
+        (_, Some(_)) if layer => status(StatusCode::METHOD_NOT_ALLOWED),
+        (Method::POST, None) => {
+            let mut response = create(&commands, channel, mode, req).await;
+            if response.status() == StatusCode::CREATED {
+                ice_servers(config, response.headers_mut());
+            }
+            response
+        }
+        (Method::PATCH, Some(id)) => update(&commands, channel, id, req).await,
+        (Method::GET, Some(id)) => {
+            let request = rpc::Request::GetStats { channel, id };
+            match call(&commands, &request).await {
+                Ok(rpc::Response::Stats(stats)) => Response::builder()
+                    .status(StatusCode::OK)
+                    .header(header::CONTENT_TYPE, JSON)
+                    .body(Body::from(serde_json::to_vec(&stats).unwrap()))
+                    .unwrap(),
+                Ok(response) => unexpected(response),
+                Err(response) => response,
+            }
+        }
+        (Method::DELETE, Some(id)) => {
+            let request = rpc::Request::DestroyTransport { channel, id };
+            match call(&commands, &request).await {
+                Ok(rpc::Response::Done) => status(StatusCode::OK),
+                Ok(response) => unexpected(response),
+                Err(response) => response,
+            }
+        }
+        _ => status(StatusCode::METHOD_NOT_ALLOWED),
+    }
+}
+
+async fn create(
+    commands: &Sender<Message>,
+    channel: String,
+    mode: rpc::Mode,
+    req: Request<Body>,
+) -> Response<Body> {
+    if !has_content_type(&req, SDP) {
+        return status(StatusCode::UNSUPPORTED_MEDIA_TYPE);
+    }
+    let path = req.uri().path().trim_end_matches('/').to_string();
+    let offer = match read_body(req).await {
+        Ok(offer) => offer,
+        Err(response) => return response,
+    };
+
+    let request = rpc::Request::CreateTransport {
+        channel,
+        mode,
+        offer,
+    };
+    match call(commands, &request).await {
+        Ok(rpc::Response::TransportCreated {
+            id,
+            ice_ufrag,
+            answer,
+        }) => {
+            info!("webrtc transport {} created", id);
+            let location = format!("{}/{}", path, id);
+            let mut response = Response::builder()
+                .status(StatusCode::CREATED)
+                .header(header::CONTENT_TYPE, SDP)
+                .header(header::LOCATION, &location)
+                .header(header::ETAG, etag(&ice_ufrag))
+                .header("Accept-Patch", TRICKLE_ICE_SDPFRAG);
+            if mode == rpc::Mode::Subscribe {
+                let link = format!("<{}/layer>; rel=\"{}\"", location, LAYER_REL);
+                response = response.header(header::LINK, link);
+            }
+            response.body(Body::from(answer)).unwrap()
+        }
+        Ok(rpc::Response::Error { message }) => Response::builder()
+            .status(StatusCode::BAD_REQUEST)
+            .body(Body::from(message))
+            .unwrap(),
+        Ok(response) => unexpected(response),
+        Err(response) => response,
+    }
+}
+
+/// Trickle ICE, or an ICE restart when `If-Match` is `*` (RFC 9725 4.3).
+async fn update(
+    commands: &Sender<Message>,
+    channel: String,
+    id: u64,
+    req: Request<Body>,
+) -> Response<Body> {
+    if !has_content_type(&req, TRICKLE_ICE_SDPFRAG) {
+        return status(StatusCode::UNSUPPORTED_MEDIA_TYPE);
+    }
+    let if_match = req
+        .headers()
+        .get(header::IF_MATCH)
+        .and_then(|value| value.to_str().ok())
+        .map(|value| value.trim().to_string());
+    let fragment = match read_body(req).await {
+        Ok(fragment) => sdp::Fragment::parse(&fragment),
+        Err(response) => return response,
+    };
+
+    let request = match (if_match.as_deref(), fragment.ice_ufrag, fragment.ice_pwd) {
+        (Some("*"), Some(ice_ufrag), Some(ice_pwd)) => rpc::Request::RestartIce {
+            channel,
+            id,
+            ice_ufrag,
+            ice_pwd,
+            candidates: fragment.candidates,
+        },
+        (Some("*"), _, _) => return status(StatusCode::BAD_REQUEST),
+        (if_match, _, _) => rpc::Request::AddRemoteCandidates {
+            channel,
+            id,
+            ice_ufrag: if_match.map(|etag| etag.trim_matches('"').to_string()),
+            candidates: fragment.candidates,
+        },
+    };
+
+    match call(commands, &request).await {
+        Ok(rpc::Response::Done) => status(StatusCode::NO_CONTENT),
+        Ok(rpc::Response::IceRestarted {
+            ice_ufrag,
+            fragment,
+        }) => Response::builder()
+            .status(StatusCode::OK)
+            .header(header::CONTENT_TYPE, TRICKLE_ICE_SDPFRAG)
+            .header(header::ETAG, etag(&ice_ufrag))
+            .body(Body::from(fragment))
+            .unwrap(),
+        Ok(rpc::Response::IceMismatch) => status(StatusCode::PRECONDITION_FAILED),
+        Ok(rpc::Response::Error { message }) => Response::builder()
+            .status(StatusCode::UNPROCESSABLE_ENTITY)
+            .body(Body::from(message))
+            .unwrap(),
+        Ok(response) => unexpected(response),
+        Err(response) => response,
+    }
+}
+
+async fn select_layer(
+    commands: &Sender<Message>,
+    channel: String,
+    id: u64,
+    req: Request<Body>,
+) -> Response<Body> {
+    if !has_content_type(&req, JSON) {
+        return status(StatusCode::UNSUPPORTED_MEDIA_TYPE);
+    }
+    let layer: LayerRequest = match read_body(req).await {
+        Ok(body) => match serde_json::from_str(&body) {
+            Ok(layer) => layer,
+            Err(err) => {
+                debug!("whep invalid layer request: {}", err);
+                return status(StatusCode::BAD_REQUEST);
+            }
+        },
+        Err(response) => return response,
+    };
+
+    let request = rpc::Request::SelectLayer {
+        channel,
+        id,
+        mid: layer.media_id,
+        encoding: layer.encoding_id,
+    };
+    match call(commands, &request).await {
+        Ok(rpc::Response::Done) => status(StatusCode::NO_CONTENT),
+        Ok(rpc::Response::Error { message }) => Response::builder()
+            .status(StatusCode::BAD_REQUEST)
+            .body(Body::from(message))
+            .unwrap(),
+        Ok(response) => unexpected(response),
+        Err(response) => response,
+    }
+}
+
+/// Calls the webrtc module, mapping the failures shared by every route.
+async fn call(
+    commands: &Sender<Message>,
+    request: &rpc::Request,
+) -> Result<rpc::Response, Response<Body>> {
+    match rpc::call(commands, request).await {
+        Ok(rpc::Response::UnknownTransport) => Err(status(StatusCode::NOT_FOUND)),
+        Ok(response) => Ok(response),
+        Err(err) => {
+            error!("webrtc command failed: {}", err);
+            Err(status(StatusCode::SERVICE_UNAVAILABLE))
+        }
+    }
+}
+
+fn unexpected(response: rpc::Response) -> Response<Body> {
+    error!("webrtc unexpected response: {:?}", response);
+    status(StatusCode::INTERNAL_SERVER_ERROR)
+}
+
+/// Reads a body of at most `MAX_BODY_SIZE` bytes, refusing larger ones
+/// from their `Content-Length` when they have one.
+async fn read_body(req: Request<Body>) -> Result<String, Response<Body>> {
+    let length = req
+        .headers()
+        .get(header::CONTENT_LENGTH)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.parse::<u64>().ok());
+    if length.is_some_and(|length| length > MAX_BODY_SIZE as u64) {
+        return Err(status(StatusCode::PAYLOAD_TOO_LARGE));
+    }
+    match sse::read_body(req.into_body(), MAX_BODY_SIZE).await {
+        Ok(Some(body)) => Ok(String::from_utf8_lossy(&body).into_owned()),
+        Ok(None) => Err(status(StatusCode::PAYLOAD_TOO_LARGE)),
+        Err(err) => {
+            debug!("whip body failed: {}", err);
+            Err(status(StatusCode::BAD_REQUEST))
+        }
+    }
+}
+
+fn has_content_type(req: &Request<Body>, expected: &str) -> bool {
+    req.headers()
+        .get(header::CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.split(';').next())
+        .is_some_and(|value| value.trim().eq_ignore_ascii_case(expected))
+}
+
+/// The ETag of a session identifies its ICE session by the local ufrag.
+fn etag(ice_ufrag: &str) -> String {
+    format!("\"{}\"", ice_ufrag)
+}
+
+/// Announces the TURN servers with fresh credentials (RFC 9725 4.6).
+fn ice_servers(config: &Config, headers: &mut http::HeaderMap) {
+    let (username, credential) = match config.auth.turn_credentials(TURN_CREDENTIALS_TTL) {
+        Some(credentials) => credentials,
+        None => return,
+    };
+    for url in &config.ice_servers {
+        let link = format!(
+            "<{}>; rel=\"ice-server\"; username=\"{}\"; credential=\"{}\"; credential-type=\"password\"",
+            url, username, credential
+        );
+        if let Ok(link) = HeaderValue::from_str(&link) {
+            headers.append(header::LINK, link);
+        }
+    }
+}
+
+fn cors(headers: &mut http::HeaderMap) {
+    headers.insert(
+        header::ACCESS_CONTROL_ALLOW_ORIGIN,
+        HeaderValue::from_static("*"),
+    );
+    headers.insert(
+        header::ACCESS_CONTROL_ALLOW_METHODS,
+        HeaderValue::from_static("GET, POST, PUT, PATCH, DELETE, OPTIONS"),
+    );
+    headers.insert(
+        header::ACCESS_CONTROL_ALLOW_HEADERS,
+        HeaderValue::from_static("Authorization, Content-Type, If-Match"),
+    );
+    headers.insert(
+        header::ACCESS_CONTROL_EXPOSE_HEADERS,
+        HeaderValue::from_static("Location, ETag, Link, Accept-Patch, Accept-Post"),
+    );
+}
+
+/// Mode, channel and session of a path, and whether it is the layer
+/// resource of the session.
+fn parse_path(path: &str) -> Option<(rpc::Mode, String, Option<u64>, bool)> {
+    let tokens: Vec<&str> = path.split('/').filter(|s| !s.is_empty()).collect();
+    let (endpoint, channel, id, layer) = match tokens.as_slice() {
+        [endpoint, channel] => (*endpoint, channel.to_string(), None, false),
+        [endpoint, channel, id] => (
+            *endpoint,
+            channel.to_string(),
+            Some(id.parse().ok()?),
+            false,
+        ),
+        [endpoint, channel, id, "layer"] => {
+            (*endpoint, channel.to_string(), Some(id.parse().ok()?), true)
+        }
+        _ => return None,
+    };
+    let mode = match endpoint {
+        "whip" | "webrtc" => rpc::Mode::Publish,
+        "whep" => rpc::Mode::Subscribe,
+        _ => return None,
+    };
+    Some((mode, channel, id, layer))
+}
+
+fn status(status: StatusCode) -> Response<Body> {
+    Response::builder()
+        .status(status)
+        .body(Body::empty())
+        .unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn admin_request(token: Option<&str>) -> Request<Body> {
+        let mut req = Request::new(Body::empty());
+        if let Some(token) = token {
+            let value = format!("Bearer {}", token);
+            req.headers_mut()
+                .insert(header::AUTHORIZATION, value.parse().unwrap());
+        }
+        req
+    }
+
+    #[tokio::test]
+    async fn admin_sessions() {
+        let server: server::ServerPtr = Default::default();
+        let mut config = Config {
+            listen: "127.0.0.1:0".parse().unwrap(),
+            auth: auth::Config::new(None),
+            proxy: proxy::Config::default(),
+            admin_token: None,
+            ice_servers: Vec::new(),
+            rtp_ingest: None,
+        };
+        let response = sessions(&server, &config, &admin_request(Some("admin")));
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+
+        config.admin_token = Some("admin".to_string());
+        let response = sessions(&server, &config, &admin_request(None));
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+        let response = sessions(&server, &config, &admin_request(Some("admim")));
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+
+        let remote: SocketAddr = "203.0.113.9:4711".parse().unwrap();
+        let session = crate::session::Session::new(server.clone(), "demo", remote).await;
+        let response = sessions(&server, &config, &admin_request(Some("admin")));
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        let listed: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(listed[0]["id"], session.id);
+        assert_eq!(listed[0]["channel"], "demo");
+        assert_eq!(listed[0]["remote"], "203.0.113.9:4711");
+
+        // Closed sessions are no longer listed
+        session.close().await;
+        let response = sessions(&server, &config, &admin_request(Some("admin")));
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        assert_eq!(&body[..], b"[]");
+    }
+
+    #[tokio::test]
+    async fn body_limit() {
+        let offer = Request::new(Body::from("v=0\r\n"));
+        assert_eq!(read_body(offer).await.unwrap(), "v=0\r\n");
+
+        let body = Request::new(Body::from(vec![b'a'; MAX_BODY_SIZE]));
+        assert_eq!(read_body(body).await.unwrap().len(), MAX_BODY_SIZE);
+
+        // Chunked bodies are cut once over the limit
+        let (mut sender, body) = Body::channel();
+        tokio::spawn(
+            async move { while sender.send_data(vec![b'a'; 1024].into()).await.is_ok() {} },
+        );
+        let response = read_body(Request::new(body)).await.unwrap_err();
+        assert_eq!(response.status(), StatusCode::PAYLOAD_TOO_LARGE);
+
+        let mut body = Request::new(Body::empty());
+        body.headers_mut()
+            .insert(header::CONTENT_LENGTH, HeaderValue::from(MAX_BODY_SIZE + 1));
+        let response = read_body(body).await.unwrap_err();
+        assert_eq!(response.status(), StatusCode::PAYLOAD_TOO_LARGE);
+    }
+}